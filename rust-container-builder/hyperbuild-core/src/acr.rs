@@ -0,0 +1,205 @@
+use crate::registry_auth::Credentials;
+use anyhow::Result;
+use serde::Deserialize;
+use std::time::Duration;
+
+// Azure Container Registry: registries at <name>.azurecr.io exchange an
+// Azure AD access token for a refresh token, which the registry's token
+// endpoint then takes as a password to hand out scoped access tokens. The
+// AD token comes from a service principal in the environment, workload
+// identity, the Azure CLI's login, or the managed identity of the machine.
+
+// The user refresh tokens go with, as `az acr login` uses
+const REFRESH_TOKEN_USER: &str = "00000000-0000-0000-0000-000000000000";
+const RESOURCE: &str = "https://management.azure.com/";
+const AUTHORITY_HOST: &str = "https://login.microsoftonline.com";
+const IMDS_ENDPOINT: &str = "http://169.254.169.254";
+
+// Whether a registry host is an Azure Container Registry, in the public or a
+// national cloud
+pub fn is_registry(host: &str) -> bool {
+    let host = host.split(':').next().unwrap_or(host);
+    [".azurecr.io", ".azurecr.cn", ".azurecr.us"]
+        .iter()
+        .any(|suffix| host.strip_suffix(suffix).is_some_and(|name| !name.is_empty()))
+}
+
+#[derive(Deserialize)]
+struct AdToken {
+    access_token: String,
+}
+
+// What `az account get-access-token` prints
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CliToken {
+    access_token: String,
+    tenant: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ExchangeResponse {
+    refresh_token: String,
+}
+
+// Credentials for the registry at `host`: the refresh token it gives for an
+// Azure AD token, and how long it is valid
+pub async fn login(client: &reqwest::Client, host: &str) -> Result<(Credentials, Duration)> {
+    let (access_token, tenant) = ad_token(client).await?;
+    let url = format!("https://{}/oauth2/exchange", host);
+    let mut form = vec![
+        ("grant_type", "access_token".to_string()),
+        ("service", host.to_string()),
+        ("access_token", access_token),
+    ];
+    if let Some(tenant) = tenant {
+        form.push(("tenant", tenant));
+    }
+    let response = client.post(&url).form(&form).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+        return Err(anyhow::anyhow!(
+            "Failed to exchange an Azure AD token for a refresh token of {}: {} - {}",
+            host,
+            status,
+            error_text
+        ));
+    }
+    let exchanged: ExchangeResponse = serde_json::from_slice(&response.bytes().await?)
+        .map_err(|e| anyhow::anyhow!("Invalid token exchange response from {}: {}", host, e))?;
+    tracing::info!("Got an ACR refresh token for {}", host);
+
+    // Refresh tokens are JWTs, valid for about three hours
+    let lifetime = jwt_expiry(&exchanged.refresh_token)
+        .map(|expiry| Duration::from_secs((expiry - chrono::Utc::now().timestamp()).max(0) as u64))
+        .unwrap_or(Duration::from_secs(3 * 60 * 60));
+    let credentials = Credentials {
+        username: REFRESH_TOKEN_USER.to_string(),
+        password: exchanged.refresh_token,
+    };
+    Ok((credentials, lifetime))
+}
+
+// An Azure AD access token for Azure Resource Manager, and the tenant it is
+// from when known, from the first source that has one
+async fn ad_token(client: &reqwest::Client) -> Result<(String, Option<String>)> {
+    let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+    let authority = env("AZURE_AUTHORITY_HOST").unwrap_or_else(|| AUTHORITY_HOST.to_string());
+
+    // A service principal's secret, or a federated token for workload identity
+    if let (Some(tenant), Some(client_id)) = (env("AZURE_TENANT_ID"), env("AZURE_CLIENT_ID")) {
+        let credential = if let Some(secret) = env("AZURE_CLIENT_SECRET") {
+            Some(("client_secret", secret))
+        } else if let Some(path) = env("AZURE_FEDERATED_TOKEN_FILE") {
+            let assertion = std::fs::read_to_string(&path)
+                .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path, e))?;
+            Some(("client_assertion", assertion.trim().to_string()))
+        } else {
+            None
+        };
+        if let Some((kind, value)) = credential {
+            let url = format!("{}/{}/oauth2/v2.0/token", authority.trim_end_matches('/'), tenant);
+            let scope = format!("{}.default", RESOURCE);
+            let mut form = vec![
+                ("grant_type", "client_credentials".to_string()),
+                ("client_id", client_id),
+                ("scope", scope),
+                (kind, value),
+            ];
+            if kind == "client_assertion" {
+                form.push((
+                    "client_assertion_type",
+                    "urn:ietf:params:oauth:client-assertion-type:jwt-bearer".to_string(),
+                ));
+            }
+            let token: AdToken = fetch_token(client.post(&url).form(&form), "Azure AD").await?;
+            return Ok((token.access_token, Some(tenant)));
+        }
+    }
+
+    // The Azure CLI's login, as `az login` left it
+    let cli = tokio::process::Command::new("az")
+        .args(["account", "get-access-token", "--resource", RESOURCE, "--output", "json"])
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .output()
+        .await;
+    if let Ok(output) = cli
+        && output.status.success()
+    {
+        let token: CliToken = serde_json::from_slice(&output.stdout)
+            .map_err(|e| anyhow::anyhow!("Invalid output from az account get-access-token: {}", e))?;
+        return Ok((token.access_token, token.tenant));
+    }
+
+    // The managed identity of an Azure VM, or of a pipeline agent on one
+    let imds = reqwest::Client::builder()
+        .user_agent(crate::registry_client::USER_AGENT)
+        .no_proxy()
+        .connect_timeout(Duration::from_secs(1))
+        .timeout(Duration::from_secs(5))
+        .build()?;
+    let endpoint = env("AZURE_IMDS_ENDPOINT").unwrap_or_else(|| IMDS_ENDPOINT.to_string());
+    let mut query = vec![("api-version", "2018-02-01".to_string()), ("resource", RESOURCE.to_string())];
+    if let Some(client_id) = env("AZURE_CLIENT_ID") {
+        query.push(("client_id", client_id));
+    }
+    let request = imds
+        .get(format!("{}/metadata/identity/oauth2/token", endpoint.trim_end_matches('/')))
+        .query(&query)
+        .header("metadata", "true");
+    let token: AdToken = fetch_token(request, "the managed identity endpoint").await.map_err(|e| {
+        anyhow::anyhow!(
+            "No Azure credentials found: set AZURE_TENANT_ID, AZURE_CLIENT_ID and AZURE_CLIENT_SECRET, run az login, \
+             or run with a managed identity ({})",
+            e
+        )
+    })?;
+    Ok((token.access_token, None))
+}
+
+async fn fetch_token<T: serde::de::DeserializeOwned>(request: reqwest::RequestBuilder, source: &str) -> Result<T> {
+    let response = request.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+        return Err(anyhow::anyhow!("Failed to get an access token from {}: {} - {}", source, status, error_text));
+    }
+    serde_json::from_slice(&response.bytes().await?)
+        .map_err(|e| anyhow::anyhow!("Invalid access token response from {}: {}", source, e))
+}
+
+// The exp claim of a JWT, in seconds since the epoch
+fn jwt_expiry(token: &str) -> Option<i64> {
+    let payload = token.split('.').nth(1)?;
+    let mut padded = payload.replace('-', "+").replace('_', "/");
+    while !padded.len().is_multiple_of(4) {
+        padded.push('=');
+    }
+    let claims: serde_json::Value = serde_json::from_slice(&openssl::base64::decode_block(&padded).ok()?).ok()?;
+    claims["exp"].as_i64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registries_and_tokens() {
+        assert!(is_registry("myregistry.azurecr.io"));
+        assert!(is_registry("myregistry.azurecr.cn:443"));
+        assert!(!is_registry("azurecr.io"));
+        assert!(!is_registry("myregistry.azurecr.io.example.com"));
+
+        let claims = openssl::base64::encode_block(br#"{"exp":1700000000,"grant_type":"refresh_token"}"#);
+        let token = format!("eyJhbGciOiJSUzI1NiJ9.{}.c2ln", claims.trim_end_matches('='));
+        assert_eq!(jwt_expiry(&token), Some(1_700_000_000));
+        assert_eq!(jwt_expiry("opaque"), None);
+
+        let cli = r#"{"accessToken": "ey...", "expiresOn": "2026-10-16 15:00:00.000000", "tenant": "t1",
+                      "tokenType": "Bearer"}"#;
+        let token: CliToken = serde_json::from_str(cli).unwrap();
+        assert_eq!(token.tenant.as_deref(), Some("t1"));
+    }
+}
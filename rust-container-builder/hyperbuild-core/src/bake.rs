@@ -0,0 +1,479 @@
+// Bake files: build targets with groups, inheritance and matrices, in HCL
+// (docker-bake.hcl) or JSON (docker-bake.json), in the style of buildx bake.
+// Targets build through the same scheduler as Compose services.
+use crate::compose::ServiceBuild;
+use crate::platform::Platform;
+use crate::reference::Reference;
+use anyhow::Result;
+use hcl::eval::{Context, Evaluate};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+// The files `bake` reads when -f is not given: every one of them that exists,
+// later ones overriding earlier ones
+pub const DEFAULT_FILES: [&str; 4] =
+    ["docker-bake.json", "docker-bake.override.json", "docker-bake.hcl", "docker-bake.override.hcl"];
+
+// A target after inheritance and matrix expansion
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct BakeTarget {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dockerfile: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub args: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub platforms: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_cache: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pull: bool,
+    // Read only to reject them with a clear error
+    #[serde(skip_serializing)]
+    target: Option<String>,
+    #[serde(skip_serializing)]
+    dockerfile_inline: Option<String>,
+}
+
+impl BakeTarget {
+    // What to build for the target `name`. The image is named by its first
+    // tag, or by the target's name when it has none.
+    pub fn build(&self, name: &str) -> Result<ServiceBuild> {
+        let invalid = |why: &str| anyhow::anyhow!("Target {}: {}", name, why);
+        if self.target.is_some() {
+            return Err(invalid("target is not supported; hyperbuild builds a Dockerfile's last stage"));
+        }
+        if self.dockerfile_inline.is_some() {
+            return Err(invalid("dockerfile-inline is not supported; put the Dockerfile in a file"));
+        }
+        // As with buildx, the context is relative to the current directory
+        // and the Dockerfile to the context
+        let context = PathBuf::from(self.context.as_deref().unwrap_or("."));
+        if context.to_string_lossy().contains("://") || context.starts_with("git@") {
+            return Err(invalid("remote build contexts are not supported"));
+        }
+        let dockerfile = context.join(self.dockerfile.as_deref().unwrap_or("Dockerfile"));
+        let (image, tags) = match self.tags.split_first() {
+            Some((image, tags)) => (image.clone(), tags.to_vec()),
+            None => (name.to_string(), Vec::new()),
+        };
+        Reference::parse(&image).map_err(|e| invalid(&format!("{} (image {:?})", e, image)))?;
+        Ok(ServiceBuild {
+            service: name.to_string(),
+            image,
+            context,
+            dockerfile,
+            args: self.args.clone().into_iter().collect(),
+            labels: self.labels.clone(),
+            tags,
+            platforms: self
+                .platforms
+                .iter()
+                .map(|spec| Platform::parse(spec))
+                .collect::<Result<Vec<_>>>()
+                .map_err(|e| invalid(&e.to_string()))?,
+            no_cache: self.no_cache,
+            pull: self.pull,
+            depends_on: Vec::new(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BakeFile {
+    #[serde(rename = "group")]
+    pub groups: BTreeMap<String, Vec<String>>,
+    #[serde(rename = "target")]
+    pub targets: BTreeMap<String, BakeTarget>,
+    // The targets a matrix target expanded to, by the matrix target's name
+    #[serde(skip)]
+    matrices: BTreeMap<String, Vec<String>>,
+}
+
+// A target's attributes, evaluated but not yet merged with what it inherits
+#[derive(Debug)]
+struct RawTarget {
+    inherits: Vec<String>,
+    attributes: Map<String, Value>,
+}
+
+impl BakeFile {
+    // Read and merge bake files in order. Variables take their value from
+    // `env` when set there.
+    pub fn load(paths: &[PathBuf], env: &HashMap<String, String>) -> Result<Self> {
+        let mut sources = Vec::new();
+        for path in paths {
+            let contents =
+                std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Failed to read {:?}: {}", path, e))?;
+            sources.push((path.clone(), contents));
+        }
+        let sources: Vec<(&Path, &str)> =
+            sources.iter().map(|(path, contents)| (path.as_path(), contents.as_str())).collect();
+        Self::parse(&sources, env)
+    }
+
+    pub fn parse(sources: &[(&Path, &str)], env: &HashMap<String, String>) -> Result<Self> {
+        let bodies = sources
+            .iter()
+            .map(|(path, contents)| {
+                let body = if path.extension().is_some_and(|extension| extension == "json") {
+                    json_body(contents)
+                } else {
+                    hcl::parse(contents).map_err(anyhow::Error::from)
+                };
+                body.map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Variables first, since every group and target may use them
+        let mut ctx = Context::new();
+        for body in &bodies {
+            for block in body.blocks().filter(|block| block.identifier() == "variable") {
+                let name = block_name(block)?;
+                let default = match block.body().attributes().find(|attribute| attribute.key() == "default") {
+                    Some(attribute) => evaluate(&attribute.expr, &ctx)
+                        .map_err(|e| anyhow::anyhow!("Variable {}: {}", name, e))?,
+                    None => hcl::Value::from(""),
+                };
+                let value = match env.get(name) {
+                    // A number or boolean default makes the variable one too
+                    Some(value) if !default.is_string() => serde_json::from_str::<Value>(value)
+                        .ok()
+                        .and_then(|value| hcl::to_value(value).ok())
+                        .unwrap_or_else(|| hcl::Value::from(value.as_str())),
+                    Some(value) => hcl::Value::from(value.as_str()),
+                    None => default,
+                };
+                ctx.declare_var(name, value);
+            }
+        }
+
+        let mut bake = BakeFile::default();
+        // Blocks for the same target in several files merge, later ones
+        // overriding the attributes they set
+        let mut blocks: BTreeMap<&str, Vec<&hcl::Block>> = BTreeMap::new();
+        for body in &bodies {
+            for block in body.blocks() {
+                let name = block_name(block)?;
+                match block.identifier() {
+                    "variable" => {}
+                    "group" => {
+                        let mut targets = Vec::new();
+                        for attribute in block.body().attributes() {
+                            let value = evaluate(&attribute.expr, &ctx)
+                                .map_err(|e| anyhow::anyhow!("Group {}: {}", name, e))?;
+                            match attribute.key() {
+                                "targets" => targets = hcl::from_value::<Vec<String>>(value)?,
+                                "description" => {}
+                                key => return Err(anyhow::anyhow!("Group {}: unknown attribute {}", name, key)),
+                            }
+                        }
+                        let group = bake.groups.entry(name.to_string()).or_default();
+                        for target in targets {
+                            if !group.contains(&target) {
+                                group.push(target);
+                            }
+                        }
+                    }
+                    "target" => blocks.entry(name).or_default().push(block),
+                    other => return Err(anyhow::anyhow!("Unknown block type {:?}", other)),
+                }
+            }
+        }
+
+        let mut raw: BTreeMap<String, RawTarget> = BTreeMap::new();
+        for (name, blocks) in blocks {
+            for (expanded, target) in expand_target(name, &blocks, &ctx)? {
+                if expanded != name {
+                    bake.matrices.entry(name.to_string()).or_default().push(expanded.clone());
+                }
+                if raw.insert(expanded.clone(), target).is_some() {
+                    return Err(anyhow::anyhow!("Target {} is defined twice", expanded));
+                }
+            }
+        }
+
+        for name in raw.keys() {
+            let attributes = inherit(name, &raw, &mut Vec::new())?;
+            let target = serde_json::from_value(Value::Object(attributes))
+                .map_err(|e| anyhow::anyhow!("Target {}: {}", name, e))?;
+            bake.targets.insert(name.clone(), target);
+        }
+        Ok(bake)
+    }
+
+    // The targets the given targets and groups stand for, in order and each
+    // once; the "default" group or target when none are given
+    pub fn resolve(&self, names: &[String]) -> Result<Vec<String>> {
+        let default = [String::from("default")];
+        let names = if names.is_empty() { &default[..] } else { names };
+        let mut targets = Vec::new();
+        let mut pending: Vec<(String, Vec<String>)> =
+            names.iter().rev().map(|name| (name.clone(), Vec::new())).collect();
+        while let Some((name, path)) = pending.pop() {
+            if path.contains(&name) {
+                return Err(anyhow::anyhow!("Group {} includes itself through {}", name, path.join(" -> ")));
+            }
+            if let Some(members) = self.groups.get(&name).or(self.matrices.get(&name)) {
+                let path: Vec<String> = path.iter().cloned().chain(std::iter::once(name.clone())).collect();
+                pending.extend(members.iter().rev().map(|member| (member.clone(), path.clone())));
+            } else if self.targets.contains_key(&name) {
+                if !targets.contains(&name) {
+                    targets.push(name);
+                }
+            } else if let Some(group) = path.last() {
+                return Err(anyhow::anyhow!("Group {} names unknown target {:?}", group, name));
+            } else if names == default {
+                return Err(anyhow::anyhow!("No \"default\" group or target; name the targets to build"));
+            } else {
+                return Err(anyhow::anyhow!("No target or group {:?}", name));
+            }
+        }
+        Ok(targets)
+    }
+}
+
+// Evaluate a target's blocks, once per combination of its matrix values when
+// it has a matrix
+fn expand_target(name: &str, blocks: &[&hcl::Block], ctx: &Context) -> Result<Vec<(String, RawTarget)>> {
+    let attribute = |key: &str| {
+        blocks.iter().rev().find_map(|block| block.body().attributes().find(|attribute| attribute.key() == key))
+    };
+    let invalid = |e: anyhow::Error| anyhow::anyhow!("Target {}: {}", name, e);
+    let mut combinations: Vec<Vec<(String, hcl::Value)>> = vec![Vec::new()];
+    if let Some(matrix) = attribute("matrix") {
+        let matrix = evaluate(&matrix.expr, ctx).map_err(invalid)?;
+        let matrix = hcl::from_value::<BTreeMap<String, Vec<hcl::Value>>>(matrix)
+            .map_err(|e| invalid(anyhow::anyhow!("matrix must map names to lists: {}", e)))?;
+        for (variable, values) in &matrix {
+            combinations = combinations
+                .into_iter()
+                .flat_map(|combination| {
+                    values.iter().map(move |value| {
+                        let mut combination = combination.clone();
+                        combination.push((variable.clone(), value.clone()));
+                        combination
+                    })
+                })
+                .collect();
+        }
+        if attribute("name").is_none() {
+            return Err(invalid(anyhow::anyhow!("a matrix target needs a name, e.g. name = \"{}-${{item}}\"", name)));
+        }
+    }
+
+    let mut targets = Vec::new();
+    for combination in combinations {
+        let mut ctx = ctx.clone();
+        for (variable, value) in combination {
+            ctx.declare_var(variable, value);
+        }
+        let mut expanded = name.to_string();
+        let mut target = RawTarget { inherits: Vec::new(), attributes: Map::new() };
+        for block in blocks {
+            let mut attributes = Map::new();
+            for attribute in block.body().attributes() {
+                let value = evaluate(&attribute.expr, &ctx).map_err(invalid)?;
+                match attribute.key() {
+                    "matrix" => {}
+                    "name" => expanded = hcl::from_value(value).map_err(|e| invalid(e.into()))?,
+                    "inherits" => target.inherits = hcl::from_value(value).map_err(|e| invalid(e.into()))?,
+                    key => {
+                        let value = serde_json::to_value(&value)?;
+                        let value = if key == "args" || key == "labels" { stringify(value) } else { value };
+                        attributes.insert(key.to_string(), value);
+                    }
+                }
+            }
+            merge(&mut target.attributes, attributes);
+        }
+        if expanded.is_empty() || !expanded.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(invalid(anyhow::anyhow!("{:?} is not a valid target name", expanded)));
+        }
+        targets.push((expanded, target));
+    }
+    Ok(targets)
+}
+
+// A target's attributes over those of the targets it inherits, in order
+fn inherit(name: &str, raw: &BTreeMap<String, RawTarget>, path: &mut Vec<String>) -> Result<Map<String, Value>> {
+    if path.iter().any(|seen| seen == name) {
+        return Err(anyhow::anyhow!("Target {} inherits from itself through {}", name, path.join(" -> ")));
+    }
+    let target = raw.get(name).ok_or_else(|| match path.last() {
+        Some(child) => anyhow::anyhow!("Target {} inherits from unknown target {}", child, name),
+        None => anyhow::anyhow!("No target {:?}", name),
+    })?;
+    path.push(name.to_string());
+    let mut attributes = Map::new();
+    for parent in &target.inherits {
+        merge(&mut attributes, inherit(parent, raw, path)?);
+    }
+    path.pop();
+    merge(&mut attributes, target.attributes.clone());
+    Ok(attributes)
+}
+
+// Overlay attributes: args and labels merge key by key, the rest replace
+fn merge(attributes: &mut Map<String, Value>, overrides: Map<String, Value>) {
+    for (key, value) in overrides {
+        match (attributes.get_mut(&key), value) {
+            (Some(Value::Object(existing)), Value::Object(entries)) if key == "args" || key == "labels" => {
+                existing.extend(entries);
+            }
+            (_, value) => {
+                attributes.insert(key, value);
+            }
+        }
+    }
+}
+
+// Numbers and booleans in args and labels are taken as strings; null drops an
+// inherited entry
+fn stringify(value: Value) -> Value {
+    match value {
+        Value::Object(entries) => Value::Object(
+            entries
+                .into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| match value {
+                    Value::Number(number) => (key, Value::String(number.to_string())),
+                    Value::Bool(flag) => (key, Value::String(flag.to_string())),
+                    value => (key, value),
+                })
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+fn evaluate(expr: &hcl::Expression, ctx: &Context) -> Result<hcl::Value> {
+    expr.evaluate(ctx).map_err(|e| anyhow::anyhow!("{}", e))
+}
+
+fn block_name(block: &hcl::Block) -> Result<&str> {
+    match block.labels() {
+        [label] => Ok(label.as_str()),
+        _ => Err(anyhow::anyhow!("A {} block needs exactly one name", block.identifier())),
+    }
+}
+
+// The JSON form nests names under each block type, e.g.
+// {"target": {"app": {"tags": ["app:${TAG}"]}}}; strings are templates, as in
+// HCL's JSON syntax
+fn json_body(contents: &str) -> Result<hcl::Body> {
+    let file: BTreeMap<String, BTreeMap<String, Map<String, Value>>> = serde_json::from_str(contents)?;
+    let mut body = hcl::Body::builder();
+    for (kind, blocks) in file {
+        for (name, attributes) in blocks {
+            let attributes =
+                attributes.into_iter().map(|(key, value)| hcl::Attribute::new(key, json_expression(value)));
+            let block = hcl::Block::builder(kind.as_str()).add_label(name).add_attributes(attributes);
+            body = body.add_block(block.build());
+        }
+    }
+    Ok(body.build())
+}
+
+fn json_expression(value: Value) -> hcl::Expression {
+    match value {
+        Value::String(template) => hcl::Expression::TemplateExpr(Box::new(template.into())),
+        Value::Array(values) => hcl::Expression::Array(values.into_iter().map(json_expression).collect()),
+        Value::Object(entries) => hcl::Expression::Object(
+            entries
+                .into_iter()
+                .map(|(key, value)| (hcl::ObjectKey::Expression(hcl::Expression::String(key)), json_expression(value)))
+                .collect(),
+        ),
+        value => hcl::to_expression(value).unwrap_or(hcl::Expression::Null),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bake_groups_inheritance_and_matrix() {
+        let hcl = r#"
+variable "TAG" {
+  default = "latest"
+}
+variable "REGISTRY" {
+  default = "ghcr.io/acme"
+}
+
+group "default" {
+  targets = ["api", "workers"]
+}
+group "workers" {
+  targets = ["worker"]
+}
+
+target "common" {
+  args = { GO_VERSION = "1.22", CGO_ENABLED = 0 }
+  labels = { "org.opencontainers.image.vendor" = "acme" }
+  platforms = ["linux/amd64"]
+}
+
+target "api" {
+  inherits = ["common"]
+  context = "services/api"
+  args = { GO_VERSION = "1.23" }
+  tags = ["${REGISTRY}/api:${TAG}", "${REGISTRY}/api:latest"]
+}
+
+target "worker" {
+  inherits = ["common"]
+  name = "worker-${kind}"
+  matrix = {
+    kind = ["email", "video"]
+  }
+  dockerfile = "Dockerfile.${kind}"
+  args = { KIND = kind }
+  tags = ["${REGISTRY}/worker-${kind}:${TAG}"]
+}
+"#;
+        let env = HashMap::from([("TAG".to_string(), "v2".to_string())]);
+        let json = r#"{"target": {"worker": {"pull": true}, "api": {"tags": ["${REGISTRY}/api:${TAG}-json"]}}}"#;
+        let sources = [(Path::new("docker-bake.hcl"), hcl), (Path::new("docker-bake.override.json"), json)];
+        let bake = BakeFile::parse(&sources, &env).unwrap();
+
+        assert_eq!(bake.resolve(&[]).unwrap(), vec!["api", "worker-email", "worker-video"]);
+        assert_eq!(bake.resolve(&["worker".to_string(), "api".to_string()]).unwrap()[2], "api");
+        assert!(bake.resolve(&["nope".to_string()]).is_err());
+
+        let api = &bake.targets["api"];
+        assert_eq!(api.args["GO_VERSION"], "1.23");
+        assert_eq!(api.args["CGO_ENABLED"], "0");
+        assert_eq!(api.tags, vec!["ghcr.io/acme/api:v2-json"]);
+        assert_eq!(api.platforms, vec!["linux/amd64"]);
+        let build = api.build("api").unwrap();
+        assert_eq!(build.image, "ghcr.io/acme/api:v2-json");
+        assert_eq!(build.dockerfile, PathBuf::from("services/api/Dockerfile"));
+
+        let video = &bake.targets["worker-video"];
+        assert_eq!(video.dockerfile.as_deref(), Some("Dockerfile.video"));
+        assert_eq!(video.args["KIND"], "video");
+        assert_eq!(video.labels["org.opencontainers.image.vendor"], "acme");
+        assert_eq!(video.tags, vec!["ghcr.io/acme/worker-video:v2"]);
+        // The override file's worker block applies to every expansion
+        assert!(video.pull);
+        assert!(!bake.targets.contains_key("worker"));
+
+        let cyclic = "target \"a\" {\n  inherits = [\"b\"]\n}\ntarget \"b\" {\n  inherits = [\"a\"]\n}\n";
+        assert!(BakeFile::parse(&[(Path::new("x.hcl"), cyclic)], &env).is_err());
+        let unsupported = "target \"a\" {\n  output = [\"type=docker\"]\n}\n";
+        assert!(BakeFile::parse(&[(Path::new("x.hcl"), unsupported)], &env).is_err());
+    }
+}
@@ -0,0 +1,300 @@
+//! Builds as a library call: a [`BuildRequest`] describes one and runs it,
+//! and a [`Build`] is what it produced.
+use crate::engine::{BuildEngine, BuildOptions, PullPolicy, sha256_digest};
+use crate::platform::Platform;
+use crate::progress::{Progress, ProgressEvent};
+use crate::reference::Reference;
+use crate::settings::Settings;
+use crate::storage::{Image, ImageList, StorageManager};
+use anyhow::Result;
+use std::path::PathBuf;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_util::sync::CancellationToken;
+
+/// Progress of a running build: steps, RUN output and the finished image
+pub type BuildEvents = UnboundedReceiver<ProgressEvent>;
+
+/// A build of the Dockerfile in a context directory, set up with builder
+/// calls and started with [`BuildRequest::run`]. The CLI's `build` command is
+/// a BuildRequest too.
+pub struct BuildRequest {
+    context: PathBuf,
+    dockerfile: Option<PathBuf>,
+    tags: Vec<String>,
+    platforms: Vec<Platform>,
+    store: Option<StorageManager>,
+    options: BuildOptions,
+    progress: Option<Progress>,
+    cancel: CancellationToken,
+}
+
+impl BuildRequest {
+    pub fn new(context: impl Into<PathBuf>) -> Self {
+        Self {
+            context: context.into(),
+            dockerfile: None,
+            tags: Vec::new(),
+            platforms: Vec::new(),
+            store: None,
+            options: BuildOptions::default(),
+            progress: None,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Defaults to the context's Dockerfile
+    pub fn dockerfile(mut self, path: impl Into<PathBuf>) -> Self {
+        self.dockerfile = Some(path.into());
+        self
+    }
+
+    /// The image is built as the first tag and also stored under the others
+    pub fn tag(mut self, name: impl Into<String>) -> Self {
+        self.tags.push(name.into());
+        self
+    }
+
+    pub fn tags(mut self, names: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.tags.extend(names.into_iter().map(Into::into));
+        self
+    }
+
+    /// More than one platform builds an image index with one image per platform
+    pub fn platform(mut self, platform: Platform) -> Self {
+        self.platforms.push(platform);
+        self
+    }
+
+    /// Defaults to the store the CLI uses when no --output-dir is given
+    pub fn store(mut self, store: StorageManager) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Everything the CLI's build flags control; replaces what earlier calls set,
+    /// except tags, platforms and the event stream
+    pub fn options(mut self, options: BuildOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn build_arg(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.options.build_args.insert(name.into(), value.into());
+        self
+    }
+
+    /// A file exposed to `RUN --mount=type=secret,id=<id>` steps
+    pub fn secret(mut self, id: impl Into<String>, path: impl Into<PathBuf>) -> Self {
+        self.options.secrets.insert(id.into(), path.into());
+        self
+    }
+
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.options.labels.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn no_cache(mut self, no_cache: bool) -> Self {
+        self.options.no_cache = no_cache;
+        self
+    }
+
+    pub fn pull(mut self, policy: PullPolicy) -> Self {
+        self.options.pull = policy;
+        self
+    }
+
+    /// Stream the build's progress; without it, progress is not reported
+    pub fn events(&mut self) -> BuildEvents {
+        let (progress, events) = Progress::channel();
+        self.progress = Some(progress);
+        events
+    }
+
+    /// Cancelling this token stops the build and removes what it left behind
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    pub async fn run(self) -> Result<Build> {
+        let Some(name) = self.tags.first().cloned() else {
+            return Err(anyhow::anyhow!("A build needs at least one tag to name the image"));
+        };
+        for tag in &self.tags {
+            if Reference::parse(tag)?.digest().is_some() {
+                return Err(anyhow::anyhow!("Cannot name a build {}: a digest reference names content, not a tag", tag));
+            }
+        }
+        let store = match self.store {
+            Some(store) => store,
+            None => {
+                let store = StorageManager::new(Settings::default().store_root(None))?;
+                store.init().await?;
+                store
+            }
+        };
+        let dockerfile = self.dockerfile.unwrap_or_else(|| self.context.join("Dockerfile"));
+
+        let mut options = self.options;
+        options.tags = self.tags[1..].to_vec();
+        options.platform = self.platforms.first().cloned();
+        if let Some(progress) = self.progress {
+            options.progress = progress;
+        }
+        let mut engine = BuildEngine::with_options(store, self.context, options).with_cancellation(self.cancel);
+        if self.platforms.len() > 1 {
+            let list = engine.build_image_list(&dockerfile, &name, &self.platforms).await?;
+            return Ok(Build::Index(list));
+        }
+        Ok(Build::Image(engine.build_image(&dockerfile, &name).await?))
+    }
+}
+
+/// What a build produced: one image, or an index of per-platform images
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum Build {
+    Image(Image),
+    Index(ImageList),
+}
+
+impl Build {
+    pub fn name(&self) -> &str {
+        match self {
+            Build::Image(image) => &image.name,
+            Build::Index(list) => &list.name,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        match self {
+            Build::Image(image) => &image.id,
+            Build::Index(list) => &list.id,
+        }
+    }
+
+    /// Digest of the manifest or index, as a registry will know it once pushed
+    pub fn digest(&self) -> Result<String> {
+        let bytes = match self {
+            Build::Image(image) => serde_json::to_vec(&image.manifest)?,
+            Build::Index(list) => serde_json::to_vec(&list.index)?,
+        };
+        Ok(sha256_digest(&bytes)?.to_string())
+    }
+
+    pub fn images(&self) -> Vec<&Image> {
+        match self {
+            Build::Image(image) => vec![image],
+            Build::Index(list) => list.images.iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_request_needs_a_tag_name() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = StorageManager::new(tempdir.path().join("store")).unwrap();
+
+        let untagged = BuildRequest::new(tempdir.path()).store(store).run().await;
+        assert!(untagged.unwrap_err().to_string().contains("at least one tag"));
+
+        let store = StorageManager::new(tempdir.path().join("store")).unwrap();
+        let pinned = BuildRequest::new(tempdir.path())
+            .store(store)
+            .tag("app@sha256:".to_string() + &"a".repeat(64))
+            .run()
+            .await;
+        assert!(pinned.unwrap_err().to_string().contains("digest reference"));
+
+        let store = StorageManager::new(tempdir.path().join("store")).unwrap();
+        let missing = BuildRequest::new(tempdir.path()).store(store).tag("app:latest").run().await;
+        assert!(missing.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_moved_base_tag_misses_the_cache() {
+        use crate::metrics::Metrics;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let context = tempdir.path().join("context");
+        std::fs::create_dir(&context).unwrap();
+        std::fs::write(context.join("base.Dockerfile"), "FROM scratch\nCOPY a /a\n").unwrap();
+        std::fs::write(context.join("Dockerfile"), "FROM base:1\nCOPY b /b\n").unwrap();
+        std::fs::write(context.join("a"), "first").unwrap();
+        std::fs::write(context.join("b"), "app").unwrap();
+        let store = || StorageManager::new(tempdir.path().join("store")).unwrap();
+        store().init().await.unwrap();
+
+        let build_base = || async {
+            let request = BuildRequest::new(&context).store(store()).dockerfile(context.join("base.Dockerfile"));
+            request.tag("base:1").run().await.unwrap();
+        };
+        // The steps of the app build answered from the cache
+        let build_app = || async {
+            let metrics = Metrics::default();
+            let options = BuildOptions { metrics: metrics.clone(), ..Default::default() };
+            BuildRequest::new(&context).store(store()).options(options).tag("app:1").run().await.unwrap();
+            let text = metrics.render();
+            text.lines().find_map(|line| line.strip_prefix("hyperbuild_steps_cached_total ")).unwrap().to_string()
+        };
+
+        build_base().await;
+        assert_eq!(build_app().await, "0");
+        assert_eq!(build_app().await, "1");
+
+        // base:1 now names another image, which the app's COPY was not built on
+        std::fs::write(context.join("a"), "second").unwrap();
+        build_base().await;
+        assert_eq!(build_app().await, "0");
+    }
+
+    #[tokio::test]
+    async fn test_chroot_run_needs_a_shell() {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::write(tempdir.path().join("Dockerfile"), "FROM scratch\nRUN true\n").unwrap();
+        let store = StorageManager::new(tempdir.path().join("store")).unwrap();
+        store.init().await.unwrap();
+        let error = BuildRequest::new(tempdir.path()).store(store).tag("app:latest").run().await.unwrap_err();
+        assert!(format!("{:#}", error).contains("RUN true: the stage rootfs has no /bin/sh"), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn test_over_budget_step_names_its_instruction() {
+        use crate::engine::executor::StepLimits;
+        use crate::engine::plugins::{Plugin, Plugins};
+        use std::time::Duration;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let context = tempdir.path().join("context");
+        std::fs::create_dir(&context).unwrap();
+        std::fs::write(context.join("Dockerfile"), "FROM scratch AS app\nRUN sleep 10\n").unwrap();
+        let store = StorageManager::new(tempdir.path().join("store")).unwrap();
+        store.init().await.unwrap();
+
+        // An executor plugin that never finishes in time
+        let options = BuildOptions {
+            plugins: Plugins::new(vec![Plugin {
+                name: "slow".to_string(),
+                command: vec!["sleep".to_string(), "10".to_string()],
+                instructions: Vec::new(),
+                executor: true,
+            }]),
+            step_limits: StepLimits { timeout: Some(Duration::from_millis(200)), ..Default::default() },
+            ..Default::default()
+        };
+        let error = BuildRequest::new(&context)
+            .store(store)
+            .options(options)
+            .tag("app:latest")
+            .run()
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("Dockerfile line 2: app step 2/2 (RUN /bin/sh -c sleep 10): "), "{}", error);
+        assert!(error.contains("timed out after 200ms"), "{}", error);
+    }
+}
@@ -0,0 +1,118 @@
+// `build --ci github`: GitHub Actions workflow commands in the build output,
+// so its log folds into a group per stage, a failure is annotated on the
+// Dockerfile line that caused it, and later steps can read the image's ID and
+// digest as outputs of the build step
+use crate::dockerfile::LineError;
+use anyhow::Result;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// The CI system output is written for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ci {
+    GitHub,
+}
+
+impl Ci {
+    // The `--ci` value, else GitHub Actions when it is the one running us
+    pub fn detect(flag: Option<&str>) -> Result<Option<Self>> {
+        match flag {
+            Some("github") => Ok(Some(Ci::GitHub)),
+            Some("none") => Ok(None),
+            Some(other) => Err(anyhow::anyhow!("Unknown --ci {:?}: expected github or none", other)),
+            None => Ok((std::env::var("GITHUB_ACTIONS").as_deref() == Ok("true")).then_some(Ci::GitHub)),
+        }
+    }
+
+    // The line starting a collapsible group of log lines, which ends at the next one
+    pub fn start_group(&self, name: &str) -> String {
+        match self {
+            Ci::GitHub => format!("::group::{}", escape_data(name)),
+        }
+    }
+
+    pub fn end_group(&self) -> &'static str {
+        match self {
+            Ci::GitHub => "::endgroup::",
+        }
+    }
+
+    // The line annotating a failed build, on the Dockerfile line at fault when
+    // the error names one
+    pub fn error(&self, error: &anyhow::Error, dockerfile: &Path) -> String {
+        let message = escape_data(&format!("{:#}", error));
+        let line = error.chain().find_map(|cause| cause.downcast_ref::<LineError>()).map(|e| e.line);
+        match (self, line) {
+            (Ci::GitHub, Some(line)) => {
+                let file = workspace_path(dockerfile);
+                format!("::error file={},line={},title=Build failed::{}", escape_property(&file), line, message)
+            }
+            (Ci::GitHub, None) => format!("::error title=Build failed::{}", message),
+        }
+    }
+
+    // Make `name=value` pairs outputs of the running step (GITHUB_OUTPUT)
+    pub fn set_outputs(&self, outputs: &[(&str, &str)]) -> Result<()> {
+        match self {
+            Ci::GitHub => match std::env::var_os("GITHUB_OUTPUT") {
+                Some(path) => append_outputs(Path::new(&path), outputs),
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+fn append_outputs(path: &Path, outputs: &[(&str, &str)]) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("Failed to open GITHUB_OUTPUT {:?}: {}", path, e))?;
+    for (name, value) in outputs {
+        writeln!(file, "{}={}", name, value)?;
+    }
+    Ok(())
+}
+
+// Annotations point at files relative to the repository checkout, where workflows usually build from
+fn workspace_path(path: &Path) -> String {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let relative = std::env::var_os("GITHUB_WORKSPACE")
+        .map(PathBuf::from)
+        .and_then(|workspace| absolute.strip_prefix(workspace).ok().map(Path::to_path_buf));
+    relative.unwrap_or_else(|| path.to_path_buf()).to_string_lossy().to_string()
+}
+
+// Workflow commands end at a newline, so messages escape them, and `%` which does the escaping
+fn escape_data(value: &str) -> String {
+    value.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+// Properties are also separated by `,` and end at `:`
+fn escape_property(value: &str) -> String {
+    escape_data(value).replace(':', "%3A").replace(',', "%2C")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_github_annotations_and_outputs() {
+        let failed = anyhow::Error::from(LineError { line: 4, message: "RUN make: exit code 2\n50% done".to_string() });
+        let annotation = Ci::GitHub.error(&failed, Path::new("/src/Dockerfile"));
+        let message = "Dockerfile line 4: RUN make: exit code 2%0A50%25 done";
+        assert_eq!(annotation, format!("::error file=/src/Dockerfile,line=4,title=Build failed::{}", message));
+        let failed = anyhow::anyhow!("No such image: app:1");
+        let annotation = Ci::GitHub.error(&failed, Path::new("Dockerfile"));
+        assert_eq!(annotation, "::error title=Build failed::No such image: app:1");
+        assert_eq!(Ci::GitHub.start_group("builder, stage 1"), "::group::builder, stage 1");
+        assert!(Ci::detect(Some("gitlab")).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output");
+        append_outputs(&path, &[("imageid", "sha256:ab")]).unwrap();
+        append_outputs(&path, &[("digest", "sha256:cd")]).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "imageid=sha256:ab\ndigest=sha256:cd\n");
+    }
+}
@@ -0,0 +1,458 @@
+// The `build:` sections of a Compose file, for `compose build`. Only what
+// building needs is read; ports, volumes, networks and the like are ignored.
+use crate::dockerfile::{DockerfileParser, expand_vars};
+use crate::platform::Platform;
+use crate::reference::Reference;
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+// The files `compose build` looks for when -f is not given, in order
+pub const DEFAULT_FILES: [&str; 4] = ["compose.yaml", "compose.yml", "docker-compose.yaml", "docker-compose.yml"];
+
+#[derive(Debug, Deserialize)]
+struct ComposeFile {
+    name: Option<String>,
+    #[serde(default)]
+    services: BTreeMap<String, ServiceSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ServiceSpec {
+    image: Option<String>,
+    build: Option<BuildSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum BuildSpec {
+    Context(String),
+    Section(BuildSection),
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct BuildSection {
+    context: Option<String>,
+    dockerfile: Option<String>,
+    dockerfile_inline: Option<String>,
+    #[serde(default)]
+    args: KeyValues,
+    #[serde(default)]
+    labels: KeyValues,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    platforms: Vec<String>,
+    target: Option<String>,
+    #[serde(default)]
+    no_cache: bool,
+    #[serde(default)]
+    pull: bool,
+}
+
+// `KEY: value` maps or `KEY=value` lists; a key without a value takes it
+// from the environment
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum KeyValues {
+    Map(BTreeMap<String, Option<serde_yaml::Value>>),
+    List(Vec<String>),
+}
+
+impl Default for KeyValues {
+    fn default() -> Self {
+        KeyValues::List(Vec::new())
+    }
+}
+
+impl KeyValues {
+    fn resolve(self, env: &HashMap<String, String>) -> BTreeMap<String, String> {
+        let pairs: Vec<(String, Option<String>)> = match self {
+            KeyValues::Map(map) => map.into_iter().map(|(key, value)| (key, value.and_then(scalar))).collect(),
+            KeyValues::List(list) => list
+                .into_iter()
+                .map(|item| match item.split_once('=') {
+                    Some((key, value)) => (key.to_string(), Some(value.to_string())),
+                    None => (item, None),
+                })
+                .collect(),
+        };
+        pairs
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let value = value.or_else(|| env.get(&key).cloned())?;
+                Some((key, value))
+            })
+            .collect()
+    }
+}
+
+fn scalar(value: serde_yaml::Value) -> Option<String> {
+    match value {
+        serde_yaml::Value::String(value) => Some(value),
+        serde_yaml::Value::Number(value) => Some(value.to_string()),
+        serde_yaml::Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+// One service to build
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceBuild {
+    pub service: String,
+    // The image's name: the service's `image`, else <project>-<service>
+    pub image: String,
+    pub context: PathBuf,
+    pub dockerfile: PathBuf,
+    pub args: HashMap<String, String>,
+    pub labels: BTreeMap<String, String>,
+    // Further names for the image
+    pub tags: Vec<String>,
+    pub platforms: Vec<Platform>,
+    pub no_cache: bool,
+    pub pull: bool,
+    // Services whose images this one's Dockerfile builds FROM
+    pub depends_on: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ComposeProject {
+    pub name: String,
+    // In service name order
+    pub builds: Vec<ServiceBuild>,
+}
+
+impl ComposeProject {
+    // Read a Compose file, with ${VAR} references filled in from `env` and
+    // the .env file next to it
+    pub fn load(path: &Path, env: &HashMap<String, String>) -> Result<Self> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Failed to read {:?}: {}", path, e))?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let mut env = env.clone();
+        let dotenv = dir.join(".env");
+        if dotenv.is_file() {
+            for (key, value) in parse_dotenv(&std::fs::read_to_string(&dotenv)?) {
+                env.entry(key).or_insert(value);
+            }
+        }
+        let default_name = std::fs::canonicalize(&dir)
+            .ok()
+            .and_then(|dir| dir.file_name().map(|name| name.to_string_lossy().to_string()))
+            .unwrap_or_default();
+        Self::parse(&contents, &dir, &default_name, &env).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(contents: &str, dir: &Path, default_name: &str, env: &HashMap<String, String>) -> Result<Self> {
+        let file: ComposeFile = serde_yaml::from_str(&interpolate(contents, env)?)?;
+        let name = project_name(
+            file.name
+                .as_deref()
+                .or(env.get("COMPOSE_PROJECT_NAME").map(String::as_str))
+                .unwrap_or(default_name),
+        );
+        if name.is_empty() {
+            return Err(anyhow::anyhow!("The project needs a name: set `name:` or COMPOSE_PROJECT_NAME"));
+        }
+
+        let mut builds = Vec::new();
+        for (service, spec) in file.services {
+            let Some(build) = spec.build else {
+                continue;
+            };
+            let section = match build {
+                BuildSpec::Context(context) => BuildSection { context: Some(context), ..Default::default() },
+                BuildSpec::Section(section) => section,
+            };
+            let invalid = |why: &str| anyhow::anyhow!("Service {}: {}", service, why);
+            if section.target.is_some() {
+                return Err(invalid("build.target is not supported; hyperbuild builds a Dockerfile's last stage"));
+            }
+            if section.dockerfile_inline.is_some() {
+                return Err(invalid("build.dockerfile_inline is not supported; put the Dockerfile in a file"));
+            }
+            let context = section.context.unwrap_or_else(|| ".".to_string());
+            if context.contains("://") || context.starts_with("git@") {
+                return Err(invalid("remote build contexts are not supported"));
+            }
+            let context = if dir == Path::new(".") { PathBuf::from(context) } else { dir.join(context) };
+            // As with Compose, a relative Dockerfile is found in the context
+            let dockerfile = context.join(section.dockerfile.unwrap_or_else(|| "Dockerfile".to_string()));
+            let image = spec.image.unwrap_or_else(|| format!("{}-{}", name, service));
+            Reference::parse(&image).map_err(|e| invalid(&e.to_string()))?;
+            builds.push(ServiceBuild {
+                image,
+                context,
+                dockerfile,
+                args: section.args.resolve(env).into_iter().collect(),
+                labels: section.labels.resolve(env),
+                tags: section.tags,
+                platforms: section
+                    .platforms
+                    .iter()
+                    .map(|spec| Platform::parse(spec))
+                    .collect::<Result<Vec<_>>>()
+                    .map_err(|e| invalid(&e.to_string()))?,
+                no_cache: section.no_cache,
+                pull: section.pull,
+                depends_on: Vec::new(),
+                service,
+            });
+        }
+        Ok(ComposeProject { name, builds })
+    }
+
+    // Keep only the named services; all of them when none are named
+    pub fn select(&mut self, services: &[String]) -> Result<()> {
+        if services.is_empty() {
+            return Ok(());
+        }
+        for service in services {
+            if !self.builds.iter().any(|build| &build.service == service) {
+                return Err(anyhow::anyhow!("No service {:?} with a build section", service));
+            }
+        }
+        self.builds.retain(|build| services.contains(&build.service));
+        Ok(())
+    }
+
+    // Find which services build FROM another's image, so those wait for it;
+    // the rest can build in parallel
+    pub fn resolve_dependencies(&mut self) -> Result<()> {
+        resolve_dependencies(&mut self.builds)
+    }
+}
+
+// Fill in each build's depends_on with the others whose images its Dockerfile
+// builds FROM, and fail on a cycle. Bake targets are ordered the same way.
+pub fn resolve_dependencies(builds: &mut [ServiceBuild]) -> Result<()> {
+    let images: Vec<(Option<ImageKey>, String)> =
+        builds.iter().map(|build| (image_key(&build.image), build.service.clone())).collect();
+    for build in builds.iter_mut() {
+        let contents = std::fs::read_to_string(&build.dockerfile)
+            .map_err(|e| anyhow::anyhow!("{}: failed to read {:?}: {}", build.service, build.dockerfile, e))?;
+        let parsed = DockerfileParser::parse(&contents).map_err(|e| anyhow::anyhow!("{}: {}", build.service, e))?;
+        // FROM lines see only the ARGs declared before the first FROM
+        let vars: HashMap<String, String> = parsed
+            .args
+            .iter()
+            .filter_map(|(name, default)| {
+                let value = build.args.get(name).or(default.as_ref())?;
+                Some((name.clone(), value.clone()))
+            })
+            .collect();
+        for stage in &parsed.stages {
+            let Some(base) = image_key(&expand_vars(&stage.base_image, &vars)) else {
+                continue;
+            };
+            for (image, service) in &images {
+                let builds_from = image.as_ref() == Some(&base) && *service != build.service;
+                if builds_from && !build.depends_on.contains(service) {
+                    build.depends_on.push(service.clone());
+                }
+            }
+        }
+    }
+    check_cycles(builds)
+}
+
+fn check_cycles(builds: &[ServiceBuild]) -> Result<()> {
+    let depends_on: HashMap<&str, &Vec<String>> =
+        builds.iter().map(|build| (build.service.as_str(), &build.depends_on)).collect();
+    // Walk each build's dependencies; reaching it again is a cycle
+    for build in builds {
+        let mut pending: Vec<&str> = build.depends_on.iter().map(String::as_str).collect();
+        let mut seen: Vec<&str> = Vec::new();
+        while let Some(service) = pending.pop() {
+            if service == build.service {
+                return Err(anyhow::anyhow!(
+                    "{} builds FROM its own image through {}",
+                    build.service,
+                    seen.join(" -> ")
+                ));
+            }
+            if seen.contains(&service) {
+                continue;
+            }
+            seen.push(service);
+            pending.extend(depends_on.get(service).into_iter().flat_map(|deps| deps.iter().map(String::as_str)));
+        }
+    }
+    Ok(())
+}
+
+// Registry, repository and tag or digest, so alpine and docker.io/library/alpine:latest match
+type ImageKey = (String, String, String);
+
+fn image_key(name: &str) -> Option<ImageKey> {
+    let reference = Reference::parse(name).ok()?;
+    Some((
+        reference.registry().to_string(),
+        reference.repository().to_string(),
+        reference.manifest_reference().to_string(),
+    ))
+}
+
+// Compose project names are lower case letters, digits, dashes and underscores
+fn project_name(name: &str) -> String {
+    name.to_ascii_lowercase()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect::<String>()
+        .trim_start_matches(['-', '_'])
+        .to_string()
+}
+
+// KEY=VALUE lines; blank lines and # comments are skipped, quotes removed
+fn parse_dotenv(contents: &str) -> Vec<(String, String)> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (key, value) = line.strip_prefix("export ").unwrap_or(line).split_once('=')?;
+            let value = value.trim();
+            let unquoted = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .or_else(|| value.strip_prefix('\'').and_then(|value| value.strip_suffix('\'')))
+                .unwrap_or(value);
+            Some((key.trim().to_string(), unquoted.to_string()))
+        })
+        .collect()
+}
+
+// Compose interpolation: $VAR, ${VAR}, ${VAR-default}, ${VAR+alternative},
+// ${VAR?error}, their `:` forms, which also treat an empty VAR as unset, and
+// $$ for a literal $
+pub fn interpolate(input: &str, env: &HashMap<String, String>) -> Result<String> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            output.push('$');
+            rest = after;
+        } else if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("Unterminated variable reference ${{{}", braced))?;
+            output.push_str(&substitute(&braced[..end], env)?);
+            rest = &braced[end + 1..];
+        } else {
+            let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            if end == 0 {
+                output.push('$');
+            } else {
+                output.push_str(env.get(&rest[..end]).map(String::as_str).unwrap_or_default());
+            }
+            rest = &rest[end..];
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+fn substitute(expression: &str, env: &HashMap<String, String>) -> Result<String> {
+    let end = expression
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(expression.len());
+    let (name, modifier) = expression.split_at(end);
+    let set = env.contains_key(name);
+    let set_and_not_empty = env.get(name).is_some_and(|value| !value.is_empty());
+    let value = env.get(name).cloned().unwrap_or_default();
+    let (operator, word) = match modifier.get(..2) {
+        Some(operator @ (":-" | ":?" | ":+")) => (operator, &modifier[2..]),
+        _ => modifier.split_at(modifier.len().min(1)),
+    };
+    match operator {
+        "" => Ok(value),
+        ":-" => Ok(if set_and_not_empty { value } else { word.to_string() }),
+        "-" => Ok(if set { value } else { word.to_string() }),
+        ":+" => Ok(if set_and_not_empty { word.to_string() } else { String::new() }),
+        "+" => Ok(if set { word.to_string() } else { String::new() }),
+        ":?" if !set_and_not_empty => Err(anyhow::anyhow!("{} is required: {}", name, word)),
+        "?" if !set => Err(anyhow::anyhow!("{} is required: {}", name, word)),
+        ":?" | "?" => Ok(value),
+        _ => Err(anyhow::anyhow!("Invalid variable reference ${{{}}}", expression)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_compose_builds() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        for (service, dockerfile) in [
+            ("base", "FROM alpine:3.19\n"),
+            ("api", "ARG BASE=myorg/base\nARG VERSION\nFROM ${BASE}:${VERSION}\n"),
+            ("web", "FROM node:20 AS build\nFROM myorg/base:1.2\n"),
+        ] {
+            std::fs::create_dir_all(dir.join(service)).unwrap();
+            std::fs::write(dir.join(service).join("Dockerfile"), dockerfile).unwrap();
+        }
+        std::fs::write(dir.join("web").join("Dockerfile.prod"), "FROM myorg/base:1.2\n").unwrap();
+
+        let compose = r#"
+services:
+  base:
+    build: ./base
+    image: myorg/base:${VERSION:-latest}
+  api:
+    build:
+      context: ./api
+      args:
+        VERSION: 1.2
+        TOKEN:
+  web:
+    build:
+      context: web
+      dockerfile: Dockerfile.prod
+      args: ["MODE=prod"]
+      labels: ["team=web"]
+      platforms: [linux/amd64, linux/arm64]
+    ports: ["8080:80"]
+  db:
+    image: postgres:16
+"#;
+        let env: HashMap<String, String> =
+            [("VERSION", "1.2"), ("TOKEN", "secret")].into_iter().map(|(k, v)| (k.into(), v.into())).collect();
+        let mut project = ComposeProject::parse(compose, dir, "My App", &env).unwrap();
+        assert_eq!(project.name, "myapp");
+        let services: Vec<&str> = project.builds.iter().map(|build| build.service.as_str()).collect();
+        assert_eq!(services, vec!["api", "base", "web"]);
+
+        let api = &project.builds[0];
+        assert_eq!(api.image, "myapp-api");
+        assert_eq!(api.args.get("VERSION").map(String::as_str), Some("1.2"));
+        assert_eq!(api.args.get("TOKEN").map(String::as_str), Some("secret"));
+        let web = &project.builds[2];
+        assert_eq!(web.dockerfile, dir.join("web").join("Dockerfile.prod"));
+        assert_eq!(web.labels.get("team").map(String::as_str), Some("web"));
+        assert_eq!(web.platforms.len(), 2);
+        assert_eq!(project.builds[1].image, "myorg/base:1.2");
+
+        project.resolve_dependencies().unwrap();
+        assert_eq!(project.builds[0].depends_on, vec!["base"]);
+        assert!(project.builds[1].depends_on.is_empty());
+        assert_eq!(project.builds[2].depends_on, vec!["base"]);
+
+        project.select(&["web".to_string()]).unwrap();
+        assert_eq!(project.builds.len(), 1);
+        assert!(project.select(&["db".to_string()]).is_err());
+
+        assert_eq!(interpolate("$$HOME ${UNSET-x} ${VERSION:+y} $VERSION", &env).unwrap(), "$HOME x y 1.2");
+        assert!(interpolate("${VERSION%.*}", &env).is_err());
+        assert!(interpolate("${UNSET:?must be set}", &env).unwrap_err().to_string().contains("must be set"));
+        let targeted = "services:\n  app:\n    build:\n      context: .\n      target: prod\n";
+        assert!(ComposeProject::parse(targeted, dir, "x", &env).is_err());
+    }
+}
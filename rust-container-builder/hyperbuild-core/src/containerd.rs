@@ -0,0 +1,636 @@
+// Loading stored images into containerd over its gRPC API, so nerdctl, ctr
+// and the kubelet can run them without going through a registry. Blobs are
+// written to its content store under a lease, labelled so its garbage
+// collector keeps what the image refers to, then the image record is created
+// and its layers are unpacked into the snapshotter.
+use crate::reference::Reference;
+use crate::storage::Image;
+use anyhow::Result;
+use oci_spec::image::{Descriptor, MediaType};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+use tonic::codec::ProstCodec;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::codegen::tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tonic::{Code, Request, Status};
+
+const DEFAULT_ADDRESS: &str = "/run/containerd/containerd.sock";
+const DEFAULT_NAMESPACE: &str = "default";
+const DEFAULT_SNAPSHOTTER: &str = "overlayfs";
+
+// Blobs are streamed to the content store in messages of this size
+const WRITE_CHUNK_SIZE: usize = 1 << 20;
+
+// The [containerd] table; what it leaves out is taken from CONTAINERD_ADDRESS,
+// CONTAINERD_NAMESPACE and CONTAINERD_SNAPSHOTTER, as with ctr
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ContainerdSettings {
+    // The socket containerd serves its API on (/run/containerd/containerd.sock)
+    pub address: Option<PathBuf>,
+    // Where images go: k8s.io for the kubelet, default for nerdctl and ctr
+    pub namespace: Option<String>,
+    // What layers are unpacked with (overlayfs)
+    pub snapshotter: Option<String>,
+}
+
+impl ContainerdSettings {
+    pub fn address(&self) -> PathBuf {
+        self.address
+            .clone()
+            .or_else(|| std::env::var_os("CONTAINERD_ADDRESS").map(PathBuf::from))
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_ADDRESS))
+    }
+
+    pub fn namespace(&self) -> String {
+        self.namespace
+            .clone()
+            .or_else(|| std::env::var("CONTAINERD_NAMESPACE").ok().filter(|v| !v.is_empty()))
+            .unwrap_or_else(|| DEFAULT_NAMESPACE.to_string())
+    }
+
+    pub fn snapshotter(&self) -> String {
+        self.snapshotter
+            .clone()
+            .or_else(|| std::env::var("CONTAINERD_SNAPSHOTTER").ok().filter(|v| !v.is_empty()))
+            .unwrap_or_else(|| DEFAULT_SNAPSHOTTER.to_string())
+    }
+}
+
+pub struct ContainerdClient {
+    channel: Channel,
+    namespace: String,
+    snapshotter: String,
+}
+
+impl ContainerdClient {
+    pub async fn connect(settings: &ContainerdSettings) -> Result<Self> {
+        let (address, namespace) = (settings.address(), settings.namespace());
+        // Namespaces are sent in a header, and containerd only accepts these
+        let valid = namespace.chars().next().is_some_and(|c| c.is_ascii_alphanumeric())
+            && namespace.chars().all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+        if !valid {
+            return Err(anyhow::anyhow!("Invalid containerd namespace {:?}", namespace));
+        }
+
+        // The URI is required but unused: every connection goes to the socket
+        let socket = address.clone();
+        let channel = Endpoint::from_static("http://containerd")
+            .connect_with_connector(tower::service_fn(move |_: Uri| {
+                let socket = socket.clone();
+                async move {
+                    let stream = tokio::net::UnixStream::connect(socket).await?;
+                    Ok::<_, std::io::Error>(hyper_util::rt::TokioIo::new(stream))
+                }
+            }))
+            .await
+            .map_err(|e| {
+                // The transport error says little; what failed is its source
+                let cause = std::error::Error::source(&e).map_or_else(|| e.to_string(), |source| source.to_string());
+                anyhow::anyhow!("Failed to connect to containerd at {}: {}", address.display(), cause)
+            })?;
+        Ok(Self { channel, namespace, snapshotter: settings.snapshotter() })
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    // Load `image` into containerd as `name`, and return the name containerd
+    // knows it by, e.g. docker.io/library/app:latest for app
+    pub async fn import(&self, image: &Image, name: &str) -> Result<String> {
+        let name = containerd_name(name)?;
+        let lease = self.create_lease().await?;
+        let imported = self.import_leased(image, &name, &lease).await;
+        // Without the lease, what the image refers to is kept by its labels
+        let release = api::DeleteLeaseRequest { id: lease, sync: false };
+        let released = self.call::<_, ()>(api::LEASES_DELETE, None, release).await;
+        imported?;
+        released.map_err(|e| error("deleting lease", e))?;
+        Ok(name)
+    }
+
+    async fn import_leased(&self, image: &Image, name: &str, lease: &str) -> Result<()> {
+        let manifest = &image.manifest;
+        if manifest.layers().len() != image.layers.len() {
+            let (listed, stored) = (manifest.layers().len(), image.layers.len());
+            return Err(anyhow::anyhow!("Image {} lists {} layers but stores {}", name, listed, stored));
+        }
+        let diff_ids: Vec<String> = image.layers.iter().map(|layer| layer.diff_id.clone()).collect();
+        let chain_ids = chain_ids(&diff_ids);
+
+        for (layer, descriptor) in image.layers.iter().zip(manifest.layers()) {
+            // The unpacker reads this label to check what it applied
+            let labels = HashMap::from([("containerd.io/uncompressed".to_string(), layer.diff_id.clone())]);
+            self.write_blob(lease, &descriptor_of(descriptor), labels, layer.open()?).await?;
+        }
+
+        // The config keeps the unpacked snapshots, and the manifest everything else
+        let mut labels = HashMap::new();
+        if let Some(top) = chain_ids.last() {
+            labels.insert(format!("containerd.io/gc.ref.snapshot.{}", self.snapshotter), top.clone());
+        }
+        let config = descriptor_of(manifest.config());
+        self.write_blob(lease, &config, labels, Box::new(std::io::Cursor::new(image.raw_config.clone()))).await?;
+
+        let mut labels = HashMap::from([("containerd.io/gc.ref.content.config".to_string(), config.digest.clone())]);
+        for (index, descriptor) in manifest.layers().iter().enumerate() {
+            labels.insert(format!("containerd.io/gc.ref.content.l.{}", index), descriptor.digest().to_string());
+        }
+        let manifest_json = serde_json::to_vec(manifest)?;
+        let target = api::Descriptor {
+            media_type: manifest.media_type().clone().unwrap_or(MediaType::ImageManifest).to_string(),
+            digest: format!("sha256:{:x}", Sha256::digest(&manifest_json)),
+            size: manifest_json.len() as i64,
+            annotations: HashMap::new(),
+        };
+        self.write_blob(lease, &target, labels, Box::new(std::io::Cursor::new(manifest_json))).await?;
+
+        let layers: Vec<api::Descriptor> = manifest.layers().iter().map(descriptor_of).collect();
+        self.unpack(lease, &layers, &diff_ids, &chain_ids).await?;
+
+        let mut labels = HashMap::new();
+        // The kubelet only lists images the CRI plugin considers its own
+        if self.namespace == "k8s.io" {
+            labels.insert("io.cri-containerd.image".to_string(), "managed".to_string());
+        }
+        let record = api::ImageRecord { name: name.to_string(), labels, target: Some(target) };
+        let created = self
+            .call::<_, ()>(api::IMAGES_CREATE, Some(lease), api::CreateImageRequest { image: Some(record.clone()) })
+            .await;
+        match created {
+            Ok(()) => Ok(()),
+            // Loading a name again points it at the new image
+            Err(status) if status.code() == Code::AlreadyExists => self
+                .call::<_, ()>(api::IMAGES_UPDATE, Some(lease), api::UpdateImageRequest { image: Some(record) })
+                .await
+                .map_err(|e| error("updating image", e)),
+            Err(status) => Err(error("creating image", status)),
+        }
+    }
+
+    // A lease holds what is written until the image refers to it; it expires
+    // by itself if the import is interrupted
+    async fn create_lease(&self) -> Result<String> {
+        let expires = chrono::Utc::now() + chrono::Duration::hours(1);
+        let request = api::CreateLeaseRequest {
+            id: format!("hyperbuild-{}", uuid::Uuid::new_v4()),
+            labels: HashMap::from([(
+                "containerd.io/gc.expire".to_string(),
+                expires.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            )]),
+        };
+        let id = request.id.clone();
+        self.call::<_, ()>(api::LEASES_CREATE, None, request)
+            .await
+            .map_err(|e| error("creating lease", e))?;
+        Ok(id)
+    }
+
+    // Write a blob unless containerd has it, and label it either way
+    async fn write_blob(
+        &self,
+        lease: &str,
+        descriptor: &api::Descriptor,
+        labels: HashMap<String, String>,
+        mut data: Box<dyn Read + Send>,
+    ) -> Result<()> {
+        let info = api::InfoRequest { digest: descriptor.digest.clone() };
+        match self.call::<_, ()>(api::CONTENT_INFO, Some(lease), info).await {
+            Ok(()) => return self.label_blob(lease, &descriptor.digest, labels).await,
+            Err(status) if status.code() == Code::NotFound => {}
+            Err(status) => return Err(error("reading content", status)),
+        }
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(4);
+        let (reference, expected, total) =
+            (format!("hyperbuild-{}", descriptor.digest), descriptor.digest.clone(), descriptor.size);
+        let reader = tokio::task::spawn_blocking(move || -> Result<()> {
+            let mut offset = 0;
+            loop {
+                let mut chunk = vec![0; WRITE_CHUNK_SIZE];
+                let read = data.read(&mut chunk)?;
+                if read == 0 {
+                    break;
+                }
+                chunk.truncate(read);
+                let request = api::WriteContentRequest {
+                    action: api::WRITE,
+                    reference: reference.clone(),
+                    total,
+                    expected: expected.clone(),
+                    offset,
+                    data: chunk,
+                    labels: HashMap::new(),
+                };
+                offset += read as i64;
+                if sender.blocking_send(request).is_err() {
+                    // The call failed, and reports why
+                    return Ok(());
+                }
+            }
+            // containerd checks the size and digest when the blob is committed
+            let commit = api::WriteContentRequest {
+                action: api::COMMIT,
+                reference,
+                total,
+                expected,
+                offset,
+                data: Vec::new(),
+                labels,
+            };
+            let _ = sender.blocking_send(commit);
+            Ok(())
+        });
+
+        let written = async {
+            let mut grpc = self.grpc().await?;
+            let request = self.request(ReceiverStream::new(receiver), Some(lease))?;
+            let codec = ProstCodec::<api::WriteContentRequest, api::WriteContentResponse>::default();
+            let mut responses =
+                grpc.streaming(request, PathAndQuery::from_static(api::CONTENT_WRITE), codec).await?.into_inner();
+            while responses.message().await?.is_some() {}
+            Ok::<(), Status>(())
+        }
+        .await;
+        let short = &descriptor.digest[..descriptor.digest.len().min(19)];
+        written.map_err(|e| error(&format!("writing {}", short), e))?;
+        reader.await?
+    }
+
+    async fn label_blob(&self, lease: &str, digest: &str, labels: HashMap<String, String>) -> Result<()> {
+        if labels.is_empty() {
+            return Ok(());
+        }
+        // Only these labels are set; others, e.g. for another snapshotter, stay
+        let paths = labels.keys().map(|key| format!("labels.{}", key)).collect();
+        let request = api::UpdateContentRequest {
+            info: Some(api::ContentInfo { digest: digest.to_string(), labels }),
+            update_mask: Some(api::FieldMask { paths }),
+        };
+        self.call::<_, ()>(api::CONTENT_UPDATE, Some(lease), request)
+            .await
+            .map_err(|e| error("labelling content", e))
+    }
+
+    // Apply each layer on top of the one below it, as a snapshot named by its
+    // chain ID, so containers can start from the image at once. Snapshots
+    // another image already unpacked are reused.
+    async fn unpack(
+        &self,
+        lease: &str,
+        layers: &[api::Descriptor],
+        diff_ids: &[String],
+        chain_ids: &[String],
+    ) -> Result<()> {
+        let mut parent = String::new();
+        for ((layer, diff_id), chain_id) in layers.iter().zip(diff_ids).zip(chain_ids) {
+            let stat = api::SnapshotKey { snapshotter: self.snapshotter.clone(), key: chain_id.clone() };
+            match self.call::<_, ()>(api::SNAPSHOTS_STAT, Some(lease), stat).await {
+                Ok(()) => {
+                    parent = chain_id.clone();
+                    continue;
+                }
+                Err(status) if status.code() == Code::NotFound => {}
+                Err(status) => return Err(error("reading snapshot", status)),
+            }
+
+            let key = format!("extract-{}-{}", uuid::Uuid::new_v4(), chain_id);
+            let prepare = api::PrepareSnapshotRequest {
+                snapshotter: self.snapshotter.clone(),
+                key: key.clone(),
+                parent: parent.clone(),
+                labels: HashMap::from([("containerd.io/snapshot.ref".to_string(), chain_id.clone())]),
+            };
+            let prepared: api::PrepareSnapshotResponse = self
+                .call(api::SNAPSHOTS_PREPARE, Some(lease), prepare)
+                .await
+                .map_err(|e| error("preparing snapshot", e))?;
+
+            // A snapshot left uncommitted is removed; the lease would keep it otherwise
+            let committed = self.apply_layer(lease, layer, diff_id, chain_id, &key, prepared.mounts).await;
+            if !matches!(committed, Ok(true)) {
+                let remove = api::SnapshotKey { snapshotter: self.snapshotter.clone(), key };
+                let _ = self.call::<_, ()>(api::SNAPSHOTS_REMOVE, Some(lease), remove).await;
+            }
+            committed?;
+            parent = chain_id.clone();
+        }
+        Ok(())
+    }
+
+    async fn apply_layer(
+        &self,
+        lease: &str,
+        layer: &api::Descriptor,
+        diff_id: &str,
+        chain_id: &str,
+        key: &str,
+        mounts: Vec<api::Mount>,
+    ) -> Result<bool> {
+        let apply = api::ApplyRequest { diff: Some(layer.clone()), mounts };
+        let applied: api::ApplyResponse = self
+            .call(api::DIFF_APPLY, Some(lease), apply)
+            .await
+            .map_err(|e| error("applying layer", e))?;
+        let applied = applied.applied.map(|applied| applied.digest).unwrap_or_default();
+        if applied != diff_id {
+            return Err(anyhow::anyhow!("Layer {} unpacked to {}, expected {}", layer.digest, applied, diff_id));
+        }
+
+        let commit = api::CommitSnapshotRequest {
+            snapshotter: self.snapshotter.clone(),
+            name: chain_id.to_string(),
+            key: key.to_string(),
+        };
+        match self.call::<_, ()>(api::SNAPSHOTS_COMMIT, Some(lease), commit).await {
+            Ok(()) => Ok(true),
+            // Another import unpacked the same layers meanwhile
+            Err(status) if status.code() == Code::AlreadyExists => Ok(false),
+            Err(status) => Err(error("committing snapshot", status)),
+        }
+    }
+
+    async fn grpc(&self) -> Result<tonic::client::Grpc<Channel>, Status> {
+        let mut grpc = tonic::client::Grpc::new(self.channel.clone());
+        grpc.ready().await.map_err(|e| Status::unavailable(e.to_string()))?;
+        Ok(grpc)
+    }
+
+    // Every call names the namespace, and the lease what it writes is held by
+    #[allow(clippy::result_large_err)]
+    fn request<T>(&self, message: T, lease: Option<&str>) -> Result<Request<T>, Status> {
+        let mut request = Request::new(message);
+        let invalid = |e: tonic::metadata::errors::InvalidMetadataValue| Status::invalid_argument(e.to_string());
+        request.metadata_mut().insert("containerd-namespace", self.namespace.parse().map_err(invalid)?);
+        if let Some(lease) = lease {
+            request.metadata_mut().insert("containerd-lease", lease.parse().map_err(invalid)?);
+        }
+        Ok(request)
+    }
+
+    async fn call<Req, Resp>(&self, method: &'static str, lease: Option<&str>, message: Req) -> Result<Resp, Status>
+    where
+        Req: prost::Message + Send + Sync + 'static,
+        Resp: prost::Message + Default + Send + Sync + 'static,
+    {
+        let mut grpc = self.grpc().await?;
+        let codec = ProstCodec::<Req, Resp>::default();
+        let request = self.request(message, lease)?;
+        Ok(grpc.unary(request, PathAndQuery::from_static(method), codec).await?.into_inner())
+    }
+}
+
+fn error(what: &str, status: Status) -> anyhow::Error {
+    anyhow::anyhow!("containerd failed {}: {}", what, status.message())
+}
+
+// The name containerd, nerdctl and the kubelet look an image up by: the
+// registry, the full repository path and a tag
+pub fn containerd_name(name: &str) -> Result<String> {
+    let reference = Reference::parse(name)?;
+    let name = format!("{}/{}", reference.registry(), reference.repository());
+    Ok(match reference.digest() {
+        Some(digest) => format!("{}@{}", name, digest),
+        None => format!("{}:{}", name, reference.tag().unwrap_or("latest")),
+    })
+}
+
+// The chain ID of each layer, which names the snapshot holding the filesystem
+// up to it: the first layer's diff ID, then sha256 of "parent diff_id"
+pub fn chain_ids(diff_ids: &[String]) -> Vec<String> {
+    let mut chain_ids: Vec<String> = Vec::with_capacity(diff_ids.len());
+    for diff_id in diff_ids {
+        let chain_id = match chain_ids.last() {
+            None => diff_id.clone(),
+            Some(parent) => format!("sha256:{:x}", Sha256::digest(format!("{} {}", parent, diff_id))),
+        };
+        chain_ids.push(chain_id);
+    }
+    chain_ids
+}
+
+fn descriptor_of(descriptor: &Descriptor) -> api::Descriptor {
+    api::Descriptor {
+        media_type: descriptor.media_type().to_string(),
+        digest: descriptor.digest().to_string(),
+        size: descriptor.size() as i64,
+        annotations: HashMap::new(),
+    }
+}
+
+// The few messages of containerd's API this client uses, with the field
+// numbers of its .proto files; fields it does not read are left out
+mod api {
+    use std::collections::HashMap;
+
+    pub const CONTENT_INFO: &str = "/containerd.services.content.v1.Content/Info";
+    pub const CONTENT_UPDATE: &str = "/containerd.services.content.v1.Content/Update";
+    pub const CONTENT_WRITE: &str = "/containerd.services.content.v1.Content/Write";
+    pub const IMAGES_CREATE: &str = "/containerd.services.images.v1.Images/Create";
+    pub const IMAGES_UPDATE: &str = "/containerd.services.images.v1.Images/Update";
+    pub const LEASES_CREATE: &str = "/containerd.services.leases.v1.Leases/Create";
+    pub const LEASES_DELETE: &str = "/containerd.services.leases.v1.Leases/Delete";
+    pub const SNAPSHOTS_PREPARE: &str = "/containerd.services.snapshots.v1.Snapshots/Prepare";
+    pub const SNAPSHOTS_COMMIT: &str = "/containerd.services.snapshots.v1.Snapshots/Commit";
+    pub const SNAPSHOTS_STAT: &str = "/containerd.services.snapshots.v1.Snapshots/Stat";
+    pub const SNAPSHOTS_REMOVE: &str = "/containerd.services.snapshots.v1.Snapshots/Remove";
+    pub const DIFF_APPLY: &str = "/containerd.services.diff.v1.Diff/Apply";
+
+    // WriteAction values
+    pub const WRITE: i32 = 1;
+    pub const COMMIT: i32 = 2;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Descriptor {
+        #[prost(string, tag = "1")]
+        pub media_type: String,
+        #[prost(string, tag = "2")]
+        pub digest: String,
+        #[prost(int64, tag = "3")]
+        pub size: i64,
+        #[prost(map = "string, string", tag = "5")]
+        pub annotations: HashMap<String, String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Mount {
+        #[prost(string, tag = "1")]
+        pub r#type: String,
+        #[prost(string, tag = "2")]
+        pub source: String,
+        #[prost(string, tag = "3")]
+        pub target: String,
+        #[prost(string, repeated, tag = "4")]
+        pub options: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct FieldMask {
+        #[prost(string, repeated, tag = "1")]
+        pub paths: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct InfoRequest {
+        #[prost(string, tag = "1")]
+        pub digest: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ContentInfo {
+        #[prost(string, tag = "1")]
+        pub digest: String,
+        #[prost(map = "string, string", tag = "5")]
+        pub labels: HashMap<String, String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct UpdateContentRequest {
+        #[prost(message, optional, tag = "1")]
+        pub info: Option<ContentInfo>,
+        #[prost(message, optional, tag = "2")]
+        pub update_mask: Option<FieldMask>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WriteContentRequest {
+        #[prost(int32, tag = "1")]
+        pub action: i32,
+        #[prost(string, tag = "2")]
+        pub reference: String,
+        #[prost(int64, tag = "3")]
+        pub total: i64,
+        #[prost(string, tag = "4")]
+        pub expected: String,
+        #[prost(int64, tag = "5")]
+        pub offset: i64,
+        #[prost(bytes = "vec", tag = "6")]
+        pub data: Vec<u8>,
+        #[prost(map = "string, string", tag = "7")]
+        pub labels: HashMap<String, String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WriteContentResponse {
+        #[prost(int32, tag = "1")]
+        pub action: i32,
+        #[prost(int64, tag = "4")]
+        pub offset: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ImageRecord {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(map = "string, string", tag = "2")]
+        pub labels: HashMap<String, String>,
+        #[prost(message, optional, tag = "3")]
+        pub target: Option<Descriptor>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CreateImageRequest {
+        #[prost(message, optional, tag = "1")]
+        pub image: Option<ImageRecord>,
+    }
+
+    // Without an update mask, the labels and target are replaced
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct UpdateImageRequest {
+        #[prost(message, optional, tag = "1")]
+        pub image: Option<ImageRecord>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CreateLeaseRequest {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(map = "string, string", tag = "3")]
+        pub labels: HashMap<String, String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DeleteLeaseRequest {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(bool, tag = "2")]
+        pub sync: bool,
+    }
+
+    // The request of Stat and Remove
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SnapshotKey {
+        #[prost(string, tag = "1")]
+        pub snapshotter: String,
+        #[prost(string, tag = "2")]
+        pub key: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PrepareSnapshotRequest {
+        #[prost(string, tag = "1")]
+        pub snapshotter: String,
+        #[prost(string, tag = "2")]
+        pub key: String,
+        #[prost(string, tag = "3")]
+        pub parent: String,
+        #[prost(map = "string, string", tag = "4")]
+        pub labels: HashMap<String, String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PrepareSnapshotResponse {
+        #[prost(message, repeated, tag = "1")]
+        pub mounts: Vec<Mount>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommitSnapshotRequest {
+        #[prost(string, tag = "1")]
+        pub snapshotter: String,
+        #[prost(string, tag = "2")]
+        pub name: String,
+        #[prost(string, tag = "3")]
+        pub key: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ApplyRequest {
+        #[prost(message, optional, tag = "1")]
+        pub diff: Option<Descriptor>,
+        #[prost(message, repeated, tag = "2")]
+        pub mounts: Vec<Mount>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ApplyResponse {
+        #[prost(message, optional, tag = "1")]
+        pub applied: Option<Descriptor>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_containerd_names_and_chain_ids() {
+        assert_eq!(containerd_name("app").unwrap(), "docker.io/library/app:latest");
+        assert_eq!(containerd_name("team/app:v1").unwrap(), "docker.io/team/app:v1");
+        assert_eq!(containerd_name("registry.local:5000/app:dev").unwrap(), "registry.local:5000/app:dev");
+
+        let diff_ids = vec!["sha256:aaa".to_string(), "sha256:bbb".to_string()];
+        let chain = chain_ids(&diff_ids);
+        assert_eq!(chain[0], "sha256:aaa");
+        assert_eq!(chain[1], format!("sha256:{:x}", Sha256::digest("sha256:aaa sha256:bbb")));
+        assert!(chain_ids(&[]).is_empty());
+
+        let settings = ContainerdSettings { namespace: Some("k8s.io".to_string()), ..Default::default() };
+        assert_eq!(settings.namespace(), "k8s.io");
+    }
+}
@@ -0,0 +1,414 @@
+// Comparing two stored images for `diff`: the layers they do not share, the
+// config fields that differ, and the files that differ in their merged
+// filesystems, e.g. to find what broke between an old tag and a new one.
+use crate::dive::{ChangeKind, layer_instructions};
+use crate::engine::snapshot::{self, OPAQUE_WHITEOUT, WHITEOUT_PREFIX};
+use crate::report::human_size;
+use crate::storage::Image;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    Hardlink,
+    Other,
+}
+
+// A path in a merged filesystem, as the diff compares it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileInfo {
+    pub kind: FileKind,
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+    // sha256 of a regular file's content
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LayerSummary {
+    pub digest: String,
+    pub diff_id: String,
+    pub size: u64,
+    pub instruction: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConfigChange {
+    // e.g. env.PATH, label.org.opencontainers.image.version or entrypoint
+    pub field: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileDiff {
+    pub path: String,
+    pub kind: ChangeKind,
+    // What differs about a modified path: type, content, mode, owner or link
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changed: Vec<&'static str>,
+    pub old: Option<FileInfo>,
+    pub new: Option<FileInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageDiff {
+    pub old: String,
+    pub new: String,
+    // Layers both images start with
+    pub shared_layers: usize,
+    // Layers after the shared ones
+    pub old_layers: Vec<LayerSummary>,
+    pub new_layers: Vec<LayerSummary>,
+    pub config: Vec<ConfigChange>,
+    pub files: Vec<FileDiff>,
+}
+
+impl ImageDiff {
+    pub fn is_empty(&self) -> bool {
+        self.old_layers.is_empty() && self.new_layers.is_empty() && self.config.is_empty() && self.files.is_empty()
+    }
+}
+
+// Compare two stored images; blocking, as both images' layers are read
+pub fn diff_images(old: &Image, new: &Image) -> Result<ImageDiff> {
+    let shared_layers = old
+        .layers
+        .iter()
+        .zip(&new.layers)
+        .take_while(|(old, new)| old.diff_id == new.diff_id)
+        .count();
+    let files = if shared_layers == old.layers.len() && shared_layers == new.layers.len() {
+        Vec::new()
+    } else {
+        diff_files(&merged_files(old)?, &merged_files(new)?)
+    };
+    Ok(ImageDiff {
+        old: old.name.clone(),
+        new: new.name.clone(),
+        shared_layers,
+        old_layers: layer_summaries(old, shared_layers),
+        new_layers: layer_summaries(new, shared_layers),
+        config: diff_config(&config_fields(old), &config_fields(new)),
+        files,
+    })
+}
+
+fn layer_summaries(image: &Image, skip: usize) -> Vec<LayerSummary> {
+    let mut instructions = layer_instructions(image).into_iter();
+    image
+        .layers
+        .iter()
+        .map(|layer| LayerSummary {
+            digest: layer.digest.clone(),
+            diff_id: layer.diff_id.clone(),
+            size: layer.diff_size,
+            instruction: instructions.next().unwrap_or_default(),
+        })
+        .skip(skip)
+        .collect()
+}
+
+// The config fields a diff compares, flattened to one string per field
+fn config_fields(image: &Image) -> BTreeMap<String, String> {
+    let mut fields = BTreeMap::new();
+    let config = &image.config;
+    let mut platform = format!("{}/{}", config.os(), config.architecture());
+    if let Some(variant) = config.variant() {
+        platform = format!("{}/{}", platform, variant);
+    }
+    fields.insert("platform".to_string(), platform);
+
+    let Some(runtime) = config.config() else {
+        return fields;
+    };
+    for variable in runtime.env().iter().flatten() {
+        let (name, value) = variable.split_once('=').unwrap_or((variable, ""));
+        fields.insert(format!("env.{}", name), value.to_string());
+    }
+    for (key, value) in runtime.labels().iter().flatten() {
+        fields.insert(format!("label.{}", key), value.clone());
+    }
+    let lists = [
+        ("entrypoint", runtime.entrypoint()),
+        ("cmd", runtime.cmd()),
+        ("exposed_ports", runtime.exposed_ports()),
+        ("volumes", runtime.volumes()),
+    ];
+    for (name, list) in lists {
+        if let Some(list) = list {
+            fields.insert(name.to_string(), serde_json::to_string(list).unwrap_or_default());
+        }
+    }
+    let strings = [
+        ("workdir", runtime.working_dir()),
+        ("user", runtime.user()),
+        ("stop_signal", runtime.stop_signal()),
+    ];
+    for (name, value) in strings {
+        if let Some(value) = value.as_ref().filter(|value| !value.is_empty()) {
+            fields.insert(name.to_string(), value.clone());
+        }
+    }
+    fields
+}
+
+fn diff_config(old: &BTreeMap<String, String>, new: &BTreeMap<String, String>) -> Vec<ConfigChange> {
+    let fields: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    fields
+        .into_iter()
+        .filter(|field| old.get(*field) != new.get(*field))
+        .map(|field| ConfigChange {
+            field: field.clone(),
+            old: old.get(field).cloned(),
+            new: new.get(field).cloned(),
+        })
+        .collect()
+}
+
+// Every path of an image's merged filesystem
+pub fn merged_files(image: &Image) -> Result<BTreeMap<String, FileInfo>> {
+    let mut files = BTreeMap::new();
+    for layer in &image.layers {
+        apply_layer(&mut files, layer.open_tar()?)?;
+    }
+    Ok(files)
+}
+
+// Apply one uncompressed layer tar to a file listing, honouring whiteouts
+pub fn apply_layer<R: Read>(files: &mut BTreeMap<String, FileInfo>, layer: R) -> Result<()> {
+    use sha2::{Digest, Sha256};
+
+    let mut archive = tar::Archive::new(layer);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = snapshot::normalize(&entry.path()?);
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let parent = path.parent().unwrap_or(Path::new("")).to_string_lossy().to_string();
+        if name.is_empty() {
+            continue;
+        }
+        if name == OPAQUE_WHITEOUT {
+            let below = if parent.is_empty() { String::new() } else { format!("{}/", parent) };
+            files.retain(|path, _| !path.starts_with(&below));
+            continue;
+        }
+        if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            let target = if parent.is_empty() { hidden.to_string() } else { format!("{}/{}", parent, hidden) };
+            let below = format!("{}/", target);
+            files.retain(|path, _| path != &target && !path.starts_with(&below));
+            continue;
+        }
+
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        let kind = if entry_type.is_dir() {
+            FileKind::Dir
+        } else if entry_type.is_symlink() {
+            FileKind::Symlink
+        } else if entry_type.is_hard_link() {
+            FileKind::Hardlink
+        } else if entry_type.is_file() {
+            FileKind::File
+        } else {
+            FileKind::Other
+        };
+        let mode = header.mode()? & 0o7777;
+        let (uid, gid) = (header.uid()?, header.gid()?);
+        let link = entry.link_name()?.map(|link| link.to_string_lossy().to_string());
+        let size = entry.size();
+        let digest = if kind == FileKind::File {
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut entry, &mut hasher)?;
+            Some(format!("sha256:{:x}", hasher.finalize()))
+        } else {
+            None
+        };
+
+        let path = path.to_string_lossy().to_string();
+        // A file or link replacing a directory hides what was under it
+        if kind != FileKind::Dir {
+            let below = format!("{}/", path);
+            files.retain(|path, _| !path.starts_with(&below));
+        }
+        files.insert(path, FileInfo { kind, size, mode, uid, gid, link, digest });
+    }
+    Ok(())
+}
+
+pub fn diff_files(old: &BTreeMap<String, FileInfo>, new: &BTreeMap<String, FileInfo>) -> Vec<FileDiff> {
+    let paths: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    let mut diffs = Vec::new();
+    for path in paths {
+        let (before, after) = (old.get(path), new.get(path));
+        let (kind, changed) = match (before, after) {
+            (Some(before), Some(after)) => {
+                let mut changed = Vec::new();
+                if before.kind != after.kind {
+                    changed.push("type");
+                } else if before.digest != after.digest {
+                    changed.push("content");
+                }
+                if before.mode != after.mode {
+                    changed.push("mode");
+                }
+                if (before.uid, before.gid) != (after.uid, after.gid) {
+                    changed.push("owner");
+                }
+                if before.kind == after.kind && before.link != after.link {
+                    changed.push("link");
+                }
+                if changed.is_empty() {
+                    continue;
+                }
+                (ChangeKind::Modified, changed)
+            }
+            (None, Some(_)) => (ChangeKind::Added, Vec::new()),
+            (Some(_), None) => (ChangeKind::Deleted, Vec::new()),
+            (None, None) => continue,
+        };
+        diffs.push(FileDiff {
+            path: path.clone(),
+            kind,
+            changed,
+            old: before.cloned(),
+            new: after.cloned(),
+        });
+    }
+    diffs
+}
+
+// `diff --format text`: one section each for layers, config and files
+pub fn render_text(diff: &ImageDiff) -> String {
+    let mut text = format!("--- {}\n+++ {}\n", diff.old, diff.new);
+    if diff.is_empty() {
+        text.push_str("\nThe images have the same layers and config\n");
+        return text;
+    }
+
+    text.push_str(&format!("\nLayers: {} shared\n", diff.shared_layers));
+    for (marker, layers) in [('-', &diff.old_layers), ('+', &diff.new_layers)] {
+        for layer in layers {
+            text.push_str(&format!(
+                "{} {}  {:>9}  {}\n",
+                marker,
+                short_digest(&layer.diff_id),
+                human_size(layer.size),
+                layer.instruction
+            ));
+        }
+    }
+
+    if !diff.config.is_empty() {
+        text.push_str("\nConfig:\n");
+        for change in &diff.config {
+            let line = match (&change.old, &change.new) {
+                (Some(old), Some(new)) => format!("~ {}: {} -> {}", change.field, old, new),
+                (None, Some(new)) => format!("+ {}: {}", change.field, new),
+                (Some(old), None) => format!("- {}: {}", change.field, old),
+                (None, None) => continue,
+            };
+            text.push_str(&line);
+            text.push('\n');
+        }
+    }
+
+    if !diff.files.is_empty() {
+        text.push_str(&format!("\nFiles: {} changed\n", diff.files.len()));
+        for file in &diff.files {
+            let detail = match (&file.old, &file.new) {
+                (Some(old), Some(new)) if file.changed.contains(&"content") => {
+                    format!(" (content, {} -> {})", human_size(old.size), human_size(new.size))
+                }
+                (Some(_), Some(_)) => format!(" ({})", file.changed.join(", ")),
+                (None, Some(info)) | (Some(info), None) if info.kind == FileKind::File => {
+                    format!(" ({})", human_size(info.size))
+                }
+                _ => String::new(),
+            };
+            text.push_str(&format!("{} /{}{}\n", file.kind.marker(), file.path, detail));
+        }
+    }
+    text
+}
+
+fn short_digest(digest: &str) -> &str {
+    let hex = digest.strip_prefix("sha256:").unwrap_or(digest);
+    &hex[..hex.len().min(12)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::layer_tar;
+
+    #[test]
+    fn test_diff_files_and_config() {
+        let mut old = BTreeMap::new();
+        apply_layer(&mut old, layer_tar(&[("etc/app.conf", b"v1", 0o644), ("bin/tool", b"x", 0o755)]).as_slice())
+            .unwrap();
+        apply_layer(&mut old, layer_tar(&[("var/cache/a", b"aaa", 0o644)]).as_slice()).unwrap();
+        let mut new = old.clone();
+        apply_layer(
+            &mut new,
+            layer_tar(&[("etc/app.conf", b"v2", 0o644), ("bin/tool", b"x", 0o700), ("var/.wh.cache", b"", 0)])
+                .as_slice(),
+        )
+        .unwrap();
+        apply_layer(&mut new, layer_tar(&[("usr/bin/new", b"new", 0o755)]).as_slice()).unwrap();
+
+        let files = diff_files(&old, &new);
+        let summary: Vec<(&str, ChangeKind, Vec<&str>)> =
+            files.iter().map(|file| (file.path.as_str(), file.kind, file.changed.clone())).collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("bin/tool", ChangeKind::Modified, vec!["mode"]),
+                ("etc/app.conf", ChangeKind::Modified, vec!["content"]),
+                ("usr/bin/new", ChangeKind::Added, vec![]),
+                ("var/cache/a", ChangeKind::Deleted, vec![]),
+            ]
+        );
+
+        let old_config: BTreeMap<String, String> =
+            [("env.VERSION", "1.0"), ("env.PATH", "/bin"), ("label.team", "web")]
+                .into_iter()
+                .map(|(field, value)| (field.to_string(), value.to_string()))
+                .collect();
+        let mut new_config = old_config.clone();
+        new_config.insert("env.VERSION".to_string(), "1.1".to_string());
+        new_config.remove("label.team");
+        new_config.insert("user".to_string(), "app".to_string());
+        let config = diff_config(&old_config, &new_config);
+        let fields: Vec<&str> = config.iter().map(|change| change.field.as_str()).collect();
+        assert_eq!(fields, vec!["env.VERSION", "label.team", "user"]);
+
+        let diff = ImageDiff {
+            old: "app:1".to_string(),
+            new: "app:2".to_string(),
+            shared_layers: 2,
+            old_layers: Vec::new(),
+            new_layers: Vec::new(),
+            config,
+            files,
+        };
+        let text = render_text(&diff);
+        assert!(text.contains("~ env.VERSION: 1.0 -> 1.1\n"));
+        assert!(text.contains("- label.team: web\n"));
+        assert!(text.contains("~ /etc/app.conf (content, 2 B -> 2 B)\n"));
+        assert!(text.contains("~ /bin/tool (mode)\n"));
+        assert!(text.contains("+ /usr/bin/new (3 B)\n"));
+    }
+}
@@ -0,0 +1,431 @@
+// Layer by layer analysis of a stored image for `dive`: which files each
+// layer adds, modifies or deletes, and how many bytes the image ships that a
+// later layer hides again by overwriting or deleting them.
+use crate::engine::snapshot::{self, OPAQUE_WHITEOUT, WHITEOUT_PREFIX};
+use crate::report::human_size;
+use crate::storage::Image;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+}
+
+impl ChangeKind {
+    pub fn marker(self) -> char {
+        match self {
+            ChangeKind::Added => '+',
+            ChangeKind::Modified => '~',
+            ChangeKind::Deleted => '-',
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileChange {
+    pub path: String,
+    pub kind: ChangeKind,
+    // Bytes written, or for a deletion the bytes it hid
+    pub size: u64,
+    pub is_dir: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LayerAnalysis {
+    pub digest: String,
+    // The compressed blob, as pushed
+    pub size: u64,
+    pub diff_size: u64,
+    // The history entry's created_by, e.g. the Dockerfile instruction
+    pub instruction: String,
+    pub changes: Vec<FileChange>,
+}
+
+// A path whose content was shipped more than once, or shipped and deleted
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WastedFile {
+    pub path: String,
+    // Bytes of the copies a later layer hides
+    pub bytes: u64,
+    pub copies: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageAnalysis {
+    pub name: String,
+    pub id: String,
+    pub layers: Vec<LayerAnalysis>,
+    // Bytes of every file in every layer
+    pub file_bytes: u64,
+    pub wasted_bytes: u64,
+    // Share of file bytes still visible in the final filesystem
+    pub efficiency: f64,
+    // Largest first
+    pub wasted: Vec<WastedFile>,
+}
+
+// One line of a layer's file tree
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeRow {
+    pub path: String,
+    pub name: String,
+    pub depth: usize,
+    // A directory's size is that of the files shown under it
+    pub size: u64,
+    pub is_dir: bool,
+    // How the layer changed this path; None for paths it left alone
+    pub kind: Option<ChangeKind>,
+}
+
+// Read every layer of a stored image; blocking, as layers are decompressed
+pub fn analyze(image: &Image) -> Result<ImageAnalysis> {
+    let mut instructions = layer_instructions(image).into_iter();
+    let mut analyzer = Analyzer::default();
+    let mut layers = Vec::new();
+    for layer in &image.layers {
+        let changes = analyzer.add_layer(layer.open_tar()?)?;
+        layers.push(LayerAnalysis {
+            digest: layer.digest.clone(),
+            size: layer.size,
+            diff_size: layer.diff_size,
+            instruction: instructions.next().unwrap_or_default(),
+            changes,
+        });
+    }
+    Ok(analyzer.finish(&image.name, &image.id, layers))
+}
+
+// The instruction that made each layer, from the history entries that have one
+pub(crate) fn layer_instructions(image: &Image) -> Vec<String> {
+    image
+        .config
+        .history()
+        .as_ref()
+        .map(|history| {
+            history
+                .iter()
+                .filter(|entry| !entry.empty_layer().unwrap_or(false))
+                .map(|entry| instruction(entry.created_by().as_deref().unwrap_or_default()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Images built elsewhere record RUN steps as the shell command and other
+// steps behind a `#(nop)` marker
+fn instruction(created_by: &str) -> String {
+    let created_by = created_by.trim();
+    let created_by = created_by.strip_prefix("/bin/sh -c #(nop)").unwrap_or(created_by);
+    created_by.trim().to_string()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Visible {
+    size: u64,
+    is_dir: bool,
+}
+
+// Replays layer tars, lowest first, over the filesystem they build
+#[derive(Debug, Default)]
+pub struct Analyzer {
+    visible: BTreeMap<String, Visible>,
+    file_bytes: u64,
+    wasted: HashMap<String, WastedFile>,
+}
+
+impl Analyzer {
+    // The changes one uncompressed layer tar makes to the layers below it
+    pub fn add_layer<R: Read>(&mut self, layer: R) -> Result<Vec<FileChange>> {
+        let mut changes = Vec::new();
+        let mut archive = tar::Archive::new(layer);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let path = snapshot::normalize(&entry.path()?);
+            let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+            let parent = path.parent().unwrap_or(Path::new("")).to_string_lossy().to_string();
+            if name.is_empty() {
+                continue;
+            }
+
+            if name == OPAQUE_WHITEOUT {
+                let children: Vec<(String, bool)> = self
+                    .visible
+                    .iter()
+                    .filter(|(child, _)| parent_of(child) == parent)
+                    .map(|(child, visible)| (child.clone(), visible.is_dir))
+                    .collect();
+                for (child, is_dir) in children {
+                    let size = self.hide(&child);
+                    changes.push(FileChange { path: child, kind: ChangeKind::Deleted, size, is_dir });
+                }
+                continue;
+            }
+            if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                let target = join(&parent, hidden);
+                let is_dir = self.visible.get(&target).is_some_and(|visible| visible.is_dir);
+                let size = self.hide(&target);
+                changes.push(FileChange { path: target, kind: ChangeKind::Deleted, size, is_dir });
+                continue;
+            }
+
+            let path = path.to_string_lossy().to_string();
+            let is_dir = entry.header().entry_type().is_dir();
+            let size = if is_dir { 0 } else { entry.size() };
+            let kind = match self.visible.get(&path).copied() {
+                // Layers repeat the directories they write into
+                Some(previous) if previous.is_dir && is_dir => continue,
+                Some(previous) => {
+                    if previous.is_dir {
+                        self.hide(&path);
+                    } else {
+                        self.waste(&path, previous.size);
+                    }
+                    ChangeKind::Modified
+                }
+                None => ChangeKind::Added,
+            };
+            self.file_bytes += size;
+            self.visible.insert(path.clone(), Visible { size, is_dir });
+            changes.push(FileChange { path, kind, size, is_dir });
+        }
+        Ok(changes)
+    }
+
+    pub fn finish(self, name: &str, id: &str, layers: Vec<LayerAnalysis>) -> ImageAnalysis {
+        let mut wasted: Vec<WastedFile> = self.wasted.into_values().collect();
+        wasted.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.path.cmp(&b.path)));
+        let wasted_bytes = wasted.iter().map(|file| file.bytes).sum();
+        let efficiency = if self.file_bytes == 0 {
+            1.0
+        } else {
+            1.0 - wasted_bytes as f64 / self.file_bytes as f64
+        };
+        ImageAnalysis {
+            name: name.to_string(),
+            id: id.to_string(),
+            layers,
+            file_bytes: self.file_bytes,
+            wasted_bytes,
+            efficiency,
+            wasted,
+        }
+    }
+
+    // Remove a path and everything under it, returning the file bytes hidden
+    fn hide(&mut self, path: &str) -> u64 {
+        let hidden: Vec<(String, Visible)> = self
+            .visible
+            .range(path.to_string()..)
+            .take_while(|(candidate, _)| candidate.starts_with(path))
+            .filter(|(candidate, _)| is_within(candidate, path))
+            .map(|(candidate, visible)| (candidate.clone(), *visible))
+            .collect();
+        let mut bytes = 0;
+        for (candidate, visible) in hidden {
+            self.visible.remove(&candidate);
+            if !visible.is_dir {
+                self.waste(&candidate, visible.size);
+                bytes += visible.size;
+            }
+        }
+        bytes
+    }
+
+    fn waste(&mut self, path: &str, bytes: u64) {
+        let file = self.wasted.entry(path.to_string()).or_insert_with(|| WastedFile {
+            path: path.to_string(),
+            bytes: 0,
+            copies: 0,
+        });
+        file.bytes += bytes;
+        file.copies += 1;
+    }
+}
+
+impl ImageAnalysis {
+    pub fn image_size(&self) -> u64 {
+        self.layers.iter().map(|layer| layer.diff_size).sum()
+    }
+
+    // The file tree of layer `layer`: only the paths it changed, or with
+    // `whole` the whole filesystem as of that layer, changes marked
+    pub fn tree(&self, layer: usize, whole: bool) -> Vec<TreeRow> {
+        let mut nodes: BTreeMap<Vec<String>, TreeRow> = BTreeMap::new();
+        let mut add = |path: &str, size: u64, is_dir: bool, kind: Option<ChangeKind>| {
+            let components: Vec<String> = path.split('/').map(str::to_string).collect();
+            for depth in 1..components.len() {
+                let key = components[..depth].to_vec();
+                let ancestor = nodes.entry(key.clone()).or_insert_with(|| TreeRow {
+                    path: key.join("/"),
+                    name: key[depth - 1].clone(),
+                    depth: depth - 1,
+                    size: 0,
+                    is_dir: true,
+                    kind: None,
+                });
+                if kind != Some(ChangeKind::Deleted) {
+                    ancestor.size += size;
+                }
+            }
+            let name = components.last().cloned().unwrap_or_default();
+            let node = nodes.entry(components).or_insert_with(|| TreeRow {
+                path: path.to_string(),
+                name,
+                depth: path.matches('/').count(),
+                size: 0,
+                is_dir,
+                kind: None,
+            });
+            node.size += size;
+            node.is_dir = is_dir;
+            if kind.is_some() {
+                node.kind = kind;
+            }
+        };
+
+        if whole {
+            let mut visible: BTreeMap<&str, (u64, bool)> = BTreeMap::new();
+            for analysis in &self.layers[..layer] {
+                for change in &analysis.changes {
+                    match change.kind {
+                        ChangeKind::Deleted => {
+                            visible.retain(|path, _| !is_within(path, &change.path));
+                        }
+                        _ => {
+                            visible.insert(&change.path, (change.size, change.is_dir));
+                        }
+                    }
+                }
+            }
+            for change in &self.layers[layer].changes {
+                if change.kind == ChangeKind::Deleted {
+                    visible.retain(|path, _| !is_within(path, &change.path));
+                } else {
+                    visible.remove(change.path.as_str());
+                }
+            }
+            for (path, (size, is_dir)) in visible {
+                add(path, size, is_dir, None);
+            }
+        }
+        for change in &self.layers[layer].changes {
+            add(&change.path, change.size, change.is_dir, Some(change.kind));
+        }
+        nodes.into_values().collect()
+    }
+}
+
+// `dive --report`: the layers, the image's efficiency and its largest wasted files
+pub fn render_report(analysis: &ImageAnalysis, top: usize) -> String {
+    let mut report = format!("Image: {} ({})\n\n", analysis.name, analysis.id);
+    report.push_str(&format!("{:>3}  {:>9}  {:>7}  INSTRUCTION\n", "#", "SIZE", "CHANGES"));
+    for (index, layer) in analysis.layers.iter().enumerate() {
+        report.push_str(&format!(
+            "{:>3}  {:>9}  {:>7}  {}\n",
+            index + 1,
+            human_size(layer.diff_size),
+            layer.changes.len(),
+            layer.instruction
+        ));
+    }
+    report.push_str(&format!("\nTotal image size: {}\n", human_size(analysis.image_size())));
+    report.push_str(&format!("Potential wasted space: {}\n", human_size(analysis.wasted_bytes)));
+    report.push_str(&format!("Image efficiency score: {:.1} %\n", analysis.efficiency * 100.0));
+    if !analysis.wasted.is_empty() {
+        report.push_str(&format!("\n{:>6}  {:>9}  PATH\n", "COPIES", "WASTED"));
+        for file in analysis.wasted.iter().take(top) {
+            report.push_str(&format!("{:>6}  {:>9}  /{}\n", file.copies, human_size(file.bytes), file.path));
+        }
+    }
+    report
+}
+
+fn is_within(path: &str, ancestor: &str) -> bool {
+    path == ancestor || path.strip_prefix(ancestor).is_some_and(|rest| rest.starts_with('/'))
+}
+
+fn parent_of(path: &str) -> &str {
+    path.rsplit_once('/').map(|(parent, _)| parent).unwrap_or("")
+}
+
+fn join(parent: &str, name: &str) -> String {
+    if parent.is_empty() { name.to_string() } else { format!("{}/{}", parent, name) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::layer_tar;
+
+    #[test]
+    fn test_layer_changes_and_wasted_space() {
+        let mut analyzer = Analyzer::default();
+        let base = layer_tar(&[
+            ("etc/", b"", 0o755),
+            ("etc/os-release", b"debian", 0o644),
+            ("var/", b"", 0o755),
+            ("var/cache/", b"", 0o755),
+            ("var/cache/apt.bin", &[0u8; 100], 0o644),
+            ("var/cache/lists", &[0u8; 50], 0o644),
+        ]);
+        let update = layer_tar(&[
+            ("etc/", b"", 0o755),
+            ("etc/os-release", b"debian 12", 0o644),
+            ("etc/app.conf", b"x", 0o644),
+            ("var/.wh.cache", b"", 0o644),
+        ]);
+        let mut layers = Vec::new();
+        for (tar, instruction) in [(base, "ADD rootfs.tar /"), (update, "RUN upgrade")] {
+            layers.push(LayerAnalysis {
+                digest: String::new(),
+                size: 0,
+                diff_size: tar.len() as u64,
+                instruction: instruction.to_string(),
+                changes: analyzer.add_layer(tar.as_slice()).unwrap(),
+            });
+        }
+
+        let changes = &layers[1].changes;
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0].kind, ChangeKind::Modified);
+        assert_eq!((changes[1].path.as_str(), changes[1].kind), ("etc/app.conf", ChangeKind::Added));
+        assert_eq!(
+            changes[2],
+            FileChange { path: "var/cache".to_string(), kind: ChangeKind::Deleted, size: 150, is_dir: true }
+        );
+
+        let analysis = analyzer.finish("app:latest", "abc", layers);
+        assert_eq!(analysis.file_bytes, 166);
+        assert_eq!(analysis.wasted_bytes, 156);
+        assert_eq!(analysis.wasted[0].path, "var/cache/apt.bin");
+        assert!((analysis.efficiency - 10.0 / 166.0).abs() < 1e-9);
+
+        let changed: Vec<(String, Option<ChangeKind>)> =
+            analysis.tree(1, false).into_iter().map(|row| (row.path, row.kind)).collect();
+        assert_eq!(
+            changed,
+            vec![
+                ("etc".to_string(), None),
+                ("etc/app.conf".to_string(), Some(ChangeKind::Added)),
+                ("etc/os-release".to_string(), Some(ChangeKind::Modified)),
+                ("var".to_string(), None),
+                ("var/cache".to_string(), Some(ChangeKind::Deleted)),
+            ]
+        );
+        let whole = analysis.tree(1, true);
+        assert_eq!(whole.len(), 5);
+        assert_eq!((whole[0].path.as_str(), whole[0].size), ("etc", 10));
+        assert_eq!(whole[3].kind, None);
+
+        let report = render_report(&analysis, 10);
+        assert!(report.contains("Image efficiency score: 6.0 %"));
+        assert!(report.contains("/var/cache/apt.bin"));
+    }
+}
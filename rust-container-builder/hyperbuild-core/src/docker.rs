@@ -0,0 +1,158 @@
+// Loading stored images into a Docker daemon over its API socket, so they
+// run with plain `docker run`. The image is streamed to /images/load as the
+// same archive `build --output type=docker` writes, without a file between.
+use crate::engine::export;
+use crate::storage::Image;
+use anyhow::Result;
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use http_body_util::channel::{Channel, Sender};
+use hyper::{Request, StatusCode};
+use serde_json::Value;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+
+// The archive is sent in chunks of this size
+const CHUNK_SIZE: usize = 256 << 10;
+
+// The socket to reach the daemon on: `address`, else DOCKER_HOST when it is a
+// unix:// one, as with the docker CLI
+pub fn socket(address: Option<PathBuf>) -> Result<PathBuf> {
+    if let Some(address) = address {
+        return Ok(address);
+    }
+    match std::env::var("DOCKER_HOST") {
+        Ok(host) if !host.is_empty() => match host.strip_prefix("unix://") {
+            Some(path) => Ok(PathBuf::from(path)),
+            None => Err(anyhow::anyhow!("DOCKER_HOST {:?} is not a unix:// socket, the only kind supported", host)),
+        },
+        _ => Ok(PathBuf::from(DEFAULT_SOCKET)),
+    }
+}
+
+// Stream `image` to the daemon behind `socket` and return the names it loaded it as
+pub async fn load(socket: &Path, image: &Image) -> Result<Vec<String>> {
+    let stream = tokio::net::UnixStream::connect(socket)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to connect to the Docker daemon at {}: {}", socket.display(), e))?;
+    let (mut client, connection) = hyper::client::conn::http1::handshake(hyper_util::rt::TokioIo::new(stream))
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to connect to the Docker daemon at {}: {}", socket.display(), e))?;
+    tokio::spawn(connection);
+
+    let (sender, body) = Channel::<Bytes, std::io::Error>::new(4);
+    // The host is required but unused: the request goes to the socket
+    let request = Request::post("/images/load?quiet=1")
+        .header("Host", "docker")
+        .header("Content-Type", "application/x-tar")
+        .body(body)?;
+
+    // The archive is written as the daemon reads it, from layers decompressed on the fly
+    let archived = image.clone();
+    let runtime = tokio::runtime::Handle::current();
+    let writer = tokio::task::spawn_blocking(move || {
+        let mut writer = BodyWriter { sender: Some(sender), runtime, buffer: Vec::with_capacity(CHUNK_SIZE) };
+        let written = write_archive(&mut writer, &archived);
+        // Failing the body rather than ending it keeps the daemon from loading a truncated archive
+        if let (Err(e), Some(sender)) = (&written, writer.sender.take()) {
+            sender.abort(std::io::Error::other(e.to_string()));
+        }
+        written
+    });
+    let (response, written) = tokio::join!(client.send_request(request), writer);
+    let response = match (response, written?) {
+        // A daemon that stopped reading the archive says why in its response
+        (Ok(response), Err(_)) if response.status() != StatusCode::OK => response,
+        (_, Err(e)) => return Err(e),
+        (Ok(response), Ok(())) => response,
+        (Err(e), Ok(())) => return Err(anyhow::anyhow!("Failed to send the image to the Docker daemon: {}", e)),
+    };
+
+    let status = response.status();
+    let body = response
+        .into_body()
+        .collect()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read the Docker daemon's response: {}", e))?
+        .to_bytes();
+    if status != StatusCode::OK {
+        let message = serde_json::from_slice::<Value>(&body)
+            .ok()
+            .and_then(|error| error["message"].as_str().map(String::from))
+            .unwrap_or_else(|| String::from_utf8_lossy(&body).trim().to_string());
+        return Err(anyhow::anyhow!("Docker daemon failed loading {}: {} ({})", image.name, message, status));
+    }
+    loaded_names(&body)
+}
+
+fn write_archive(writer: &mut BodyWriter, image: &Image) -> Result<()> {
+    let mut builder = tar::Builder::new(writer);
+    export::write_docker_archive(&mut builder, image)?;
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+// The names in the messages /images/load answers with, one JSON object each:
+// {"stream":"Loaded image: app:1\n"} or {"error":"..."}
+fn loaded_names(body: &[u8]) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for message in serde_json::Deserializer::from_slice(body).into_iter::<Value>() {
+        let message = message.map_err(|e| anyhow::anyhow!("Invalid response from the Docker daemon: {}", e))?;
+        if let Some(error) = message["error"].as_str() {
+            return Err(anyhow::anyhow!("Docker daemon failed loading the image: {}", error));
+        }
+        let line = message["stream"].as_str().unwrap_or_default().trim();
+        if let Some(name) = line.strip_prefix("Loaded image: ").or_else(|| line.strip_prefix("Loaded image ID: ")) {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+// Where the archive is written: chunks of the request body, sent from the
+// blocking thread the archive is written on
+struct BodyWriter {
+    sender: Option<Sender<Bytes, std::io::Error>>,
+    runtime: tokio::runtime::Handle,
+    buffer: Vec<u8>,
+}
+
+impl BodyWriter {
+    fn send(&mut self) -> std::io::Result<()> {
+        let chunk = Bytes::from(std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE)));
+        let closed = || std::io::Error::new(std::io::ErrorKind::BrokenPipe, "the Docker daemon closed the connection");
+        let sender = self.sender.as_mut().ok_or_else(closed)?;
+        self.runtime.block_on(sender.send_data(chunk)).map_err(|_| closed())
+    }
+}
+
+impl Write for BodyWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() { Ok(()) } else { self.send() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_docker_load_messages() {
+        let messages = [r#"{"stream":"Loaded image: my-app:dev\n"}"#, r#"{"stream":"Loaded image ID: sha256:ab\n"}"#];
+        let body = messages.join("\r\n");
+        assert_eq!(loaded_names(body.as_bytes()).unwrap(), ["my-app:dev", "sha256:ab"]);
+        let body = br#"{"errorDetail":{"message":"no space left"},"error":"no space left"}"#;
+        assert!(loaded_names(body).unwrap_err().to_string().contains("no space left"));
+        assert_eq!(socket(Some(PathBuf::from("/tmp/docker.sock"))).unwrap(), Path::new("/tmp/docker.sock"));
+    }
+}
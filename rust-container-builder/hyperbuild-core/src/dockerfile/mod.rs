@@ -0,0 +1,918 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    From {
+        image: String,
+        alias: Option<String>,
+    },
+    Run {
+        command: String,
+        options: RunOptions,
+    },
+    Cmd {
+        command: Vec<String>,
+    },
+    Label {
+        key: String,
+        value: String,
+    },
+    Env {
+        key: String,
+        value: String,
+    },
+    Copy {
+        src: Vec<String>,
+        dest: String,
+        from: Option<String>, // For --from flag
+    },
+    Add {
+        src: Vec<String>,
+        dest: String,
+    },
+    Workdir {
+        path: String,
+    },
+    Expose {
+        ports: Vec<String>, // Normalized to port/protocol, e.g. "80/tcp"
+    },
+    Entrypoint {
+        command: Vec<String>,
+    },
+    Volume {
+        volumes: Vec<String>,
+    },
+    User {
+        user: String,
+    },
+    Arg {
+        key: String,
+        default: Option<String>,
+    },
+    Onbuild {
+        instruction: Box<Instruction>,
+    },
+    StopSignal {
+        signal: String,
+    },
+    // Durations are in nanoseconds, as stored in the image config
+    Healthcheck {
+        interval: Option<u64>,
+        timeout: Option<u64>,
+        start_period: Option<u64>,
+        retries: Option<u32>,
+        cmd: Vec<String>,
+    },
+    Shell {
+        shell: Vec<String>,
+    },
+    // An instruction hyperbuild does not know, e.g. `VAULT secret/app /etc/app.json`,
+    // left for a plugin to handle; `keyword` is as written in the Dockerfile
+    Custom {
+        keyword: String,
+        args: String,
+    },
+}
+
+// Flags given to a RUN instruction, e.g. `RUN --mount=type=secret,id=npmrc ...`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunOptions {
+    pub mounts: Vec<RunMount>,
+    // `--network=...`; unset means the build-wide default
+    pub network: Option<NetworkMode>,
+    // `--retry=N`; unset means the build-wide `--retry`
+    pub retry: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NetworkMode {
+    // Whatever the build was started with (the host network unless --network says otherwise)
+    #[default]
+    Default,
+    // A private network namespace with no interfaces configured
+    None,
+    Host,
+}
+
+impl NetworkMode {
+    pub fn parse(mode: &str) -> Result<Self> {
+        match mode {
+            "default" => Ok(Self::Default),
+            "none" => Ok(Self::None),
+            "host" => Ok(Self::Host),
+            other => Err(anyhow::anyhow!("Unknown network mode {:?}, expected none, host or default", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunMount {
+    Secret {
+        id: String,
+        target: Option<String>,
+        required: bool,
+    },
+    // Mount types hyperbuild does not implement yet (cache, bind, ssh, ...)
+    Unsupported {
+        mount_type: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct ParsedDockerfile {
+    pub stages: Vec<BuildStage>,
+    // Global ARGs declared before the first FROM, with their defaults
+    pub args: HashMap<String, Option<String>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BuildStage {
+    pub name: Option<String>,
+    pub base_image: String,
+    pub instructions: Vec<Instruction>,
+    // Dockerfile lines of its FROM and of each of its instructions
+    pub line: usize,
+    pub lines: Vec<usize>,
+}
+
+// An error at one line of the Dockerfile: one it cannot parse, or a step that failed
+#[derive(Debug, thiserror::Error)]
+#[error("Dockerfile line {line}: {message}")]
+pub struct LineError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Instruction {
+    // Expand `$VAR` references in the fields Docker substitutes at build time.
+    // Shell-form commands are left alone; the shell expands those when run.
+    pub fn expand(&self, vars: &HashMap<String, String>) -> Instruction {
+        let expand_all = |values: &[String]| values.iter().map(|v| expand_vars(v, vars)).collect();
+        match self {
+            Instruction::From { image, alias } => Instruction::From {
+                image: expand_vars(image, vars),
+                alias: alias.clone(),
+            },
+            Instruction::Label { key, value } => Instruction::Label {
+                key: expand_vars(key, vars),
+                value: expand_vars(value, vars),
+            },
+            Instruction::Env { key, value } => Instruction::Env {
+                key: key.clone(),
+                value: expand_vars(value, vars),
+            },
+            Instruction::Copy { src, dest, from } => Instruction::Copy {
+                src: expand_all(src),
+                dest: expand_vars(dest, vars),
+                from: from.as_ref().map(|f| expand_vars(f, vars)),
+            },
+            Instruction::Add { src, dest } => Instruction::Add {
+                src: expand_all(src),
+                dest: expand_vars(dest, vars),
+            },
+            Instruction::Workdir { path } => Instruction::Workdir {
+                path: expand_vars(path, vars),
+            },
+            Instruction::Volume { volumes } => Instruction::Volume {
+                volumes: expand_all(volumes),
+            },
+            Instruction::User { user } => Instruction::User {
+                user: expand_vars(user, vars),
+            },
+            Instruction::Arg { key, default } => Instruction::Arg {
+                key: key.clone(),
+                default: default.as_ref().map(|d| expand_vars(d, vars)),
+            },
+            Instruction::StopSignal { signal } => Instruction::StopSignal {
+                signal: expand_vars(signal, vars),
+            },
+            Instruction::Custom { keyword, args } => Instruction::Custom {
+                keyword: keyword.clone(),
+                args: expand_vars(args, vars),
+            },
+            other => other.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = |values: &[String]| serde_json::to_string(values).unwrap_or_default();
+        match self {
+            Instruction::From { image, alias: Some(alias) } => write!(f, "FROM {} AS {}", image, alias),
+            Instruction::From { image, alias: None } => write!(f, "FROM {}", image),
+            Instruction::Run { command, .. } => write!(f, "RUN {}", command),
+            Instruction::Cmd { command } => write!(f, "CMD {}", json(command)),
+            Instruction::Label { key, value } => write!(f, "LABEL {}={}", key, value),
+            Instruction::Env { key, value } => write!(f, "ENV {}={}", key, value),
+            Instruction::Copy { src, dest, from } => {
+                write!(f, "COPY ")?;
+                if let Some(from) = from {
+                    write!(f, "--from={} ", from)?;
+                }
+                write!(f, "{} {}", src.join(" "), dest)
+            }
+            Instruction::Add { src, dest } => write!(f, "ADD {} {}", src.join(" "), dest),
+            Instruction::Workdir { path } => write!(f, "WORKDIR {}", path),
+            Instruction::Expose { ports } => write!(f, "EXPOSE {}", ports.join(" ")),
+            Instruction::Entrypoint { command } => write!(f, "ENTRYPOINT {}", json(command)),
+            Instruction::Volume { volumes } => write!(f, "VOLUME {}", json(volumes)),
+            Instruction::User { user } => write!(f, "USER {}", user),
+            Instruction::Arg { key, default: Some(default) } => write!(f, "ARG {}={}", key, default),
+            Instruction::Arg { key, default: None } => write!(f, "ARG {}", key),
+            Instruction::Onbuild { instruction } => write!(f, "ONBUILD {}", instruction),
+            Instruction::StopSignal { signal } => write!(f, "STOPSIGNAL {}", signal),
+            Instruction::Healthcheck { cmd, .. } => write!(f, "HEALTHCHECK {}", json(cmd)),
+            Instruction::Shell { shell } => write!(f, "SHELL {}", json(shell)),
+            Instruction::Custom { keyword, args } if args.is_empty() => write!(f, "{}", keyword),
+            Instruction::Custom { keyword, args } => write!(f, "{} {}", keyword, args),
+        }
+    }
+}
+
+// Expand `$NAME`, `${NAME}`, `${NAME:-default}` and `${NAME:+alternative}`.
+// Unset variables expand to an empty string and `\$` yields a literal `$`.
+pub fn expand_vars(input: &str, vars: &HashMap<String, String>) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' && chars.peek() == Some(&'$') {
+            output.push(chars.next().unwrap());
+            continue;
+        }
+        if c != '$' {
+            output.push(c);
+            continue;
+        }
+
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            let mut expr = String::new();
+            for c in chars.by_ref() {
+                if c == '}' {
+                    break;
+                }
+                expr.push(c);
+            }
+
+            let value = if let Some((name, default)) = expr.split_once(":-") {
+                vars.get(name).filter(|v| !v.is_empty()).cloned().unwrap_or_else(|| default.to_string())
+            } else if let Some((name, alternative)) = expr.split_once(":+") {
+                vars.get(name).filter(|v| !v.is_empty()).map(|_| alternative.to_string()).unwrap_or_default()
+            } else {
+                vars.get(&expr).cloned().unwrap_or_default()
+            };
+            output.push_str(&value);
+        } else {
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_ascii_alphanumeric() || c == '_' {
+                    name.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+
+            if name.is_empty() {
+                output.push('$');
+            } else {
+                output.push_str(vars.get(&name).map(String::as_str).unwrap_or(""));
+            }
+        }
+    }
+
+    output
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+        .unwrap_or(value)
+}
+
+// Parse Go-style durations such as "30s", "1m30s" or "500ms" into nanoseconds
+pub fn parse_duration(value: &str) -> Result<u64> {
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    let mut chars = value.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let mut unit = c.to_string();
+        while let Some(&next) = chars.peek() {
+            if next.is_ascii_alphabetic() {
+                unit.push(next);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+
+        let scale: u64 = match unit.as_str() {
+            "ns" => 1,
+            "us" => 1_000,
+            "ms" => 1_000_000,
+            "s" => 1_000_000_000,
+            "m" => 60 * 1_000_000_000,
+            "h" => 3600 * 1_000_000_000,
+            _ => return Err(anyhow::anyhow!("Invalid duration {:?}", value)),
+        };
+        let amount: u64 = number
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid duration {:?}", value))?;
+        total += amount * scale;
+        number.clear();
+    }
+
+    if !number.is_empty() || value.is_empty() {
+        return Err(anyhow::anyhow!("Invalid duration {:?}: missing unit", value));
+    }
+    Ok(total)
+}
+
+pub struct DockerfileParser;
+
+impl DockerfileParser {
+    pub async fn parse_from_path<P: AsRef<Path>>(path: P) -> Result<ParsedDockerfile> {
+        let content = tokio::fs::read_to_string(path).await?;
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<ParsedDockerfile> {
+        let mut instructions = Vec::new();
+        let mut args = HashMap::new();
+        
+        // Split content into lines, numbered from 1, and process
+        let lines: Vec<(usize, &str)> = content
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+
+        for (number, line) in lines {
+            let instruction = Self::parse_line(line).map_err(|e| LineError { line: number, message: e.to_string() })?;
+            // ARGs before the first FROM are global and may be used in FROM lines
+            if let Instruction::Arg { key, default } = &instruction
+                && !instructions.iter().any(|(_, i)| matches!(i, Instruction::From { .. }))
+            {
+                args.insert(key.clone(), default.clone());
+            }
+            instructions.push((number, instruction));
+        }
+
+        // Group instructions into stages based on FROM commands
+        let stages = Self::group_into_stages(instructions)?;
+
+        Ok(ParsedDockerfile { stages, args })
+    }
+
+    fn parse_line(line: &str) -> Result<Instruction> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.is_empty() {
+            return Ok(Instruction::Run {
+                command: "".to_string(),
+                options: RunOptions::default(),
+            });
+        }
+
+        let instruction = parts[0].to_uppercase();
+        let args_str = line[line.find(' ').unwrap_or(line.len())..].trim_start();
+
+        match instruction.as_str() {
+            "FROM" => Self::parse_from(args_str),
+            "RUN" => Self::parse_run(args_str),
+            "CMD" => Ok(Self::parse_cmd(args_str)?),
+            "LABEL" => Ok(Self::parse_label(args_str)?),
+            "ENV" => Ok(Self::parse_env(args_str)?),
+            "COPY" => Ok(Self::parse_copy(args_str)),
+            "ADD" => Ok(Self::parse_add(args_str)),
+            "WORKDIR" => Ok(Instruction::Workdir {
+                path: args_str.to_string(),
+            }),
+            "EXPOSE" => Ok(Self::parse_expose(args_str)?),
+            "ENTRYPOINT" => Ok(Self::parse_entrypoint(args_str)?),
+            "VOLUME" => Ok(Self::parse_volume(args_str)),
+            "USER" => Ok(Instruction::User {
+                user: args_str.to_string(),
+            }),
+            "ARG" => Ok(Self::parse_arg(args_str)?),
+            "ONBUILD" => Ok(Self::parse_onbuild(args_str)?),
+            "STOPSIGNAL" => Ok(Instruction::StopSignal {
+                signal: args_str.to_string(),
+            }),
+            "HEALTHCHECK" => Self::parse_healthcheck(args_str),
+            "SHELL" => Ok(Self::parse_shell(args_str)),
+            // Plugins may handle it; otherwise the build runs it like RUN
+            _ => Ok(Instruction::Custom {
+                keyword: parts[0].to_string(),
+                args: args_str.to_string(),
+            }),
+        }
+    }
+
+    fn parse_from(args: &str) -> Result<Instruction> {
+        let parts: Vec<&str> = args.split_whitespace().collect();
+        if parts.is_empty() {
+            return Err(anyhow::anyhow!("FROM instruction requires an image"));
+        }
+
+        let image = parts[0].to_string();
+        let alias = if parts.len() > 2 && parts[1].to_uppercase() == "AS" {
+            Some(parts[2].to_string())
+        } else {
+            None
+        };
+
+        Ok(Instruction::From { image, alias })
+    }
+
+    fn parse_run(args: &str) -> Result<Instruction> {
+        let mut options = RunOptions::default();
+        let mut rest = args;
+
+        // Leading --flag=value tokens configure the step, the remainder is the command
+        while rest.starts_with("--") {
+            let (flag, remainder) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            if let Some(spec) = flag.strip_prefix("--mount=") {
+                options.mounts.push(Self::parse_run_mount(spec)?);
+            } else if let Some(mode) = flag.strip_prefix("--network=") {
+                options.network = Some(NetworkMode::parse(mode)?);
+            } else if let Some(count) = flag.strip_prefix("--retry=") {
+                let count = count
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid RUN --retry value {:?}: expected a number", count))?;
+                options.retry = Some(count);
+            } else {
+                return Err(anyhow::anyhow!("Unknown RUN flag: {}", flag));
+            }
+            rest = remainder.trim_start();
+        }
+
+        Ok(Instruction::Run {
+            command: rest.to_string(),
+            options,
+        })
+    }
+
+    fn parse_run_mount(spec: &str) -> Result<RunMount> {
+        let mut fields = HashMap::new();
+        for part in spec.split(',') {
+            match part.split_once('=') {
+                Some((key, value)) => fields.insert(key, value),
+                None => fields.insert(part, "true"),
+            };
+        }
+
+        match fields.get("type").copied().unwrap_or("bind") {
+            "secret" => {
+                let target = fields.get("target").or(fields.get("dst")).map(|t| t.to_string());
+                let id = match fields.get("id") {
+                    Some(id) => id.to_string(),
+                    // Like BuildKit, the id defaults to the target's file name
+                    None => target
+                        .as_deref()
+                        .and_then(|t| Path::new(t).file_name())
+                        .map(|name| name.to_string_lossy().to_string())
+                        .ok_or_else(|| anyhow::anyhow!("Secret mount requires an id: {}", spec))?,
+                };
+                Ok(RunMount::Secret {
+                    id,
+                    target,
+                    required: fields.get("required").is_some_and(|r| *r == "true"),
+                })
+            }
+            other => Ok(RunMount::Unsupported {
+                mount_type: other.to_string(),
+            }),
+        }
+    }
+
+    fn parse_cmd(args: &str) -> Result<Instruction> {
+        Ok(Instruction::Cmd {
+            command: Self::parse_command_form(args),
+        })
+    }
+
+    // Exec form is a JSON array; anything else is shell form run through /bin/sh -c
+    fn parse_command_form(args: &str) -> Vec<String> {
+        if args.trim_start().starts_with('[')
+            && let Ok(exec) = serde_json::from_str::<Vec<String>>(args)
+        {
+            return exec;
+        }
+        vec!["/bin/sh".to_string(), "-c".to_string(), args.to_string()]
+    }
+
+    fn parse_label(args: &str) -> Result<Instruction> {
+        let (key, value) = args
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("LABEL requires key=value format"))?;
+
+        Ok(Instruction::Label {
+            key: unquote(key.trim()).to_string(),
+            value: unquote(value.trim()).to_string(),
+        })
+    }
+
+    fn parse_env(args: &str) -> Result<Instruction> {
+        // Both ENV KEY=VALUE and the legacy ENV KEY VALUE forms are accepted
+        let (key, value) = match args.split_once(|c: char| c == '=' || c.is_whitespace()) {
+            Some((key, value)) if !key.is_empty() => (key, value),
+            _ => return Err(anyhow::anyhow!("ENV requires key=value format")),
+        };
+
+        Ok(Instruction::Env {
+            key: key.to_string(),
+            value: unquote(value.trim()).to_string(),
+        })
+    }
+
+    fn parse_copy(args: &str) -> Instruction {
+        // Simplified parsing - flags other than --from are accepted but ignored
+        let mut from = None;
+        let mut src_dest = Vec::new();
+        for part in args.split_whitespace() {
+            if let Some(stage) = part.strip_prefix("--from=") {
+                from = Some(stage.to_string());
+            } else if !part.starts_with("--") || !src_dest.is_empty() {
+                src_dest.push(part);
+            }
+        }
+
+        if src_dest.len() < 2 {
+            return Instruction::Copy {
+                src: vec![],
+                dest: "".to_string(),
+                from,
+            };
+        }
+
+        let dest = src_dest.pop().unwrap().to_string();
+        let src = src_dest.iter().map(|s| s.to_string()).collect();
+
+        Instruction::Copy { src, dest, from }
+    }
+
+    fn parse_add(args: &str) -> Instruction {
+        let parts: Vec<&str> = args.split_whitespace().collect();
+        if parts.len() < 2 {
+            return Instruction::Add {
+                src: vec![],
+                dest: "".to_string(),
+            };
+        }
+
+        let dest = parts.last().unwrap().to_string();
+        let src: Vec<String> = parts[..parts.len() - 1].iter().map(|s| s.to_string()).collect();
+
+        Instruction::Add { src, dest }
+    }
+
+    fn parse_expose(args: &str) -> Result<Instruction> {
+        let mut ports = Vec::new();
+        for spec in args.split_whitespace() {
+            let (port, protocol) = spec.split_once('/').unwrap_or((spec, "tcp"));
+            let port = port
+                .parse::<u16>()
+                .map_err(|_| anyhow::anyhow!("Invalid EXPOSE port: {}", spec))?;
+            ports.push(format!("{}/{}", port, protocol.to_lowercase()));
+        }
+
+        if ports.is_empty() {
+            return Err(anyhow::anyhow!("EXPOSE requires at least one port"));
+        }
+        Ok(Instruction::Expose { ports })
+    }
+
+    fn parse_entrypoint(args: &str) -> Result<Instruction> {
+        Ok(Instruction::Entrypoint {
+            command: Self::parse_command_form(args),
+        })
+    }
+
+    fn parse_volume(args: &str) -> Instruction {
+        if let Ok(volumes) = serde_json::from_str::<Vec<String>>(args) {
+            return Instruction::Volume { volumes };
+        }
+
+        let volumes: Vec<String> = args
+            .split_whitespace()
+            .map(|s| s.trim_matches('"').to_string())
+            .collect();
+
+        Instruction::Volume { volumes }
+    }
+
+    fn parse_arg(args: &str) -> Result<Instruction> {
+        let parts: Vec<&str> = args.split('=').collect();
+        let key = parts[0].to_string();
+        let default = if parts.len() > 1 {
+            Some(parts[1..].join("="))
+        } else {
+            None
+        };
+
+        Ok(Instruction::Arg { key, default })
+    }
+
+    fn parse_onbuild(args: &str) -> Result<Instruction> {
+        let inner_instruction = Self::parse_line(args)?;
+        Ok(Instruction::Onbuild {
+            instruction: Box::new(inner_instruction),
+        })
+    }
+
+    fn parse_healthcheck(args: &str) -> Result<Instruction> {
+        let (mut interval, mut timeout, mut start_period, mut retries) = (None, None, None, None);
+        let mut rest = args.trim_start();
+
+        while rest.starts_with("--") {
+            let (flag, remainder) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            let (name, value) = flag
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("HEALTHCHECK flag {} requires a value", flag))?;
+            match name {
+                "--interval" => interval = Some(parse_duration(value)?),
+                "--timeout" => timeout = Some(parse_duration(value)?),
+                "--start-period" => start_period = Some(parse_duration(value)?),
+                "--retries" => retries = Some(value.parse::<u32>()?),
+                _ => return Err(anyhow::anyhow!("Unknown HEALTHCHECK flag: {}", name)),
+            }
+            rest = remainder.trim_start();
+        }
+
+        let (kind, command) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let cmd = match kind.to_uppercase().as_str() {
+            "NONE" => vec!["NONE".to_string()],
+            "CMD" => match serde_json::from_str::<Vec<String>>(command) {
+                Ok(exec) => std::iter::once("CMD".to_string()).chain(exec).collect(),
+                Err(_) => vec!["CMD-SHELL".to_string(), command.trim().to_string()],
+            },
+            _ => return Err(anyhow::anyhow!("HEALTHCHECK requires CMD or NONE")),
+        };
+
+        Ok(Instruction::Healthcheck {
+            interval,
+            timeout,
+            start_period,
+            retries,
+            cmd,
+        })
+    }
+
+    fn parse_shell(args: &str) -> Instruction {
+        if let Ok(shell) = serde_json::from_str::<Vec<String>>(args) {
+            return Instruction::Shell { shell };
+        }
+
+        let parts: Vec<String> = args
+            .split_whitespace()
+            .map(|s| s.to_string())
+            .collect();
+
+        Instruction::Shell { shell: parts }
+    }
+
+    fn group_into_stages(instructions: Vec<(usize, Instruction)>) -> Result<Vec<BuildStage>> {
+        let mut stages = Vec::new();
+        let mut current_stage: Option<BuildStage> = None;
+
+        for (line, instruction) in instructions {
+            if let Instruction::From { image, alias } = instruction {
+                // Save previous stage if it exists
+                if let Some(stage) = current_stage.take() {
+                    stages.push(stage);
+                }
+
+                // Start new stage, named by its own FROM ... AS alias
+                current_stage = Some(BuildStage {
+                    name: alias,
+                    base_image: image,
+                    instructions: Vec::new(),
+                    line,
+                    lines: Vec::new(),
+                });
+            } else if let Some(stage) = current_stage.as_mut() {
+                stage.instructions.push(instruction);
+                stage.lines.push(line);
+            } else if !matches!(instruction, Instruction::Arg { .. }) {
+                // Only ARG may precede the first FROM; those are global args
+                let message = format!("FROM must come first, found {:?}", instruction);
+                return Err(LineError { line, message }.into());
+            }
+        }
+
+        // Add the final stage
+        if let Some(stage) = current_stage {
+            stages.push(stage);
+        }
+
+        Ok(stages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_simple_dockerfile() {
+        let dockerfile_content = r#"
+        FROM alpine:latest
+        RUN apk update
+        RUN apk add curl
+        CMD ["echo", "hello world"]
+        "#;
+
+        let parsed = DockerfileParser::parse(dockerfile_content).unwrap();
+        assert_eq!(parsed.stages.len(), 1);
+        assert_eq!(parsed.stages[0].instructions.len(), 3);
+    }
+
+    #[test]
+    fn test_parse_multistage_dockerfile() {
+        let dockerfile_content = r#"
+        FROM golang:1.19 AS builder
+        WORKDIR /app
+        COPY . .
+        RUN go build -o myapp .
+
+        FROM alpine:latest
+        RUN apk add --no-cache ca-certificates
+        COPY --from=builder /app/myapp /myapp
+        CMD ["/myapp"]
+        "#;
+
+        let parsed = DockerfileParser::parse(dockerfile_content).unwrap();
+        assert_eq!(parsed.stages.len(), 2);
+        assert_eq!(parsed.stages[0].name.as_deref(), Some("builder"));
+        assert_eq!(parsed.stages[1].name, None);
+        assert_eq!(
+            parsed.stages[1].instructions[1],
+            Instruction::Copy {
+                src: vec!["/app/myapp".to_string()],
+                dest: "/myapp".to_string(),
+                from: Some("builder".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_errors_carry_line_numbers() {
+        let dockerfile_content = "FROM golang:1.19 AS builder\nRUN go build\n\n# runtime\n\
+                                  FROM alpine\nCOPY --from=builder /app /app\nCMD [\"/app\"]\n";
+
+        let parsed = DockerfileParser::parse(dockerfile_content).unwrap();
+        assert_eq!((parsed.stages[0].line, parsed.stages[0].lines.as_slice()), (1, [2].as_slice()));
+        assert_eq!((parsed.stages[1].line, parsed.stages[1].lines.as_slice()), (5, [6, 7].as_slice()));
+
+        // Blank lines and comments still count
+        let error = DockerfileParser::parse("FROM alpine\n\n# comment\nEXPOSE http\n").unwrap_err();
+        let error = error.downcast_ref::<LineError>().unwrap();
+        assert_eq!(error.line, 4);
+        assert!(error.to_string().starts_with("Dockerfile line 4: "));
+    }
+
+    #[test]
+    fn test_expand_vars() {
+        let vars = HashMap::from([
+            ("VERSION".to_string(), "3.19".to_string()),
+            ("EMPTY".to_string(), String::new()),
+        ]);
+
+        assert_eq!(expand_vars("alpine:$VERSION", &vars), "alpine:3.19");
+        assert_eq!(expand_vars("alpine:${VERSION}-slim", &vars), "alpine:3.19-slim");
+        assert_eq!(expand_vars("${EMPTY:-fallback}", &vars), "fallback");
+        assert_eq!(expand_vars("${VERSION:+set}${MISSING:+set}", &vars), "set");
+        assert_eq!(expand_vars("\\$VERSION costs $", &vars), "$VERSION costs $");
+        assert_eq!(expand_vars("$MISSING/bin", &vars), "/bin");
+    }
+
+    #[test]
+    fn test_global_args() {
+        let dockerfile_content = r#"
+        ARG VERSION=3.19
+        FROM alpine:${VERSION}
+        ARG TARGET
+        RUN echo $TARGET
+        "#;
+
+        let parsed = DockerfileParser::parse(dockerfile_content).unwrap();
+        assert_eq!(parsed.args.get("VERSION"), Some(&Some("3.19".to_string())));
+        assert!(!parsed.args.contains_key("TARGET"));
+    }
+
+    #[test]
+    fn test_parse_run_secret_mount() {
+        let parsed = DockerfileParser::parse(
+            r#"
+            FROM alpine
+            RUN --mount=type=secret,id=npmrc,target=/root/.npmrc,required npm ci
+            RUN --mount=type=secret,target=/etc/token --network=none cat /etc/token
+            RUN --retry=3 apk add curl
+            "#,
+        )
+        .unwrap();
+
+        let instructions = &parsed.stages[0].instructions;
+        assert_eq!(
+            instructions[0],
+            Instruction::Run {
+                command: "npm ci".to_string(),
+                options: RunOptions {
+                    mounts: vec![RunMount::Secret {
+                        id: "npmrc".to_string(),
+                        target: Some("/root/.npmrc".to_string()),
+                        required: true,
+                    }],
+                    network: None,
+                    retry: None,
+                },
+            }
+        );
+        assert!(matches!(
+            &instructions[1],
+            Instruction::Run { options, command } if options.mounts[0] == RunMount::Secret {
+                id: "token".to_string(),
+                target: Some("/etc/token".to_string()),
+                required: false,
+            } && options.network == Some(NetworkMode::None) && command == "cat /etc/token"
+        ));
+        assert!(matches!(
+            &instructions[2],
+            Instruction::Run { options, command } if options.retry == Some(3) && command == "apk add curl"
+        ));
+    }
+
+    #[test]
+    fn test_parse_config_instructions() {
+        let parsed = DockerfileParser::parse(
+            r#"
+            FROM alpine
+            CMD ["echo", "hello world"]
+            ENTRYPOINT exec /app
+            EXPOSE 80 53/UDP
+            LABEL description="a test image"
+            ENV PATH /usr/local/bin:/usr/bin
+            HEALTHCHECK --interval=1m30s --retries=3 CMD curl -f http://localhost/
+            "#,
+        )
+        .unwrap();
+
+        let instructions = &parsed.stages[0].instructions;
+        assert_eq!(
+            instructions[0],
+            Instruction::Cmd {
+                command: vec!["echo".to_string(), "hello world".to_string()],
+            }
+        );
+        assert_eq!(
+            instructions[1],
+            Instruction::Entrypoint {
+                command: vec!["/bin/sh".to_string(), "-c".to_string(), "exec /app".to_string()],
+            }
+        );
+        assert_eq!(
+            instructions[2],
+            Instruction::Expose {
+                ports: vec!["80/tcp".to_string(), "53/udp".to_string()],
+            }
+        );
+        assert_eq!(
+            instructions[3],
+            Instruction::Label {
+                key: "description".to_string(),
+                value: "a test image".to_string(),
+            }
+        );
+        assert_eq!(
+            instructions[4],
+            Instruction::Env {
+                key: "PATH".to_string(),
+                value: "/usr/local/bin:/usr/bin".to_string(),
+            }
+        );
+        assert_eq!(
+            instructions[5],
+            Instruction::Healthcheck {
+                interval: Some(90_000_000_000),
+                timeout: None,
+                start_period: None,
+                retries: Some(3),
+                cmd: vec!["CMD-SHELL".to_string(), "curl -f http://localhost/".to_string()],
+            }
+        );
+    }
+}
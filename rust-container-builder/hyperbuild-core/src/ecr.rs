@@ -0,0 +1,385 @@
+use crate::registry_auth::Credentials;
+use anyhow::Result;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Amazon ECR: registries at <account>.dkr.ecr.<region>.amazonaws.com take
+// an authorization token from the ECR API, which AWS credentials from the
+// environment, a profile or the instance's role are exchanged for
+
+const TARGET: &str = "AmazonEC2ContainerRegistry_V20150921.GetAuthorizationToken";
+const METADATA_ENDPOINT: &str = "http://169.254.169.254";
+const CONTAINER_ENDPOINT: &str = "http://169.254.170.2";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EcrRegistry {
+    pub account: String,
+    pub region: String,
+    // amazonaws.com, or amazonaws.com.cn in the China regions
+    pub domain: String,
+}
+
+impl EcrRegistry {
+    // The ECR registry a host[:port] names, if it is one
+    pub fn parse(host: &str) -> Option<Self> {
+        let host = host.split(':').next()?;
+        let (account, rest) = host.split_once(".dkr.ecr.")?;
+        let (region, domain) = rest.split_once('.')?;
+        let valid = account.len() == 12 && account.bytes().all(|b| b.is_ascii_digit());
+        (valid && matches!(domain, "amazonaws.com" | "amazonaws.com.cn")).then(|| Self {
+            account: account.to_string(),
+            region: region.to_string(),
+            domain: domain.to_string(),
+        })
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RoleCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: Option<String>,
+}
+
+impl AwsCredentials {
+    // The first credentials found, in the order the AWS CLI looks: the
+    // environment, the profile's entry in the shared credentials and config
+    // files, the ECS task role, then the EC2 instance role
+    pub async fn load() -> Result<Self> {
+        if let Some(credentials) = Self::from_env() {
+            return Ok(credentials);
+        }
+        let profile = std::env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_string());
+        if let Some(credentials) = Self::from_profile(&profile)? {
+            return Ok(credentials);
+        }
+        // Metadata endpoints answer at once where they exist; elsewhere the
+        // connection should fail quickly rather than stall the push
+        let client = reqwest::Client::builder()
+            .user_agent(crate::registry_client::USER_AGENT)
+            .no_proxy()
+            .connect_timeout(Duration::from_secs(1))
+            .timeout(Duration::from_secs(5))
+            .build()?;
+        if let Some(credentials) = Self::from_container(&client).await? {
+            return Ok(credentials);
+        }
+        Self::from_instance(&client).await.map_err(|e| {
+            anyhow::anyhow!(
+                "No AWS credentials found: set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, configure the {:?} profile \
+                 in ~/.aws/credentials, or run with an instance or task role ({})",
+                profile,
+                e
+            )
+        })
+    }
+
+    fn from_env() -> Option<Self> {
+        Some(Self {
+            access_key_id: std::env::var("AWS_ACCESS_KEY_ID").ok().filter(|value| !value.is_empty())?,
+            secret_access_key: std::env::var("AWS_SECRET_ACCESS_KEY").ok().filter(|value| !value.is_empty())?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok().filter(|value| !value.is_empty()),
+        })
+    }
+
+    fn from_profile(profile: &str) -> Result<Option<Self>> {
+        let home = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".aws"));
+        let file = |variable: &str, name: &str| {
+            std::env::var_os(variable)
+                .map(PathBuf::from)
+                .or_else(|| home.as_ref().map(|home| home.join(name)))
+        };
+        // The config file names profiles other than the default "profile NAME"
+        let config_section = if profile == "default" { profile.to_string() } else { format!("profile {}", profile) };
+        let sources = [
+            (file("AWS_SHARED_CREDENTIALS_FILE", "credentials"), profile.to_string()),
+            (file("AWS_CONFIG_FILE", "config"), config_section),
+        ];
+        for (path, section) in sources {
+            let Some(path) = path else { continue };
+            let contents = match std::fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(anyhow::anyhow!("Failed to read {:?}: {}", path, e)),
+            };
+            if let Some(credentials) = Self::from_ini(&contents, &section) {
+                return Ok(Some(credentials));
+            }
+        }
+        Ok(None)
+    }
+
+    // The keys of `section` in an AWS credentials or config file
+    fn from_ini(contents: &str, section: &str) -> Option<Self> {
+        let mut current = None;
+        let mut keys = std::collections::HashMap::new();
+        for line in contents.lines().map(str::trim) {
+            if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+                current = Some(name.trim());
+            } else if current == Some(section)
+                && let Some((key, value)) = line.split_once('=')
+            {
+                keys.insert(key.trim(), value.trim());
+            }
+        }
+        Some(Self {
+            access_key_id: keys.get("aws_access_key_id")?.to_string(),
+            secret_access_key: keys.get("aws_secret_access_key")?.to_string(),
+            session_token: keys.get("aws_session_token").map(|token| token.to_string()),
+        })
+    }
+
+    // The ECS task role (also CodeBuild's), from the container credentials endpoint
+    async fn from_container(client: &reqwest::Client) -> Result<Option<Self>> {
+        let url = match (
+            std::env::var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI"),
+            std::env::var("AWS_CONTAINER_CREDENTIALS_FULL_URI"),
+        ) {
+            (Ok(relative), _) => format!("{}{}", CONTAINER_ENDPOINT, relative),
+            (_, Ok(full)) => full,
+            _ => return Ok(None),
+        };
+        let mut request = client.get(&url);
+        if let Ok(token) = std::env::var("AWS_CONTAINER_AUTHORIZATION_TOKEN") {
+            request = request.header("authorization", token);
+        }
+        let response = request.send().await?.error_for_status()?;
+        let role: RoleCredentials = serde_json::from_slice(&response.bytes().await?)
+            .map_err(|e| anyhow::anyhow!("Invalid container credentials from {}: {}", url, e))?;
+        Ok(Some(role.into()))
+    }
+
+    // The EC2 instance role, through IMDSv2
+    async fn from_instance(client: &reqwest::Client) -> Result<Self> {
+        let endpoint = std::env::var("AWS_EC2_METADATA_SERVICE_ENDPOINT").unwrap_or_else(|_| METADATA_ENDPOINT.into());
+        let endpoint = endpoint.trim_end_matches('/');
+        let token = client
+            .put(format!("{}/latest/api/token", endpoint))
+            .header("x-aws-ec2-metadata-token-ttl-seconds", "300")
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let get = |path: String| {
+            let request = client.get(format!("{}{}", endpoint, path)).header("x-aws-ec2-metadata-token", &token);
+            async move { request.send().await?.error_for_status()?.text().await }
+        };
+        let roles = get("/latest/meta-data/iam/security-credentials/".to_string()).await?;
+        let role = roles
+            .lines()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("the instance has no IAM role"))?;
+        let credentials = get(format!("/latest/meta-data/iam/security-credentials/{}", role)).await?;
+        let role: RoleCredentials = serde_json::from_str(&credentials)
+            .map_err(|e| anyhow::anyhow!("Invalid instance role credentials: {}", e))?;
+        Ok(role.into())
+    }
+}
+
+impl From<RoleCredentials> for AwsCredentials {
+    fn from(role: RoleCredentials) -> Self {
+        Self {
+            access_key_id: role.access_key_id,
+            secret_access_key: role.secret_access_key,
+            session_token: role.token,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenResponse {
+    authorization_data: Vec<AuthorizationData>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthorizationData {
+    authorization_token: String,
+    // Seconds since the epoch
+    expires_at: Option<f64>,
+}
+
+// Exchange AWS credentials for the registry's credentials, the user AWS
+// and a token, and how long they are valid (12 hours)
+pub async fn login(client: &reqwest::Client, registry: &EcrRegistry) -> Result<(Credentials, Duration)> {
+    let aws = AwsCredentials::load().await?;
+    // $AWS_ENDPOINT_URL_ECR points the API elsewhere, as for the AWS CLI
+    let endpoint = std::env::var("AWS_ENDPOINT_URL_ECR")
+        .or_else(|_| std::env::var("AWS_ENDPOINT_URL"))
+        .unwrap_or_else(|_| format!("https://api.ecr.{}.{}", registry.region, registry.domain));
+    let host = endpoint
+        .split_once("://")
+        .map_or(endpoint.as_str(), |(_, rest)| rest)
+        .split('/')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let body = serde_json::to_vec(&serde_json::json!({ "registryIds": [registry.account] }))?;
+    let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+
+    let mut headers = vec![
+        ("content-type", "application/x-amz-json-1.1".to_string()),
+        ("host", host),
+        ("x-amz-date", amz_date.clone()),
+        ("x-amz-target", TARGET.to_string()),
+    ];
+    if let Some(token) = &aws.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+    let authorization = sign_v4(&aws, &registry.region, "ecr", "POST", "/", &headers, &body, &amz_date)?;
+
+    let mut request = client.post(format!("{}/", endpoint.trim_end_matches('/'))).body(body);
+    for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+        request = request.header(*name, value);
+    }
+    let response = request.header("authorization", authorization).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+        return Err(anyhow::anyhow!(
+            "Failed to get an ECR authorization token for {}.dkr.ecr.{}: {} - {}",
+            registry.account,
+            registry.region,
+            status,
+            error_text
+        ));
+    }
+    let response: TokenResponse = serde_json::from_slice(&response.bytes().await?)
+        .map_err(|e| anyhow::anyhow!("Invalid ECR authorization token response: {}", e))?;
+    let token = response
+        .authorization_data
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("ECR returned no authorization token"))?;
+    // The token is base64 of AWS:<password>, as basic authentication takes it
+    let decoded = String::from_utf8(openssl::base64::decode_block(&token.authorization_token)?)?;
+    let (username, password) = decoded
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Invalid ECR authorization token"))?;
+    tracing::info!("Got an ECR authorization token for {}.dkr.ecr.{}", registry.account, registry.region);
+    let lifetime = match token.expires_at {
+        Some(expires_at) => Duration::from_secs_f64((expires_at - chrono::Utc::now().timestamp() as f64).max(0.0)),
+        None => Duration::from_secs(12 * 60 * 60),
+    };
+    let credentials = Credentials {
+        username: username.to_string(),
+        password: password.to_string(),
+    };
+    Ok((credentials, lifetime))
+}
+
+// The Authorization header of an AWS Signature Version 4 signed request;
+// `headers` are the signed ones, with lowercase names
+#[allow(clippy::too_many_arguments)]
+fn sign_v4(
+    credentials: &AwsCredentials,
+    region: &str,
+    service: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+    amz_date: &str,
+) -> Result<String> {
+    let mut headers = headers.to_vec();
+    headers.sort();
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+    let canonical_request = format!(
+        "{}\n{}\n\n{}\n{}\n{:x}",
+        method,
+        path,
+        canonical_headers,
+        signed_headers,
+        Sha256::digest(body)
+    );
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+        amz_date,
+        scope,
+        Sha256::digest(canonical_request.as_bytes())
+    );
+    let key = signing_key(&credentials.secret_access_key, date, region, service)?;
+    let signature = hex(&hmac(&key, string_to_sign.as_bytes())?);
+    Ok(format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id, scope, signed_headers, signature
+    ))
+}
+
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Result<Vec<u8>> {
+    let mut key = format!("AWS4{}", secret).into_bytes();
+    for part in [date, region, service, "aws4_request"] {
+        key = hmac(&key, part.as_bytes())?;
+    }
+    Ok(key)
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    Ok(openssl::sign::Signer::new(MessageDigest::sha256(), &key)?.sign_oneshot_to_vec(data)?)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registries_credentials_and_signing() {
+        let registry = EcrRegistry::parse("123456789012.dkr.ecr.eu-west-1.amazonaws.com").unwrap();
+        assert_eq!((registry.account.as_str(), registry.region.as_str()), ("123456789012", "eu-west-1"));
+        let china = EcrRegistry::parse("123456789012.dkr.ecr.cn-north-1.amazonaws.com.cn").unwrap();
+        assert_eq!(china.domain, "amazonaws.com.cn");
+        assert!(EcrRegistry::parse("public.ecr.aws").is_none());
+        assert!(EcrRegistry::parse("registry.example.com").is_none());
+
+        let ini = "[default]\naws_access_key_id = AKIDDEFAULT\naws_secret_access_key = s1\n\n\
+                   [profile ci]\naws_access_key_id=AKIDCI\naws_secret_access_key=s2\naws_session_token=t\n";
+        assert_eq!(AwsCredentials::from_ini(ini, "default").unwrap().access_key_id, "AKIDDEFAULT");
+        assert_eq!(AwsCredentials::from_ini(ini, "profile ci").unwrap().session_token.as_deref(), Some("t"));
+        assert!(AwsCredentials::from_ini(ini, "ci").is_none());
+
+        // The examples of the AWS Signature Version 4 documentation and test suite
+        let secret = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY";
+        assert_eq!(
+            hex(&signing_key(secret, "20120215", "us-east-1", "iam").unwrap()),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+        let credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: secret.to_string(),
+            session_token: None,
+        };
+        let headers = [
+            ("host", "example.amazonaws.com".to_string()),
+            ("x-amz-date", "20150830T123600Z".to_string()),
+        ];
+        assert_eq!(
+            sign_v4(&credentials, "us-east-1", "service", "GET", "/", &headers, b"", "20150830T123600Z").unwrap(),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use crate::dockerfile::Instruction;
+use crate::platform::Platform;
+use crate::storage::{Layer, StorageManager};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+
+// Label used to carry inline cache metadata inside the image config, so it
+// travels with the image when it is pushed and pulled.
+pub const INLINE_CACHE_LABEL: &str = "dev.hyperbuild.cache.v0";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct InlineCacheRecord {
+    pub key: String,
+    // The diff_id of the step's layer, which stays the same however the
+    // layer is compressed; None for steps that changed no files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer_digest: Option<String>,
+}
+
+// What a cached step produced
+#[derive(Debug, Clone)]
+pub enum CachedStep {
+    Layer(Layer),
+    Empty,
+}
+
+#[derive(Debug, Default)]
+pub struct CacheIndex {
+    entries: HashMap<String, CachedStep>,
+}
+
+impl CacheIndex {
+    // Collect cache records from the inline metadata of the given images.
+    // Images that are missing or carry no metadata are skipped.
+    pub async fn load_inline(storage: &StorageManager, images: &[String]) -> Result<Self> {
+        let mut entries = HashMap::new();
+
+        for name in images {
+            let Some(image) = storage.get_image_by_name(name).await? else {
+                tracing::warn!("Cache source {} not found in storage, ignoring", name);
+                continue;
+            };
+
+            let Some(encoded) = image.config.get_config_annotation(INLINE_CACHE_LABEL) else {
+                tracing::warn!("Cache source {} has no inline cache metadata", name);
+                continue;
+            };
+
+            let records = decode_inline(encoded)?;
+            storage.touch(&image.id).await?;
+            for record in records {
+                let cached = match &record.layer_digest {
+                    None => CachedStep::Empty,
+                    Some(digest) => {
+                        let layer = image
+                            .layers
+                            .iter()
+                            .find(|layer| &layer.diff_id == digest && layer.path.exists());
+                        match layer {
+                            Some(layer) => CachedStep::Layer(layer.clone()),
+                            None => continue,
+                        }
+                    }
+                };
+                entries.entry(record.key).or_insert(cached);
+            }
+        }
+
+        Ok(Self { entries })
+    }
+
+    // Add the step results earlier builds recorded in the store, where their
+    // layers are still stored. Inline records loaded first take precedence.
+    pub async fn load_local(&mut self, storage: &StorageManager) -> Result<()> {
+        for (key, record) in storage.cache_records().await? {
+            let cached = match record.layer {
+                Some(layer) if layer.path.exists() => CachedStep::Layer(layer),
+                Some(_) => continue,
+                None => CachedStep::Empty,
+            };
+            self.entries.entry(key).or_insert(cached);
+        }
+        Ok(())
+    }
+
+    pub fn lookup(&self, key: &str) -> Option<&CachedStep> {
+        self.entries.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+pub fn encode_inline(records: &[InlineCacheRecord]) -> Result<String> {
+    Ok(serde_json::to_string(records)?)
+}
+
+pub fn decode_inline(encoded: &str) -> Result<Vec<InlineCacheRecord>> {
+    serde_json::from_str(encoded)
+        .map_err(|e| anyhow::anyhow!("Invalid inline cache metadata: {}", e))
+}
+
+// The key for the first instruction of a stage is derived from its base image
+// and the target platform, since the same steps give different layers per platform.
+// The digest of the manifest the name resolved to is part of it, so steps are
+// not reused on a new image when a tag such as alpine:3 moves.
+pub fn base_cache_key(base_image: &str, manifest_digest: Option<&str>, platform: &Platform) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(base_image.as_bytes());
+    if let Some(digest) = manifest_digest {
+        hasher.update(digest.as_bytes());
+    }
+    hasher.update(platform.to_string().as_bytes());
+    format!("sha256:{:x}", hasher.finalize())
+}
+
+// Each key chains the parent key with the instruction and, for instructions
+// reading from the build context, a digest of the files they read.
+pub fn compute_cache_key(parent: &str, instruction: &Instruction, content_digest: Option<&str>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(parent.as_bytes());
+    hasher.update(format!("{:?}", instruction).as_bytes());
+    if let Some(digest) = content_digest {
+        hasher.update(digest.as_bytes());
+    }
+    format!("sha256:{:x}", hasher.finalize())
+}
+
+// Hash the paths and contents of the given sources inside the build context.
+// Missing sources are hashed by name only so the key is still deterministic.
+pub fn hash_context_sources(context_dir: &Path, sources: &[String]) -> Result<String> {
+    let mut hasher = Sha256::new();
+
+    for src in sources {
+        let path = context_dir.join(src);
+        let mut files = Vec::new();
+        collect_files(&path, &mut files)?;
+        files.sort();
+
+        hasher.update(src.as_bytes());
+        for file in files {
+            let relative = file.strip_prefix(context_dir).unwrap_or(&file);
+            hasher.update(relative.to_string_lossy().as_bytes());
+            hasher.update(std::fs::read(&file)?);
+        }
+    }
+
+    Ok(format!("sha256:{:x}", hasher.finalize()))
+}
+
+fn collect_files(path: &Path, files: &mut Vec<std::path::PathBuf>) -> Result<()> {
+    if path.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            collect_files(&entry?.path(), files)?;
+        }
+    } else if path.is_file() {
+        files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_key_chains_parent() {
+        let run = Instruction::Run {
+            command: "echo hi".to_string(),
+            options: Default::default(),
+        };
+        let platform = Platform::parse("linux/amd64").unwrap();
+        let base = base_cache_key("alpine:latest", Some("sha256:aaa"), &platform);
+
+        let first = compute_cache_key(&base, &run, None);
+        assert_eq!(first, compute_cache_key(&base, &run, None));
+
+        let other_base = base_cache_key("debian:latest", Some("sha256:aaa"), &platform);
+        assert_ne!(first, compute_cache_key(&other_base, &run, None));
+        let moved_tag = base_cache_key("alpine:latest", Some("sha256:bbb"), &platform);
+        assert_ne!(first, compute_cache_key(&moved_tag, &run, None));
+        let arm64 = Platform::parse("linux/arm64").unwrap();
+        let other_platform = base_cache_key("alpine:latest", Some("sha256:aaa"), &arm64);
+        assert_ne!(first, compute_cache_key(&other_platform, &run, None));
+        assert_ne!(first, compute_cache_key(&base, &run, Some("sha256:abc")));
+    }
+
+    #[test]
+    fn test_inline_records_roundtrip() {
+        let records = vec![
+            InlineCacheRecord {
+                key: "sha256:key".to_string(),
+                layer_digest: Some("sha256:layer".to_string()),
+            },
+            InlineCacheRecord {
+                key: "sha256:empty".to_string(),
+                layer_digest: None,
+            },
+        ];
+
+        let encoded = encode_inline(&records).unwrap();
+        assert_eq!(decode_inline(&encoded).unwrap(), records);
+    }
+}
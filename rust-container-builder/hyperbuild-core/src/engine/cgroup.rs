@@ -0,0 +1,85 @@
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// A cgroup v2 group holding the processes of one RUN step, so its memory and
+// CPU can be capped and everything it started can be killed at once.
+#[derive(Debug)]
+pub struct StepCgroup {
+    path: PathBuf,
+}
+
+impl StepCgroup {
+    pub fn create(memory_bytes: Option<u64>, cpus: Option<f64>) -> Result<Self> {
+        let root = Path::new(CGROUP_ROOT);
+        if !root.join("cgroup.controllers").exists() {
+            return Err(anyhow::anyhow!(
+                "Step resource limits need root privileges and cgroup v2 mounted at {}",
+                CGROUP_ROOT
+            ));
+        }
+
+        // The root cgroup may hold processes and still delegate controllers
+        let parent = root.join("hyperbuild");
+        fs::create_dir_all(&parent)?;
+        enable_controllers(root)?;
+        enable_controllers(&parent)?;
+
+        let path = parent.join(format!("step-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&path).map_err(|e| anyhow::anyhow!("Failed to create cgroup {:?}: {}", path, e))?;
+        let cgroup = Self { path };
+
+        if let Some(bytes) = memory_bytes {
+            cgroup.write("memory.max", &bytes.to_string())?;
+            // Swapping would let a step quietly exceed its budget
+            let _ = cgroup.write("memory.swap.max", "0");
+        }
+        if let Some(cpus) = cpus {
+            let period = 100_000u64;
+            let quota = ((cpus * period as f64) as u64).max(1_000);
+            cgroup.write("cpu.max", &format!("{} {}", quota, period))?;
+        }
+
+        Ok(cgroup)
+    }
+
+    // File a process writes its pid to in order to join this cgroup
+    pub fn procs_file(&self) -> PathBuf {
+        self.path.join("cgroup.procs")
+    }
+
+    pub fn oom_killed(&self) -> bool {
+        fs::read_to_string(self.path.join("memory.events")).is_ok_and(|events| {
+            events
+                .lines()
+                .any(|line| line.strip_prefix("oom_kill ").is_some_and(|count| count.trim() != "0"))
+        })
+    }
+
+    // Kill every process in the group, including ones that escaped the process tree
+    pub fn kill(&self) -> Result<()> {
+        self.write("cgroup.kill", "1")
+    }
+
+    pub fn remove(self) -> Result<()> {
+        fs::remove_dir(&self.path).map_err(|e| anyhow::anyhow!("Failed to remove cgroup {:?}: {}", self.path, e))
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<()> {
+        fs::write(self.path.join(file), value)
+            .map_err(|e| anyhow::anyhow!("Failed to set {} in {:?}: {}", file, self.path, e))
+    }
+}
+
+fn enable_controllers(cgroup: &Path) -> Result<()> {
+    let subtree = fs::read_to_string(cgroup.join("cgroup.subtree_control"))?;
+    for controller in ["memory", "cpu"] {
+        if !subtree.split_whitespace().any(|c| c == controller) {
+            fs::write(cgroup.join("cgroup.subtree_control"), format!("+{}", controller))
+                .map_err(|e| anyhow::anyhow!("Failed to enable the {} controller in {:?}: {}", controller, cgroup, e))?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,294 @@
+use crate::dockerfile::Instruction;
+use crate::platform::Platform;
+use anyhow::Result;
+use oci_spec::image::{History, ImageConfiguration};
+use serde_json::{Map, Value, json};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Healthcheck {
+    // ["NONE"], ["CMD", args...] or ["CMD-SHELL", command]
+    pub test: Vec<String>,
+    pub interval: Option<u64>,
+    pub timeout: Option<u64>,
+    pub start_period: Option<u64>,
+    pub retries: Option<u32>,
+}
+
+// Runtime configuration accumulated while building a stage. Stages built on
+// another stage or image start from its config; the final stage's config
+// becomes the `config` section of the image.
+#[derive(Debug, Clone)]
+pub struct StageConfig {
+    pub env: Vec<(String, String)>,
+    pub workdir: String,
+    pub user: Option<String>,
+    pub cmd: Option<Vec<String>>,
+    pub entrypoint: Option<Vec<String>>,
+    pub labels: BTreeMap<String, String>,
+    pub exposed_ports: BTreeSet<String>,
+    pub volumes: BTreeSet<String>,
+    pub stop_signal: Option<String>,
+    pub healthcheck: Option<Healthcheck>,
+    pub shell: Option<Vec<String>>,
+    // Set while CMD still comes from the base, so ENTRYPOINT can reset it
+    cmd_inherited: bool,
+}
+
+impl Default for StageConfig {
+    fn default() -> Self {
+        Self {
+            env: Vec::new(),
+            workdir: "/".to_string(),
+            user: None,
+            cmd: None,
+            entrypoint: None,
+            labels: BTreeMap::new(),
+            exposed_ports: BTreeSet::new(),
+            volumes: BTreeSet::new(),
+            stop_signal: None,
+            healthcheck: None,
+            shell: None,
+            cmd_inherited: false,
+        }
+    }
+}
+
+impl StageConfig {
+    // Start from the config of a base image, given its raw config JSON
+    pub fn from_image_config(raw: &[u8]) -> Result<Self> {
+        let image: Value = serde_json::from_slice(raw)?;
+        let mut config = Self::default();
+        let Some(section) = image.get("config").and_then(Value::as_object) else {
+            return Ok(config);
+        };
+
+        if let Some(env) = string_list(section, "Env") {
+            config.env = env
+                .iter()
+                .filter_map(|entry| entry.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+        }
+        if let Some(workdir) = section.get("WorkingDir").and_then(Value::as_str)
+            && !workdir.is_empty()
+        {
+            config.workdir = workdir.to_string();
+        }
+        config.user = section
+            .get("User")
+            .and_then(Value::as_str)
+            .filter(|user| !user.is_empty())
+            .map(str::to_string);
+        config.cmd = string_list(section, "Cmd");
+        config.cmd_inherited = config.cmd.is_some();
+        config.entrypoint = string_list(section, "Entrypoint");
+        config.shell = string_list(section, "Shell");
+        config.stop_signal = section.get("StopSignal").and_then(Value::as_str).map(str::to_string);
+
+        if let Some(labels) = section.get("Labels").and_then(Value::as_object) {
+            config.labels = labels
+                .iter()
+                .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                .collect();
+        }
+        if let Some(ports) = section.get("ExposedPorts").and_then(Value::as_object) {
+            config.exposed_ports = ports.keys().cloned().collect();
+        }
+        if let Some(volumes) = section.get("Volumes").and_then(Value::as_object) {
+            config.volumes = volumes.keys().cloned().collect();
+        }
+        if let Some(healthcheck) = section.get("Healthcheck").and_then(Value::as_object) {
+            config.healthcheck = Some(Healthcheck {
+                test: string_list(healthcheck, "Test").unwrap_or_default(),
+                interval: healthcheck.get("Interval").and_then(Value::as_u64),
+                timeout: healthcheck.get("Timeout").and_then(Value::as_u64),
+                start_period: healthcheck.get("StartPeriod").and_then(Value::as_u64),
+                retries: healthcheck.get("Retries").and_then(Value::as_u64).map(|r| r as u32),
+            });
+        }
+
+        Ok(config)
+    }
+
+    // The config a child stage starts from when built on this one
+    pub fn inherited(&self) -> Self {
+        Self {
+            cmd_inherited: self.cmd.is_some(),
+            ..self.clone()
+        }
+    }
+
+    // Record the effect of an (already expanded) instruction
+    pub fn apply(&mut self, instruction: &Instruction) {
+        match instruction {
+            Instruction::Env { key, value } => {
+                self.env.retain(|(k, _)| k != key);
+                self.env.push((key.clone(), value.clone()));
+            }
+            Instruction::Workdir { path } => self.workdir = super::resolve_path(&self.workdir, path),
+            Instruction::User { user } => self.user = Some(user.clone()),
+            Instruction::Cmd { command } => {
+                self.cmd = Some(command.clone());
+                self.cmd_inherited = false;
+            }
+            Instruction::Entrypoint { command } => {
+                // An ENTRYPOINT resets a CMD inherited from the base image
+                if self.cmd_inherited {
+                    self.cmd = None;
+                    self.cmd_inherited = false;
+                }
+                self.entrypoint = Some(command.clone());
+            }
+            Instruction::Label { key, value } => {
+                self.labels.insert(key.clone(), value.clone());
+            }
+            Instruction::Expose { ports } => self.exposed_ports.extend(ports.iter().cloned()),
+            Instruction::Volume { volumes } => self.volumes.extend(volumes.iter().cloned()),
+            Instruction::StopSignal { signal } => self.stop_signal = Some(signal.clone()),
+            Instruction::Healthcheck {
+                interval,
+                timeout,
+                start_period,
+                retries,
+                cmd,
+            } => {
+                self.healthcheck = Some(Healthcheck {
+                    test: cmd.clone(),
+                    interval: *interval,
+                    timeout: *timeout,
+                    start_period: *start_period,
+                    retries: *retries,
+                });
+            }
+            Instruction::Shell { shell } => self.shell = Some(shell.clone()),
+            _ => {}
+        }
+    }
+
+    // Build the image configuration. The raw JSON is the config blob as
+    // stored and pushed: object keys are sorted so the digest is stable, and
+    // it carries fields oci-spec does not model (Healthcheck, Shell).
+    pub fn to_image_config(
+        &self,
+        platform: &Platform,
+        created: &str,
+        history: Vec<History>,
+        diff_ids: Vec<String>,
+    ) -> Result<(ImageConfiguration, Vec<u8>)> {
+        let mut section = Map::new();
+        if let Some(user) = &self.user {
+            section.insert("User".to_string(), json!(user));
+        }
+        if !self.exposed_ports.is_empty() {
+            let ports: Map<String, Value> = self.exposed_ports.iter().map(|p| (p.clone(), json!({}))).collect();
+            section.insert("ExposedPorts".to_string(), Value::Object(ports));
+        }
+        if !self.env.is_empty() {
+            let env: Vec<String> = self.env.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            section.insert("Env".to_string(), json!(env));
+        }
+        if let Some(entrypoint) = &self.entrypoint {
+            section.insert("Entrypoint".to_string(), json!(entrypoint));
+        }
+        if let Some(cmd) = &self.cmd {
+            section.insert("Cmd".to_string(), json!(cmd));
+        }
+        if !self.volumes.is_empty() {
+            let volumes: Map<String, Value> = self.volumes.iter().map(|v| (v.clone(), json!({}))).collect();
+            section.insert("Volumes".to_string(), Value::Object(volumes));
+        }
+        if self.workdir != "/" {
+            section.insert("WorkingDir".to_string(), json!(self.workdir));
+        }
+        if !self.labels.is_empty() {
+            section.insert("Labels".to_string(), json!(self.labels));
+        }
+        if let Some(signal) = &self.stop_signal {
+            section.insert("StopSignal".to_string(), json!(signal));
+        }
+
+        let mut image = json!({
+            "created": created,
+            "architecture": platform.architecture,
+            "os": platform.os,
+            "config": section,
+            "rootfs": {
+                "type": "layers",
+                "diff_ids": diff_ids
+            },
+            "history": history,
+        });
+        if let Some(variant) = &platform.variant {
+            image["variant"] = json!(variant);
+        }
+
+        // The typed config is parsed before adding fields it cannot represent
+        let config: ImageConfiguration = serde_json::from_value(image.clone())?;
+
+        if let Some(healthcheck) = &self.healthcheck {
+            let mut check = Map::new();
+            check.insert("Test".to_string(), json!(healthcheck.test));
+            for (key, value) in [
+                ("Interval", healthcheck.interval),
+                ("Timeout", healthcheck.timeout),
+                ("StartPeriod", healthcheck.start_period),
+                ("Retries", healthcheck.retries.map(u64::from)),
+            ] {
+                if let Some(value) = value {
+                    check.insert(key.to_string(), json!(value));
+                }
+            }
+            image["config"]["Healthcheck"] = Value::Object(check);
+        }
+        if let Some(shell) = &self.shell {
+            image["config"]["Shell"] = json!(shell);
+        }
+
+        Ok((config, serde_json::to_vec(&image)?))
+    }
+}
+
+fn string_list(section: &Map<String, Value>, key: &str) -> Option<Vec<String>> {
+    let values = section.get(key)?.as_array()?;
+    Some(values.iter().filter_map(|v| v.as_str().map(str::to_string)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_roundtrip_keeps_healthcheck() {
+        let mut config = StageConfig::default();
+        config.apply(&Instruction::Cmd {
+            command: vec!["serve".to_string()],
+        });
+        config.apply(&Instruction::Expose {
+            ports: vec!["8080/tcp".to_string()],
+        });
+        config.apply(&Instruction::Healthcheck {
+            interval: Some(30_000_000_000),
+            timeout: None,
+            start_period: None,
+            retries: Some(3),
+            cmd: vec!["CMD-SHELL".to_string(), "true".to_string()],
+        });
+
+        let platform = Platform::parse("linux/arm64").unwrap();
+        let (typed, raw) = config
+            .to_image_config(&platform, "2024-01-01T00:00:00Z", Vec::new(), Vec::new())
+            .unwrap();
+        assert_eq!(typed.architecture().to_string(), "arm64");
+
+        let mut inherited = StageConfig::from_image_config(&raw).unwrap();
+        assert_eq!(inherited.healthcheck, config.healthcheck);
+        assert!(inherited.exposed_ports.contains("8080/tcp"));
+
+        // ENTRYPOINT in the child resets the CMD inherited from the base
+        inherited.apply(&Instruction::Entrypoint {
+            command: vec!["/app".to_string()],
+        });
+        assert_eq!(inherited.cmd, None);
+    }
+}
@@ -0,0 +1,460 @@
+use super::config::StageConfig;
+use super::executor::{self, DEFAULT_PATH};
+use crate::storage::{Image, StorageManager};
+use anyhow::Result;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+
+// OCI runtimes `--runtime auto` looks for on PATH, in order
+const RUNTIMES: [&str; 2] = ["runc", "crun"];
+
+// Capabilities the container's processes keep, the same set Docker grants by default
+const CAPABILITIES: [&str; 14] = [
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_FSETID",
+    "CAP_FOWNER",
+    "CAP_MKNOD",
+    "CAP_NET_RAW",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETFCAP",
+    "CAP_SETPCAP",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_SYS_CHROOT",
+    "CAP_KILL",
+    "CAP_AUDIT_WRITE",
+];
+
+// What runs the container's process
+#[derive(Debug, Clone, PartialEq)]
+pub enum Runtime {
+    // An OCI runtime binary such as runc or crun, given a bundle to run
+    Oci(String),
+    // chroot(8), as RUN steps use: no namespaces, and the image needs /bin/sh
+    Chroot,
+}
+
+impl Runtime {
+    // "auto" picks the first OCI runtime on PATH and falls back to chroot
+    pub fn parse(spec: &str) -> Result<Self> {
+        match spec {
+            "auto" => Ok(RUNTIMES
+                .iter()
+                .find(|runtime| on_path(runtime))
+                .map(|runtime| Self::Oci(runtime.to_string()))
+                .unwrap_or(Self::Chroot)),
+            "chroot" => Ok(Self::Chroot),
+            "" => Err(anyhow::anyhow!("Empty --runtime: expected auto, chroot or an OCI runtime such as runc")),
+            runtime => Ok(Self::Oci(runtime.to_string())),
+        }
+    }
+}
+
+fn on_path(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
+
+// A host path bind-mounted into the container (`-v SOURCE:TARGET[:ro]`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Volume {
+    pub source: PathBuf,
+    pub target: String,
+    pub read_only: bool,
+}
+
+impl Volume {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = || anyhow::anyhow!("Invalid volume {:?}: expected SOURCE:TARGET[:ro|rw]", spec);
+        let mut parts = spec.split(':');
+        let (Some(source), Some(target)) = (parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let read_only = match parts.next() {
+            None | Some("rw") => false,
+            Some("ro") => true,
+            Some(_) => return Err(invalid()),
+        };
+        if parts.next().is_some() || source.is_empty() || !target.starts_with('/') {
+            return Err(invalid());
+        }
+        let source = std::fs::canonicalize(source)
+            .map_err(|e| anyhow::anyhow!("Volume source {} cannot be used: {}", source, e))?;
+        Ok(Self {
+            source,
+            target: target.to_string(),
+            read_only,
+        })
+    }
+}
+
+// A host TCP port forwarded to a container port (`-p HOST:CONTAINER`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortMapping {
+    pub host: u16,
+    pub container: u16,
+}
+
+impl PortMapping {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = || anyhow::anyhow!("Invalid port mapping {:?}: expected HOST:CONTAINER or PORT", spec);
+        let ports = match spec.strip_suffix("/udp") {
+            Some(_) => return Err(anyhow::anyhow!("Port mapping {}: only TCP ports can be published", spec)),
+            None => spec.strip_suffix("/tcp").unwrap_or(spec),
+        };
+        let (host, container) = ports.split_once(':').unwrap_or((ports, ports));
+        Ok(Self {
+            host: host.parse().map_err(|_| invalid())?,
+            container: container.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+// How to run an image, on top of what its config says
+#[derive(Debug, Clone)]
+pub struct ContainerSpec {
+    // Replaces the image's CMD when not empty
+    pub command: Vec<String>,
+    // Replaces the image's ENTRYPOINT, and drops its CMD
+    pub entrypoint: Option<Vec<String>>,
+    // Set on top of the image's ENV
+    pub env: Vec<(String, String)>,
+    pub workdir: Option<String>,
+    pub user: Option<String>,
+    pub volumes: Vec<Volume>,
+    pub ports: Vec<PortMapping>,
+    pub runtime: Runtime,
+}
+
+// Run an image's entrypoint and command on an overlay of its layers, with
+// the terminal's stdin and output, and return the exit code. The overlay
+// and whatever the process wrote to it are removed afterwards.
+pub async fn run_image(storage: &StorageManager, image: &Image, spec: &ContainerSpec) -> Result<i32> {
+    let config = StageConfig::from_image_config(&image.raw_config)?;
+    let args = process_args(&config, spec)?;
+    let env = process_env(&config, spec);
+    let workdir = spec.workdir.clone().unwrap_or(config.workdir);
+    let user = spec.user.clone().or(config.user);
+
+    let bundle = storage.tmp_dir().join(format!("run_{}", uuid::Uuid::new_v4()));
+    let rootfs = match storage.mount_layers(&image.layers, &bundle.join("rootfs")).await {
+        Ok(rootfs) => rootfs,
+        Err(e) => {
+            let _ = tokio::fs::remove_dir_all(&bundle).await;
+            return Err(e);
+        }
+    };
+    let ran = async {
+        // The container shares the host's network, so ports are forwarded on the loopback
+        let mut forwards = Vec::new();
+        for mapping in spec.ports.iter().filter(|mapping| mapping.host != mapping.container) {
+            forwards.push(forward_port(*mapping).await?);
+        }
+        let status = match &spec.runtime {
+            Runtime::Oci(runtime) => {
+                let uid_gid = match &user {
+                    Some(user) => resolve_user(&rootfs.target, user)?,
+                    None => (0, 0),
+                };
+                let config = oci_config(&args, &env, &workdir, uid_gid, &spec.volumes);
+                tokio::fs::write(bundle.join("config.json"), serde_json::to_vec_pretty(&config)?).await?;
+                run_oci(runtime, &bundle).await
+            }
+            Runtime::Chroot => run_chroot(&rootfs.target, user.as_deref(), &workdir, &args, &env, &spec.volumes).await,
+        };
+        for forward in forwards {
+            forward.abort();
+        }
+        status
+    }
+    .await;
+
+    rootfs.unmount().await?;
+    tokio::fs::remove_dir_all(&bundle).await?;
+    Ok(exit_code(ran?))
+}
+
+// ENTRYPOINT followed by CMD, with docker run's overrides
+fn process_args(config: &StageConfig, spec: &ContainerSpec) -> Result<Vec<String>> {
+    let (entrypoint, cmd) = match &spec.entrypoint {
+        Some(entrypoint) => (Some(entrypoint.clone()), spec.command.clone()),
+        None if spec.command.is_empty() => (config.entrypoint.clone(), config.cmd.clone().unwrap_or_default()),
+        None => (config.entrypoint.clone(), spec.command.clone()),
+    };
+    let args: Vec<String> = entrypoint.into_iter().flatten().chain(cmd).collect();
+    if args.is_empty() {
+        return Err(anyhow::anyhow!("The image has no ENTRYPOINT or CMD; give a command to run"));
+    }
+    Ok(args)
+}
+
+fn process_env(config: &StageConfig, spec: &ContainerSpec) -> Vec<(String, String)> {
+    let mut env = config.env.clone();
+    for (key, value) in &spec.env {
+        env.retain(|(k, _)| k != key);
+        env.push((key.clone(), value.clone()));
+    }
+    if !env.iter().any(|(k, _)| k == "PATH") {
+        env.push(("PATH".to_string(), DEFAULT_PATH.to_string()));
+    }
+    env
+}
+
+// The uid and gid a USER value ("name", "uid", "name:group" or "uid:gid")
+// stands for in the rootfs' /etc/passwd and /etc/group
+fn resolve_user(rootfs: &Path, user: &str) -> Result<(u32, u32)> {
+    let (name, group) = match user.split_once(':') {
+        Some((name, group)) => (name, Some(group)),
+        None => (user, None),
+    };
+    let passwd = std::fs::read_to_string(rootfs.join("etc/passwd")).unwrap_or_default();
+    let entry = passwd
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() >= 4 && (fields[0] == name || fields[2] == name));
+    let uid = match name.parse() {
+        Ok(uid) => uid,
+        Err(_) => entry
+            .as_ref()
+            .and_then(|fields| fields[2].parse().ok())
+            .ok_or_else(|| anyhow::anyhow!("User {} is not in the image's /etc/passwd", name))?,
+    };
+    let gid = match group {
+        Some(group) => match group.parse() {
+            Ok(gid) => gid,
+            Err(_) => std::fs::read_to_string(rootfs.join("etc/group"))
+                .unwrap_or_default()
+                .lines()
+                .map(|line| line.split(':').collect::<Vec<_>>())
+                .find(|fields| fields.len() >= 3 && fields[0] == group)
+                .and_then(|fields| fields[2].parse().ok())
+                .ok_or_else(|| anyhow::anyhow!("Group {} is not in the image's /etc/group", group))?,
+        },
+        None => entry.and_then(|fields| fields[3].parse().ok()).unwrap_or(0),
+    };
+    Ok((uid, gid))
+}
+
+// An OCI runtime config: the process in its own PID, IPC, UTS and mount
+// namespaces on the host's network, with /proc, /dev, /sys and the volumes
+fn oci_config(
+    args: &[String],
+    env: &[(String, String)],
+    workdir: &str,
+    (uid, gid): (u32, u32),
+    volumes: &[Volume],
+) -> serde_json::Value {
+    let mut mounts = vec![
+        json!({ "destination": "/proc", "type": "proc", "source": "proc" }),
+        json!({ "destination": "/dev", "type": "tmpfs", "source": "tmpfs",
+                "options": ["nosuid", "strictatime", "mode=755", "size=65536k"] }),
+        json!({ "destination": "/dev/pts", "type": "devpts", "source": "devpts",
+                "options": ["nosuid", "noexec", "newinstance", "ptmxmode=0666", "mode=0620"] }),
+        json!({ "destination": "/dev/shm", "type": "tmpfs", "source": "shm",
+                "options": ["nosuid", "noexec", "nodev", "mode=1777", "size=65536k"] }),
+        json!({ "destination": "/sys", "type": "sysfs", "source": "sysfs",
+                "options": ["nosuid", "noexec", "nodev", "ro"] }),
+    ];
+    // Name resolution is the host's, as the network is
+    for file in ["/etc/resolv.conf", "/etc/hosts"] {
+        if Path::new(file).exists() {
+            mounts.push(json!({ "destination": file, "type": "bind", "source": file, "options": ["rbind", "ro"] }));
+        }
+    }
+    for volume in volumes {
+        let access = if volume.read_only { "ro" } else { "rw" };
+        mounts.push(json!({
+            "destination": volume.target,
+            "type": "bind",
+            "source": volume.source,
+            "options": ["rbind", access],
+        }));
+    }
+    let env: Vec<String> = env.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+    json!({
+        "ociVersion": "1.0.2",
+        "process": {
+            "terminal": false,
+            "user": { "uid": uid, "gid": gid },
+            "args": args,
+            "env": env,
+            "cwd": workdir,
+            "capabilities": {
+                "bounding": CAPABILITIES,
+                "effective": CAPABILITIES,
+                "permitted": CAPABILITIES,
+            },
+            "noNewPrivileges": true,
+        },
+        "root": { "path": "rootfs" },
+        "hostname": "hyperbuild",
+        "mounts": mounts,
+        "linux": {
+            "namespaces": [{ "type": "pid" }, { "type": "ipc" }, { "type": "uts" }, { "type": "mount" }],
+            "maskedPaths": ["/proc/kcore", "/proc/keys", "/proc/timer_list", "/sys/firmware"],
+            "readonlyPaths": ["/proc/bus", "/proc/fs", "/proc/irq", "/proc/sys", "/proc/sysrq-trigger"],
+        },
+    })
+}
+
+async fn run_oci(runtime: &str, bundle: &Path) -> Result<ExitStatus> {
+    let id = format!("hyperbuild-{}", uuid::Uuid::new_v4().simple());
+    let mut command = Command::new(runtime);
+    command.arg("run").arg("--bundle").arg(bundle).arg(&id);
+    let status = wait(command, runtime).await;
+    // Gone already unless the runtime itself was killed
+    let _ = Command::new(runtime).args(["delete", "--force", &id]).output().await;
+    status
+}
+
+async fn run_chroot(
+    rootfs: &Path,
+    user: Option<&str>,
+    workdir: &str,
+    args: &[String],
+    env: &[(String, String)],
+    volumes: &[Volume],
+) -> Result<ExitStatus> {
+    let mut mounted = Vec::new();
+    let ran = async {
+        for volume in volumes {
+            let target = rootfs.join(super::snapshot::normalize(Path::new(&volume.target)));
+            if volume.source.is_dir() {
+                tokio::fs::create_dir_all(&target).await?;
+            } else {
+                tokio::fs::create_dir_all(target.parent().unwrap_or(rootfs)).await?;
+                tokio::fs::write(&target, b"").await?;
+            }
+            mount(&["--rbind".as_ref(), volume.source.as_os_str(), target.as_os_str()]).await?;
+            mounted.push(target.clone());
+            if volume.read_only {
+                mount(&["-o".as_ref(), "remount,bind,ro".as_ref(), target.as_os_str()]).await?;
+            }
+        }
+        let argv = executor::chroot_argv(rootfs, user, workdir, args);
+        let mut command = Command::new(&argv[0]);
+        command.args(&argv[1..]).env_clear().envs(env.iter().cloned());
+        wait(command, &argv[0]).await
+    }
+    .await;
+    for target in mounted.iter().rev() {
+        let _ = Command::new("umount").arg("--recursive").arg(target).status().await;
+    }
+    ran
+}
+
+async fn mount(args: &[&std::ffi::OsStr]) -> Result<()> {
+    let output = Command::new("mount").args(args).output().await?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("mount {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(())
+}
+
+// Run the process in the foreground. Ctrl+C reaches it through the terminal,
+// so it is only noted here, which lets the overlay be cleaned up; a second
+// one kills the process.
+async fn wait(mut command: Command, program: &str) -> Result<ExitStatus> {
+    let mut child = command
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("Failed to start {}: {}", program, e))?;
+    let mut interrupts = 0;
+    loop {
+        tokio::select! {
+            status = child.wait() => return Ok(status?),
+            _ = tokio::signal::ctrl_c() => {
+                interrupts += 1;
+                if interrupts > 1 {
+                    let _ = child.start_kill();
+                }
+            }
+        }
+    }
+}
+
+// A process killed by a signal exits with 128 plus the signal, as in a shell
+fn exit_code(status: ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+    status.code().or_else(|| status.signal().map(|signal| 128 + signal)).unwrap_or(1)
+}
+
+// Accept connections on the host port and relay each to the container port
+async fn forward_port(mapping: PortMapping) -> Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(("0.0.0.0", mapping.host))
+        .await
+        .map_err(|e| anyhow::anyhow!("Cannot publish port {}: {}", mapping.host, e))?;
+    Ok(tokio::spawn(async move {
+        while let Ok((mut inbound, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                if let Ok(mut outbound) = TcpStream::connect(("127.0.0.1", mapping.container)).await {
+                    let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                }
+            });
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_container_settings() {
+        let mut config = StageConfig::default();
+        config.env = vec![("PATH".to_string(), "/app/bin".to_string()), ("MODE".to_string(), "prod".to_string())];
+        config.cmd = Some(vec!["serve".to_string()]);
+        config.entrypoint = Some(vec!["/app/bin/server".to_string()]);
+        let mut spec = ContainerSpec {
+            command: Vec::new(),
+            entrypoint: None,
+            env: vec![("MODE".to_string(), "dev".to_string())],
+            workdir: None,
+            user: None,
+            volumes: Vec::new(),
+            ports: Vec::new(),
+            runtime: Runtime::Chroot,
+        };
+        assert_eq!(process_args(&config, &spec).unwrap(), ["/app/bin/server", "serve"]);
+        spec.command = vec!["check".to_string()];
+        assert_eq!(process_args(&config, &spec).unwrap(), ["/app/bin/server", "check"]);
+        spec.entrypoint = Some(vec!["/bin/sh".to_string()]);
+        spec.command.clear();
+        assert_eq!(process_args(&config, &spec).unwrap(), ["/bin/sh"]);
+        spec.entrypoint = Some(Vec::new());
+        assert!(process_args(&config, &spec).is_err());
+        let env = process_env(&config, &spec);
+        assert_eq!(env, [("PATH".to_string(), "/app/bin".to_string()), ("MODE".to_string(), "dev".to_string())]);
+
+        assert_eq!(PortMapping::parse("8080:80").unwrap(), PortMapping { host: 8080, container: 80 });
+        assert_eq!(PortMapping::parse("80/tcp").unwrap(), PortMapping { host: 80, container: 80 });
+        assert!(PortMapping::parse("53:53/udp").is_err());
+        assert!(PortMapping::parse("http:80").is_err());
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let source = tempdir.path().to_string_lossy();
+        let volume = Volume::parse(&format!("{}:/data:ro", source)).unwrap();
+        assert_eq!((volume.target.as_str(), volume.read_only), ("/data", true));
+        assert!(!Volume::parse(&format!("{}:/data", source)).unwrap().read_only);
+        assert!(Volume::parse(&format!("{}:data", source)).is_err());
+        assert!(Volume::parse(&format!("{}:/data:rx", source)).is_err());
+        assert!(Volume::parse("/no/such/dir:/data").is_err());
+
+        std::fs::create_dir(tempdir.path().join("etc")).unwrap();
+        std::fs::write(tempdir.path().join("etc/passwd"), "root:x:0:0::/root:/bin/sh\napp:x:1000:1001::/app:/bin/sh\n")
+            .unwrap();
+        std::fs::write(tempdir.path().join("etc/group"), "root:x:0:\nstaff:x:50:\n").unwrap();
+        assert_eq!(resolve_user(tempdir.path(), "app").unwrap(), (1000, 1001));
+        assert_eq!(resolve_user(tempdir.path(), "1000").unwrap(), (1000, 1001));
+        assert_eq!(resolve_user(tempdir.path(), "app:staff").unwrap(), (1000, 50));
+        assert_eq!(resolve_user(tempdir.path(), "2000:3000").unwrap(), (2000, 3000));
+        assert_eq!(resolve_user(tempdir.path(), "2000").unwrap(), (2000, 0));
+        assert!(resolve_user(tempdir.path(), "nobody").is_err());
+        assert!(resolve_user(tempdir.path(), "app:wheel").is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use crate::dockerfile::{BuildStage, Instruction};
+use std::collections::BTreeSet;
+
+// Dependency graph between the stages of a Dockerfile. A stage depends on an
+// earlier stage when it uses it as its base image or copies from it.
+#[derive(Debug, Clone)]
+pub struct StageGraph {
+    deps: Vec<BTreeSet<usize>>,
+}
+
+impl StageGraph {
+    pub fn new(stages: &[BuildStage]) -> Self {
+        let deps = stages
+            .iter()
+            .enumerate()
+            .map(|(idx, stage)| {
+                let mut deps = BTreeSet::new();
+                if let Some(base) = resolve_stage_ref(stages, &stage.base_image, idx) {
+                    deps.insert(base);
+                }
+                for instruction in &stage.instructions {
+                    if let Instruction::Copy { from: Some(from), .. } = instruction
+                        && let Some(dep) = resolve_stage_ref(stages, from, idx)
+                    {
+                        deps.insert(dep);
+                    }
+                }
+                deps
+            })
+            .collect();
+
+        Self { deps }
+    }
+
+    pub fn dependencies(&self, stage_idx: usize) -> &BTreeSet<usize> {
+        &self.deps[stage_idx]
+    }
+
+    // All stages that must be built to produce `target`, including itself
+    pub fn required_for(&self, target: usize) -> BTreeSet<usize> {
+        let mut required = BTreeSet::new();
+        let mut pending = vec![target];
+        while let Some(idx) = pending.pop() {
+            if required.insert(idx) {
+                pending.extend(self.deps[idx].iter().copied());
+            }
+        }
+        required
+    }
+}
+
+// Resolve a stage reference (alias or numeric index) to a stage defined
+// before `current`. Anything else refers to an external image.
+pub fn resolve_stage_ref(stages: &[BuildStage], reference: &str, current: usize) -> Option<usize> {
+    if let Ok(idx) = reference.parse::<usize>() {
+        return (idx < current).then_some(idx);
+    }
+
+    stages[..current]
+        .iter()
+        .rposition(|stage| stage.name.as_deref().is_some_and(|name| name.eq_ignore_ascii_case(reference)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dockerfile::DockerfileParser;
+
+    #[test]
+    fn test_independent_stages() {
+        let parsed = DockerfileParser::parse(
+            r#"
+            FROM alpine AS base
+            RUN echo base
+
+            FROM golang AS backend
+            RUN go build
+
+            FROM node AS frontend
+            RUN npm run build
+
+            FROM base
+            COPY --from=backend /app /app
+            COPY --from=2 /dist /dist
+            "#,
+        )
+        .unwrap();
+
+        let graph = StageGraph::new(&parsed.stages);
+        assert!(graph.dependencies(1).is_empty());
+        assert!(graph.dependencies(2).is_empty());
+        assert_eq!(graph.dependencies(3), &BTreeSet::from([0, 1, 2]));
+        assert_eq!(graph.required_for(1), BTreeSet::from([1]));
+        assert_eq!(graph.required_for(3).len(), 4);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::platform::Platform;
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+const BINFMT_DIR: &str = "/proc/sys/fs/binfmt_misc";
+
+// ELF magic and mask for each QEMU target, as in QEMU's qemu-binfmt-conf.sh
+const QEMU_MAGIC: &[(&str, &str, &str)] = &[
+    (
+        "x86_64",
+        r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x3e\x00",
+        r"\xff\xff\xff\xff\xff\xfe\xfe\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff",
+    ),
+    (
+        "aarch64",
+        r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\xb7\x00",
+        r"\xff\xff\xff\xff\xff\xff\xff\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff",
+    ),
+    (
+        "arm",
+        r"\x7fELF\x01\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x28\x00",
+        r"\xff\xff\xff\xff\xff\xff\xff\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff",
+    ),
+    (
+        "riscv64",
+        r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\xf3\x00",
+        r"\xff\xff\xff\xff\xff\xff\xff\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff",
+    ),
+    (
+        "ppc64le",
+        r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x15\x00",
+        r"\xff\xff\xff\xff\xff\xff\xff\xfc\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\x00",
+    ),
+    (
+        "s390x",
+        r"\x7fELF\x02\x02\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x16",
+        r"\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff",
+    ),
+];
+
+// How RUN steps for a target platform get executed
+#[derive(Debug, Clone, PartialEq)]
+pub enum Emulation {
+    Native,
+    // A binfmt_misc handler runs foreign binaries through this interpreter
+    Qemu { interpreter: PathBuf },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct BinfmtEntry {
+    enabled: bool,
+    interpreter: PathBuf,
+    flags: String,
+}
+
+// Make sure RUN steps for `target` can execute on this host, registering a
+// qemu-user-static handler when one is installed but not yet registered.
+pub fn ensure_available(target: &Platform) -> Result<Emulation> {
+    let host = Platform::host();
+    if runs_natively(&host, target) {
+        return Ok(Emulation::Native);
+    }
+
+    let qemu_arch = qemu_arch(&target.architecture)
+        .ok_or_else(|| anyhow::anyhow!("No emulator known for architecture {} (needed for RUN on {})", target.architecture, target))?;
+
+    if !Path::new(BINFMT_DIR).join("status").exists() {
+        return Err(anyhow::anyhow!(
+            "RUN steps for {} need QEMU emulation, but binfmt_misc is not mounted at {}",
+            target,
+            BINFMT_DIR
+        ));
+    }
+
+    let handler = Path::new(BINFMT_DIR).join(format!("qemu-{}", qemu_arch));
+    if !handler.exists() {
+        register_qemu(qemu_arch)?;
+    }
+
+    let entry = parse_binfmt_entry(&std::fs::read_to_string(&handler)?);
+    if !entry.enabled {
+        return Err(anyhow::anyhow!("The binfmt_misc handler {:?} is disabled", handler));
+    }
+    // Without the F flag the kernel looks for the interpreter inside the chroot
+    if !entry.flags.contains('F') {
+        return Err(anyhow::anyhow!(
+            "The binfmt_misc handler {:?} is registered without the F (fix binary) flag, so {:?} is not usable \
+             inside the build rootfs; re-register it with the F flag",
+            handler,
+            entry.interpreter
+        ));
+    }
+
+    tracing::info!("Running RUN steps for {} under emulation with {:?}", target, entry.interpreter);
+    Ok(Emulation::Qemu {
+        interpreter: entry.interpreter,
+    })
+}
+
+fn runs_natively(host: &Platform, target: &Platform) -> bool {
+    host.os == target.os
+        && (host.architecture == target.architecture || (host.architecture == "amd64" && target.architecture == "386"))
+}
+
+fn qemu_arch(architecture: &str) -> Option<&'static str> {
+    match architecture {
+        "amd64" => Some("x86_64"),
+        "arm64" => Some("aarch64"),
+        "arm" => Some("arm"),
+        "riscv64" => Some("riscv64"),
+        "ppc64le" => Some("ppc64le"),
+        "s390x" => Some("s390x"),
+        _ => None,
+    }
+}
+
+// Register an installed qemu-<arch>-static with the F flag, so the kernel
+// keeps the interpreter open and it works from inside any chroot
+fn register_qemu(qemu_arch: &str) -> Result<()> {
+    let binary = format!("qemu-{}-static", qemu_arch);
+    let interpreter = find_in_path(&binary).ok_or_else(|| {
+        anyhow::anyhow!(
+            "No binfmt_misc handler for {} is registered and {} was not found; install qemu-user-static \
+             or register handlers (e.g. docker run --privileged tonistiigi/binfmt --install all)",
+            qemu_arch,
+            binary
+        )
+    })?;
+    let (_, magic, mask) = QEMU_MAGIC
+        .iter()
+        .find(|(arch, _, _)| *arch == qemu_arch)
+        .ok_or_else(|| anyhow::anyhow!("No binfmt_misc magic known for {}", qemu_arch))?;
+
+    let registration = format!(":qemu-{}:M::{}:{}:{}:F", qemu_arch, magic, mask, interpreter.display());
+    std::fs::write(Path::new(BINFMT_DIR).join("register"), registration)
+        .map_err(|e| anyhow::anyhow!("Failed to register {:?} with binfmt_misc (requires root): {}", interpreter, e))?;
+    tracing::info!("Registered {:?} as the binfmt_misc handler for {}", interpreter, qemu_arch);
+    Ok(())
+}
+
+fn find_in_path(binary: &str) -> Option<PathBuf> {
+    let path = std::env::var("PATH").unwrap_or_default();
+    std::env::split_paths(&path)
+        .chain([PathBuf::from("/usr/bin"), PathBuf::from("/usr/local/bin")])
+        .map(|dir| dir.join(binary))
+        .find(|candidate| candidate.is_file())
+}
+
+fn parse_binfmt_entry(contents: &str) -> BinfmtEntry {
+    let mut entry = BinfmtEntry {
+        enabled: false,
+        interpreter: PathBuf::new(),
+        flags: String::new(),
+    };
+
+    for line in contents.lines() {
+        if line == "enabled" {
+            entry.enabled = true;
+        } else if let Some(interpreter) = line.strip_prefix("interpreter ") {
+            entry.interpreter = PathBuf::from(interpreter);
+        } else if let Some(flags) = line.strip_prefix("flags:") {
+            entry.flags = flags.trim().to_string();
+        }
+    }
+
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_binfmt_entry() {
+        let entry = parse_binfmt_entry(
+            "enabled\ninterpreter /usr/bin/qemu-aarch64-static\nflags: OCF\noffset 0\nmagic 7f454c46\nmask ffffffff\n",
+        );
+        assert!(entry.enabled);
+        assert_eq!(entry.interpreter, PathBuf::from("/usr/bin/qemu-aarch64-static"));
+        assert!(entry.flags.contains('F'));
+    }
+
+    #[test]
+    fn test_native_platforms() {
+        let host = Platform::parse("linux/amd64").unwrap();
+        assert!(runs_natively(&host, &Platform::parse("linux/386").unwrap()));
+        assert!(!runs_natively(&host, &Platform::parse("linux/arm64").unwrap()));
+        assert_eq!(qemu_arch("arm64"), Some("aarch64"));
+    }
+}
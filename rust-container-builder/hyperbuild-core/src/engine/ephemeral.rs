@@ -0,0 +1,92 @@
+use super::snapshot;
+use anyhow::Result;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// Files placed into a stage rootfs for the duration of one RUN step, such as
+// secrets or a generated /etc/hosts. Removing them undoes every trace,
+// including directory mtime changes, so the step's layer diff never
+// contains them or evidence of them.
+#[derive(Debug)]
+pub struct EphemeralFiles {
+    rootfs: PathBuf,
+    placed: Vec<PathBuf>,
+    backups: Vec<(PathBuf, PathBuf)>,
+    created_dirs: Vec<PathBuf>,
+    dir_mtimes: Vec<(PathBuf, SystemTime)>,
+}
+
+impl EphemeralFiles {
+    pub fn new(rootfs: &Path) -> Self {
+        Self {
+            rootfs: rootfs.to_path_buf(),
+            placed: Vec::new(),
+            backups: Vec::new(),
+            created_dirs: Vec::new(),
+            dir_mtimes: Vec::new(),
+        }
+    }
+
+    // Write `contents` to `target`, an absolute path inside the rootfs
+    pub fn place(&mut self, target: &str, contents: &[u8], mode: u32) -> Result<()> {
+        let dest = self.rootfs.join(snapshot::normalize(Path::new(target)));
+
+        // Remember the mtimes of existing ancestors so they can be restored
+        let mut ancestor = dest.parent();
+        while let Some(dir) = ancestor {
+            if dir.is_dir() {
+                self.dir_mtimes.push((dir.to_path_buf(), fs::metadata(dir)?.modified()?));
+            } else {
+                self.created_dirs.push(dir.to_path_buf());
+            }
+            if dir == self.rootfs {
+                break;
+            }
+            ancestor = dir.parent();
+        }
+
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // A file already at the target is moved aside and restored afterwards
+        if fs::symlink_metadata(&dest).is_ok() {
+            let backup = dest.with_extension("hyperbuild-backup");
+            fs::rename(&dest, &backup)?;
+            self.backups.push((dest.clone(), backup));
+        }
+
+        fs::write(&dest, contents).map_err(|e| anyhow::anyhow!("Failed to write {}: {}", target, e))?;
+        fs::set_permissions(&dest, fs::Permissions::from_mode(mode))?;
+        self.placed.push(dest);
+        Ok(())
+    }
+
+    pub fn remove(self) -> Result<()> {
+        // Later placements may have backed up earlier ones, so undo in reverse
+        for path in self.placed.iter().rev() {
+            if fs::symlink_metadata(path).is_ok() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        for (original, backup) in self.backups.iter().rev() {
+            fs::rename(backup, original)?;
+        }
+
+        // Created directories were recorded deepest first
+        for dir in &self.created_dirs {
+            if fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_none()) {
+                fs::remove_dir(dir)?;
+            }
+        }
+
+        for (dir, mtime) in self.dir_mtimes.iter().rev() {
+            fs::File::open(dir)?.set_modified(*mtime)?;
+        }
+
+        Ok(())
+    }
+}
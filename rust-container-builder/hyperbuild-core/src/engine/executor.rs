@@ -0,0 +1,239 @@
+use crate::dockerfile::NetworkMode;
+use crate::progress::LogSink;
+use anyhow::Result;
+use async_trait::async_trait;
+use super::cgroup::StepCgroup;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{ChildStderr, ChildStdout};
+use tokio_util::sync::CancellationToken;
+
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+// Everything an executor needs to run one RUN step inside a stage rootfs
+#[derive(Debug, Clone)]
+pub struct StepSpec {
+    pub command: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub workdir: String,
+    pub user: Option<String>,
+    pub rootfs: PathBuf,
+    pub network: NetworkMode,
+    pub limits: StepLimits,
+    // Kills the command when the build is cancelled
+    pub cancel: CancellationToken,
+    // Receives the command's output; without one it goes to the terminal
+    pub log: Option<LogSink>,
+}
+
+// Budget for a single RUN step; memory and CPU limits are enforced with cgroup v2
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StepLimits {
+    pub memory_bytes: Option<u64>,
+    pub cpus: Option<f64>,
+    pub timeout: Option<Duration>,
+}
+
+#[async_trait]
+pub trait Executor: Send + Sync {
+    async fn run(&self, spec: &StepSpec) -> Result<()>;
+}
+
+// Runs steps with chroot(8). This needs root privileges but no container
+// runtime, which keeps hyperbuild usable on minimal build hosts.
+pub struct ChrootExecutor;
+
+#[async_trait]
+impl Executor for ChrootExecutor {
+    async fn run(&self, spec: &StepSpec) -> Result<()> {
+        let cgroup = match (spec.limits.memory_bytes, spec.limits.cpus) {
+            (None, None) => None,
+            (memory, cpus) => Some(StepCgroup::create(memory, cpus)?),
+        };
+
+        let outcome = run_chroot(spec, cgroup.as_ref()).await;
+        if let Some(cgroup) = cgroup {
+            let oom_killed = cgroup.oom_killed();
+            // Processes the step left behind would keep the cgroup busy
+            let removed = cgroup.kill().and_then(|()| cgroup.remove());
+            if oom_killed && outcome.is_err() {
+                return Err(anyhow::anyhow!(
+                    "Command {:?} exceeded its memory limit of {} bytes",
+                    spec.command,
+                    spec.limits.memory_bytes.unwrap_or_default()
+                ));
+            }
+            // The step's own failure says more than a cgroup left behind
+            outcome?;
+            removed?;
+            return Ok(());
+        }
+        outcome
+    }
+}
+
+// The error of a step stopped at its time limit, worded alike for every executor
+pub(super) fn timed_out(what: String, limit: Option<Duration>) -> anyhow::Error {
+    anyhow::anyhow!("{} timed out after {:?}", what, limit.unwrap_or_default())
+}
+
+async fn run_chroot(spec: &StepSpec, cgroup: Option<&StepCgroup>) -> Result<()> {
+    let mut argv: Vec<String> = Vec::new();
+
+    // The step joins its cgroup before exec, so every process it starts is accounted
+    if let Some(cgroup) = cgroup {
+        argv.extend(["/bin/sh".to_string(), "-c".to_string(), "echo $$ > \"$0\" && exec \"$@\"".to_string()]);
+        argv.push(cgroup.procs_file().to_string_lossy().to_string());
+    }
+
+    // Steps without network get their own, empty network namespace
+    if spec.network == NetworkMode::None {
+        argv.extend(["unshare".to_string(), "--net".to_string()]);
+    }
+
+    argv.extend(chroot_argv(&spec.rootfs, spec.user.as_deref(), &spec.workdir, &spec.command));
+
+    let mut command = tokio::process::Command::new(&argv[0]);
+    command
+        .args(&argv[1..])
+        .env_clear()
+        .envs(spec.env.iter().cloned())
+        // A group of its own lets a timed out or cancelled step be killed with all its children
+        .process_group(0)
+        .kill_on_drop(true);
+    if spec.log.is_some() {
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    }
+
+    let mut child = command
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("Failed to start {}: {}", argv[0], e))?;
+    let pid = child.id();
+    let output = spec
+        .log
+        .clone()
+        .map(|log| tokio::spawn(forward_output(child.stdout.take(), child.stderr.take(), log)));
+
+    let finished = async {
+        let status = child.wait().await;
+        // Processes the step left running in the background would hold its output open
+        if let Some(output) = output {
+            match cgroup {
+                Some(cgroup) => cgroup.kill()?,
+                None => kill_process_group(pid),
+            }
+            let _ = output.await;
+        }
+        Ok::<_, anyhow::Error>(status?)
+    };
+
+    let deadline = async {
+        match spec.limits.timeout {
+            Some(limit) => tokio::time::sleep(limit).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let status = tokio::select! {
+        status = finished => status?,
+        _ = deadline => {
+            stop(&mut child, cgroup).await?;
+            return Err(timed_out(format!("Command {:?}", spec.command), spec.limits.timeout));
+        }
+        _ = spec.cancel.cancelled() => {
+            stop(&mut child, cgroup).await?;
+            return Err(anyhow::anyhow!("Command {:?} was cancelled", spec.command));
+        }
+    };
+
+    if !status.success() {
+        return Err(anyhow::anyhow!("Command {:?} failed with {}", spec.command, status));
+    }
+
+    Ok(())
+}
+
+// chroot(8) into `rootfs` as `user`, then run `command` in `workdir`
+pub(crate) fn chroot_argv(rootfs: &Path, user: Option<&str>, workdir: &str, command: &[String]) -> Vec<String> {
+    let mut argv = vec!["chroot".to_string()];
+    if let Some(user) = user {
+        argv.push(format!("--userspec={}", user));
+    }
+
+    // chroot starts in /, so change into the working directory first
+    argv.push(rootfs.to_string_lossy().to_string());
+    argv.extend(["/bin/sh", "-c", "cd \"$0\" && exec \"$@\"", workdir].map(String::from));
+    argv.extend(command.iter().cloned());
+    argv
+}
+
+// Send stdout and stderr to the log sink as they arrive, one line at a time
+pub(super) async fn forward_output(stdout: Option<ChildStdout>, stderr: Option<ChildStderr>, log: LogSink) {
+    async fn forward<R: AsyncRead + Unpin>(stream: Option<R>, log: &LogSink) {
+        let Some(stream) = stream else { return };
+        let mut lines = BufReader::new(stream).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            log.line(line);
+        }
+    }
+    tokio::join!(forward(stdout, &log), forward(stderr, &log));
+}
+
+// Kill the step with everything it started and wait for it to exit
+async fn stop(child: &mut tokio::process::Child, cgroup: Option<&StepCgroup>) -> Result<()> {
+    match cgroup {
+        Some(cgroup) => cgroup.kill()?,
+        None => kill_process_group(child.id()),
+    }
+    let _ = child.kill().await;
+    Ok(())
+}
+
+pub(super) fn kill_process_group(pid: Option<u32>) {
+    if let Some(pid) = pid {
+        let _ = std::process::Command::new("kill")
+            .args(["-s", "KILL", "--", &format!("-{}", pid)])
+            // The group is usually gone already
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_executors_word_timeouts_alike() {
+        let limit = Some(Duration::from_millis(200));
+        let command = timed_out(format!("Command {:?}", ["sleep", "10"]), limit);
+        assert_eq!(command.to_string(), "Command [\"sleep\", \"10\"] timed out after 200ms");
+        let plugin = timed_out(format!("Plugin {:?}", "slow"), limit);
+        assert_eq!(plugin.to_string(), "Plugin \"slow\" timed out after 200ms");
+    }
+
+    #[tokio::test]
+    async fn test_chroot_step_times_out() {
+        // chroot(8) needs root; / stands in for a stage rootfs with a shell
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let spec = StepSpec {
+            command: vec!["sleep".to_string(), "10".to_string()],
+            env: vec![("PATH".to_string(), DEFAULT_PATH.to_string())],
+            workdir: "/".to_string(),
+            user: None,
+            rootfs: PathBuf::from("/"),
+            network: NetworkMode::Default,
+            limits: StepLimits { timeout: Some(Duration::from_millis(200)), ..Default::default() },
+            cancel: CancellationToken::new(),
+            log: None,
+        };
+        let started = std::time::Instant::now();
+        let error = ChrootExecutor.run(&spec).await.unwrap_err();
+        assert_eq!(error.to_string(), "Command [\"sleep\", \"10\"] timed out after 200ms");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
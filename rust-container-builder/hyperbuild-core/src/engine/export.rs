@@ -0,0 +1,458 @@
+use super::snapshot;
+use crate::platform::Platform;
+use crate::reference::split;
+use crate::storage::{Image, ImageList, Layer};
+use anyhow::Result;
+use oci_spec::image::{
+    Descriptor, DescriptorBuilder, ImageIndexBuilder, MediaType,
+};
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+// Where the result of a build is written besides the image store (`--output`)
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildOutput {
+    // The final stage's filesystem, copied into a directory
+    Local { dest: PathBuf },
+    // The final stage's filesystem as a tar archive
+    Tar { dest: PathBuf },
+    // The image as an OCI image layout tar
+    Oci { dest: PathBuf },
+    // The image as an OCI image layout directory (`type=oci,tar=false`)
+    OciDir { dest: PathBuf },
+    // The image as a `docker save` tar, loadable with `docker load`
+    Docker { dest: PathBuf },
+}
+
+impl BuildOutput {
+    // Parse a `type=TYPE,dest=PATH` value
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut output_type = None;
+        let mut dest = None;
+        let mut tar = true;
+
+        for part in spec.split(',') {
+            match part.split_once('=') {
+                Some(("type", value)) => output_type = Some(value),
+                Some(("dest", value)) => dest = Some(PathBuf::from(value)),
+                Some(("tar", value)) => {
+                    tar = value
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("Invalid --output value {:?}: tar must be true or false", spec))?
+                }
+                _ => return Err(anyhow::anyhow!("Invalid --output value {:?}: expected type=TYPE,dest=PATH", spec)),
+            }
+        }
+
+        let dest = dest.ok_or_else(|| anyhow::anyhow!("--output {:?} is missing dest=PATH", spec))?;
+        match output_type {
+            Some("oci") if !tar => Ok(BuildOutput::OciDir { dest }),
+            Some(_) if !tar => Err(anyhow::anyhow!("--output {:?}: only type=oci can be written untarred", spec)),
+            Some("local") => Ok(BuildOutput::Local { dest }),
+            Some("tar") => Ok(BuildOutput::Tar { dest }),
+            Some("oci") => Ok(BuildOutput::Oci { dest }),
+            Some("docker") => Ok(BuildOutput::Docker { dest }),
+            Some(other) => Err(anyhow::anyhow!("Unsupported output type: {}", other)),
+            None => Err(anyhow::anyhow!("--output {:?} is missing type=TYPE", spec)),
+        }
+    }
+
+    // Whether this output holds the image rather than the final stage's filesystem
+    pub fn exports_image(&self) -> bool {
+        matches!(self, BuildOutput::Oci { .. } | BuildOutput::OciDir { .. } | BuildOutput::Docker { .. })
+    }
+
+    // The output for one platform of a multi-platform build. Filesystems go
+    // into a directory per platform, as with buildx; an OCI archive is
+    // written once for the whole index, so there is none per platform.
+    pub fn for_platform(&self, platform: &Platform) -> Result<Option<Self>> {
+        match self {
+            BuildOutput::Local { dest } => Ok(Some(BuildOutput::Local {
+                dest: dest.join(platform.to_string().replace('/', "_")),
+            })),
+            BuildOutput::Oci { .. } | BuildOutput::OciDir { .. } => Ok(None),
+            BuildOutput::Tar { .. } => Err(anyhow::anyhow!(
+                "type=tar exports a single platform; use type=local for multi-platform builds"
+            )),
+            BuildOutput::Docker { .. } => Err(anyhow::anyhow!(
+                "type=docker archives hold a single platform; use type=oci for multi-platform builds"
+            )),
+        }
+    }
+
+    pub fn dest(&self) -> &Path {
+        match self {
+            BuildOutput::Local { dest }
+            | BuildOutput::Tar { dest }
+            | BuildOutput::Oci { dest }
+            | BuildOutput::OciDir { dest }
+            | BuildOutput::Docker { dest } => dest,
+        }
+    }
+}
+
+impl std::fmt::Display for BuildOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let output_type = match self {
+            BuildOutput::Local { .. } => "local",
+            BuildOutput::Tar { .. } => "tar",
+            BuildOutput::Oci { .. } | BuildOutput::OciDir { .. } => "oci",
+            BuildOutput::Docker { .. } => "docker",
+        };
+        write!(f, "type={},dest={}", output_type, self.dest().display())?;
+        if let BuildOutput::OciDir { .. } = self {
+            write!(f, ",tar=false")?;
+        }
+        Ok(())
+    }
+}
+
+// Write the final stage's filesystem to the requested output
+pub fn export_rootfs(output: &BuildOutput, rootfs: &Path) -> Result<()> {
+    match output {
+        BuildOutput::Local { dest } => {
+            fs::create_dir_all(dest)?;
+            for entry in fs::read_dir(rootfs)? {
+                let entry = entry?;
+                snapshot::copy_tree(&entry.path(), &dest.join(entry.file_name()))?;
+            }
+            tracing::info!("Exported the build result to {:?}", dest);
+        }
+        BuildOutput::Tar { dest } => {
+            let mut builder = create_archive(dest)?;
+            builder.follow_symlinks(false);
+            for entry in fs::read_dir(rootfs)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    builder.append_dir_all(entry.file_name(), entry.path())?;
+                } else {
+                    builder.append_path_with_name(entry.path(), entry.file_name())?;
+                }
+            }
+            builder.into_inner()?.flush()?;
+            tracing::info!("Exported the build result to {:?}", dest);
+        }
+        other => return Err(anyhow::anyhow!("Output {:?} does not export a filesystem", other)),
+    }
+    Ok(())
+}
+
+// Write a single-platform image as an OCI layout or docker archive
+pub fn export_image(output: &BuildOutput, image: &Image) -> Result<()> {
+    match output {
+        BuildOutput::OciDir { dest } => {
+            let mut layout = LayoutDir::create(dest)?;
+            write_oci_image(&mut layout, image)?;
+        }
+        BuildOutput::Oci { dest } => {
+            let mut builder = create_archive(dest)?;
+            write_oci_image(&mut builder, image)?;
+            builder.into_inner()?.flush()?;
+        }
+        BuildOutput::Docker { dest } => {
+            let mut builder = create_archive(dest)?;
+            write_docker_archive(&mut builder, image)?;
+            builder.into_inner()?.flush()?;
+        }
+        other => return Err(anyhow::anyhow!("Output {:?} does not export an image", other)),
+    }
+
+    tracing::info!("Exported {} to {:?}", image.name, output.dest());
+    Ok(())
+}
+
+// Write an image as a `docker save` archive, to a file or the Docker daemon
+pub fn write_docker_archive<W: Write>(builder: &mut tar::Builder<W>, image: &Image) -> Result<()> {
+    let mut written = BTreeSet::new();
+    let config_hex = sha256_hex(&image.raw_config);
+    append_file(builder, &format!("{}.json", config_hex), &image.raw_config)?;
+
+    let mut layer_paths = Vec::new();
+    // docker load wants the uncompressed tars, named by their diff_ids
+    for layer in &image.layers {
+        let path = format!("{}/layer.tar", hex(&layer.diff_id));
+        if written.insert(layer.diff_id.clone()) {
+            append_layer(builder, &path, layer)?;
+        }
+        layer_paths.push(path);
+    }
+
+    let repo_tag = repo_tag(&image.name);
+    let manifest = json!([{
+        "Config": format!("{}.json", config_hex),
+        "RepoTags": [repo_tag],
+        "Layers": layer_paths,
+    }]);
+    append_file(builder, "manifest.json", &serde_json::to_vec(&manifest)?)?;
+
+    // The pre-1.10 index of names, still read by some tools, pointing at the top layer
+    if let ((repository, Some(tag), None), Some(top)) = (split(&repo_tag), image.layers.last()) {
+        let repositories = json!({ repository: { tag: hex(&top.diff_id) } });
+        append_file(builder, "repositories", &serde_json::to_vec(&repositories)?)?;
+    }
+    Ok(())
+}
+
+fn write_oci_image<S: LayoutSink>(sink: &mut S, image: &Image) -> Result<()> {
+    let mut written = BTreeSet::new();
+    let descriptor = append_oci_image(sink, &mut written, image)?;
+    let descriptor = annotate_ref(descriptor, &image.name);
+    append_oci_index(sink, vec![descriptor])
+}
+
+// Write a multi-platform image as an OCI layout whose index lists every platform
+pub fn export_image_list(output: &BuildOutput, list: &ImageList) -> Result<()> {
+    match output {
+        BuildOutput::Oci { dest } => {
+            let mut builder = create_archive(dest)?;
+            write_oci_image_list(&mut builder, list)?;
+            builder.into_inner()?.flush()?;
+        }
+        BuildOutput::OciDir { dest } => write_oci_image_list(&mut LayoutDir::create(dest)?, list)?,
+        _ => return Err(anyhow::anyhow!("Multi-platform images can only be exported with type=oci")),
+    }
+    tracing::info!("Exported {} to {:?}", list.name, output.dest());
+    Ok(())
+}
+
+fn write_oci_image_list<S: LayoutSink>(sink: &mut S, list: &ImageList) -> Result<()> {
+    let mut written = BTreeSet::new();
+    let mut manifests = Vec::new();
+    for (image, listed) in list.images.iter().zip(list.index.manifests()) {
+        let mut descriptor = append_oci_image(sink, &mut written, image)?;
+        descriptor.set_platform(listed.platform().clone());
+        manifests.push(descriptor);
+    }
+
+    // The layout's index.json refers to a nested index, as buildx writes it
+    let index = ImageIndexBuilder::default()
+        .schema_version(2u32)
+        .media_type(MediaType::ImageIndex)
+        .manifests(manifests)
+        .build()?;
+    let index_json = serde_json::to_vec(&index)?;
+    append_blob(sink, &mut written, &index_json)?;
+    let descriptor = DescriptorBuilder::default()
+        .media_type(MediaType::ImageIndex)
+        .digest(format!("sha256:{}", sha256_hex(&index_json)).parse::<oci_spec::image::Digest>()?)
+        .size(index_json.len() as u64)
+        .build()?;
+    append_oci_index(sink, vec![annotate_ref(descriptor, &list.name)])
+}
+
+// Where layout files are written: entries of a tar archive or files in a directory
+trait LayoutSink {
+    fn add(&mut self, path: &str, size: u64, data: impl Read) -> Result<()>;
+}
+
+impl<W: Write> LayoutSink for tar::Builder<W> {
+    fn add(&mut self, path: &str, size: u64, data: impl Read) -> Result<()> {
+        let mut header = archive_header(size);
+        self.append_data(&mut header, path, data)?;
+        Ok(())
+    }
+}
+
+struct LayoutDir {
+    root: PathBuf,
+}
+
+impl LayoutDir {
+    fn create(root: &Path) -> Result<Self> {
+        fs::create_dir_all(root.join("blobs").join("sha256"))
+            .map_err(|e| anyhow::anyhow!("Failed to create {:?}: {}", root, e))?;
+        Ok(Self { root: root.to_path_buf() })
+    }
+}
+
+impl LayoutSink for LayoutDir {
+    fn add(&mut self, path: &str, _size: u64, mut data: impl Read) -> Result<()> {
+        let mut file = std::io::BufWriter::new(fs::File::create(self.root.join(path))?);
+        std::io::copy(&mut data, &mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+fn create_archive(dest: &Path) -> Result<tar::Builder<std::io::BufWriter<fs::File>>> {
+    if let Some(parent) = dest.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent)?;
+    }
+    let file = fs::File::create(dest).map_err(|e| anyhow::anyhow!("Failed to create {:?}: {}", dest, e))?;
+    Ok(tar::Builder::new(std::io::BufWriter::new(file)))
+}
+
+// Add an image's blobs to an OCI layout and return its manifest descriptor.
+// Layers are copied as the gzip blobs stored, so the layout holds the very
+// manifest the image was saved and pushed with.
+fn append_oci_image<S: LayoutSink>(sink: &mut S, written: &mut BTreeSet<String>, image: &Image) -> Result<Descriptor> {
+    append_blob(sink, written, &image.raw_config)?;
+    for layer in &image.layers {
+        if written.insert(layer.digest.clone()) {
+            sink.add(&format!("blobs/sha256/{}", hex(&layer.digest)), layer.size, layer.open()?)?;
+        }
+    }
+
+    let manifest_json = serde_json::to_vec(&image.manifest)?;
+    append_blob(sink, written, &manifest_json)?;
+
+    Ok(DescriptorBuilder::default()
+        .media_type(MediaType::ImageManifest)
+        .digest(format!("sha256:{}", sha256_hex(&manifest_json)).parse::<oci_spec::image::Digest>()?)
+        .size(manifest_json.len() as u64)
+        .build()?)
+}
+
+// Name the image the way containerd and podman look it up when loading the layout
+fn annotate_ref(mut descriptor: Descriptor, name: &str) -> Descriptor {
+    let reference = repo_tag(name);
+    let tag = split(&reference).1.unwrap_or("latest");
+    descriptor.set_annotations(Some(HashMap::from([
+        ("io.containerd.image.name".to_string(), reference.clone()),
+        ("org.opencontainers.image.ref.name".to_string(), tag.to_string()),
+    ])));
+    descriptor
+}
+
+fn append_oci_index<S: LayoutSink>(sink: &mut S, manifests: Vec<Descriptor>) -> Result<()> {
+    let index = ImageIndexBuilder::default()
+        .schema_version(2u32)
+        .media_type(MediaType::ImageIndex)
+        .manifests(manifests)
+        .build()?;
+    append_file(sink, "oci-layout", br#"{"imageLayoutVersion":"1.0.0"}"#)?;
+    // Going through Value sorts the annotations, which are a HashMap
+    append_file(sink, "index.json", &serde_json::to_vec(&serde_json::to_value(&index)?)?)
+}
+
+fn append_blob<S: LayoutSink>(sink: &mut S, written: &mut BTreeSet<String>, data: &[u8]) -> Result<()> {
+    let hex = sha256_hex(data);
+    if written.insert(format!("sha256:{}", hex)) {
+        append_file(sink, &format!("blobs/sha256/{}", hex), data)?;
+    }
+    Ok(())
+}
+
+// Stream a stored layer into the archive uncompressed
+fn append_layer<S: LayoutSink>(sink: &mut S, path: &str, layer: &Layer) -> Result<()> {
+    sink.add(path, layer.diff_size, layer.open_tar()?)
+}
+
+fn append_file<S: LayoutSink>(sink: &mut S, path: &str, data: &[u8]) -> Result<()> {
+    sink.add(path, data.len() as u64, data)
+}
+
+// Archive metadata is fixed so the same image always exports to the same bytes
+fn archive_header(size: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header
+}
+
+// The reference an image is loaded as, with the implicit :latest tag made explicit
+fn repo_tag(name: &str) -> String {
+    match split(name) {
+        (_, None, None) => format!("{}:latest", name),
+        _ => name.to_string(),
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn hex(digest: &str) -> &str {
+    digest.strip_prefix("sha256:").unwrap_or(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_output() {
+        assert_eq!(
+            BuildOutput::parse("type=local,dest=./out").unwrap(),
+            BuildOutput::Local {
+                dest: PathBuf::from("./out")
+            }
+        );
+        assert!(BuildOutput::parse("type=tar").is_err());
+        assert!(BuildOutput::parse("type=s3,dest=bucket").is_err());
+
+        let local = BuildOutput::parse("dest=out,type=local").unwrap();
+        let platform = Platform::parse("linux/arm/v7").unwrap();
+        assert_eq!(
+            local.for_platform(&platform).unwrap(),
+            Some(BuildOutput::Local {
+                dest: PathBuf::from("out/linux_arm_v7")
+            })
+        );
+
+        let dir = BuildOutput::parse("type=oci,dest=layout,tar=false").unwrap();
+        assert_eq!(dir.to_string(), "type=oci,dest=layout,tar=false");
+        assert!(BuildOutput::parse("type=local,dest=out,tar=false").is_err());
+
+        let docker = BuildOutput::parse("type=docker,dest=image.tar").unwrap();
+        assert!(docker.exports_image());
+        assert!(docker.for_platform(&platform).is_err());
+    }
+
+    #[test]
+    fn test_repo_tag() {
+        assert_eq!(repo_tag("app"), "app:latest");
+        assert_eq!(repo_tag("localhost:5000/app"), "localhost:5000/app:latest");
+        assert_eq!(repo_tag("localhost:5000/app:v1"), "localhost:5000/app:v1");
+    }
+
+    #[tokio::test]
+    async fn test_export_names_image_by_its_tag() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = crate::storage::StorageManager::new(root.path().join("store")).unwrap();
+        storage.init().await.unwrap();
+        let config = oci_spec::image::ImageConfiguration::default();
+        let raw_config = serde_json::to_vec(&config).unwrap();
+        let config_digest = format!("sha256:{}", sha256_hex(&raw_config));
+        let image = Image {
+            id: "image_1".to_string(),
+            name: "demo:1".to_string(),
+            manifest: crate::engine::image_manifest(&config_digest, raw_config.len() as u64, &[]).unwrap(),
+            layers: Vec::new(),
+            config,
+            raw_config,
+        };
+        storage.save_image(&image).await.unwrap();
+        storage.tag("demo:1", "demo:2").await.unwrap();
+        storage.untag("demo:1").await.unwrap();
+
+        // Saved by its second tag after the first is gone
+        let image = storage.get_image_by_name("demo:2").await.unwrap().unwrap();
+        let layout = root.path().join("layout");
+        export_image(&BuildOutput::OciDir { dest: layout.clone() }, &image).unwrap();
+        let index: serde_json::Value = serde_json::from_slice(&fs::read(layout.join("index.json")).unwrap()).unwrap();
+        assert_eq!(index["manifests"][0]["annotations"]["io.containerd.image.name"], "demo:2");
+
+        let archive = root.path().join("image.tar");
+        export_image(&BuildOutput::Docker { dest: archive.clone() }, &image).unwrap();
+        let mut tar = tar::Archive::new(fs::File::open(&archive).unwrap());
+        let mut entry = tar
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .find(|entry| entry.path().unwrap().to_str() == Some("manifest.json"))
+            .unwrap();
+        let mut manifest = String::new();
+        entry.read_to_string(&mut manifest).unwrap();
+        let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+        assert_eq!(manifest[0]["RepoTags"], json!(["demo:2"]));
+    }
+}
@@ -0,0 +1,135 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::os::fd::AsFd;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookEvent {
+    BuildStart,
+    PreStep,
+    PostStep,
+    BuildEnd,
+}
+
+impl std::fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            HookEvent::BuildStart => "build-start",
+            HookEvent::PreStep => "pre-step",
+            HookEvent::PostStep => "post-step",
+            HookEvent::BuildEnd => "build-end",
+        };
+        f.write_str(name)
+    }
+}
+
+// A program run at some point of every build, e.g.
+//
+//   [[hooks]]
+//   on = "post-step"
+//   command = ["/usr/local/bin/audit-step"]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    pub on: HookEvent,
+    pub command: Vec<String>,
+    // A required hook that fails stops the build; others only log a warning
+    #[serde(default)]
+    pub required: bool,
+}
+
+// The configured hooks, run with a JSON description of the event on stdin
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    hooks: Vec<Hook>,
+    build_id: Option<String>,
+}
+
+impl Hooks {
+    pub fn new(hooks: Vec<Hook>, build_id: Option<String>) -> Self {
+        Self { hooks, build_id }
+    }
+
+    // Run every hook for `event` in order; `context` fields are added to the payload
+    pub async fn run(&self, event: HookEvent, context: Value) -> Result<()> {
+        let hooks: Vec<&Hook> = self.hooks.iter().filter(|hook| hook.on == event).collect();
+        if hooks.is_empty() {
+            return Ok(());
+        }
+
+        let mut payload = json!({ "event": event, "build_id": self.build_id });
+        if let (Some(payload), Value::Object(context)) = (payload.as_object_mut(), context) {
+            payload.extend(context);
+        }
+        let payload = serde_json::to_vec(&payload)?;
+
+        for hook in hooks {
+            if let Err(e) = run_hook(hook, &payload).await {
+                if hook.required {
+                    return Err(anyhow::anyhow!("{} hook {:?} failed: {}", event, hook.command, e));
+                }
+                tracing::warn!("{} hook {:?} failed: {}", event, hook.command, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn run_hook(hook: &Hook, payload: &[u8]) -> Result<()> {
+    let (program, args) = hook
+        .command
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("hook has an empty command"))?;
+
+    // Hook output goes to stderr, keeping stdout for progress output
+    let stderr = std::io::stderr().as_fd().try_clone_to_owned()?;
+    let mut child = tokio::process::Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::from(stderr))
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("failed to start {}: {}", program, e))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // A hook that does not read its input is not an error
+        let _ = stdin.write_all(payload).await;
+    }
+    let status = child.wait().await?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("exited with {}", status));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_required_hook_failure() {
+        let hooks = Hooks::new(
+            vec![
+                Hook {
+                    on: HookEvent::PreStep,
+                    command: vec!["sh".to_string(), "-c".to_string(), "grep -q '\"step\":\"RUN make\"'".to_string()],
+                    required: true,
+                },
+                Hook {
+                    on: HookEvent::BuildEnd,
+                    command: vec!["false".to_string()],
+                    required: false,
+                },
+            ],
+            Some("build_1".to_string()),
+        );
+
+        hooks.run(HookEvent::PreStep, json!({ "step": "RUN make" })).await.unwrap();
+        assert!(hooks.run(HookEvent::PreStep, json!({ "step": "RUN test" })).await.is_err());
+        // Optional hooks only warn
+        hooks.run(HookEvent::BuildEnd, json!({})).await.unwrap();
+    }
+}
@@ -0,0 +1,349 @@
+use crate::storage::{CompressionAlgorithm, Image, ImageList, Layer, StorageManager};
+use anyhow::Result;
+use oci_spec::image::{Descriptor, ImageConfiguration, ImageIndex, ImageIndexBuilder, ImageManifest, MediaType};
+use serde::Deserialize;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const NAME_ANNOTATION: &str = "io.containerd.image.name";
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+// Import the images of an OCI image layout or `docker save` archive, given
+// as a directory or a (possibly gzipped) tar of one, into local storage.
+// `name` replaces the names recorded in it. Returns the names loaded.
+pub async fn load(storage: &StorageManager, path: &Path, name: Option<&str>) -> Result<Vec<String>> {
+    if path.is_dir() {
+        return load_layout(storage, path, name).await;
+    }
+
+    // Archives are unpacked into the store's scratch space first
+    let unpacked = tempfile::tempdir_in(storage.tmp_dir())?;
+    let (archive, target) = (path.to_path_buf(), unpacked.path().to_path_buf());
+    tokio::task::spawn_blocking(move || unpack(&archive, &target)).await??;
+    load_layout(storage, unpacked.path(), name).await
+}
+
+fn unpack(archive: &Path, target: &Path) -> Result<()> {
+    let open = || std::fs::File::open(archive).map_err(|e| anyhow::anyhow!("Failed to open {:?}: {}", archive, e));
+    let mut magic = [0u8; 2];
+    let gzipped = open()?.read_exact(&mut magic).is_ok() && magic == [0x1f, 0x8b];
+    let reader: Box<dyn Read> = if gzipped {
+        Box::new(flate2::read::MultiGzDecoder::new(std::io::BufReader::new(open()?)))
+    } else {
+        Box::new(std::io::BufReader::new(open()?))
+    };
+    tar::Archive::new(reader)
+        .unpack(target)
+        .map_err(|e| anyhow::anyhow!("Failed to unpack {:?}: {}", archive, e))?;
+    Ok(())
+}
+
+async fn load_layout(storage: &StorageManager, dir: &Path, name: Option<&str>) -> Result<Vec<String>> {
+    // Docker 25 and later write both; the OCI index is the more complete of the two
+    if !dir.join("oci-layout").exists() {
+        if dir.join("manifest.json").exists() {
+            return load_docker_archive(storage, dir, name).await;
+        }
+        return Err(anyhow::anyhow!(
+            "{:?} is neither an OCI image layout nor a docker archive: it has no oci-layout or manifest.json",
+            dir
+        ));
+    }
+    let index: ImageIndex = serde_json::from_slice(&tokio::fs::read(dir.join("index.json")).await?)
+        .map_err(|e| anyhow::anyhow!("Invalid index.json in {:?}: {}", dir, e))?;
+    if name.is_some() && index.manifests().len() > 1 {
+        return Err(anyhow::anyhow!(
+            "{:?} holds {} images; a name can only be given to a single image",
+            dir,
+            index.manifests().len()
+        ));
+    }
+
+    let mut loaded = Vec::new();
+    for descriptor in index.manifests() {
+        let image_name = match name {
+            Some(name) => name.to_string(),
+            None => descriptor_name(descriptor).ok_or_else(|| {
+                anyhow::anyhow!("Image {} in {:?} has no name; give it one with --tag", descriptor.digest(), dir)
+            })?,
+        };
+
+        match descriptor.media_type() {
+            MediaType::ImageIndex => {
+                load_image_list(storage, dir, descriptor, &image_name).await?;
+            }
+            MediaType::ImageManifest => {
+                load_image(storage, dir, descriptor, &image_name).await?;
+            }
+            other => {
+                tracing::warn!("Skipping {} in {:?}: unsupported media type {}", descriptor.digest(), dir, other);
+                continue;
+            }
+        }
+        loaded.push(image_name);
+    }
+    Ok(loaded)
+}
+
+// The full reference containerd records, else the OCI ref name
+fn descriptor_name(descriptor: &Descriptor) -> Option<String> {
+    let annotations = descriptor.annotations().as_ref()?;
+    annotations
+        .get(NAME_ANNOTATION)
+        .or_else(|| annotations.get(REF_NAME_ANNOTATION))
+        .cloned()
+}
+
+async fn load_image_list(storage: &StorageManager, dir: &Path, descriptor: &Descriptor, name: &str) -> Result<ImageList> {
+    let nested: ImageIndex = serde_json::from_slice(&read_blob(dir, descriptor).await?)?;
+
+    let mut images = Vec::new();
+    let mut manifests = Vec::new();
+    for entry in nested.manifests() {
+        // Attestation manifests are listed with an unknown platform
+        let Some(platform) = entry.platform().clone().filter(|platform| platform.os().to_string() != "unknown") else {
+            continue;
+        };
+        let image = load_image(storage, dir, entry, name).await?;
+        manifests.push(super::manifest_descriptor(&image.manifest, platform)?);
+        images.push(image);
+    }
+
+    let index = ImageIndexBuilder::default()
+        .schema_version(2u32)
+        .media_type(MediaType::ImageIndex)
+        .manifests(manifests)
+        .build()?;
+    let list = ImageList {
+        id: format!("index_{}", uuid::Uuid::new_v4()),
+        name: name.to_string(),
+        index,
+        images,
+    };
+    storage.save_image_list(&list).await?;
+    Ok(list)
+}
+
+async fn load_image(storage: &StorageManager, dir: &Path, descriptor: &Descriptor, name: &str) -> Result<Image> {
+    let manifest: ImageManifest = serde_json::from_slice(&read_blob(dir, descriptor).await?)?;
+    let raw_config = read_blob(dir, manifest.config()).await?;
+    let config: ImageConfiguration = serde_json::from_slice(&raw_config)?;
+
+    let mut layers = Vec::new();
+    for descriptor in manifest.layers() {
+        layers.push(load_layer(storage, dir, descriptor).await?);
+    }
+
+    let config_digest = manifest.config().digest().to_string();
+    let image = Image {
+        id: format!("image_{}", uuid::Uuid::new_v4()),
+        name: name.to_string(),
+        manifest: super::image_manifest(&config_digest, raw_config.len() as u64, &layers)?,
+        layers,
+        config,
+        raw_config,
+    };
+    storage.save_image(&image).await?;
+    Ok(image)
+}
+
+// An entry of a `docker save` manifest.json
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ArchiveManifest {
+    config: String,
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+async fn load_docker_archive(storage: &StorageManager, dir: &Path, name: Option<&str>) -> Result<Vec<String>> {
+    let entries: Vec<ArchiveManifest> = serde_json::from_slice(&tokio::fs::read(dir.join("manifest.json")).await?)
+        .map_err(|e| anyhow::anyhow!("Invalid manifest.json in {:?}: {}", dir, e))?;
+    if name.is_some() && entries.len() > 1 {
+        return Err(anyhow::anyhow!(
+            "{:?} holds {} images; a name can only be given to a single image",
+            dir,
+            entries.len()
+        ));
+    }
+
+    let mut loaded = Vec::new();
+    for entry in entries {
+        let names = match name {
+            Some(name) => vec![name.to_string()],
+            None => entry.repo_tags.clone().unwrap_or_default(),
+        };
+        let Some(first) = names.first() else {
+            return Err(anyhow::anyhow!("Image {} in {:?} has no name; give it one with --tag", entry.config, dir));
+        };
+
+        let raw_config = tokio::fs::read(archive_path(dir, &entry.config)?)
+            .await
+            .map_err(|e| anyhow::anyhow!("Config {} is missing from the archive: {}", entry.config, e))?;
+        let config: ImageConfiguration = serde_json::from_slice(&raw_config)?;
+        let diff_ids = config.rootfs().diff_ids();
+        if diff_ids.len() != entry.layers.len() {
+            return Err(anyhow::anyhow!(
+                "Image {} lists {} layers but its config has {} diff_ids",
+                first,
+                entry.layers.len(),
+                diff_ids.len()
+            ));
+        }
+
+        // Archive layers are checked against the uncompressed digests in the config
+        let mut layers = Vec::new();
+        for (path, diff_id) in entry.layers.iter().zip(diff_ids) {
+            let (layer, _) = store_layer(storage, &archive_path(dir, path)?).await?;
+            if &layer.diff_id != diff_id {
+                return Err(anyhow::anyhow!("Layer {} does not match diff_id {} (got {})", path, diff_id, layer.diff_id));
+            }
+            layers.push(layer);
+        }
+
+        let config_digest = format!("sha256:{:x}", <sha2::Sha256 as sha2::Digest>::digest(&raw_config));
+        let image = Image {
+            id: format!("image_{}", uuid::Uuid::new_v4()),
+            name: first.clone(),
+            manifest: super::image_manifest(&config_digest, raw_config.len() as u64, &layers)?,
+            layers,
+            config,
+            raw_config,
+        };
+        storage.save_image(&image).await?;
+        for extra in &names[1..] {
+            storage.tag(&image.id, extra).await?;
+        }
+        loaded.extend(names);
+    }
+    Ok(loaded)
+}
+
+// A path named in manifest.json, which must stay inside the archive
+fn archive_path(dir: &Path, path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path);
+    if relative.is_absolute() || relative.components().any(|c| matches!(c, std::path::Component::ParentDir)) {
+        return Err(anyhow::anyhow!("Invalid path {:?} in manifest.json", path));
+    }
+    Ok(dir.join(relative))
+}
+
+// Layers may be stored uncompressed, as hyperbuild, buildx and docker write them, or gzip or zstd compressed
+async fn load_layer(storage: &StorageManager, dir: &Path, descriptor: &Descriptor) -> Result<Layer> {
+    let digest = descriptor.digest().to_string();
+    let path = blob_path(dir, descriptor)?;
+    if !path.exists() {
+        return Err(anyhow::anyhow!("Layer {} is missing from the layout", digest));
+    }
+    let (layer, blob_digest) = store_layer(storage, &path).await?;
+    if blob_digest != digest {
+        return Err(anyhow::anyhow!("Layer {} does not match its digest (got {})", digest, blob_digest));
+    }
+    Ok(layer)
+}
+
+// Store a layer file; also returns the digest of the file as it is
+async fn store_layer(storage: &StorageManager, path: &Path) -> Result<(Layer, String)> {
+    let mut magic = [0u8; 4];
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to open layer {:?}: {}", path, e))?;
+    let read = tokio::io::AsyncReadExt::read(&mut file, &mut magic).await?;
+
+    match &magic[..read] {
+        head if CompressionAlgorithm::detect(head).is_some() => {
+            let layer = storage.import_layer(tokio::fs::read(path).await?).await?;
+            let blob_digest = layer.digest.clone();
+            Ok((layer, blob_digest))
+        }
+        _ => {
+            let layer = storage.create_layer_from_reader(tokio::fs::File::open(path).await?).await?;
+            let diff_id = layer.diff_id.clone();
+            Ok((layer, diff_id))
+        }
+    }
+}
+
+async fn read_blob(dir: &Path, descriptor: &Descriptor) -> Result<Vec<u8>> {
+    use sha2::{Digest, Sha256};
+
+    let digest = descriptor.digest().to_string();
+    let data = tokio::fs::read(blob_path(dir, descriptor)?)
+        .await
+        .map_err(|e| anyhow::anyhow!("Blob {} is missing from the layout: {}", digest, e))?;
+    if format!("sha256:{:x}", Sha256::digest(&data)) != digest {
+        return Err(anyhow::anyhow!("Blob {} does not match its digest", digest));
+    }
+    Ok(data)
+}
+
+fn blob_path(dir: &Path, descriptor: &Descriptor) -> Result<PathBuf> {
+    let digest = descriptor.digest().to_string();
+    let hex = digest
+        .strip_prefix("sha256:")
+        .filter(|hex| hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+        .ok_or_else(|| anyhow::anyhow!("Unsupported blob digest {}", digest))?;
+    Ok(dir.join("blobs").join("sha256").join(hex))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::export::{self, BuildOutput};
+
+    #[tokio::test]
+    async fn test_layout_roundtrip() {
+        let root = tempfile::tempdir().unwrap();
+        let source = StorageManager::new(root.path().join("source")).unwrap();
+        source.init().await.unwrap();
+
+        let layer = source.create_layer(&layer_tar()).await.unwrap();
+        let mut config = ImageConfiguration::default();
+        let mut rootfs = config.rootfs().clone();
+        rootfs.set_diff_ids(vec![layer.diff_id.clone()]);
+        config.set_rootfs(rootfs);
+        let raw_config = serde_json::to_vec(&config).unwrap();
+        let config_digest = format!("sha256:{:x}", <sha2::Sha256 as sha2::Digest>::digest(&raw_config));
+        let layers = vec![layer];
+        let image = Image {
+            id: "image_1".to_string(),
+            name: "app:v1".to_string(),
+            manifest: crate::engine::image_manifest(&config_digest, raw_config.len() as u64, &layers).unwrap(),
+            layers,
+            config,
+            raw_config,
+        };
+
+        let archive = root.path().join("image.tar");
+        export::export_image(&BuildOutput::Docker { dest: archive.clone() }, &image).unwrap();
+        let layout = root.path().join("layout");
+        export::export_image(&BuildOutput::OciDir { dest: layout.clone() }, &image).unwrap();
+
+        let target = StorageManager::new(root.path().join("target")).unwrap();
+        target.init().await.unwrap();
+        assert_eq!(load(&target, &layout, None).await.unwrap(), vec!["app:v1"]);
+
+        let loaded = target.get_image_by_name("app:v1").await.unwrap().unwrap();
+        assert_eq!(loaded.raw_config, image.raw_config);
+        assert_eq!(loaded.layers[0].diff_id, image.layers[0].diff_id);
+
+        assert_eq!(load(&target, &archive, Some("app:docker")).await.unwrap(), vec!["app:docker"]);
+        let loaded = target.get_image_by_name("app:docker").await.unwrap().unwrap();
+        assert_eq!(loaded.layers[0].diff_id, image.layers[0].diff_id);
+
+        // A blob that does not match its digest is rejected
+        let blob = layout.join("blobs/sha256").join(image.layers[0].digest.trim_start_matches("sha256:"));
+        std::fs::write(&blob, b"tampered").unwrap();
+        assert!(load(&target, &layout, Some("app:v2")).await.is_err());
+    }
+
+    fn layer_tar() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "hello.txt", &b"hello"[..]).unwrap();
+        builder.into_inner().unwrap()
+    }
+}
@@ -0,0 +1,1361 @@
+use crate::dockerfile::{self, BuildStage, DockerfileParser, Instruction, ParsedDockerfile};
+use crate::dockerfile::{LineError, NetworkMode, RunOptions};
+use crate::metrics::Metrics;
+use crate::platform::Platform;
+use crate::progress::{LogSink, Progress, StepProgress};
+use crate::reference::Reference;
+use crate::registry_client::{RegistryClient, RegistrySettings};
+use crate::storage::{CompressionAlgorithm, Image, ImageList, Layer, StorageManager};
+use anyhow::Result;
+use oci_spec::image::{
+    DescriptorBuilder, History, HistoryBuilder, ImageIndexBuilder, ImageManifest, ImageManifestBuilder, MediaType,
+};
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+use tokio_util::sync::CancellationToken;
+
+pub mod cache;
+pub mod cgroup;
+pub mod config;
+pub mod container;
+pub mod dag;
+pub mod emulation;
+pub mod ephemeral;
+pub mod executor;
+pub mod export;
+pub mod hooks;
+pub mod import;
+pub mod plugins;
+pub mod sbom;
+pub mod secrets;
+pub mod snapshot;
+pub mod squash;
+
+use cache::{CacheIndex, CachedStep, InlineCacheRecord};
+use config::StageConfig;
+use dag::StageGraph;
+use executor::{ChrootExecutor, Executor, StepLimits, StepSpec};
+use ephemeral::EphemeralFiles;
+use export::BuildOutput;
+use hooks::{HookEvent, Hooks};
+use plugins::{PluginExecutor, Plugins};
+use sbom::SbomFormat;
+use snapshot::Snapshot;
+
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    // Embed cache metadata in the image config (`--cache-to type=inline`)
+    pub inline_cache: bool,
+    // Images whose inline cache metadata may satisfy build steps
+    pub cache_from: Vec<String>,
+    // Run every step, ignoring the store's build cache and `cache_from`
+    pub no_cache: bool,
+    // The build's id, recorded on the build cache records it creates
+    pub build_id: Option<String>,
+    // Maximum number of stages built concurrently (0 uses the available CPUs)
+    pub parallelism: usize,
+    // Values for ARG instructions, overriding their defaults
+    pub build_args: HashMap<String, String>,
+    // Secret files by id, exposed to `RUN --mount=type=secret` steps only
+    pub secrets: HashMap<String, PathBuf>,
+    // Platform recorded in the image config (defaults to the host)
+    pub platform: Option<Platform>,
+    // Reproducible mode: timestamps in layers, history and config are clamped to this
+    pub source_date_epoch: Option<u64>,
+    // Network for RUN steps that do not pass their own --network
+    pub network: NetworkMode,
+    // Hostname to address entries added to /etc/hosts during RUN steps (`--add-host`)
+    pub extra_hosts: Vec<(String, String)>,
+    // Memory, CPU and time budget applied to every RUN step
+    pub step_limits: StepLimits,
+    // How often a failing RUN step is run again, unless it passes its own --retry
+    pub retries: u32,
+    // Merge the layers this build created into one layer
+    pub squash: bool,
+    // With `squash`, only merge the layers created from this stage onward
+    pub squash_from: Option<String>,
+    // Also write the final stage's filesystem here (`--output`)
+    pub output: Option<BuildOutput>,
+    // Where step progress and RUN output are reported
+    pub progress: Progress,
+    // Counts builds, cache hits, step durations and registry traffic
+    pub metrics: Metrics,
+    // Programs run when the build starts and ends and around each step
+    pub hooks: Hooks,
+    // Programs handling custom instructions, and the one RUN steps may be run with instead of chroot
+    pub plugins: Plugins,
+    // Generate an SBOM of the final stage's filesystem and store it with the image
+    pub sbom: Option<SbomFormat>,
+    // When FROM images are fetched from their registry (`--pull`)
+    pub pull: PullPolicy,
+    // More names for the built image, besides the one it is built as (`-t`)
+    pub tags: Vec<String>,
+    // Set on the image on top of the Dockerfile's LABELs (`--label`)
+    pub labels: BTreeMap<String, String>,
+    // How base images are pulled: retries and the like, from the config file
+    pub registry: RegistrySettings,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PullPolicy {
+    // Pull every base image on each build, so moving tags are re-resolved
+    Always,
+    // Pull only the base images local storage does not have
+    #[default]
+    Missing,
+    // Only use local storage; a missing base image fails the build
+    Never,
+}
+
+impl PullPolicy {
+    pub fn parse(policy: &str) -> Result<Self> {
+        match policy {
+            "always" => Ok(Self::Always),
+            "missing" => Ok(Self::Missing),
+            "never" => Ok(Self::Never),
+            other => Err(anyhow::anyhow!("Unknown pull policy {:?}, expected always, missing or never", other)),
+        }
+    }
+}
+
+pub struct BuildEngine {
+    storage: StorageManager,
+    context_dir: PathBuf,
+    options: BuildOptions,
+    cancel: CancellationToken,
+}
+
+// Shared state handed to each concurrently running stage build
+struct StageContext {
+    storage: StorageManager,
+    context_dir: PathBuf,
+    cache_index: CacheIndex,
+    build_id: Option<String>,
+    stages: Vec<BuildStage>,
+    build_args: HashMap<String, String>,
+    global_args: HashMap<String, String>,
+    secrets: HashMap<String, PathBuf>,
+    executor: Arc<dyn Executor>,
+    // Base images pulled for this build, which take precedence over older local copies
+    pulled: HashMap<String, Image>,
+    network: NetworkMode,
+    extra_hosts: Vec<(String, String)>,
+    step_limits: StepLimits,
+    retries: u32,
+    // Cancelled on Ctrl+C or when another stage fails
+    cancel: CancellationToken,
+    progress: Progress,
+    metrics: Metrics,
+    hooks: Hooks,
+    plugins: Plugins,
+    // Target platform, exposed to the build as TARGETPLATFORM and friends
+    platform: Platform,
+    source_date_epoch: Option<u64>,
+    // Scratch space holding one rootfs directory per stage
+    build_dir: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+struct StageResult {
+    layers: Vec<Layer>,
+    cache_records: Vec<InlineCacheRecord>,
+    cache_key: String,
+    history: Vec<History>,
+    // Inherited by stages built on top of this one
+    config: StageConfig,
+    // Stages in this stage's FROM chain, with the layer count when each one started
+    stage_starts: Vec<(usize, usize)>,
+    rootfs: PathBuf,
+}
+
+impl BuildEngine {
+    pub fn new(storage: StorageManager, context_dir: PathBuf) -> Self {
+        Self::with_options(storage, context_dir, BuildOptions::default())
+    }
+
+    pub fn with_options(storage: StorageManager, context_dir: PathBuf, options: BuildOptions) -> Self {
+        Self {
+            storage,
+            context_dir,
+            options,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    // Build under a token the caller already holds, e.g. one shared by several builds
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    // Cancelling this token stops the build at the next step, killing a
+    // running RUN command, and removes everything the build left behind
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    #[tracing::instrument(
+        name = "build",
+        skip_all,
+        fields(build_id = self.options.build_id.as_deref(), image = image_name)
+    )]
+    pub async fn build_image(&mut self, dockerfile_path: &PathBuf, image_name: &str) -> Result<Image> {
+        self.options.metrics.build_started();
+        self.options
+            .hooks
+            .run(HookEvent::BuildStart, json!({ "image": image_name }))
+            .await
+            .inspect_err(|_| self.options.metrics.build_failed())?;
+        let built = async {
+            let image = self.build_platform_image(dockerfile_path, image_name).await?;
+            for tag in &self.options.tags {
+                self.storage.tag(&image.id, tag).await?;
+            }
+            let manifest_digest = sha256_digest(&serde_json::to_vec(&image.manifest)?)?;
+            Ok((image, manifest_digest.to_string()))
+        }
+        .await;
+        self.finish_build(image_name, built).await
+    }
+
+    // Report the end of a build to progress and hooks
+    async fn finish_build<T>(&self, image_name: &str, built: Result<(T, String)>) -> Result<T> {
+        let context = match &built {
+            Ok((_, digest)) => json!({ "image": image_name, "status": "succeeded", "digest": digest }),
+            Err(e) => json!({ "image": image_name, "status": "failed", "error": e.to_string() }),
+        };
+        let hooked = self.options.hooks.run(HookEvent::BuildEnd, context).await;
+        if built.is_err() || hooked.is_err() {
+            self.options.metrics.build_failed();
+        }
+        let (built, digest) = built?;
+        hooked?;
+        self.options.progress.build_finished(image_name, digest);
+        Ok(built)
+    }
+
+    async fn build_platform_image(&mut self, dockerfile_path: &PathBuf, image_name: &str) -> Result<Image> {
+        // Parse the Dockerfile
+        let mut parsed_dockerfile = DockerfileParser::parse_from_path(dockerfile_path).await?;
+        if parsed_dockerfile.stages.is_empty() {
+            return Err(anyhow::anyhow!("Dockerfile does not contain any FROM instruction"));
+        }
+
+        let platform = self.options.platform.clone().unwrap_or_else(Platform::host);
+        let build_args = self.effective_build_args(&platform);
+
+        // Fail early if RUN steps for a foreign platform cannot be emulated; an
+        // executor plugin decides for itself where they run
+        let plugins = &self.options.plugins;
+        let has_run_steps = parsed_dockerfile.stages.iter().any(|stage| {
+            stage.instructions.iter().any(|i| match i {
+                Instruction::Run { .. } => true,
+                Instruction::Custom { keyword, .. } => plugins.for_instruction(keyword).is_none(),
+                _ => false,
+            })
+        });
+        if has_run_steps && plugins.executor().is_none() {
+            emulation::ensure_available(&platform)?;
+        }
+
+        // Resolve global ARGs and expand them in FROM lines
+        let global_args: HashMap<String, String> = parsed_dockerfile
+            .args
+            .iter()
+            .filter_map(|(key, default)| {
+                let value = build_args.get(key).or(default.as_ref())?;
+                Some((key.clone(), value.clone()))
+            })
+            .collect();
+        for stage in &mut parsed_dockerfile.stages {
+            stage.base_image = dockerfile::expand_vars(&stage.base_image, &global_args);
+        }
+        self.warn_unused_build_args(&parsed_dockerfile);
+        let pulled = self.pull_base_images(&parsed_dockerfile.stages, &platform).await?;
+
+        // Load cache records from any --cache-from images, then the store's own
+        let cache_index = if self.options.no_cache {
+            CacheIndex::default()
+        } else {
+            let mut cache_index = CacheIndex::load_inline(&self.storage, &self.options.cache_from).await?;
+            if !cache_index.is_empty() {
+                tracing::info!("Loaded {} inline cache records", cache_index.len());
+            }
+            cache_index.load_local(&self.storage).await?;
+            cache_index
+        };
+
+        // The last stage is the image we produce; only the stages it depends on are built
+        let graph = StageGraph::new(&parsed_dockerfile.stages);
+        let target = parsed_dockerfile.stages.len() - 1;
+        let squash_from = match &self.options.squash_from {
+            Some(name) => Some(
+                dag::resolve_stage_ref(&parsed_dockerfile.stages, name, parsed_dockerfile.stages.len())
+                    .ok_or_else(|| anyhow::anyhow!("--squash-from: no stage named {}", name))?,
+            ),
+            None => None,
+        };
+        let build_dir = self.storage.tmp_dir().join(format!("build_{}", uuid::Uuid::new_v4()));
+        let results = self
+            .build_stages(
+                parsed_dockerfile.stages,
+                &graph,
+                target,
+                StageContext {
+                    storage: self.storage.clone_for_build(),
+                    context_dir: self.context_dir.clone(),
+                    cache_index,
+                    build_id: self.options.build_id.clone(),
+                    stages: Vec::new(),
+                    build_args,
+                    global_args,
+                    secrets: self.options.secrets.clone(),
+                    executor: match self.options.plugins.executor() {
+                        Some(plugin) => Arc::new(PluginExecutor::new(plugin.clone())),
+                        None => Arc::new(ChrootExecutor),
+                    },
+                    pulled,
+                    network: self.options.network,
+                    extra_hosts: self.options.extra_hosts.clone(),
+                    step_limits: self.options.step_limits,
+                    retries: self.options.retries,
+                    cancel: self.cancel.child_token(),
+                    progress: self.options.progress.clone(),
+                    metrics: self.options.metrics.clone(),
+                    hooks: self.options.hooks.clone(),
+                    plugins: self.options.plugins.clone(),
+                    platform: platform.clone(),
+                    source_date_epoch: self.options.source_date_epoch,
+                    build_dir: build_dir.clone(),
+                },
+            )
+            .await;
+
+        // The final stage's filesystem is exported before its rootfs goes away
+        let exported = match (&results, &self.options.output) {
+            (Ok(results), Some(output)) if !output.exports_image() && !self.cancel.is_cancelled() => {
+                let step = self.options.progress.start(format!("exporting to {}", output));
+                let output = output.clone();
+                let rootfs = results[&target].rootfs.clone();
+                let exported = tokio::task::spawn_blocking(move || export::export_rootfs(&output, &rootfs))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|exported| exported);
+                match &exported {
+                    Ok(()) => step.done(),
+                    Err(e) => step.failed(e),
+                }
+                exported
+            }
+            _ => Ok(()),
+        };
+
+        // The SBOM describes the final stage's filesystem, so it is also scanned before cleanup
+        let inventory = match (&results, self.options.sbom) {
+            (Ok(results), Some(_)) if !self.cancel.is_cancelled() => {
+                let rootfs = results[&target].rootfs.clone();
+                tokio::task::spawn_blocking(move || sbom::scan(&rootfs))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|inventory| inventory)
+                    .map(Some)
+            }
+            _ => Ok(None),
+        };
+
+        // Stage rootfs directories are only needed while building
+        if build_dir.exists() {
+            tokio::fs::remove_dir_all(&build_dir).await?;
+        }
+        let mut results = results?;
+        exported?;
+        let inventory = inventory?;
+        self.check_cancelled()?;
+        let step = self.options.progress.start("exporting to image".to_string());
+        let mut final_stage = results
+            .remove(&target)
+            .ok_or_else(|| anyhow::anyhow!("Final stage was not built"))?;
+        if self.options.squash {
+            self.squash_final_stage(&mut final_stage, squash_from).await?;
+        }
+        let final_layers = final_stage.layers;
+        let cache_records = final_stage.cache_records;
+        let mut stage_config = final_stage.config;
+        stage_config.labels.extend(self.options.labels.clone());
+
+        // Create the final image
+        let image_id = format!("image_{}", uuid::Uuid::new_v4());
+
+        if self.options.inline_cache {
+            stage_config
+                .labels
+                .insert(cache::INLINE_CACHE_LABEL.to_string(), cache::encode_inline(&cache_records)?);
+        }
+
+        // diff_ids are the digests of the uncompressed layer tars, in order
+        let diff_ids = final_layers.iter().map(|layer| layer.diff_id.clone()).collect();
+        let created = build_timestamp(self.options.source_date_epoch);
+        let (config, raw_config) = stage_config.to_image_config(&platform, &created, final_stage.history, diff_ids)?;
+
+        // Calculate digest for the config
+        use sha2::{Digest, Sha256};
+        let mut hasher = Sha256::new();
+        hasher.update(&raw_config);
+        let hash_result = hasher.finalize();
+        let config_digest = format!("sha256:{:x}", hash_result);
+        let config_size = raw_config.len() as u64;
+
+        let manifest = image_manifest(&config_digest, config_size, &final_layers)?;
+
+        let image = Image {
+            id: image_id,
+            name: image_name.to_string(),
+            layers: final_layers,
+            config,
+            raw_config,
+            manifest,
+        };
+
+        // Save the image to storage
+        self.check_cancelled()?;
+        self.storage.save_image(&image).await?;
+        if let (Some(format), Some(inventory)) = (self.options.sbom, &inventory) {
+            let document = sbom::render(format, image_name, &created, inventory)?;
+            let path = self.storage.save_sbom(&image.id, format.media_type(), &document).await?;
+            tracing::info!("SBOM with {} packages written to {:?}", inventory.packages.len(), path);
+        }
+
+        if let Some(output) = self.options.output.clone()
+            && output.exports_image()
+        {
+            let image = image.clone();
+            tokio::task::spawn_blocking(move || export::export_image(&output, &image)).await??;
+        }
+        step.done();
+
+        Ok(image)
+    }
+
+    async fn build_stages(
+        &self,
+        stages: Vec<BuildStage>,
+        graph: &StageGraph,
+        target: usize,
+        ctx: StageContext,
+    ) -> Result<HashMap<usize, StageResult>> {
+        let required = graph.required_for(target);
+        let parallelism = match self.options.parallelism {
+            0 => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            n => n,
+        };
+
+        let ctx = Arc::new(StageContext { stages, ..ctx });
+
+        let mut results: HashMap<usize, StageResult> = HashMap::new();
+        let mut started = BTreeSet::new();
+        let mut running = JoinSet::new();
+
+        while results.len() < required.len() {
+            // Start every stage whose dependencies are complete, up to the limit
+            for &idx in &required {
+                if running.len() >= parallelism {
+                    break;
+                }
+                if started.contains(&idx) || !graph.dependencies(idx).iter().all(|dep| results.contains_key(dep)) {
+                    continue;
+                }
+
+                let deps: HashMap<usize, StageResult> = graph
+                    .dependencies(idx)
+                    .iter()
+                    .map(|dep| (*dep, results[dep].clone()))
+                    .collect();
+                let span = tracing::info_span!("stage", stage = %stage_label(&ctx.stages[idx], idx));
+                let ctx = Arc::clone(&ctx);
+                started.insert(idx);
+                running.spawn(async move { (idx, build_stage(&ctx, idx, &deps).await) }.instrument(span));
+            }
+
+            // Dropping the JoinSet on error aborts the stages still running
+            let Some(joined) = running.join_next().await else {
+                return Err(anyhow::anyhow!("Build stages could not be scheduled"));
+            };
+            let (idx, result) = joined?;
+            match result {
+                Ok(result) => {
+                    results.insert(idx, result);
+                }
+                Err(e) => {
+                    // Give the other stages a chance to stop their steps and clean up
+                    ctx.cancel.cancel();
+                    while running.join_next().await.is_some() {}
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(results)
+    }
+
+    // Replace the layers created by this build (or from `from_stage` onward)
+    // with a single layer. Base image layers are left as they are.
+    async fn squash_final_stage(&self, stage: &mut StageResult, from_stage: Option<usize>) -> Result<()> {
+        let start = match from_stage {
+            Some(idx) => stage
+                .stage_starts
+                .iter()
+                .find(|(stage_idx, _)| *stage_idx == idx)
+                .map(|(_, start)| *start)
+                .ok_or_else(|| anyhow::anyhow!("--squash-from stage is not part of the final stage's FROM chain"))?,
+            None => stage.stage_starts.first().map(|(_, start)| *start).unwrap_or(0),
+        };
+
+        let count = stage.layers.len().saturating_sub(start);
+        if count < 2 {
+            tracing::info!("Nothing to squash: {} layer(s) after the squash point", count);
+            return Ok(());
+        }
+
+        let layers = stage.layers[start..].to_vec();
+        let data = tokio::task::spawn_blocking(move || {
+            let readers = layers.iter().map(Layer::open_tar).collect::<Result<Vec<_>>>()?;
+            squash::squash_layers(readers)
+        })
+        .await??;
+        let squashed = self.storage.create_layer(&data).await?;
+        tracing::info!("Squashed {} layers into {}", count, squashed.digest);
+
+        // History entries of the merged layers stay, but no longer own a layer
+        let mut seen = 0;
+        for entry in &mut stage.history {
+            if entry.empty_layer() == Some(true) {
+                continue;
+            }
+            if seen >= start {
+                entry.set_empty_layer(Some(true));
+            }
+            seen += 1;
+        }
+        stage.history.push(
+            HistoryBuilder::default()
+                .created(build_timestamp(self.options.source_date_epoch))
+                .comment(format!("merge of {} layers", count))
+                .build()?,
+        );
+
+        stage.layers.truncate(start);
+        stage.layers.push(squashed);
+        // Cache records for merged layers would point at layers the image no longer has
+        let remaining: BTreeSet<&str> = stage.layers.iter().map(|layer| layer.diff_id.as_str()).collect();
+        stage.cache_records.retain(|record| match &record.layer_digest {
+            Some(digest) => remaining.contains(digest.as_str()),
+            None => true,
+        });
+        Ok(())
+    }
+
+    // Build once per platform and tie the results together with an image index
+    #[tracing::instrument(
+        name = "build",
+        skip_all,
+        fields(build_id = self.options.build_id.as_deref(), image = image_name)
+    )]
+    pub async fn build_image_list(
+        &mut self,
+        dockerfile_path: &PathBuf,
+        image_name: &str,
+        platforms: &[Platform],
+    ) -> Result<ImageList> {
+        let platform_names: Vec<String> = platforms.iter().map(|platform| platform.to_string()).collect();
+        self.options.metrics.build_started();
+        self.options
+            .hooks
+            .run(HookEvent::BuildStart, json!({ "image": image_name, "platforms": platform_names }))
+            .await
+            .inspect_err(|_| self.options.metrics.build_failed())?;
+        let built = self.build_platforms(dockerfile_path, image_name, platforms).await;
+        self.finish_build(image_name, built).await
+    }
+
+    async fn build_platforms(
+        &mut self,
+        dockerfile_path: &PathBuf,
+        image_name: &str,
+        platforms: &[Platform],
+    ) -> Result<(ImageList, String)> {
+        let original = self.options.platform.clone();
+        let original_output = self.options.output.clone();
+        let mut images = Vec::new();
+        let mut descriptors = Vec::new();
+
+        for platform in platforms {
+            tracing::info!("Building {} for {}", image_name, platform);
+            self.options.platform = Some(platform.clone());
+            self.options.output = match &original_output {
+                Some(output) => output.for_platform(platform)?,
+                None => None,
+            };
+            let image = self.build_platform_image(dockerfile_path, image_name).await;
+            self.options.platform = original.clone();
+            self.options.output = original_output.clone();
+            let image = image?;
+
+            let mut oci_platform = oci_spec::image::PlatformBuilder::default()
+                .os(oci_spec::image::Os::from(platform.os.as_str()))
+                .architecture(oci_spec::image::Arch::from(platform.architecture.as_str()))
+                .build()?;
+            oci_platform.set_variant(platform.variant.clone());
+            descriptors.push(manifest_descriptor(&image.manifest, oci_platform)?);
+            images.push(image);
+        }
+
+        let index = ImageIndexBuilder::default()
+            .schema_version(2u32)
+            .media_type(MediaType::ImageIndex)
+            .manifests(descriptors)
+            .build()?;
+
+        let list = ImageList {
+            id: format!("index_{}", uuid::Uuid::new_v4()),
+            name: image_name.to_string(),
+            index,
+            images,
+        };
+        self.storage.save_image_list(&list).await?;
+        for tag in &self.options.tags {
+            self.storage.tag(&list.id, tag).await?;
+        }
+        let index_digest = sha256_digest(&serde_json::to_vec(&list.index)?)?;
+
+        if let Some(output) = original_output
+            && output.exports_image()
+        {
+            let list = list.clone();
+            tokio::task::spawn_blocking(move || export::export_image_list(&output, &list)).await??;
+        }
+        Ok((list, index_digest.to_string()))
+    }
+
+    // Fetch the FROM and COPY --from images the pull policy asks for, once per build and before any stage starts
+    async fn pull_base_images(&self, stages: &[BuildStage], platform: &Platform) -> Result<HashMap<String, Image>> {
+        let mut bases: BTreeSet<&str> = BTreeSet::new();
+        for (idx, stage) in stages.iter().enumerate() {
+            if stage.base_image != "scratch" && dag::resolve_stage_ref(stages, &stage.base_image, idx).is_none() {
+                bases.insert(&stage.base_image);
+            }
+            for instruction in &stage.instructions {
+                if let Instruction::Copy { from: Some(from), .. } = instruction
+                    && dag::resolve_stage_ref(stages, from, idx).is_none()
+                {
+                    bases.insert(from);
+                }
+            }
+        }
+
+        let mut pulled = HashMap::new();
+        for base in bases {
+            let pull = match self.options.pull {
+                PullPolicy::Always => true,
+                PullPolicy::Missing => find_base_image(&self.storage, base, platform).await?.is_none(),
+                PullPolicy::Never => {
+                    if find_base_image(&self.storage, base, platform).await?.is_none() {
+                        return Err(anyhow::anyhow!(
+                            "Base image {} is not in local storage and --pull=never does not allow pulling it",
+                            base
+                        ));
+                    }
+                    false
+                }
+            };
+            if !pull {
+                continue;
+            }
+
+            self.check_cancelled()?;
+            let step = self.options.progress.start(format!("pulling {}", base));
+            let image = async {
+                let registry_url = Reference::parse(base)?.registry_url();
+                let client = RegistryClient::new(registry_url)?
+                    .with_settings(self.options.registry.clone())?
+                    .with_metrics(self.options.metrics.clone());
+                client.ping().await?;
+                client.pull_image_to_storage(base, &self.storage, platform).await
+            }
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to pull base image {}: {}", base, e));
+            match &image {
+                Ok(_) => step.done(),
+                Err(e) => step.failed(e),
+            }
+            pulled.insert(base.to_string(), image?);
+        }
+        Ok(pulled)
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(anyhow::anyhow!("Build cancelled"));
+        }
+        Ok(())
+    }
+
+    // User build args plus the automatic platform args BuildKit provides
+    fn effective_build_args(&self, target: &Platform) -> HashMap<String, String> {
+        let build = Platform::host();
+        let mut args = HashMap::new();
+        for (prefix, platform) in [("TARGET", target), ("BUILD", &build)] {
+            args.insert(format!("{}PLATFORM", prefix), platform.to_string());
+            args.insert(format!("{}OS", prefix), platform.os.clone());
+            args.insert(format!("{}ARCH", prefix), platform.architecture.clone());
+            args.insert(format!("{}VARIANT", prefix), platform.variant.clone().unwrap_or_default());
+        }
+        args.extend(self.options.build_args.clone());
+        args
+    }
+
+    fn warn_unused_build_args(&self, parsed: &ParsedDockerfile) {
+        let declared = |key: &String| {
+            parsed.args.contains_key(key)
+                || parsed.stages.iter().any(|stage| {
+                    stage
+                        .instructions
+                        .iter()
+                        .any(|i| matches!(i, Instruction::Arg { key: k, .. } if k == key))
+                })
+        };
+
+        let mut unused: Vec<&String> = self.options.build_args.keys().filter(|key| !declared(key)).collect();
+        if !unused.is_empty() {
+            unused.sort();
+            tracing::warn!("One or more build args were not consumed: {:?}", unused);
+        }
+    }
+}
+
+async fn build_stage(ctx: &StageContext, stage_idx: usize, deps: &HashMap<usize, StageResult>) -> Result<StageResult> {
+    let stage = &ctx.stages[stage_idx];
+    tracing::info!("Processing stage {} of {}: {}",
+                  stage_idx + 1,
+                  ctx.stages.len(),
+                  stage.name.as_deref().unwrap_or(&stage.base_image));
+
+    let rootfs = ctx.build_dir.join(format!("stage-{}", stage_idx));
+    ctx.progress.stage_started(stage_idx, stage_label(stage, stage_idx));
+    let total_steps = 1 + stage.instructions.iter().filter(|i| creates_layer(i)).count();
+    let mut step_number = 1;
+    let from_step = ctx
+        .progress
+        .start(step_name(ctx, stage_idx, step_number, total_steps, &format!("FROM {}", stage.base_image)));
+
+    // A stage built on top of another stage inherits its layers, cache chain and filesystem
+    let mut result = match dag::resolve_stage_ref(&ctx.stages, &stage.base_image, stage_idx) {
+        Some(base) => {
+            let base = deps[&base].clone();
+            let (src, dest) = (base.rootfs.clone(), rootfs.clone());
+            tokio::task::spawn_blocking(move || snapshot::copy_tree(&src, &dest)).await??;
+            StageResult {
+                config: base.config.inherited(),
+                stage_starts: base.stage_starts.iter().copied().chain([(stage_idx, base.layers.len())]).collect(),
+                rootfs,
+                ..base
+            }
+        }
+        None => match prepare_base_rootfs(ctx, &stage.base_image, &rootfs)
+            .await
+            .map_err(|e| LineError { line: stage.line, message: format!("FROM {}: {:#}", stage.base_image, e) })?
+        {
+            // The base image's layers and history become the start of this stage's chain
+            Some(image) => StageResult {
+                layers: image.layers.clone(),
+                cache_records: Vec::new(),
+                cache_key: cache::base_cache_key(
+                    &stage.base_image,
+                    Some(sha256_digest(&serde_json::to_vec(&image.manifest)?)?.as_ref()),
+                    &ctx.platform,
+                ),
+                history: image.config.history().clone().unwrap_or_default(),
+                config: StageConfig::from_image_config(&image.raw_config)?,
+                stage_starts: vec![(stage_idx, image.layers.len())],
+                rootfs,
+            },
+            None => StageResult {
+                layers: Vec::new(),
+                cache_records: Vec::new(),
+                cache_key: cache::base_cache_key(&stage.base_image, None, &ctx.platform),
+                history: Vec::new(),
+                config: StageConfig::default(),
+                stage_starts: vec![(stage_idx, 0)],
+                rootfs,
+            },
+        },
+    };
+
+    from_step.done();
+
+    // ARGs declared in this stage, in declaration order
+    let mut args: Vec<(String, String)> = Vec::new();
+
+    for (inst_idx, instruction) in stage.instructions.iter().enumerate() {
+        if ctx.cancel.is_cancelled() {
+            return Err(anyhow::anyhow!("Build cancelled"));
+        }
+        let vars: HashMap<String, String> = args.iter().chain(result.config.env.iter()).cloned().collect();
+        let instruction = &match instruction {
+            // Unknown instructions no plugin handles run as shell commands, as they always have
+            Instruction::Custom { .. } if !handled(ctx, instruction) => Instruction::Run {
+                command: instruction.to_string(),
+                options: RunOptions::default(),
+            },
+            other => other.expand(&vars),
+        };
+        tracing::info!("Processing instruction {}: {:?}", inst_idx, instruction);
+
+        match instruction {
+            Instruction::Arg { key, default } => {
+                let value = ctx
+                    .build_args
+                    .get(key)
+                    .or(default.as_ref())
+                    .or_else(|| ctx.global_args.get(key));
+                if let Some(value) = value {
+                    args.retain(|(k, _)| k != key);
+                    args.push((key.clone(), value.clone()));
+                }
+            }
+            Instruction::Run { options, .. } => secrets::validate(&ctx.secrets, &options.mounts)?,
+            other => result.config.apply(other),
+        }
+
+        // RUN steps see the stage's ARGs, so they are part of the recorded command
+        let created_by = match instruction {
+            Instruction::Run { command, .. } if !args.is_empty() => {
+                let assignments: Vec<String> = args.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                format!("RUN |{} {} /bin/sh -c {}", args.len(), assignments.join(" "), command)
+            }
+            Instruction::Run { command, .. } => format!("RUN /bin/sh -c {}", command),
+            other => other.to_string(),
+        };
+
+        // Secret contents are deliberately left out: only the mount spec is keyed
+        let content_digest = match instruction {
+            Instruction::Run { .. } => Some(created_by.clone()),
+            // The plugin decides what the instruction does, so it is part of the key
+            Instruction::Custom { keyword, .. } => {
+                let plugin = ctx.plugins.for_instruction(keyword).map(|plugin| &plugin.command);
+                Some(json!({ "plugin": plugin, "instruction": created_by, "args": args }).to_string())
+            }
+            Instruction::Copy { src, from: None, .. } | Instruction::Add { src, .. } => {
+                Some(cache::hash_context_sources(&ctx.context_dir, src)?)
+            }
+            Instruction::Copy { from: Some(from), .. } => match dag::resolve_stage_ref(&ctx.stages, from, stage_idx) {
+                Some(dep) => Some(deps[&dep].cache_key.clone()),
+                // An image is keyed by its id, so re-pulling a moved tag invalidates the step
+                None => Some(copy_source_image(ctx, from).await?.id),
+            },
+            _ => None,
+        };
+        result.cache_key = cache::compute_cache_key(&result.cache_key, instruction, content_digest.as_deref());
+
+        // Instructions that only change the config are recorded without a layer
+        if !creates_layer(instruction) {
+            result.history.push(history_entry(created_by, true, ctx.source_date_epoch)?);
+            continue;
+        }
+
+        step_number += 1;
+        let name = step_name(ctx, stage_idx, step_number, total_steps, &instruction.to_string());
+        let step = ctx.progress.start(name.clone());
+        let started = Instant::now();
+        let hook_context = json!({
+            "stage": stage_idx,
+            "step": { "id": step.id(), "name": name, "instruction": instruction.to_string() },
+        });
+        ctx.hooks.run(HookEvent::PreStep, hook_context.clone()).await?;
+
+        let layer = match ctx.cache_index.lookup(&result.cache_key) {
+            Some(CachedStep::Layer(cached)) => {
+                tracing::info!("CACHED instruction {}: reusing layer {}", inst_idx, cached.digest);
+                // Replay the cached layer so later steps see its files
+                ctx.storage.extract_layers(std::slice::from_ref(cached), &result.rootfs).await?;
+                step.cached(Some(&cached.digest), cached.diff_size, cached.size);
+                finish_step(ctx, instruction, started, "cached");
+                ctx.storage.touch_cache(&result.cache_key).await?;
+                ctx.hooks
+                    .run(HookEvent::PostStep, with_outcome(hook_context, "cached", Some(&cached.digest), None))
+                    .await?;
+                Some(cached.clone())
+            }
+            Some(CachedStep::Empty) => {
+                tracing::info!("CACHED instruction {}: no filesystem changes", inst_idx);
+                step.cached(None, 0, 0);
+                finish_step(ctx, instruction, started, "cached");
+                ctx.storage.touch_cache(&result.cache_key).await?;
+                ctx.hooks.run(HookEvent::PostStep, with_outcome(hook_context, "cached", None, None)).await?;
+                None
+            }
+            None => {
+                let layer_tar = match execute_instruction(ctx, stage_idx, instruction, &result, &args, deps, &step).await {
+                    Ok(layer_tar) => layer_tar,
+                    Err(e) => {
+                        step.failed(&e);
+                        finish_step(ctx, instruction, started, "failed");
+                        let context = with_outcome(hook_context, "failed", None, Some(&e));
+                        if let Err(hook_error) = ctx.hooks.run(HookEvent::PostStep, context).await {
+                            tracing::warn!("{}", hook_error);
+                        }
+                        // Name the stage and step as progress numbers them, e.g. a RUN
+                        // step that ran out of time or memory
+                        let label = stage_label(stage, stage_idx);
+                        let position = format!("{}/{}", step_number, total_steps);
+                        let message = format!("{} step {} ({}): {:#}", label, position, created_by, e);
+                        return Err(LineError { line: stage.lines[inst_idx], message }.into());
+                    }
+                };
+                // A step that changed no files, e.g. WORKDIR on an existing directory, gets no layer
+                let layer = match layer_tar {
+                    Some(layer_tar) => {
+                        let tar = tokio::fs::File::open(&layer_tar).await?;
+                        let layer = ctx.storage.create_layer_from_reader(tar).await?;
+                        step.layer_committed(&layer.digest, layer.diff_size, layer.size);
+                        Some(layer)
+                    }
+                    None => None,
+                };
+                step.done();
+                finish_step(ctx, instruction, started, "done");
+                ctx.storage
+                    .record_cache(&result.cache_key, layer.as_ref(), ctx.build_id.as_deref())
+                    .await?;
+                let digest = layer.as_ref().map(|layer| layer.digest.as_str());
+                ctx.hooks.run(HookEvent::PostStep, with_outcome(hook_context, "done", digest, None)).await?;
+                layer
+            }
+        };
+
+        result.cache_records.push(InlineCacheRecord {
+            key: result.cache_key.clone(),
+            layer_digest: layer.as_ref().map(|layer| layer.diff_id.clone()),
+        });
+        let Some(layer) = layer else {
+            result.history.push(history_entry(created_by, true, ctx.source_date_epoch)?);
+            continue;
+        };
+        result.layers.push(layer);
+        result.history.push(history_entry(created_by, false, ctx.source_date_epoch)?);
+    }
+
+    Ok(result)
+}
+
+// The name a stage is shown and logged under
+fn stage_label(stage: &BuildStage, stage_idx: usize) -> String {
+    stage.name.clone().unwrap_or_else(|| format!("stage-{}", stage_idx))
+}
+
+// Count a finished step and log one line for it; with `--log-format json` its
+// fields, and the build_id and stage of the spans around it, are keys of the JSON object
+fn finish_step(ctx: &StageContext, instruction: &Instruction, started: Instant, status: &str) {
+    let elapsed = started.elapsed();
+    match status {
+        "cached" => ctx.metrics.step_cached(),
+        _ => ctx.metrics.step_run(elapsed),
+    }
+    let duration_ms = elapsed.as_millis() as u64;
+    tracing::info!(instruction = %instruction, duration_ms, status, "Step {}", status);
+}
+
+// A post-step hook payload: the step plus how it ended
+fn with_outcome(
+    mut context: serde_json::Value,
+    status: &str,
+    digest: Option<&str>,
+    error: Option<&anyhow::Error>,
+) -> serde_json::Value {
+    context["status"] = json!(status);
+    if let Some(digest) = digest {
+        context["layer"] = json!(digest);
+    }
+    if let Some(error) = error {
+        context["error"] = json!(error.to_string());
+    }
+    context
+}
+
+// How a step is shown in progress output, e.g. "[builder 2/4] RUN make"
+fn step_name(ctx: &StageContext, stage_idx: usize, number: usize, total: usize, text: &str) -> String {
+    let stage = match (&ctx.stages[stage_idx].name, ctx.stages.len()) {
+        (Some(name), _) => format!("{} ", name),
+        (None, 1) => String::new(),
+        (None, _) => format!("stage-{} ", stage_idx),
+    };
+    format!("[{}{}/{}] {}", stage, number, total, text)
+}
+
+fn creates_layer(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Run { .. }
+            | Instruction::Copy { .. }
+            | Instruction::Add { .. }
+            | Instruction::Workdir { .. }
+            | Instruction::Custom { .. }
+    )
+}
+
+// Whether a plugin handles this custom instruction
+fn handled(ctx: &StageContext, instruction: &Instruction) -> bool {
+    matches!(instruction, Instruction::Custom { keyword, .. } if ctx.plugins.for_instruction(keyword).is_some())
+}
+
+fn history_entry(created_by: String, empty_layer: bool, source_date_epoch: Option<u64>) -> Result<History> {
+    let mut builder = HistoryBuilder::default()
+        .created(build_timestamp(source_date_epoch))
+        .created_by(created_by);
+    if empty_layer {
+        builder = builder.empty_layer(true);
+    }
+    Ok(builder.build()?)
+}
+
+pub(crate) fn sha256_digest(data: &[u8]) -> Result<oci_spec::image::Digest> {
+    use sha2::{Digest, Sha256};
+    Ok(format!("sha256:{:x}", Sha256::digest(data)).parse()?)
+}
+
+// An image index entry for a stored image's manifest
+pub(crate) fn manifest_descriptor(
+    manifest: &ImageManifest,
+    platform: oci_spec::image::Platform,
+) -> Result<oci_spec::image::Descriptor> {
+    let manifest_bytes = serde_json::to_vec(manifest)?;
+    Ok(DescriptorBuilder::default()
+        .media_type(MediaType::ImageManifest)
+        .digest(sha256_digest(&manifest_bytes)?)
+        .size(manifest_bytes.len() as u64)
+        .platform(platform)
+        .build()?)
+}
+
+pub(crate) fn image_manifest(config_digest: &str, config_size: u64, layers: &[Layer]) -> Result<ImageManifest> {
+    let config = DescriptorBuilder::default()
+        .media_type(MediaType::ImageConfig)
+        .digest(config_digest.parse::<oci_spec::image::Digest>()?)
+        .size(config_size)
+        .build()?;
+    let layers = layers
+        .iter()
+        .map(|layer| {
+            let mut descriptor = DescriptorBuilder::default();
+            let zstd = layer.compression == CompressionAlgorithm::Zstd;
+            descriptor = match (layer.urls.is_empty(), zstd) {
+                (true, false) => descriptor.media_type(MediaType::ImageLayerGzip),
+                (true, true) => descriptor.media_type(MediaType::ImageLayerZstd),
+                (false, false) => {
+                    descriptor.media_type(MediaType::ImageLayerNonDistributableGzip).urls(layer.urls.clone())
+                }
+                (false, true) => {
+                    descriptor.media_type(MediaType::ImageLayerNonDistributableZstd).urls(layer.urls.clone())
+                }
+            };
+            if let Some(toc_digest) = &layer.toc_digest {
+                let annotation = crate::storage::estargz::TOC_DIGEST_ANNOTATION.to_string();
+                descriptor = descriptor.annotations(HashMap::from([(annotation, toc_digest.clone())]));
+            }
+            Ok(descriptor
+                .digest(layer.digest.parse::<oci_spec::image::Digest>()?)
+                .size(layer.size)
+                .build()?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(ImageManifestBuilder::default()
+        .schema_version(2u32)
+        .media_type(MediaType::ImageManifest)
+        .config(config)
+        .layers(layers)
+        .build()?)
+}
+
+// RFC 3339 creation time, pinned to SOURCE_DATE_EPOCH in reproducible mode
+fn build_timestamp(source_date_epoch: Option<u64>) -> String {
+    let time = source_date_epoch
+        .and_then(|epoch| chrono::DateTime::from_timestamp(epoch as i64, 0))
+        .unwrap_or_else(chrono::Utc::now);
+    time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+// Populate a stage rootfs from a base image pulled for this build or already in local storage
+async fn prepare_base_rootfs(ctx: &StageContext, base_image: &str, rootfs: &Path) -> Result<Option<Image>> {
+    tokio::fs::create_dir_all(rootfs).await?;
+    if base_image == "scratch" {
+        return Ok(None);
+    }
+
+    let image = match ctx.pulled.get(base_image) {
+        Some(image) => image.clone(),
+        None => find_base_image(&ctx.storage, base_image, &ctx.platform)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Base image {} is not in local storage", base_image))?,
+    };
+    ctx.storage.touch(&image.id).await?;
+    ctx.storage.extract_layers(&image.layers, rootfs).await?;
+    Ok(Some(image))
+}
+
+// The image a COPY --from names: pulled for this build, or else from local storage
+async fn copy_source_image(ctx: &StageContext, reference: &str) -> Result<Image> {
+    let image = match ctx.pulled.get(reference) {
+        Some(image) => image.clone(),
+        None => find_base_image(&ctx.storage, reference, &ctx.platform)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("COPY --from={}: no such stage, and the image is not in local storage", reference))?,
+    };
+    ctx.storage.touch(&image.id).await?;
+    Ok(image)
+}
+
+// The local image for a FROM line, preferring the build's target platform
+async fn find_base_image(storage: &StorageManager, base_image: &str, platform: &Platform) -> Result<Option<Image>> {
+    if let Some(image) = storage.get_image_by_name_for_platform(base_image, platform).await? {
+        return Ok(Some(image));
+    }
+    let image = storage.get_image_by_name(base_image).await?;
+    if image.is_some() {
+        tracing::warn!("Base image {} has no {} variant, using the one in local storage", base_image, platform);
+    }
+    Ok(image)
+}
+
+async fn capture_snapshot(rootfs: &Path) -> Result<Snapshot> {
+    let rootfs = rootfs.to_path_buf();
+    tokio::task::spawn_blocking(move || Snapshot::capture(&rootfs)).await?
+}
+
+// Exponential backoff between attempts: 1s, 2s, 4s, ... up to 30s
+fn retry_delay(attempt: u32) -> std::time::Duration {
+    std::time::Duration::from_secs(1u64 << attempt.saturating_sub(1).min(5)).min(std::time::Duration::from_secs(30))
+}
+
+// Apply one instruction to the stage rootfs and return its layer as an uncompressed tar
+async fn execute_instruction(
+    ctx: &StageContext,
+    stage_idx: usize,
+    instruction: &Instruction,
+    state: &StageResult,
+    args: &[(String, String)],
+    deps: &HashMap<usize, StageResult>,
+    step: &StepProgress,
+) -> Result<Option<tempfile::TempPath>> {
+    let rootfs = &state.rootfs;
+    let before = capture_snapshot(rootfs).await?;
+
+    match instruction {
+        Instruction::Run { command, options } => {
+            let retries = options.retry.unwrap_or(ctx.retries);
+            let mut attempt = 0;
+            // A retry runs on top of whatever the failed attempt left behind, like a
+            // retry loop in the command itself would; the layer holds the changes of all attempts
+            while let Err(e) = run_step(ctx, command, options, state, args, step.log_sink()).await {
+                if attempt == retries || ctx.cancel.is_cancelled() {
+                    return Err(e);
+                }
+                attempt += 1;
+                let delay = retry_delay(attempt);
+                tracing::warn!("RUN {} failed, retrying in {:?} ({}/{}): {}", command, delay, attempt, retries, e);
+                step.retrying(attempt, retries, &e);
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = ctx.cancel.cancelled() => return Err(anyhow::anyhow!("Build cancelled")),
+                }
+            }
+        }
+        Instruction::Copy { src, dest, from } => match from {
+            None => copy_sources(&ctx.context_dir, src, dest, &state.config.workdir, rootfs).await?,
+            Some(from) => match dag::resolve_stage_ref(&ctx.stages, from, stage_idx) {
+                Some(dep) => copy_sources(&deps[&dep].rootfs, src, dest, &state.config.workdir, rootfs).await?,
+                None => {
+                    // The image is unpacked into scratch space of its own, since stages
+                    // built concurrently may copy from the same image
+                    let image = copy_source_image(ctx, from).await?;
+                    let source_root = ctx.build_dir.join(format!("copy-from-{}", uuid::Uuid::new_v4()));
+                    tokio::fs::create_dir_all(&source_root).await?;
+                    let copied = async {
+                        ctx.storage.extract_layers(&image.layers, &source_root).await?;
+                        copy_sources(&source_root, src, dest, &state.config.workdir, rootfs).await
+                    }
+                    .await;
+                    tokio::fs::remove_dir_all(&source_root).await?;
+                    copied?;
+                }
+            },
+        },
+        Instruction::Add { src, dest } => copy_sources(&ctx.context_dir, src, dest, &state.config.workdir, rootfs).await?,
+        Instruction::Workdir { .. } => {
+            tokio::fs::create_dir_all(rootfs.join(snapshot::normalize(Path::new(&state.config.workdir)))).await?;
+        }
+        Instruction::Custom { keyword, args: instruction_args } => {
+            let plugin = ctx
+                .plugins
+                .for_instruction(keyword)
+                .ok_or_else(|| anyhow::anyhow!("No plugin handles {}", keyword))?;
+            // The plugin changes the rootfs itself; what it changed becomes the layer.
+            // Paths are absolute since the plugin may run from anywhere.
+            let request = json!({
+                "protocol": plugins::PROTOCOL_VERSION,
+                "type": "instruction",
+                "build_id": ctx.build_id,
+                "stage": stage_idx,
+                "instruction": keyword.to_ascii_uppercase(),
+                "args": instruction_args,
+                "rootfs": std::path::absolute(rootfs)?,
+                "context": std::path::absolute(&ctx.context_dir)?,
+                "workdir": state.config.workdir,
+                "user": state.config.user,
+                "env": step_env(state, args).into_iter().collect::<BTreeMap<String, String>>(),
+                "platform": ctx.platform.to_string(),
+            });
+            plugins::run(plugin, &request, step.log_sink(), ctx.step_limits.timeout, &ctx.cancel).await?;
+        }
+        _ => {}
+    }
+
+    let after = capture_snapshot(rootfs).await?;
+    let changes = before.changes(&after);
+    if changes.is_empty() {
+        return Ok(None);
+    }
+    // The tar is spooled to disk rather than memory, since a layer can be many gigabytes
+    let tar_file = tempfile::NamedTempFile::new_in(ctx.storage.tmp_dir())?;
+    let (rootfs, epoch) = (rootfs.clone(), ctx.source_date_epoch);
+    tokio::task::spawn_blocking(move || -> Result<Option<tempfile::TempPath>> {
+        let writer = snapshot::write_layer_tar(std::io::BufWriter::new(tar_file.as_file()), &rootfs, &changes, epoch)?;
+        writer.into_inner().map_err(|e| e.into_error())?;
+        Ok(Some(tar_file.into_temp_path()))
+    })
+    .await?
+}
+
+async fn run_step(
+    ctx: &StageContext,
+    command: &str,
+    options: &RunOptions,
+    state: &StageResult,
+    args: &[(String, String)],
+    log: Option<LogSink>,
+) -> Result<()> {
+    // Without a shell the chroot has nothing to execute the command with;
+    // an executor plugin runs it wherever it likes
+    if ctx.plugins.executor().is_none() && std::fs::symlink_metadata(state.rootfs.join("bin/sh")).is_err() {
+        return Err(anyhow::anyhow!("RUN {}: the stage rootfs has no /bin/sh to run the command with", command));
+    }
+
+    let spec = StepSpec {
+        command: vec!["/bin/sh".to_string(), "-c".to_string(), command.to_string()],
+        env: step_env(state, args),
+        workdir: state.config.workdir.clone(),
+        user: state.config.user.clone(),
+        rootfs: state.rootfs.clone(),
+        network: match options.network {
+            Some(NetworkMode::Default) | None => ctx.network,
+            Some(mode) => mode,
+        },
+        limits: ctx.step_limits,
+        cancel: ctx.cancel.clone(),
+        log,
+    };
+
+    // Secrets and the hosts file only exist while the step runs
+    let mut files = EphemeralFiles::new(&state.rootfs);
+    if let Err(e) = place_step_files(ctx, options, &state.rootfs, &mut files) {
+        files.remove()?;
+        return Err(e);
+    }
+    let outcome = ctx.executor.run(&spec).await;
+    files.remove()?;
+    outcome
+}
+
+// ARGs are visible to RUN as environment variables, ENV takes precedence
+fn step_env(state: &StageResult, args: &[(String, String)]) -> Vec<(String, String)> {
+    let mut env: Vec<(String, String)> = args.to_vec();
+    for (key, value) in &state.config.env {
+        env.retain(|(k, _)| k != key);
+        env.push((key.clone(), value.clone()));
+    }
+    if !env.iter().any(|(k, _)| k == "PATH") {
+        env.push(("PATH".to_string(), executor::DEFAULT_PATH.to_string()));
+    }
+    env
+}
+
+fn place_step_files(ctx: &StageContext, options: &RunOptions, rootfs: &Path, files: &mut EphemeralFiles) -> Result<()> {
+    secrets::mount(&ctx.secrets, &options.mounts, files)?;
+
+    if !ctx.extra_hosts.is_empty() {
+        // Extend the image's own hosts file rather than replacing it
+        let mut hosts = std::fs::read_to_string(rootfs.join("etc/hosts"))
+            .unwrap_or_else(|_| "127.0.0.1\tlocalhost\n::1\tlocalhost ip6-localhost ip6-loopback\n".to_string());
+        if !hosts.is_empty() && !hosts.ends_with('\n') {
+            hosts.push('\n');
+        }
+        for (host, ip) in &ctx.extra_hosts {
+            hosts.push_str(&format!("{}\t{}\n", ip, host));
+        }
+        files.place("/etc/hosts", hosts.as_bytes(), 0o644)?;
+    }
+
+    Ok(())
+}
+
+// Copy build sources into the rootfs following COPY/ADD destination rules.
+// Copied files are owned by root, as with Docker, so the builder's own
+// uid/gid never leaks into a layer.
+async fn copy_sources(source_root: &Path, sources: &[String], dest: &str, workdir: &str, rootfs: &Path) -> Result<()> {
+    let dest_path = rootfs.join(snapshot::normalize(Path::new(&resolve_path(workdir, dest))));
+    let dest_is_dir = dest.ends_with('/') || sources.len() > 1 || dest_path.is_dir();
+    let source_root = source_root.to_path_buf();
+    let sources = sources.to_vec();
+
+    tokio::task::spawn_blocking(move || {
+        let mut copies = Vec::new();
+        for src in &sources {
+            let src_path = source_root.join(snapshot::normalize(Path::new(src)));
+            if src_path.is_dir() {
+                // The contents of a directory are copied, not the directory itself
+                std::fs::create_dir_all(&dest_path)?;
+                for entry in std::fs::read_dir(&src_path)? {
+                    let entry = entry?;
+                    copies.push((entry.path(), dest_path.join(entry.file_name())));
+                }
+            } else if dest_is_dir {
+                let name = src_path
+                    .file_name()
+                    .ok_or_else(|| anyhow::anyhow!("Invalid COPY source: {}", src))?;
+                copies.push((src_path.clone(), dest_path.join(name)));
+            } else {
+                copies.push((src_path, dest_path.clone()));
+            }
+        }
+
+        for (src, target) in copies {
+            snapshot::copy_tree(&src, &target)?;
+            // Changing ownership needs root; unprivileged builds keep their own uid
+            let _ = snapshot::chown_tree(&target, 0, 0);
+        }
+        Ok(())
+    })
+    .await?
+}
+
+fn resolve_path(workdir: &str, path: &str) -> String {
+    if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("{}/{}", workdir.trim_end_matches('/'), path)
+    }
+}
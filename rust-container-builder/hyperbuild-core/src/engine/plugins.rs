@@ -0,0 +1,239 @@
+use super::executor::{self, Executor, StepSpec};
+use crate::dockerfile::NetworkMode;
+use crate::progress::LogSink;
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio_util::sync::CancellationToken;
+
+// Version of the JSON requests plugins are sent, so they can refuse ones they do not understand
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Instructions the parser handles itself, which no plugin can take over
+const BUILTIN_INSTRUCTIONS: [&str; 17] = [
+    "FROM", "RUN", "CMD", "LABEL", "ENV", "COPY", "ADD", "WORKDIR", "EXPOSE", "ENTRYPOINT", "VOLUME", "USER", "ARG",
+    "ONBUILD", "STOPSIGNAL", "HEALTHCHECK", "SHELL",
+];
+
+// A program that handles Dockerfile instructions hyperbuild does not know, or
+// runs RUN steps in place of chroot, e.g.
+//
+//   [[plugins]]
+//   name = "vault"
+//   command = ["/usr/local/bin/hyperbuild-vault"]
+//   instructions = ["VAULT"]
+//
+//   [[plugins]]
+//   name = "firecracker"
+//   command = ["/usr/local/bin/fc-run"]
+//   executor = true
+//
+// It is started once per step with a JSON request on stdin; its output is the
+// step's output and a non-zero exit status fails the step.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Plugin {
+    pub name: String,
+    pub command: Vec<String>,
+    // Keywords of the instructions it handles
+    #[serde(default)]
+    pub instructions: Vec<String>,
+    // Whether RUN steps are run with it rather than chroot
+    #[serde(default)]
+    pub executor: bool,
+}
+
+// The configured plugins
+#[derive(Debug, Clone, Default)]
+pub struct Plugins {
+    plugins: Vec<Plugin>,
+}
+
+impl Plugins {
+    pub fn new(plugins: Vec<Plugin>) -> Self {
+        Self { plugins }
+    }
+
+    // The plugin handling an instruction, going by its keyword in any case
+    pub fn for_instruction(&self, keyword: &str) -> Option<&Plugin> {
+        self.plugins
+            .iter()
+            .find(|plugin| plugin.instructions.iter().any(|handled| handled.eq_ignore_ascii_case(keyword)))
+    }
+
+    // The plugin RUN steps are run with, if any
+    pub fn executor(&self) -> Option<&Plugin> {
+        self.plugins.iter().find(|plugin| plugin.executor)
+    }
+}
+
+// Check plugins from the config file: every instruction has one handler that
+// is not a built-in, and at most one plugin is the executor
+pub fn validate(plugins: &[Plugin]) -> Result<()> {
+    let mut handlers: BTreeMap<String, &str> = BTreeMap::new();
+    for plugin in plugins {
+        if plugin.command.is_empty() {
+            return Err(anyhow::anyhow!("plugin {:?} has an empty command", plugin.name));
+        }
+        for keyword in &plugin.instructions {
+            let upper = keyword.to_ascii_uppercase();
+            if upper.is_empty() || !upper.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(anyhow::anyhow!("plugin {:?}: {:?} is not an instruction keyword", plugin.name, keyword));
+            }
+            if BUILTIN_INSTRUCTIONS.contains(&upper.as_str()) {
+                return Err(anyhow::anyhow!("plugin {:?} cannot take over the built-in {}", plugin.name, upper));
+            }
+            if let Some(other) = handlers.insert(upper.clone(), &plugin.name) {
+                return Err(anyhow::anyhow!("{} is handled by both plugin {:?} and {:?}", upper, other, plugin.name));
+            }
+        }
+    }
+    let executors: Vec<&str> = plugins.iter().filter(|p| p.executor).map(|p| p.name.as_str()).collect();
+    if executors.len() > 1 {
+        return Err(anyhow::anyhow!("only one plugin can be the executor, got {}", executors.join(", ")));
+    }
+    Ok(())
+}
+
+// Runs RUN steps with a plugin, which is sent the step as a "run" request:
+// the command, its environment, working directory, user, rootfs, network and
+// limits. The timeout is enforced here; the other limits are the plugin's job.
+pub struct PluginExecutor {
+    plugin: Plugin,
+}
+
+impl PluginExecutor {
+    pub fn new(plugin: Plugin) -> Self {
+        Self { plugin }
+    }
+}
+
+#[async_trait]
+impl Executor for PluginExecutor {
+    async fn run(&self, spec: &StepSpec) -> Result<()> {
+        let request = json!({
+            "protocol": PROTOCOL_VERSION,
+            "type": "run",
+            "command": spec.command,
+            "env": spec.env.iter().cloned().collect::<BTreeMap<String, String>>(),
+            "workdir": spec.workdir,
+            "user": spec.user,
+            "rootfs": std::path::absolute(&spec.rootfs)?,
+            "network": match spec.network {
+                NetworkMode::Default => "default",
+                NetworkMode::None => "none",
+                NetworkMode::Host => "host",
+            },
+            "limits": {
+                "memory_bytes": spec.limits.memory_bytes,
+                "cpus": spec.limits.cpus,
+                "timeout_secs": spec.limits.timeout.map(|timeout| timeout.as_secs_f64()),
+            },
+        });
+        run(&self.plugin, &request, spec.log.clone(), spec.limits.timeout, &spec.cancel).await
+    }
+}
+
+// Run `plugin` with `request` on stdin until it exits, `timeout` passes or the build is cancelled
+pub async fn run(
+    plugin: &Plugin,
+    request: &Value,
+    log: Option<LogSink>,
+    timeout: Option<Duration>,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let (program, args) = plugin
+        .command
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("plugin {:?} has an empty command", plugin.name))?;
+
+    let mut command = tokio::process::Command::new(program);
+    command
+        .args(args)
+        .stdin(Stdio::piped())
+        // A group of its own lets a timed out or cancelled plugin be killed with all its children
+        .process_group(0)
+        .kill_on_drop(true);
+    if log.is_some() {
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    }
+    let mut child = command
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("Failed to start plugin {:?} ({}): {}", plugin.name, program, e))?;
+    let pid = child.id();
+    let output = log.map(|log| tokio::spawn(executor::forward_output(child.stdout.take(), child.stderr.take(), log)));
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // A plugin that does not read its request is not an error
+        let _ = stdin.write_all(&serde_json::to_vec(request)?).await;
+    }
+
+    let deadline = async {
+        match timeout {
+            Some(limit) => tokio::time::sleep(limit).await,
+            None => std::future::pending().await,
+        }
+    };
+    let status = tokio::select! {
+        status = child.wait() => status?,
+        _ = deadline => {
+            executor::kill_process_group(pid);
+            return Err(executor::timed_out(format!("Plugin {:?}", plugin.name), timeout));
+        }
+        _ = cancel.cancelled() => {
+            executor::kill_process_group(pid);
+            return Err(anyhow::anyhow!("Plugin {:?} was cancelled", plugin.name));
+        }
+    };
+    // Processes the plugin left running in the background would hold its output open
+    if let Some(output) = output {
+        executor::kill_process_group(pid);
+        let _ = output.await;
+    }
+
+    if !status.success() {
+        return Err(anyhow::anyhow!("Plugin {:?} failed with {}", plugin.name, status));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dockerfile::{DockerfileParser, Instruction};
+
+    #[tokio::test]
+    async fn test_custom_instruction_plugin() {
+        let parsed = DockerfileParser::parse("FROM alpine\nvault secret/app /etc/app.json\n").unwrap();
+        let Instruction::Custom { keyword, args } = &parsed.stages[0].instructions[0] else {
+            panic!("expected a custom instruction");
+        };
+        assert_eq!((keyword.as_str(), args.as_str()), ("vault", "secret/app /etc/app.json"));
+
+        let plugin = Plugin {
+            name: "vault".to_string(),
+            command: vec!["sh".to_string(), "-c".to_string(), "grep -q '\"args\":\"secret/app'".to_string()],
+            instructions: vec!["VAULT".to_string()],
+            executor: false,
+        };
+        let plugins = Plugins::new(vec![plugin.clone()]);
+        assert!(plugins.for_instruction(keyword).is_some() && plugins.executor().is_none());
+
+        let cancel = CancellationToken::new();
+        let request = json!({ "type": "instruction", "instruction": "VAULT", "args": args });
+        run(&plugin, &request, None, None, &cancel).await.unwrap();
+        let request = json!({ "type": "instruction", "instruction": "VAULT", "args": "other" });
+        assert!(run(&plugin, &request, None, None, &cancel).await.is_err());
+
+        let builtin = Plugin { instructions: vec!["copy".to_string()], ..plugin.clone() };
+        assert!(validate(&[builtin]).is_err());
+        let executor = Plugin { executor: true, instructions: Vec::new(), ..plugin.clone() };
+        assert!(validate(&[plugin, executor.clone()]).is_ok());
+        assert!(validate(&[executor.clone(), executor]).is_err());
+    }
+}
@@ -0,0 +1,531 @@
+use anyhow::Result;
+use serde_json::{Value, json};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+pub const SPDX_MEDIA_TYPE: &str = "application/spdx+json";
+pub const CYCLONEDX_MEDIA_TYPE: &str = "application/vnd.cyclonedx+json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbomFormat {
+    Spdx,
+    CycloneDx,
+}
+
+impl SbomFormat {
+    pub fn parse(format: &str) -> Result<Self> {
+        match format {
+            "spdx" | "spdx-json" => Ok(Self::Spdx),
+            "cyclonedx" | "cyclonedx-json" => Ok(Self::CycloneDx),
+            other => Err(anyhow::anyhow!("Unknown SBOM format {:?}, expected spdx or cyclonedx", other)),
+        }
+    }
+
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            Self::Spdx => SPDX_MEDIA_TYPE,
+            Self::CycloneDx => CYCLONEDX_MEDIA_TYPE,
+        }
+    }
+}
+
+// One package found in the image, with the file it was read from
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Package {
+    // Package URL type: apk, deb, rpm, npm, cargo, pypi or gem
+    pub ecosystem: String,
+    pub name: String,
+    pub version: String,
+    pub license: Option<String>,
+    pub source: String,
+}
+
+impl Package {
+    pub fn purl(&self, distro: Option<&str>) -> String {
+        match (self.ecosystem.as_str(), distro) {
+            ("apk" | "deb", Some(distro)) => {
+                format!("pkg:{}/{}/{}@{}", self.ecosystem, distro, self.name, self.version)
+            }
+            // An RPM epoch is a qualifier rather than part of the version
+            ("rpm", distro) => {
+                let namespace = distro.map(|distro| format!("{}/", distro)).unwrap_or_default();
+                match self.version.split_once(':') {
+                    Some((epoch, version)) => format!("pkg:rpm/{}{}@{}?epoch={}", namespace, self.name, version, epoch),
+                    None => format!("pkg:rpm/{}{}@{}", namespace, self.name, self.version),
+                }
+            }
+            _ => format!("pkg:{}/{}@{}", self.ecosystem, self.name, self.version),
+        }
+    }
+}
+
+// What a filesystem has installed, by OS package database and language lockfile
+#[derive(Debug, Default)]
+pub struct Inventory {
+    // The os-release ID, used as the purl namespace of OS packages
+    pub distro: Option<String>,
+    pub packages: Vec<Package>,
+}
+
+pub fn scan(rootfs: &Path) -> Result<Inventory> {
+    let mut packages = BTreeSet::new();
+
+    if let Ok(installed) = fs::read_to_string(rootfs.join("lib/apk/db/installed")) {
+        packages.extend(parse_apk(&installed, "/lib/apk/db/installed"));
+    }
+    if let Ok(status) = fs::read_to_string(rootfs.join("var/lib/dpkg/status")) {
+        packages.extend(parse_dpkg(&status, "/var/lib/dpkg/status"));
+    }
+    // Distroless images keep one status file per package
+    if let Ok(entries) = fs::read_dir(rootfs.join("var/lib/dpkg/status.d")) {
+        for entry in entries.flatten() {
+            let source = format!("/var/lib/dpkg/status.d/{}", entry.file_name().to_string_lossy());
+            if let Ok(status) = fs::read_to_string(entry.path()) {
+                packages.extend(parse_dpkg(&status, &source));
+            }
+        }
+    }
+    for db in ["var/lib/rpm/rpmdb.sqlite", "usr/lib/sysimage/rpm/rpmdb.sqlite"] {
+        if rootfs.join(db).is_file() {
+            packages.extend(read_rpmdb(&rootfs.join(db), &format!("/{}", db))?);
+        }
+    }
+    // An SBOM without the OS packages would look complete while missing most of the image
+    for db in ["var/lib/rpm/Packages", "var/lib/rpm/Packages.db", "usr/lib/sysimage/rpm/Packages.db"] {
+        if rootfs.join(db).is_file() {
+            return Err(anyhow::anyhow!(
+                "The image's RPM database /{} is in the Berkeley DB or NDB format, which SBOM generation cannot read",
+                db
+            ));
+        }
+    }
+
+    scan_dir(rootfs, rootfs, &mut packages)?;
+
+    Ok(Inventory {
+        distro: os_release_id(rootfs),
+        packages: packages.into_iter().collect(),
+    })
+}
+
+// Look for language lockfiles and installed Python distributions
+fn scan_dir(rootfs: &Path, dir: &Path, packages: &mut BTreeSet<Package>) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(()),
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let relative = path.strip_prefix(rootfs)?;
+        if ["proc", "sys", "dev"].iter().any(|skip| relative == Path::new(skip)) {
+            continue;
+        }
+        let source = format!("/{}", relative.display());
+        let name = entry.file_name().to_string_lossy().to_string();
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            if let Some(package) = parse_dist_info(&name, &source) {
+                packages.insert(package);
+                continue;
+            }
+            scan_dir(rootfs, &path, packages)?;
+            continue;
+        }
+        if !file_type.is_file() {
+            continue;
+        }
+
+        let parse: fn(&str, &str) -> Vec<Package> = match name.as_str() {
+            "package-lock.json" | ".package-lock.json" => parse_npm_lock,
+            "Cargo.lock" => parse_cargo_lock,
+            "requirements.txt" => parse_requirements,
+            "Gemfile.lock" => parse_gemfile_lock,
+            _ => continue,
+        };
+        if let Ok(contents) = fs::read_to_string(&path) {
+            packages.extend(parse(&contents, &source));
+        }
+    }
+    Ok(())
+}
+
+fn os_release_id(rootfs: &Path) -> Option<String> {
+    let os_release = fs::read_to_string(rootfs.join("etc/os-release"))
+        .or_else(|_| fs::read_to_string(rootfs.join("usr/lib/os-release")))
+        .ok()?;
+    os_release
+        .lines()
+        .find_map(|line| line.strip_prefix("ID="))
+        .map(|id| id.trim_matches('"').to_string())
+}
+
+fn package(ecosystem: &str, name: &str, version: &str, license: Option<&str>, source: &str) -> Package {
+    Package {
+        ecosystem: ecosystem.to_string(),
+        name: name.to_string(),
+        version: version.to_string(),
+        license: license.map(str::to_string),
+        source: source.to_string(),
+    }
+}
+
+// Stanzas of `P:name`, `V:version` and `L:license` lines, separated by blank lines
+fn parse_apk(installed: &str, source: &str) -> Vec<Package> {
+    installed
+        .split("\n\n")
+        .filter_map(|stanza| {
+            let field = |key: &str| stanza.lines().find_map(|line| line.strip_prefix(key));
+            Some(package("apk", field("P:")?, field("V:")?, field("L:"), source))
+        })
+        .collect()
+}
+
+// Paragraphs of `Field: value` lines; only installed packages count
+fn parse_dpkg(status: &str, source: &str) -> Vec<Package> {
+    status
+        .split("\n\n")
+        .filter_map(|paragraph| {
+            let field = |key: &str| {
+                paragraph
+                    .lines()
+                    .find_map(|line| line.strip_prefix(key).and_then(|rest| rest.strip_prefix(':')))
+                    .map(str::trim)
+            };
+            if field("Status").is_some_and(|status| !status.ends_with(" installed")) {
+                return None;
+            }
+            Some(package("deb", field("Package")?, field("Version")?, None, source))
+        })
+        .collect()
+}
+
+// Every package in an rpmdb.sqlite, the RPM database of Fedora 33+ and RHEL 9+
+fn read_rpmdb(path: &Path, source: &str) -> Result<Vec<Package>> {
+    // Immutable, since the database belongs to the image and nothing else has it open
+    let uri = format!("file:{}?immutable=1", path.display());
+    let flags = rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_URI;
+    let read = || -> rusqlite::Result<Vec<Vec<u8>>> {
+        let db = rusqlite::Connection::open_with_flags(&uri, flags)?;
+        let mut query = db.prepare("SELECT blob FROM Packages")?;
+        query.query_map([], |row| row.get(0))?.collect()
+    };
+    let blobs = read().map_err(|e| anyhow::anyhow!("Failed to read the RPM database {}: {}", source, e))?;
+    Ok(blobs.iter().filter_map(|blob| parse_rpm_header(blob, source)).collect())
+}
+
+// An RPM header as the database stores it: entry and data sizes, then
+// (tag, type, offset, count) index entries pointing into the data
+fn parse_rpm_header(header: &[u8], source: &str) -> Option<Package> {
+    const NAME: u32 = 1000;
+    const VERSION: u32 = 1001;
+    const RELEASE: u32 = 1002;
+    const EPOCH: u32 = 1003;
+    const LICENSE: u32 = 1014;
+    const INT32_TYPE: u32 = 4;
+    const STRING_TYPE: u32 = 6;
+
+    let be32 = |at: usize| header.get(at..at + 4).map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()));
+    let entries = be32(0)? as usize;
+    let data = header.get(8 + entries.checked_mul(16)?..)?;
+    let (mut name, mut version, mut release, mut epoch, mut license) = (None, None, None, None, None);
+
+    for entry in 0..entries {
+        let at = 8 + entry * 16;
+        let (tag, kind, offset) = (be32(at)?, be32(at + 4)?, be32(at + 8)? as usize);
+        let value = data.get(offset..)?;
+        match (tag, kind) {
+            (NAME | VERSION | RELEASE | LICENSE, STRING_TYPE) => {
+                let end = value.iter().position(|&b| b == 0)?;
+                let text = std::str::from_utf8(&value[..end]).ok()?;
+                match tag {
+                    NAME => name = Some(text),
+                    VERSION => version = Some(text),
+                    RELEASE => release = Some(text),
+                    _ => license = Some(text),
+                }
+            }
+            (EPOCH, INT32_TYPE) => epoch = Some(u32::from_be_bytes(value.get(..4)?.try_into().ok()?)),
+            _ => {}
+        }
+    }
+
+    // Imported signing keys are recorded as gpg-pubkey packages
+    let name = name.filter(|name| *name != "gpg-pubkey")?;
+    let version = match (epoch, release) {
+        (Some(epoch), Some(release)) => format!("{}:{}-{}", epoch, version?, release),
+        (None, Some(release)) => format!("{}-{}", version?, release),
+        (Some(epoch), None) => format!("{}:{}", epoch, version?),
+        (None, None) => version?.to_string(),
+    };
+    Some(package("rpm", name, &version, license, source))
+}
+
+fn parse_npm_lock(lock: &str, source: &str) -> Vec<Package> {
+    let Ok(lock) = serde_json::from_str::<Value>(lock) else {
+        return Vec::new();
+    };
+    let mut packages = Vec::new();
+    // lockfileVersion 2 and 3 key packages by their node_modules path
+    if let Some(entries) = lock.get("packages").and_then(Value::as_object) {
+        for (path, entry) in entries {
+            let Some(name) = path.rsplit("node_modules/").next().filter(|_| path.contains("node_modules/")) else {
+                continue;
+            };
+            if let Some(version) = entry.get("version").and_then(Value::as_str) {
+                let license = entry.get("license").and_then(Value::as_str);
+                packages.push(package("npm", name, version, license, source));
+            }
+        }
+    } else if let Some(entries) = lock.get("dependencies").and_then(Value::as_object) {
+        for (name, entry) in entries {
+            if let Some(version) = entry.get("version").and_then(Value::as_str) {
+                packages.push(package("npm", name, version, None, source));
+            }
+        }
+    }
+    packages
+}
+
+fn parse_cargo_lock(lock: &str, source: &str) -> Vec<Package> {
+    lock.split("[[package]]")
+        .skip(1)
+        .filter_map(|entry| {
+            let field = |key: &str| {
+                entry.lines().find_map(|line| {
+                    let (k, v) = line.split_once('=')?;
+                    (k.trim() == key).then(|| v.trim().trim_matches('"'))
+                })
+            };
+            Some(package("cargo", field("name")?, field("version")?, None, source))
+        })
+        .collect()
+}
+
+// Only pinned `name==version` requirements name a concrete package
+fn parse_requirements(requirements: &str, source: &str) -> Vec<Package> {
+    requirements
+        .lines()
+        .filter_map(|line| {
+            let line = line.split('#').next()?.split(';').next()?.trim();
+            let (name, version) = line.split_once("==")?;
+            let name = name.split('[').next()?.trim();
+            Some(package("pypi", &name.to_lowercase(), version.trim(), None, source))
+        })
+        .collect()
+}
+
+// site-packages/<name>-<version>.dist-info
+fn parse_dist_info(dir_name: &str, source: &str) -> Option<Package> {
+    let stem = dir_name.strip_suffix(".dist-info")?;
+    let (name, version) = stem.rsplit_once('-')?;
+    Some(package("pypi", &name.to_lowercase().replace('_', "-"), version, None, source))
+}
+
+// The `specs:` lines of a Gemfile.lock, indented four spaces: `name (version)`
+fn parse_gemfile_lock(lock: &str, source: &str) -> Vec<Package> {
+    lock.lines()
+        .filter_map(|line| {
+            let spec = line.strip_prefix("    ")?;
+            if spec.starts_with(' ') {
+                return None;
+            }
+            let (name, version) = spec.split_once(" (")?;
+            let version = version.strip_suffix(')')?;
+            Some(package("gem", name, version, None, source))
+        })
+        .collect()
+}
+
+// An SPDX 2.3 or CycloneDX 1.5 JSON document for the image. Identifiers are
+// derived from the contents, so reproducible builds produce the same SBOM.
+pub fn render(format: SbomFormat, image_name: &str, created: &str, inventory: &Inventory) -> Result<Vec<u8>> {
+    let purls: Vec<String> = inventory
+        .packages
+        .iter()
+        .map(|package| package.purl(inventory.distro.as_deref()))
+        .collect();
+    let fingerprint = {
+        use sha2::{Digest, Sha256};
+        let mut hasher = Sha256::new();
+        hasher.update(image_name);
+        for purl in &purls {
+            hasher.update(purl);
+        }
+        hasher.finalize()
+    };
+    let mut uuid_bytes = [0u8; 16];
+    uuid_bytes.copy_from_slice(&fingerprint[..16]);
+    let uuid = uuid::Builder::from_random_bytes(uuid_bytes).into_uuid();
+
+    let document = match format {
+        SbomFormat::Spdx => {
+            let packages: Vec<Value> = inventory
+                .packages
+                .iter()
+                .zip(&purls)
+                .enumerate()
+                .map(|(i, (package, purl))| {
+                    json!({
+                        "name": package.name,
+                        "SPDXID": format!("SPDXRef-Package-{}", i + 1),
+                        "versionInfo": package.version,
+                        "downloadLocation": "NOASSERTION",
+                        "licenseDeclared": package.license.as_deref().unwrap_or("NOASSERTION"),
+                        "sourceInfo": format!("found in {}", package.source),
+                        "externalRefs": [{
+                            "referenceCategory": "PACKAGE-MANAGER",
+                            "referenceType": "purl",
+                            "referenceLocator": purl,
+                        }],
+                    })
+                })
+                .collect();
+            json!({
+                "spdxVersion": "SPDX-2.3",
+                "dataLicense": "CC0-1.0",
+                "SPDXID": "SPDXRef-DOCUMENT",
+                "name": image_name,
+                "documentNamespace": format!("https://hyperbuild.dev/spdx/{}", uuid),
+                "creationInfo": {
+                    "created": created,
+                    "creators": ["Tool: hyperbuild"],
+                },
+                "packages": packages,
+            })
+        }
+        SbomFormat::CycloneDx => {
+            let components: Vec<Value> = inventory
+                .packages
+                .iter()
+                .zip(&purls)
+                .map(|(package, purl)| {
+                    let mut component = json!({
+                        "type": "library",
+                        "name": package.name,
+                        "version": package.version,
+                        "purl": purl,
+                    });
+                    if let Some(license) = &package.license {
+                        component["licenses"] = json!([{ "license": { "name": license } }]);
+                    }
+                    component
+                })
+                .collect();
+            json!({
+                "bomFormat": "CycloneDX",
+                "specVersion": "1.5",
+                "serialNumber": format!("urn:uuid:{}", uuid),
+                "version": 1,
+                "metadata": {
+                    "timestamp": created,
+                    "tools": [{ "name": "hyperbuild" }],
+                    "component": { "type": "container", "name": image_name },
+                },
+                "components": components,
+            })
+        }
+    };
+    Ok(serde_json::to_vec_pretty(&document)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_os_databases() {
+        let apk = "P:musl\nV:1.2.4-r2\nL:MIT\n\nP:busybox\nV:1.36.1-r5\n";
+        let packages = parse_apk(apk, "/lib/apk/db/installed");
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[0].purl(Some("alpine")), "pkg:apk/alpine/musl@1.2.4-r2");
+        assert_eq!(packages[0].license.as_deref(), Some("MIT"));
+
+        let dpkg = "Package: bash\nStatus: install ok installed\nVersion: 5.2-2\n\n\
+                    Package: old\nStatus: deinstall ok config-files\nVersion: 1.0\n";
+        let packages = parse_dpkg(dpkg, "/var/lib/dpkg/status");
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].purl(Some("debian")), "pkg:deb/debian/bash@5.2-2");
+    }
+
+    // A header with the given string and int32 entries, in the rpmdb layout
+    fn rpm_header(strings: &[(u32, &str)], ints: &[(u32, u32)]) -> Vec<u8> {
+        let (mut index, mut data) = (Vec::new(), Vec::new());
+        for (tag, value) in ints {
+            index.extend([*tag, 4, data.len() as u32, 1].iter().flat_map(|v| v.to_be_bytes()));
+            data.extend(value.to_be_bytes());
+        }
+        for (tag, value) in strings {
+            index.extend([*tag, 6, data.len() as u32, 1].iter().flat_map(|v| v.to_be_bytes()));
+            data.extend(value.as_bytes());
+            data.push(0);
+        }
+        let mut header = ((strings.len() + ints.len()) as u32).to_be_bytes().to_vec();
+        header.extend((data.len() as u32).to_be_bytes());
+        header.extend(index);
+        header.extend(data);
+        header
+    }
+
+    #[test]
+    fn test_read_rpmdb() {
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(rootfs.path().join("var/lib/rpm")).unwrap();
+        let db = rusqlite::Connection::open(rootfs.path().join("var/lib/rpm/rpmdb.sqlite")).unwrap();
+        db.execute("CREATE TABLE Packages (hnum INTEGER PRIMARY KEY AUTOINCREMENT, blob BLOB NOT NULL)", [])
+            .unwrap();
+        let bash = rpm_header(&[(1000, "bash"), (1001, "5.2.26"), (1002, "3.fc40"), (1014, "GPL-3.0-or-later")], &[]);
+        let shadow = rpm_header(&[(1000, "shadow-utils"), (1001, "4.15.1"), (1002, "2.fc40")], &[(1003, 2)]);
+        let key = rpm_header(&[(1000, "gpg-pubkey"), (1001, "a15b79cc"), (1002, "63d04c2c")], &[]);
+        for blob in [bash, shadow, key] {
+            db.execute("INSERT INTO Packages (blob) VALUES (?1)", [blob]).unwrap();
+        }
+        drop(db);
+
+        let packages = scan(rootfs.path()).unwrap().packages;
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[0].purl(Some("fedora")), "pkg:rpm/fedora/bash@5.2.26-3.fc40");
+        assert_eq!(packages[0].license.as_deref(), Some("GPL-3.0-or-later"));
+        assert_eq!(packages[1].purl(Some("fedora")), "pkg:rpm/fedora/shadow-utils@4.15.1-2.fc40?epoch=2");
+
+        // A database in a format that is not read fails the scan rather than leaving packages out
+        std::fs::write(rootfs.path().join("var/lib/rpm/Packages"), b"").unwrap();
+        assert!(scan(rootfs.path()).unwrap_err().to_string().contains("/var/lib/rpm/Packages"));
+    }
+
+    #[test]
+    fn test_parse_lockfiles() {
+        let npm = r#"{"lockfileVersion": 3, "packages": {"": {"name": "app"},
+            "node_modules/@scope/lib": {"version": "2.0.0", "license": "ISC"}}}"#;
+        let packages = parse_npm_lock(npm, "/app/package-lock.json");
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].purl(None), "pkg:npm/@scope/lib@2.0.0");
+
+        let cargo = "version = 3\n\n[[package]]\nname = \"serde\"\nversion = \"1.0.0\"\n";
+        assert_eq!(parse_cargo_lock(cargo, "/src/Cargo.lock")[0].version, "1.0.0");
+
+        let requirements = "Flask[async]==3.0.0  # web\nrequests>=2\n";
+        let packages = parse_requirements(requirements, "/requirements.txt");
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].name, "flask");
+
+        let gems = "GEM\n  specs:\n    rack (3.0.8)\n      base64 (>= 0)\n";
+        assert_eq!(parse_gemfile_lock(gems, "/Gemfile.lock").len(), 1);
+    }
+
+    #[test]
+    fn test_render_is_deterministic() {
+        let inventory = Inventory {
+            distro: Some("alpine".to_string()),
+            packages: parse_apk("P:musl\nV:1.2.4-r2\n", "/lib/apk/db/installed"),
+        };
+        let spdx = render(SbomFormat::Spdx, "app", "2024-01-01T00:00:00Z", &inventory).unwrap();
+        assert_eq!(spdx, render(SbomFormat::Spdx, "app", "2024-01-01T00:00:00Z", &inventory).unwrap());
+
+        let cyclonedx: Value =
+            serde_json::from_slice(&render(SbomFormat::CycloneDx, "app", "2024-01-01T00:00:00Z", &inventory).unwrap())
+                .unwrap();
+        assert_eq!(cyclonedx["components"][0]["purl"], "pkg:apk/alpine/musl@1.2.4-r2");
+    }
+}
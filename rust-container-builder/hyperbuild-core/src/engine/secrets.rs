@@ -0,0 +1,80 @@
+use super::ephemeral::EphemeralFiles;
+use crate::dockerfile::RunMount;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+// Check that every required secret was provided before anything runs
+pub fn validate(secrets: &HashMap<String, PathBuf>, mounts: &[RunMount]) -> Result<()> {
+    for mount in mounts {
+        if let RunMount::Secret { id, required: true, .. } = mount
+            && !secrets.contains_key(id)
+        {
+            return Err(anyhow::anyhow!("Secret {} is required but was not provided (use --secret id={},src=...)", id, id));
+        }
+    }
+    Ok(())
+}
+
+// Place the secrets a RUN step mounts; they are removed with the step's other ephemeral files
+pub fn mount(secrets: &HashMap<String, PathBuf>, mounts: &[RunMount], files: &mut EphemeralFiles) -> Result<()> {
+    validate(secrets, mounts)?;
+
+    for mount in mounts {
+        let RunMount::Secret { id, target, .. } = mount else {
+            continue;
+        };
+        let Some(source) = secrets.get(id) else {
+            tracing::warn!("Secret {} was not provided, skipping mount", id);
+            continue;
+        };
+
+        let target = target.clone().unwrap_or_else(|| format!("/run/secrets/{}", id));
+        let contents = std::fs::read(source).map_err(|e| anyhow::anyhow!("Failed to mount secret {:?}: {}", source, e))?;
+        files.place(&target, &contents, 0o400)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::snapshot::Snapshot;
+    use std::fs;
+
+    #[test]
+    fn test_unmount_leaves_no_changes() {
+        let rootfs = tempfile::tempdir().unwrap();
+        fs::create_dir_all(rootfs.path().join("run")).unwrap();
+        let secret = tempfile::NamedTempFile::new().unwrap();
+        fs::write(secret.path(), "token").unwrap();
+
+        let secrets = HashMap::from([("token".to_string(), secret.path().to_path_buf())]);
+        let mounts = vec![RunMount::Secret {
+            id: "token".to_string(),
+            target: None,
+            required: true,
+        }];
+
+        let before = Snapshot::capture(rootfs.path()).unwrap();
+        let mut files = EphemeralFiles::new(rootfs.path());
+        mount(&secrets, &mounts, &mut files).unwrap();
+        let secret_path = rootfs.path().join("run/secrets/token");
+        assert_eq!(fs::read_to_string(&secret_path).unwrap(), "token");
+
+        files.remove().unwrap();
+        let after = Snapshot::capture(rootfs.path()).unwrap();
+        assert!(before.changes(&after).is_empty());
+    }
+
+    #[test]
+    fn test_missing_required_secret() {
+        let mounts = vec![RunMount::Secret {
+            id: "npmrc".to_string(),
+            target: None,
+            required: true,
+        }];
+        assert!(validate(&HashMap::new(), &mounts).is_err());
+    }
+}
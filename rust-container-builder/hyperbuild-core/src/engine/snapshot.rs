@@ -0,0 +1,363 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+pub const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+// Metadata used to decide whether an entry changed between two snapshots
+#[derive(Debug, Clone, PartialEq)]
+struct EntryMeta {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    link_target: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    // Added or modified entry, relative to the root
+    Upsert(PathBuf),
+    Delete(PathBuf),
+}
+
+// Point-in-time listing of a rootfs, compared before and after a step to
+// find what the step changed.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    entries: BTreeMap<PathBuf, EntryMeta>,
+}
+
+impl Snapshot {
+    pub fn capture(root: &Path) -> Result<Self> {
+        let mut entries = BTreeMap::new();
+        if root.exists() {
+            walk(root, root, &mut entries)?;
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn changes(&self, after: &Snapshot) -> Vec<Change> {
+        let mut changes = Vec::new();
+
+        for (path, meta) in &after.entries {
+            if self.entries.get(path) != Some(meta) {
+                changes.push(Change::Upsert(path.clone()));
+            }
+        }
+
+        // A deleted directory is covered by a single whiteout for itself
+        let mut deleted_dirs: Vec<&PathBuf> = Vec::new();
+        for path in self.entries.keys() {
+            if after.entries.contains_key(path) || deleted_dirs.iter().any(|dir| path.starts_with(dir)) {
+                continue;
+            }
+            deleted_dirs.push(path);
+            changes.push(Change::Delete(path.clone()));
+        }
+
+        changes
+    }
+}
+
+fn walk(root: &Path, dir: &Path, entries: &mut BTreeMap<PathBuf, EntryMeta>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let metadata = fs::symlink_metadata(&path)?;
+        let link_target = if metadata.file_type().is_symlink() {
+            Some(fs::read_link(&path)?)
+        } else {
+            None
+        };
+
+        let relative = path.strip_prefix(root)?.to_path_buf();
+        entries.insert(
+            relative,
+            EntryMeta {
+                mode: metadata.mode(),
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                // Directory sizes depend on the filesystem, not the contents
+                size: if metadata.is_dir() { 0 } else { metadata.len() },
+                mtime: metadata.mtime(),
+                mtime_nsec: metadata.mtime_nsec(),
+                link_target,
+            },
+        );
+
+        if metadata.is_dir() {
+            walk(root, &path, entries)?;
+        }
+    }
+    Ok(())
+}
+
+// Write the given changes as an uncompressed layer tar. Deletions become
+// whiteout entries as described by the OCI image layer spec. Entries are
+// written in path order, and with `source_date_epoch` set no mtime is later
+// than it and every entry is owned by root, so identical changes always give
+// an identical tar whoever builds them.
+#[cfg(test)]
+pub fn build_layer_tar(root: &Path, changes: &[Change], source_date_epoch: Option<u64>) -> Result<Vec<u8>> {
+    write_layer_tar(Vec::new(), root, changes, source_date_epoch)
+}
+
+// Like build_layer_tar, but writes the tar to `writer`, e.g. a file, instead of memory
+pub fn write_layer_tar<W: Write>(
+    writer: W,
+    root: &Path,
+    changes: &[Change],
+    source_date_epoch: Option<u64>,
+) -> Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+
+    let mut changes: Vec<&Change> = changes.iter().collect();
+    changes.sort_by_key(|change| match change {
+        Change::Upsert(path) | Change::Delete(path) => path.clone(),
+    });
+
+    for change in changes {
+        match change {
+            Change::Upsert(path) => {
+                let full_path = root.join(path);
+                let metadata = fs::symlink_metadata(&full_path)?;
+                let mut header = tar::Header::new_gnu();
+                header.set_metadata_in_mode(&metadata, tar::HeaderMode::Complete);
+                if let Some(epoch) = source_date_epoch {
+                    if header.mtime()? > epoch {
+                        header.set_mtime(epoch);
+                    }
+                    header.set_uid(0);
+                    header.set_gid(0);
+                    header.set_username("")?;
+                    header.set_groupname("")?;
+                }
+
+                if metadata.file_type().is_symlink() {
+                    builder.append_link(&mut header, path, fs::read_link(&full_path)?)?;
+                } else if metadata.is_file() {
+                    builder.append_data(&mut header, path, fs::File::open(&full_path)?)?;
+                } else {
+                    builder.append_data(&mut header, path, std::io::empty())?;
+                }
+            }
+            Change::Delete(path) => {
+                let name = path
+                    .file_name()
+                    .ok_or_else(|| anyhow::anyhow!("Cannot whiteout {:?}", path))?;
+                let whiteout = path.with_file_name(format!("{}{}", WHITEOUT_PREFIX, name.to_string_lossy()));
+
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(0);
+                header.set_mode(0o644);
+                header.set_mtime(source_date_epoch.unwrap_or(0));
+                builder.append_data(&mut header, whiteout, std::io::empty())?;
+            }
+        }
+    }
+
+    Ok(builder.into_inner()?)
+}
+
+// Apply an uncompressed layer tar on top of `root`, honouring whiteouts
+pub fn apply_layer<R: Read>(reader: R, root: &Path) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    archive.set_preserve_mtime(true);
+    archive.set_overwrite(true);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_path_buf();
+        let file_name = path.file_name().map(|n| n.to_string_lossy().to_string());
+
+        if let Some(name) = file_name.as_deref()
+            && name.starts_with(WHITEOUT_PREFIX)
+        {
+            let parent = root.join(normalize(path.parent().unwrap_or(Path::new(""))));
+            if name == OPAQUE_WHITEOUT {
+                // Opaque directory: hide everything the lower layers put there
+                if parent.is_dir() {
+                    for child in fs::read_dir(&parent)? {
+                        remove_path(&child?.path())?;
+                    }
+                }
+            } else {
+                remove_path(&parent.join(&name[WHITEOUT_PREFIX.len()..]))?;
+            }
+            continue;
+        }
+
+        // Replacing a directory with a file (or vice versa) needs the old entry gone
+        let target = root.join(normalize(&path));
+        if let Ok(existing) = fs::symlink_metadata(&target)
+            && (existing.is_dir() != entry.header().entry_type().is_dir())
+        {
+            remove_path(&target)?;
+        }
+
+        entry.unpack_in(root)?;
+    }
+
+    Ok(())
+}
+
+fn remove_path(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+// Copy `src` to `dest`, recursing into directories and preserving symlinks,
+// permissions and (when possible) ownership.
+pub fn copy_tree(src: &Path, dest: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(src)
+        .map_err(|e| anyhow::anyhow!("Failed to read {:?}: {}", src, e))?;
+
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if metadata.file_type().is_symlink() {
+        remove_path(dest)?;
+        std::os::unix::fs::symlink(fs::read_link(src)?, dest)?;
+    } else if metadata.is_dir() {
+        fs::create_dir_all(dest)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &dest.join(entry.file_name()))?;
+        }
+        fs::set_permissions(dest, fs::Permissions::from_mode(metadata.mode()))?;
+    } else {
+        if fs::symlink_metadata(dest).is_ok_and(|m| m.is_dir() || m.file_type().is_symlink()) {
+            remove_path(dest)?;
+        }
+        fs::copy(src, dest)?;
+    }
+
+    // Ownership can only be preserved when running as root
+    let _ = std::os::unix::fs::lchown(dest, Some(metadata.uid()), Some(metadata.gid()));
+    Ok(())
+}
+
+// Give `path` and everything below it the given owner, without following symlinks
+pub fn chown_tree(path: &Path, uid: u32, gid: u32) -> Result<()> {
+    std::os::unix::fs::lchown(path, Some(uid), Some(gid))?;
+    if fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            chown_tree(&entry?.path(), uid, gid)?;
+        }
+    }
+    Ok(())
+}
+
+// Resolve a path inside a rootfs without letting `..` escape it
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => {}
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changes_and_apply_roundtrip() {
+        let lower = tempfile::tempdir().unwrap();
+        fs::create_dir_all(lower.path().join("etc/conf.d")).unwrap();
+        fs::write(lower.path().join("etc/conf.d/a"), "a").unwrap();
+        fs::write(lower.path().join("etc/keep"), "keep").unwrap();
+
+        let before = Snapshot::capture(lower.path()).unwrap();
+        fs::remove_dir_all(lower.path().join("etc/conf.d")).unwrap();
+        fs::write(lower.path().join("etc/new"), "new").unwrap();
+        let after = Snapshot::capture(lower.path()).unwrap();
+
+        let changes = before.changes(&after);
+        assert!(changes.contains(&Change::Delete(PathBuf::from("etc/conf.d"))));
+        assert!(changes.contains(&Change::Upsert(PathBuf::from("etc/new"))));
+        assert!(!changes.contains(&Change::Delete(PathBuf::from("etc/conf.d/a"))));
+
+        // Replaying the layer on a copy of the original tree gives the same result
+        let layer = build_layer_tar(lower.path(), &changes, None).unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        fs::create_dir_all(upper.path().join("etc/conf.d")).unwrap();
+        fs::write(upper.path().join("etc/conf.d/a"), "a").unwrap();
+        apply_layer(layer.as_slice(), upper.path()).unwrap();
+
+        assert!(!upper.path().join("etc/conf.d").exists());
+        assert_eq!(fs::read_to_string(upper.path().join("etc/new")).unwrap(), "new");
+    }
+
+    #[test]
+    fn test_normalize_stays_inside_root() {
+        assert_eq!(normalize(Path::new("/../../etc/passwd")), PathBuf::from("etc/passwd"));
+        assert_eq!(normalize(Path::new("app/./bin/../lib")), PathBuf::from("app/lib"));
+    }
+
+    #[test]
+    fn test_layer_tar_clamps_mtimes() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("b"), "b").unwrap();
+        fs::write(root.path().join("a"), "a").unwrap();
+        let changes = vec![Change::Upsert(PathBuf::from("b")), Change::Upsert(PathBuf::from("a"))];
+
+        let layer = build_layer_tar(root.path(), &changes, Some(1_000)).unwrap();
+        let mut archive = tar::Archive::new(layer.as_slice());
+        let entries: Vec<(PathBuf, u64)> = archive
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let e = e.unwrap();
+                (e.path().unwrap().to_path_buf(), e.header().mtime().unwrap())
+            })
+            .collect();
+        assert_eq!(entries, vec![(PathBuf::from("a"), 1_000), (PathBuf::from("b"), 1_000)]);
+    }
+
+    #[test]
+    fn test_reproducible_layer_tar_drops_owners() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("owned");
+        fs::write(&path, "data").unwrap();
+        // Builds as root chown; other users' files are theirs already
+        let _ = std::os::unix::fs::chown(&path, Some(1000), Some(1000));
+        let uid = fs::metadata(&path).unwrap().uid();
+        assert_ne!(uid, 0);
+        let changes = vec![Change::Upsert(PathBuf::from("owned"))];
+
+        let owner = |layer: Vec<u8>| {
+            let mut archive = tar::Archive::new(layer.as_slice());
+            let entry = archive.entries().unwrap().next().unwrap().unwrap();
+            let header = entry.header();
+            let name = |name: Option<&[u8]>| String::from_utf8_lossy(name.unwrap_or_default()).to_string();
+            (header.uid().unwrap(), header.gid().unwrap(), name(header.username_bytes()), name(header.groupname_bytes()))
+        };
+        assert_eq!(owner(build_layer_tar(root.path(), &changes, None).unwrap()).0, uid as u64);
+        let reproducible = owner(build_layer_tar(root.path(), &changes, Some(1_000)).unwrap());
+        assert_eq!(reproducible, (0, 0, String::new(), String::new()));
+    }
+}
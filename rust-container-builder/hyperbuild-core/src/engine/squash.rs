@@ -0,0 +1,155 @@
+use super::snapshot::{self, OPAQUE_WHITEOUT, WHITEOUT_PREFIX};
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+// The newest state of one path across the squashed layers
+enum Entry {
+    File {
+        header: Box<tar::Header>,
+        data: Vec<u8>,
+        link: Option<PathBuf>,
+    },
+    Whiteout,
+}
+
+// Merge uncompressed layer tars, lowest first, into a single layer with the
+// same effect on the layers below them. Files keep the header of the layer
+// that last wrote them; deletions of lower content remain as whiteouts.
+pub fn squash_layers<R: Read>(layers: Vec<R>) -> Result<Vec<u8>> {
+    let mut entries: BTreeMap<PathBuf, Entry> = BTreeMap::new();
+    // Directories whose lower-layer contents must stay hidden
+    let mut opaque: BTreeSet<PathBuf> = BTreeSet::new();
+
+    for layer in layers {
+        let mut archive = tar::Archive::new(layer);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = snapshot::normalize(&entry.path()?);
+            let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+            let parent = path.parent().unwrap_or(Path::new("")).to_path_buf();
+
+            if name == OPAQUE_WHITEOUT {
+                remove_below(&mut entries, &mut opaque, &parent);
+                opaque.insert(parent);
+                continue;
+            }
+            if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                let target = parent.join(hidden);
+                remove_below(&mut entries, &mut opaque, &target);
+                entries.insert(target, Entry::Whiteout);
+                continue;
+            }
+
+            let is_dir = entry.header().entry_type().is_dir();
+            match entries.get(&path) {
+                // A directory recreated after a deletion must not expose the old contents
+                Some(Entry::Whiteout) if is_dir => {
+                    opaque.insert(path.clone());
+                }
+                Some(Entry::File { header, .. }) if header.entry_type().is_dir() && !is_dir => {
+                    remove_below(&mut entries, &mut opaque, &path);
+                }
+                _ => {}
+            }
+
+            let link = entry.link_name()?.map(|link| link.to_path_buf());
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            entries.insert(
+                path,
+                Entry::File {
+                    header: Box::new(entry.header().clone()),
+                    data,
+                    link,
+                },
+            );
+        }
+    }
+
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, entry) in entries {
+        match entry {
+            Entry::File { mut header, data, link } => {
+                match link {
+                    Some(link) => builder.append_link(&mut header, &path, link)?,
+                    None => builder.append_data(&mut header, &path, data.as_slice())?,
+                }
+                if opaque.contains(&path) {
+                    append_marker(&mut builder, path.join(OPAQUE_WHITEOUT))?;
+                }
+            }
+            Entry::Whiteout => {
+                let name = path
+                    .file_name()
+                    .ok_or_else(|| anyhow::anyhow!("Cannot whiteout {:?}", path))?;
+                let whiteout = path.with_file_name(format!("{}{}", WHITEOUT_PREFIX, name.to_string_lossy()));
+                append_marker(&mut builder, whiteout)?;
+            }
+        }
+    }
+
+    Ok(builder.into_inner()?)
+}
+
+// Forget everything recorded strictly below `dir`
+fn remove_below(entries: &mut BTreeMap<PathBuf, Entry>, opaque: &mut BTreeSet<PathBuf>, dir: &Path) {
+    entries.retain(|path, _| path == dir || !path.starts_with(dir));
+    opaque.retain(|path| path == dir || !path.starts_with(dir));
+}
+
+fn append_marker(builder: &mut tar::Builder<Vec<u8>>, path: PathBuf) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(0);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    builder.append_data(&mut header, path, std::io::empty())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_squash_matches_applying_layers_in_order() {
+        let root = tempfile::tempdir().unwrap();
+        let mut layers = Vec::new();
+
+        // Layer 1 adds files, layer 2 deletes and recreates a directory, layer 3 edits a file
+        let before = snapshot::Snapshot::capture(root.path()).unwrap();
+        fs::create_dir_all(root.path().join("app/cache")).unwrap();
+        fs::write(root.path().join("app/cache/old"), "old").unwrap();
+        fs::write(root.path().join("app/main"), "v1").unwrap();
+        let after = snapshot::Snapshot::capture(root.path()).unwrap();
+        layers.push(snapshot::build_layer_tar(root.path(), &before.changes(&after), None).unwrap());
+
+        let before = after;
+        fs::remove_dir_all(root.path().join("app/cache")).unwrap();
+        let after = snapshot::Snapshot::capture(root.path()).unwrap();
+        layers.push(snapshot::build_layer_tar(root.path(), &before.changes(&after), None).unwrap());
+
+        let before = after;
+        fs::create_dir_all(root.path().join("app/cache")).unwrap();
+        fs::write(root.path().join("app/cache/new"), "new").unwrap();
+        fs::write(root.path().join("app/main"), "v2").unwrap();
+        let after = snapshot::Snapshot::capture(root.path()).unwrap();
+        layers.push(snapshot::build_layer_tar(root.path(), &before.changes(&after), None).unwrap());
+
+        let squashed = squash_layers(layers.iter().map(|l| l.as_slice()).collect()).unwrap();
+
+        // A lower layer with stale content is hidden by the squashed layer
+        let target = tempfile::tempdir().unwrap();
+        fs::create_dir_all(target.path().join("app/cache")).unwrap();
+        fs::write(target.path().join("app/cache/lower"), "lower").unwrap();
+        snapshot::apply_layer(squashed.as_slice(), target.path()).unwrap();
+
+        assert_eq!(fs::read_to_string(target.path().join("app/main")).unwrap(), "v2");
+        assert_eq!(fs::read_to_string(target.path().join("app/cache/new")).unwrap(), "new");
+        assert!(!target.path().join("app/cache/old").exists());
+        assert!(!target.path().join("app/cache/lower").exists());
+    }
+}
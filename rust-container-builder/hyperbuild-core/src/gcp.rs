@@ -0,0 +1,224 @@
+use crate::registry_auth::Credentials;
+use anyhow::Result;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Google Artifact Registry (<region>-docker.pkg.dev) and Container Registry
+// (gcr.io and its regional hosts) take an OAuth2 access token as the
+// password of the user oauth2accesstoken. Tokens come from the application
+// default credentials: $GOOGLE_APPLICATION_CREDENTIALS, the file `gcloud
+// auth application-default login` writes, or the metadata server on GCP.
+
+const SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+const TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+const METADATA_HOST: &str = "metadata.google.internal";
+
+// Whether a registry host is one of Google's
+pub fn is_registry(host: &str) -> bool {
+    let host = host.split(':').next().unwrap_or(host);
+    host == "gcr.io" || host.ends_with(".gcr.io") || host.ends_with("-docker.pkg.dev")
+}
+
+// A credentials file, as `gcloud` or the IAM console writes it
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum CredentialsFile {
+    // A service account key
+    ServiceAccount {
+        client_email: String,
+        private_key: String,
+        private_key_id: Option<String>,
+        token_uri: Option<String>,
+    },
+    // A user's login, from `gcloud auth application-default login`
+    AuthorizedUser {
+        client_id: String,
+        client_secret: String,
+        refresh_token: String,
+    },
+    // Workload identity federation and the like
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    // Seconds; Google's tokens last an hour
+    expires_in: Option<u64>,
+}
+
+// An access token for the registry, as its credentials, and how long it is valid
+pub async fn login(client: &reqwest::Client) -> Result<(Credentials, Duration)> {
+    let token = match credentials_file()? {
+        Some((path, contents)) => {
+            let file: CredentialsFile = serde_json::from_slice(&contents)
+                .map_err(|e| anyhow::anyhow!("Invalid Google credentials file {:?}: {}", path, e))?;
+            let request = match file {
+                CredentialsFile::ServiceAccount {
+                    client_email,
+                    private_key,
+                    private_key_id,
+                    token_uri,
+                } => {
+                    let token_uri = token_uri.unwrap_or_else(|| TOKEN_URI.to_string());
+                    let now = chrono::Utc::now().timestamp();
+                    let assertion =
+                        assertion(&client_email, &private_key, private_key_id.as_deref(), &token_uri, now)?;
+                    client.post(&token_uri).form(&[
+                        ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                        ("assertion", &assertion),
+                    ])
+                }
+                CredentialsFile::AuthorizedUser {
+                    client_id,
+                    client_secret,
+                    refresh_token,
+                } => client.post(TOKEN_URI).form(&[
+                    ("grant_type", "refresh_token"),
+                    ("client_id", &client_id),
+                    ("client_secret", &client_secret),
+                    ("refresh_token", &refresh_token),
+                ]),
+                CredentialsFile::Other => {
+                    return Err(anyhow::anyhow!(
+                        "Google credentials file {:?} is neither a service account key nor a user login; use one of \
+                         those, or docker login with an access token",
+                        path
+                    ));
+                }
+            };
+            fetch_token(request, &format!("{:?}", path)).await?
+        }
+        None => {
+            // On GCP the instance's or workload's service account gives tokens
+            let host = std::env::var("GCE_METADATA_HOST").unwrap_or_else(|_| METADATA_HOST.to_string());
+            let url = format!("http://{}/computeMetadata/v1/instance/service-accounts/default/token", host);
+            let metadata = reqwest::Client::builder()
+                .user_agent(crate::registry_client::USER_AGENT)
+                .no_proxy()
+                .connect_timeout(Duration::from_secs(1))
+                .timeout(Duration::from_secs(5))
+                .build()?;
+            let request = metadata.get(&url).header("metadata-flavor", "Google");
+            fetch_token(request, "the metadata server").await.map_err(|e| {
+                anyhow::anyhow!(
+                    "No Google credentials found: set GOOGLE_APPLICATION_CREDENTIALS to a service account key, run \
+                     gcloud auth application-default login, or run on GCP ({})",
+                    e
+                )
+            })?
+        }
+    };
+    tracing::info!("Got a Google access token for the registry");
+    let credentials = Credentials {
+        username: "oauth2accesstoken".to_string(),
+        password: token.access_token,
+    };
+    Ok((credentials, Duration::from_secs(token.expires_in.unwrap_or(3600))))
+}
+
+// $GOOGLE_APPLICATION_CREDENTIALS, else gcloud's application default
+// credentials, if they exist
+fn credentials_file() -> Result<Option<(PathBuf, Vec<u8>)>> {
+    if let Some(path) = std::env::var_os("GOOGLE_APPLICATION_CREDENTIALS") {
+        let path = PathBuf::from(path);
+        let contents = std::fs::read(&path).map_err(|e| anyhow::anyhow!("Failed to read {:?}: {}", path, e))?;
+        return Ok(Some((path, contents)));
+    }
+    let config = std::env::var_os("CLOUDSDK_CONFIG")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/gcloud")));
+    let Some(path) = config.map(|config| config.join("application_default_credentials.json")) else {
+        return Ok(None);
+    };
+    match std::fs::read(&path) {
+        Ok(contents) => Ok(Some((path, contents))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow::anyhow!("Failed to read {:?}: {}", path, e)),
+    }
+}
+
+async fn fetch_token(request: reqwest::RequestBuilder, source: &str) -> Result<TokenResponse> {
+    let response = request.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+        return Err(anyhow::anyhow!("Failed to get an access token from {}: {} - {}", source, status, error_text));
+    }
+    serde_json::from_slice(&response.bytes().await?)
+        .map_err(|e| anyhow::anyhow!("Invalid access token response from {}: {}", source, e))
+}
+
+// The JWT a service account key signs to ask for an access token, issued at `now`
+fn assertion(email: &str, private_key: &str, key_id: Option<&str>, audience: &str, now: i64) -> Result<String> {
+    let mut header = serde_json::json!({ "alg": "RS256", "typ": "JWT" });
+    if let Some(key_id) = key_id {
+        header["kid"] = key_id.into();
+    }
+    let claims = serde_json::json!({
+        "iss": email,
+        "scope": SCOPE,
+        "aud": audience,
+        "iat": now,
+        "exp": now + 3600,
+    });
+    let signed = format!(
+        "{}.{}",
+        base64url(&serde_json::to_vec(&header)?),
+        base64url(&serde_json::to_vec(&claims)?)
+    );
+    let key = PKey::private_key_from_pem(private_key.as_bytes())
+        .map_err(|e| anyhow::anyhow!("Invalid private key for {}: {}", email, e))?;
+    let signature = openssl::sign::Signer::new(MessageDigest::sha256(), &key)?.sign_oneshot_to_vec(signed.as_bytes())?;
+    Ok(format!("{}.{}", signed, base64url(&signature)))
+}
+
+fn base64url(data: &[u8]) -> String {
+    openssl::base64::encode_block(data)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registries_and_assertions() {
+        assert!(is_registry("gcr.io"));
+        assert!(is_registry("eu.gcr.io"));
+        assert!(is_registry("europe-west1-docker.pkg.dev"));
+        assert!(!is_registry("ghcr.io"));
+        assert!(!is_registry("docker.pkg.dev.example.com"));
+
+        let key = PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        let pem = String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let jwt = assertion("builder@project.iam.gserviceaccount.com", &pem, Some("k1"), TOKEN_URI, 1000).unwrap();
+        let parts: Vec<&str> = jwt.split('.').collect();
+        assert_eq!(parts.len(), 3);
+        let decode = |part: &str| {
+            let mut padded = part.replace('-', "+").replace('_', "/");
+            while !padded.len().is_multiple_of(4) {
+                padded.push('=');
+            }
+            openssl::base64::decode_block(&padded).unwrap()
+        };
+        let header: serde_json::Value = serde_json::from_slice(&decode(parts[0])).unwrap();
+        assert_eq!((header["alg"].as_str(), header["kid"].as_str()), (Some("RS256"), Some("k1")));
+        let claims: serde_json::Value = serde_json::from_slice(&decode(parts[1])).unwrap();
+        assert_eq!((claims["aud"].as_str(), claims["exp"].as_i64()), (Some(TOKEN_URI), Some(4600)));
+        let mut verifier = openssl::sign::Verifier::new(MessageDigest::sha256(), &key).unwrap();
+        let signed = format!("{}.{}", parts[0], parts[1]);
+        assert!(verifier.verify_oneshot(&decode(parts[2]), signed.as_bytes()).unwrap());
+
+        let user = r#"{"type": "authorized_user", "client_id": "c", "client_secret": "s", "refresh_token": "r"}"#;
+        assert!(matches!(serde_json::from_str(user).unwrap(), CredentialsFile::AuthorizedUser { .. }));
+        let external = r#"{"type": "external_account", "audience": "//iam.googleapis.com/..."}"#;
+        assert!(matches!(serde_json::from_str(external).unwrap(), CredentialsFile::Other));
+    }
+}
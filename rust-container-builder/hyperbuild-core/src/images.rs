@@ -0,0 +1,319 @@
+use crate::report::human_size;
+use crate::storage::{Image, StorageManager};
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+// One named image in local storage; an image that lost all its names is
+// listed once with <none> as its repository and tag
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ImageSummary {
+    pub repository: String,
+    pub tag: String,
+    pub id: String,
+    pub created: Option<String>,
+    // Bytes of the layer blobs on disk, counting a blob shared by platforms once
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Column {
+    Repository,
+    Tag,
+    Id,
+    Created,
+    Size,
+}
+
+impl Column {
+    pub const ALL: [Column; 5] = [Column::Repository, Column::Tag, Column::Id, Column::Created, Column::Size];
+
+    pub fn parse(name: &str) -> Result<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "repository" | "repo" => Ok(Column::Repository),
+            "tag" => Ok(Column::Tag),
+            "id" | "image-id" => Ok(Column::Id),
+            "created" => Ok(Column::Created),
+            "size" => Ok(Column::Size),
+            other => Err(anyhow::anyhow!(
+                "Unknown column {:?}: expected repository, tag, id, created or size",
+                other
+            )),
+        }
+    }
+
+    fn header(self) -> &'static str {
+        match self {
+            Column::Repository => "REPOSITORY",
+            Column::Tag => "TAG",
+            Column::Id => "IMAGE ID",
+            Column::Created => "CREATED",
+            Column::Size => "SIZE",
+        }
+    }
+
+    fn cell(self, image: &ImageSummary) -> String {
+        match self {
+            Column::Repository => image.repository.clone(),
+            Column::Tag => image.tag.clone(),
+            Column::Id => image.id.clone(),
+            Column::Created => image.created.clone().unwrap_or_default(),
+            Column::Size => human_size(image.size),
+        }
+    }
+}
+
+// A condition `images --filter` puts on the images listed
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    // label=KEY or label=KEY=VALUE: the image config has the label; an
+    // image index matches if one of its platform images does
+    Label { key: String, value: Option<String> },
+    // reference=PATTERN: the name, or its repository alone, matches a
+    // pattern where * and ? stand for characters other than /
+    Reference(String),
+}
+
+impl Filter {
+    pub fn parse(spec: &str) -> Result<Self> {
+        match spec.split_once('=') {
+            Some(("label", label)) if !label.is_empty() => Ok(match label.split_once('=') {
+                Some((key, value)) => Filter::Label {
+                    key: key.to_string(),
+                    value: Some(value.to_string()),
+                },
+                None => Filter::Label {
+                    key: label.to_string(),
+                    value: None,
+                },
+            }),
+            Some(("reference", pattern)) if !pattern.is_empty() => Ok(Filter::Reference(pattern.to_string())),
+            _ => Err(anyhow::anyhow!(
+                "Invalid filter {:?}: expected label=KEY, label=KEY=VALUE or reference=PATTERN",
+                spec
+            )),
+        }
+    }
+
+    fn matches_images(&self, images: &[&Image]) -> bool {
+        let Filter::Label { key, value } = self else {
+            return true;
+        };
+        images.iter().any(|image| {
+            let labels = image.config.config().as_ref().and_then(|config| config.labels().as_ref());
+            labels
+                .and_then(|labels| labels.get(key))
+                .is_some_and(|found| value.as_ref().is_none_or(|value| found == value))
+        })
+    }
+
+    fn matches_name(&self, name: &str) -> bool {
+        let Filter::Reference(pattern) = self else {
+            return true;
+        };
+        let (repository, _) = split_name(name);
+        glob(pattern.as_bytes(), name.as_bytes()) || glob(pattern.as_bytes(), repository.as_bytes())
+    }
+}
+
+// Every stored image and image index matching all the filters, one row per
+// name, newest first. Platform images of an index are only listed through the index.
+pub async fn list(storage: &StorageManager, filters: &[Filter]) -> Result<Vec<ImageSummary>> {
+    let refs = storage.refs().await?;
+    let mut rows = Vec::new();
+    let mut in_lists = BTreeSet::new();
+
+    for id in storage.list_image_lists().await? {
+        let Some(list) = storage.get_image_list(&id).await? else {
+            continue;
+        };
+        let images: Vec<&Image> = list.images.iter().collect();
+        in_lists.extend(list.images.iter().map(|image| image.id.clone()));
+        rows.extend(filtered_summaries(&id, refs.names_of(&id), &images, filters));
+    }
+    for id in storage.list_images().await? {
+        if in_lists.contains(&id) {
+            continue;
+        }
+        let Some(image) = storage.get_image(&id).await? else {
+            continue;
+        };
+        rows.extend(filtered_summaries(&id, refs.names_of(&id), &[&image], filters));
+    }
+
+    rows.sort_by(|a, b| {
+        b.created
+            .cmp(&a.created)
+            .then_with(|| a.repository.cmp(&b.repository))
+            .then_with(|| a.tag.cmp(&b.tag))
+    });
+    Ok(rows)
+}
+
+// Rows for the names that match; an image without names only matches when
+// no filter is on its name
+fn filtered_summaries(id: &str, mut names: Vec<&str>, images: &[&Image], filters: &[Filter]) -> Vec<ImageSummary> {
+    if !filters.iter().all(|filter| filter.matches_images(images)) {
+        return Vec::new();
+    }
+    if filters.iter().any(|filter| matches!(filter, Filter::Reference(_))) {
+        names.retain(|name| filters.iter().all(|filter| filter.matches_name(name)));
+        if names.is_empty() {
+            return Vec::new();
+        }
+    }
+    summaries(id, names, images)
+}
+
+fn summaries(id: &str, names: Vec<&str>, images: &[&Image]) -> Vec<ImageSummary> {
+    let created = images.iter().filter_map(|image| image.config.created().clone()).max();
+    let mut blobs: BTreeMap<&PathBuf, u64> = BTreeMap::new();
+    for layer in images.iter().flat_map(|image| &image.layers) {
+        if !blobs.contains_key(&layer.path) {
+            let size = std::fs::metadata(&layer.path).map(|metadata| metadata.len()).unwrap_or(0);
+            blobs.insert(&layer.path, size);
+        }
+    }
+    let size = blobs.values().sum();
+
+    let names = if names.is_empty() { vec![None] } else { names.into_iter().map(Some).collect() };
+    names
+        .into_iter()
+        .map(|name| {
+            let (repository, tag) = match name {
+                Some(name) => split_name(name),
+                None => ("<none>".to_string(), "<none>".to_string()),
+            };
+            ImageSummary {
+                repository,
+                tag,
+                id: id.to_string(),
+                created: created.clone(),
+                size,
+            }
+        })
+        .collect()
+}
+
+// "registry:5000/team/app:1.0" is repository "registry:5000/team/app", tag "1.0";
+// a name without a tag is the latest one, and a digest reference shows its digest
+pub fn split_name(name: &str) -> (String, String) {
+    let (repository, tag, digest) = crate::reference::split(name);
+    (repository.to_string(), digest.or(tag).unwrap_or("latest").to_string())
+}
+
+// Whether `text` matches a pattern where * stands for any run of characters
+// and ? for one character, neither crossing a /
+pub(crate) fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob(&pattern[1..], text) || (text.first().is_some_and(|c| *c != b'/') && glob(pattern, &text[1..]))
+        }
+        (Some(b'?'), Some(c)) if *c != b'/' => glob(&pattern[1..], &text[1..]),
+        (Some(p), Some(c)) if p == c => glob(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
+
+pub fn render_table(images: &[ImageSummary], columns: &[Column]) -> String {
+    let mut rows = vec![columns.iter().map(|column| column.header().to_string()).collect::<Vec<_>>()];
+    for image in images {
+        rows.push(columns.iter().map(|column| column.cell(image)).collect());
+    }
+
+    let mut widths = vec![0usize; columns.len()];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut table = String::new();
+    for row in &rows {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .zip(columns)
+            .map(|((cell, width), column)| match column {
+                Column::Size => format!("{:>width$}", cell, width = width),
+                _ => format!("{:<width$}", cell, width = width),
+            })
+            .collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_name_and_render() {
+        assert_eq!(split_name("app:1.0"), ("app".to_string(), "1.0".to_string()));
+        assert_eq!(split_name("app"), ("app".to_string(), "latest".to_string()));
+        assert_eq!(
+            split_name("localhost:5000/team/app"),
+            ("localhost:5000/team/app".to_string(), "latest".to_string())
+        );
+        assert_eq!(
+            split_name("localhost:5000/app:v2"),
+            ("localhost:5000/app".to_string(), "v2".to_string())
+        );
+        assert_eq!(
+            split_name("localhost:5000/app@sha256:abc"),
+            ("localhost:5000/app".to_string(), "sha256:abc".to_string())
+        );
+
+        let images = [ImageSummary {
+            repository: "app".to_string(),
+            tag: "latest".to_string(),
+            id: "image_1".to_string(),
+            created: None,
+            size: 2_500_000,
+        }];
+        let table = render_table(&images, &[Column::Repository, Column::Size]);
+        assert_eq!(table, "REPOSITORY    SIZE\napp         2.5 MB\n");
+        assert!(Column::parse("digest").is_err());
+    }
+
+    #[test]
+    fn test_filters() {
+        assert_eq!(
+            Filter::parse("label=team=payments").unwrap(),
+            Filter::Label {
+                key: "team".to_string(),
+                value: Some("payments".to_string())
+            }
+        );
+        assert!(Filter::parse("label=").is_err());
+        assert!(Filter::parse("dangling=true").is_err());
+
+        let reference = Filter::parse("reference=registry.example.com/*").unwrap();
+        assert!(reference.matches_name("registry.example.com/app:1.0"));
+        assert!(!reference.matches_name("registry.example.com/team/app:1.0"));
+        assert!(!reference.matches_name("other.example.com/app"));
+        assert!(Filter::parse("reference=app:v?").unwrap().matches_name("app:v2"));
+        assert!(!Filter::parse("reference=app").unwrap().matches_name("application"));
+
+        let mut labels = oci_spec::image::ConfigBuilder::default().build().unwrap();
+        labels.set_labels(Some([("team".to_string(), "payments".to_string())].into()));
+        let mut config = oci_spec::image::ImageConfiguration::default();
+        config.set_config(Some(labels));
+        let image = Image {
+            id: "image_1".to_string(),
+            name: "app".to_string(),
+            layers: vec![],
+            config,
+            raw_config: vec![],
+            manifest: crate::engine::image_manifest(&format!("sha256:{}", "0".repeat(64)), 0, &[]).unwrap(),
+        };
+        assert!(Filter::parse("label=team").unwrap().matches_images(&[&image]));
+        assert!(!Filter::parse("label=team=search").unwrap().matches_images(&[&image]));
+        assert!(filtered_summaries("image_1", vec![], &[&image], &[reference]).is_empty());
+        assert_eq!(filtered_summaries("image_1", vec![], &[&image], &[]).len(), 1);
+    }
+}
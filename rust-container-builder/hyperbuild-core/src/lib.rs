@@ -0,0 +1,66 @@
+//! Container image builds from Dockerfiles: the engine that runs them, the
+//! store images are kept in, and the registries they are pushed to and
+//! pulled from. Programs describe a build with a [`BuildRequest`]; the
+//! engine, store and registry client are internal modules, and the parts of
+//! them programs use are re-exported here.
+//!
+//! ```no_run
+//! # use hyperbuild_core::BuildRequest;
+//! # async fn example() -> anyhow::Result<()> {
+//! let mut request = BuildRequest::new("./app").tag("app:latest").build_arg("VERSION", "1.2");
+//! let mut events = request.events();
+//! tokio::spawn(async move {
+//!     while let Some(event) = events.recv().await {
+//!         println!("{:?}", event);
+//!     }
+//! });
+//! let build = request.run().await?;
+//! println!("built {} as {}", build.name(), build.digest()?);
+//! # Ok(())
+//! # }
+//! ```
+
+mod acr;
+pub mod bake;
+mod build;
+pub mod ci;
+pub mod compose;
+pub mod containerd;
+pub mod dive;
+pub mod diff;
+pub mod docker;
+mod dockerfile;
+mod storage;
+mod engine;
+mod ecr;
+mod gcp;
+pub mod images;
+pub mod metrics;
+pub mod platform;
+mod policy;
+pub mod progress;
+pub mod reference;
+mod registry_auth;
+mod registry_client;
+pub mod report;
+pub mod settings;
+pub mod signing;
+#[cfg(test)]
+mod test_support;
+pub mod watch;
+
+pub use build::{Build, BuildEvents, BuildRequest};
+pub use dockerfile::{NetworkMode, parse_duration};
+pub use engine::container::{ContainerSpec, PortMapping, Runtime, Volume, run_image};
+pub use engine::executor::StepLimits;
+pub use engine::export::{BuildOutput, export_image, export_image_list, export_rootfs};
+pub use engine::hooks::{Hook, Hooks};
+pub use engine::import::load as load_archive;
+pub use engine::plugins::Plugins;
+pub use engine::sbom::SbomFormat;
+pub use engine::{BuildEngine, BuildOptions, PullPolicy};
+pub use registry_client::{ArtifactFile, FetchedManifest, MediaTypes, RegistryClient, RegistrySettings};
+pub use storage::estargz::{TocEntry, clean_name as clean_toc_name};
+pub use storage::{BackupReport, CachePruneReport, DedupeReport, PruneReport, RestoreReport};
+pub use storage::{CacheRecord, Image, ImageList, Layer, PruneOptions, StorageManager};
+pub use storage::{Problem, Usage, UsageKind, VerifyReport};
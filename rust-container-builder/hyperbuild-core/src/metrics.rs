@@ -0,0 +1,220 @@
+use anyhow::Result;
+use bytes::Bytes;
+use http_body_util::Full;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Upper bounds, in seconds, of the step duration histogram's buckets
+const STEP_BUCKETS: [f64; 11] = [0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+// Counters and histograms about builds and registry transfers, rendered in the
+// Prometheus text format. Clones share their values, so one handle can be
+// given to the engine and every registry client of a command.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    builds_started: AtomicU64,
+    builds_failed: AtomicU64,
+    steps_cached: AtomicU64,
+    steps_run: AtomicU64,
+    pushed_bytes: AtomicU64,
+    pulled_bytes: AtomicU64,
+    step_durations: Mutex<Histogram>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    // Observations at or below each of STEP_BUCKETS
+    buckets: [u64; STEP_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    pub fn build_started(&self) {
+        self.inner.builds_started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn build_failed(&self) {
+        self.inner.builds_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // A build step answered from the build cache
+    pub fn step_cached(&self) {
+        self.inner.steps_cached.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // A build step that ran, whether it succeeded or not
+    pub fn step_run(&self, duration: Duration) {
+        self.inner.steps_run.fetch_add(1, Ordering::Relaxed);
+        let seconds = duration.as_secs_f64();
+        let mut histogram = self.inner.step_durations.lock().unwrap();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(STEP_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    // Request bodies sent to a registry
+    pub fn bytes_pushed(&self, bytes: u64) {
+        self.inner.pushed_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // Response bodies received from a registry
+    pub fn bytes_pulled(&self, bytes: u64) {
+        self.inner.pulled_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // Every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let counters = &self.inner;
+        let mut out = String::new();
+        for (name, help, value) in [
+            ("hyperbuild_builds_started_total", "Builds started.", &counters.builds_started),
+            ("hyperbuild_builds_failed_total", "Builds that failed or were cancelled.", &counters.builds_failed),
+            ("hyperbuild_steps_cached_total", "Build steps answered from the build cache.", &counters.steps_cached),
+            ("hyperbuild_steps_run_total", "Build steps that ran.", &counters.steps_run),
+            ("hyperbuild_pushed_bytes_total", "Bytes sent to registries.", &counters.pushed_bytes),
+            ("hyperbuild_pulled_bytes_total", "Bytes received from registries.", &counters.pulled_bytes),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        let cached = counters.steps_cached.load(Ordering::Relaxed);
+        let steps = cached + counters.steps_run.load(Ordering::Relaxed);
+        if steps > 0 {
+            let name = "hyperbuild_cache_hit_ratio";
+            let _ = writeln!(out, "# HELP {} Share of build steps answered from the build cache.", name);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, cached as f64 / steps as f64);
+        }
+
+        let histogram = counters.step_durations.lock().unwrap();
+        let name = "hyperbuild_step_duration_seconds";
+        let _ = writeln!(out, "# HELP {} How long build steps that ran took.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (count, bound) in histogram.buckets.iter().zip(STEP_BUCKETS) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count);
+        let _ = writeln!(out, "{}_sum {}", name, histogram.sum);
+        let _ = writeln!(out, "{}_count {}", name, histogram.count);
+        out
+    }
+
+    // Write the metrics for node_exporter's textfile collector; the file is
+    // replaced in one rename, so it is never scraped half written
+    pub fn write_textfile(&self, path: &Path) -> Result<()> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        std::fs::write(&partial, self.render())?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    // Answer Prometheus scrapes of /metrics on `listener` until the task is
+    // dropped; other paths are 404
+    pub async fn serve(self, listener: tokio::net::TcpListener) -> Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let metrics = self.clone();
+            let service = hyper::service::service_fn(move |request: hyper::Request<hyper::body::Incoming>| {
+                let response = match (request.method(), request.uri().path()) {
+                    (&hyper::Method::GET, "/metrics") => hyper::Response::builder()
+                        .header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")
+                        .body(Full::new(Bytes::from(metrics.render()))),
+                    _ => hyper::Response::builder()
+                        .status(hyper::StatusCode::NOT_FOUND)
+                        .body(Full::new(Bytes::from_static(b"Not found; metrics are at /metrics\n"))),
+                };
+                std::future::ready(response)
+            });
+            tokio::spawn(async move {
+                let connection = hyper_util::rt::TokioIo::new(stream);
+                if let Err(e) = hyper::server::conn::http1::Builder::new().serve_connection(connection, service).await {
+                    tracing::debug!("Metrics connection failed: {}", e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus_text() {
+        let metrics = Metrics::default();
+        assert!(!metrics.render().contains("hyperbuild_cache_hit_ratio"));
+
+        let shared = metrics.clone();
+        shared.build_started();
+        metrics.step_cached();
+        metrics.step_run(Duration::from_millis(300));
+        metrics.step_run(Duration::from_secs(700));
+        metrics.step_cached();
+        metrics.step_run(Duration::from_secs(2));
+        metrics.bytes_pushed(1024);
+        metrics.bytes_pulled(10);
+        metrics.bytes_pulled(20);
+
+        let text = metrics.render();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"# TYPE hyperbuild_builds_started_total counter"));
+        assert!(lines.contains(&"hyperbuild_builds_started_total 1"));
+        assert!(lines.contains(&"hyperbuild_builds_failed_total 0"));
+        assert!(lines.contains(&"hyperbuild_pushed_bytes_total 1024"));
+        assert!(lines.contains(&"hyperbuild_pulled_bytes_total 30"));
+        assert!(lines.contains(&"hyperbuild_cache_hit_ratio 0.4"));
+        assert!(lines.contains(&"hyperbuild_step_duration_seconds_bucket{le=\"0.1\"} 0"));
+        assert!(lines.contains(&"hyperbuild_step_duration_seconds_bucket{le=\"0.5\"} 1"));
+        assert!(lines.contains(&"hyperbuild_step_duration_seconds_bucket{le=\"2.5\"} 2"));
+        assert!(lines.contains(&"hyperbuild_step_duration_seconds_bucket{le=\"600\"} 2"));
+        assert!(lines.contains(&"hyperbuild_step_duration_seconds_bucket{le=\"+Inf\"} 3"));
+        assert!(lines.contains(&"hyperbuild_step_duration_seconds_sum 702.3"));
+        assert!(lines.contains(&"hyperbuild_step_duration_seconds_count 3"));
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("hyperbuild.prom");
+        metrics.write_textfile(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), text);
+        assert_eq!(std::fs::read_dir(tempdir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_serve_metrics() {
+        let metrics = Metrics::default();
+        metrics.build_started();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(metrics.clone().serve(listener));
+
+        // Scrapes see what was counted since the server started
+        metrics.bytes_pulled(512);
+        let response = reqwest::get(format!("{}/metrics", url)).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let content_type = response.headers()[reqwest::header::CONTENT_TYPE].to_str().unwrap();
+        assert!(content_type.starts_with("text/plain; version=0.0.4"));
+        let text = response.text().await.unwrap();
+        assert_eq!(text, metrics.render());
+        assert!(text.lines().any(|line| line == "hyperbuild_builds_started_total 1"));
+        assert!(text.lines().any(|line| line == "hyperbuild_pulled_bytes_total 512"));
+
+        let response = reqwest::get(format!("{}/", url)).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        server.abort();
+    }
+}
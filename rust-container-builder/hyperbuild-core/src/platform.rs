@@ -0,0 +1,78 @@
+use anyhow::Result;
+use std::fmt;
+
+// A target platform such as linux/amd64 or linux/arm64/v8
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    pub fn host() -> Self {
+        let architecture = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "x86" => "386",
+            "aarch64" => "arm64",
+            "powerpc64" => "ppc64le",
+            other => other,
+        };
+
+        Self {
+            os: std::env::consts::OS.to_string(),
+            architecture: architecture.to_string(),
+            variant: None,
+        }
+    }
+
+    pub fn parse(spec: &str) -> Result<Self> {
+        let parts: Vec<&str> = spec.trim().split('/').collect();
+        match parts.as_slice() {
+            [os, architecture] if !os.is_empty() && !architecture.is_empty() => Ok(Self {
+                os: os.to_string(),
+                architecture: normalize_architecture(architecture).to_string(),
+                variant: None,
+            }),
+            [os, architecture, variant] if !os.is_empty() && !architecture.is_empty() => Ok(Self {
+                os: os.to_string(),
+                architecture: normalize_architecture(architecture).to_string(),
+                variant: Some(variant.to_string()),
+            }),
+            _ => Err(anyhow::anyhow!("Invalid platform {:?}, expected os/arch[/variant]", spec)),
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+// Accept the common aliases people type for GOARCH values
+fn normalize_architecture(architecture: &str) -> &str {
+    match architecture {
+        "x86_64" | "x86-64" => "amd64",
+        "aarch64" => "arm64",
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_platform() {
+        let platform = Platform::parse("linux/aarch64/v8").unwrap();
+        assert_eq!(platform.architecture, "arm64");
+        assert_eq!(platform.to_string(), "linux/arm64/v8");
+        assert_eq!(Platform::parse("linux/amd64").unwrap().variant, None);
+        assert!(Platform::parse("linux").is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use crate::signing::Verifier;
+use anyhow::Result;
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+// A trust policy in the format of containers-policy.json(5): the
+// requirements images from each registry, namespace or repository must meet
+// before they are pulled, whether by `pull` or as a build's base image. Only
+// the docker transport applies; other transports' scopes are ignored.
+pub struct TrustPolicy {
+    default: Vec<Requirement>,
+    scopes: HashMap<String, Vec<Requirement>>,
+}
+
+pub enum Requirement {
+    Accept,
+    Reject,
+    Signed(Verifier),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    default: Option<Vec<RequirementFile>>,
+    #[serde(default)]
+    transports: HashMap<String, HashMap<String, Vec<RequirementFile>>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RequirementFile {
+    #[serde(rename = "type")]
+    kind: String,
+    key_path: Option<String>,
+    // A base64 PEM public key, in place of key_path
+    key_data: Option<String>,
+    fulcio: Option<FulcioFile>,
+    rekor_public_key_path: Option<String>,
+    rekor_public_key_data: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FulcioFile {
+    ca_path: Option<String>,
+    ca_data: Option<String>,
+    oidc_issuer: String,
+    subject_email: String,
+}
+
+impl TrustPolicy {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents =
+            std::fs::read(path).map_err(|e| anyhow::anyhow!("Failed to read trust policy {:?}: {}", path, e))?;
+        Self::parse(&contents).map_err(|e| anyhow::anyhow!("Invalid trust policy {:?}: {}", path, e))
+    }
+
+    fn parse(contents: &[u8]) -> Result<Self> {
+        let mut file: PolicyFile = serde_json::from_slice(contents)?;
+        let default = file
+            .default
+            .ok_or_else(|| anyhow::anyhow!("no default requirements; use [{{\"type\": \"insecureAcceptAnything\"}}]"))?;
+        let requirements = |list: Vec<RequirementFile>| -> Result<Vec<Requirement>> {
+            list.into_iter().map(Requirement::parse).collect()
+        };
+        let mut scopes = HashMap::new();
+        for (scope, list) in file.transports.remove("docker").unwrap_or_default() {
+            let list = requirements(list).map_err(|e| e.context(format!("in scope {}", scope)))?;
+            scopes.insert(scope, list);
+        }
+        Ok(Self { default: requirements(default)?, scopes })
+    }
+
+    // The requirements for `repository` (host/path, with docker.io for
+    // Docker Hub) at `reference`, a tag or digest: those of the most
+    // specific scope naming it, from the tag or digest itself through the
+    // repository, its namespaces and host to wildcard domains (*.example.com),
+    // else the default ones
+    pub fn requirements(&self, repository: &str, reference: &str) -> &[Requirement] {
+        let separator = if reference.starts_with("sha256:") { '@' } else { ':' };
+        let mut candidates = vec![format!("{}{}{}", repository, separator, reference)];
+        let mut scope = repository;
+        loop {
+            candidates.push(scope.to_string());
+            match scope.rsplit_once('/') {
+                Some((parent, _)) => scope = parent,
+                None => break,
+            }
+        }
+        let host = scope.split(':').next().unwrap_or(scope);
+        let mut domain = host;
+        while let Some((_, parent)) = domain.split_once('.') {
+            candidates.push(format!("*.{}", parent));
+            domain = parent;
+        }
+        candidates
+            .iter()
+            .find_map(|scope| self.scopes.get(scope))
+            .unwrap_or(&self.default)
+    }
+}
+
+impl Requirement {
+    fn parse(file: RequirementFile) -> Result<Self> {
+        match file.kind.as_str() {
+            "insecureAcceptAnything" => Ok(Requirement::Accept),
+            "reject" => Ok(Requirement::Reject),
+            "sigstoreSigned" => {
+                let key = match (&file.key_path, &file.key_data, file.fulcio) {
+                    (Some(path), None, None) => read(path)?,
+                    (None, Some(data), None) => openssl::base64::decode_block(data)?,
+                    (None, None, Some(fulcio)) => {
+                        let roots = match (&fulcio.ca_path, &fulcio.ca_data) {
+                            (Some(path), None) => read(path)?,
+                            (None, Some(data)) => openssl::base64::decode_block(data)?,
+                            _ => return Err(anyhow::anyhow!("fulcio needs one of caPath and caData")),
+                        };
+                        let rekor = match (&file.rekor_public_key_path, &file.rekor_public_key_data) {
+                            (Some(path), None) => read(path)?,
+                            (None, Some(data)) => openssl::base64::decode_block(data)?,
+                            _ => {
+                                return Err(anyhow::anyhow!(
+                                    "fulcio needs one of rekorPublicKeyPath and rekorPublicKeyData"
+                                ));
+                            }
+                        };
+                        return Ok(Requirement::Signed(Verifier::Keyless {
+                            identity: fulcio.subject_email,
+                            issuer: fulcio.oidc_issuer,
+                            roots: X509::stack_from_pem(&roots)?,
+                            rekor: PKey::public_key_from_pem(&rekor)?,
+                        }));
+                    }
+                    _ => return Err(anyhow::anyhow!("sigstoreSigned needs one of keyPath, keyData and fulcio")),
+                };
+                Ok(Requirement::Signed(Verifier::Key(PKey::public_key_from_pem(&key)?)))
+            }
+            "signedBy" => Err(anyhow::anyhow!("signedBy (GPG) requirements are not supported; use sigstoreSigned")),
+            other => Err(anyhow::anyhow!("unknown requirement type {:?}", other)),
+        }
+    }
+}
+
+fn read(path: &str) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scopes_and_requirements() {
+        let group = openssl::ec::EcGroup::from_curve_name(openssl::nid::Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(openssl::ec::EcKey::generate(&group).unwrap()).unwrap();
+        let key_data = openssl::base64::encode_block(&key.public_key_to_pem().unwrap());
+        let policy = serde_json::json!({
+            "default": [{ "type": "insecureAcceptAnything" }],
+            "transports": {
+                "docker": {
+                    "registry.example.com/team": [{ "type": "sigstoreSigned", "keyData": key_data }],
+                    "registry.example.com/team/scratch": [{ "type": "insecureAcceptAnything" }],
+                    "registry.example.com/team/app:dev": [{ "type": "reject" }],
+                    "*.corp.example": [{ "type": "reject" }],
+                },
+                "docker-daemon": { "": [{ "type": "reject" }] },
+            },
+        });
+        let policy = TrustPolicy::parse(&serde_json::to_vec(&policy).unwrap()).unwrap();
+        let kinds = |repository: &str, reference: &str| -> Vec<&str> {
+            policy
+                .requirements(repository, reference)
+                .iter()
+                .map(|requirement| match requirement {
+                    Requirement::Accept => "accept",
+                    Requirement::Reject => "reject",
+                    Requirement::Signed(_) => "signed",
+                })
+                .collect()
+        };
+        assert_eq!(kinds("registry.example.com/team/app", "v1"), ["signed"]);
+        assert_eq!(kinds("registry.example.com/team/app", "dev"), ["reject"]);
+        assert_eq!(kinds("registry.example.com/team/app", &format!("sha256:{}", "0".repeat(64))), ["signed"]);
+        assert_eq!(kinds("registry.example.com/team/scratch/tool", "v1"), ["accept"]);
+        assert_eq!(kinds("registry.example.com/other", "v1"), ["accept"]);
+        assert_eq!(kinds("mirror.eu.corp.example:5000/app", "v1"), ["reject"]);
+        assert_eq!(kinds("docker.io/library/alpine", "3.20"), ["accept"]);
+
+        assert!(TrustPolicy::parse(br#"{"transports": {}}"#).is_err());
+        assert!(TrustPolicy::parse(br#"{"default": [{"type": "signedBy", "keyType": "GPGKeys"}]}"#).is_err());
+        assert!(TrustPolicy::parse(br#"{"default": [{"type": "sigstoreSigned"}]}"#).is_err());
+    }
+}
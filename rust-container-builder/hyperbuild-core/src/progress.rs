@@ -0,0 +1,641 @@
+use crate::ci::Ci;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+// What happened during a build; steps are numbered in the order they start
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    StageStarted { stage: usize, name: String },
+    StepStarted { id: usize, name: String },
+    // Sizes are of the uncompressed layer tar and of the stored (gzip) blob;
+    // a cached step that changed no files has no layer digest
+    StepCached { id: usize, digest: Option<String>, size: u64, compressed_size: u64 },
+    #[serde(rename = "log")]
+    StepLog { id: usize, line: String },
+    LayerCommitted { id: usize, digest: String, size: u64, compressed_size: u64 },
+    // Bytes of the blob a step uploads or downloads, so far and in all
+    StepBytes { id: usize, current: u64, total: u64 },
+    StepDone { id: usize },
+    // A failed RUN step is about to run again; `attempt` counts the retries so far
+    StepRetrying { id: usize, attempt: u32, retries: u32, error: String },
+    StepFailed { id: usize, error: String },
+    // `digest` is the digest of the image manifest (or index)
+    BuildFinished { image: String, digest: String },
+}
+
+// Handle the engine reports progress through. The default one reports to
+// nobody, in which case RUN output goes straight to the terminal.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    sender: Option<UnboundedSender<ProgressEvent>>,
+    next_id: Arc<AtomicUsize>,
+}
+
+impl Progress {
+    pub fn channel() -> (Self, UnboundedReceiver<ProgressEvent>) {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let progress = Self {
+            sender: Some(sender),
+            next_id: Arc::new(AtomicUsize::new(1)),
+        };
+        (progress, receiver)
+    }
+
+    pub fn start(&self, name: String) -> StepProgress {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.send(ProgressEvent::StepStarted { id, name });
+        StepProgress {
+            id,
+            progress: self.clone(),
+            finished: false,
+        }
+    }
+
+    // A step that moves a blob of `total` bytes to or from a registry
+    pub fn transfer(&self, name: String, total: u64) -> TransferMeter {
+        TransferMeter {
+            step: self.start(name),
+            total,
+            reported: None,
+        }
+    }
+
+    pub fn stage_started(&self, stage: usize, name: String) {
+        self.send(ProgressEvent::StageStarted { stage, name });
+    }
+
+    pub fn build_finished(&self, image: &str, digest: String) {
+        self.send(ProgressEvent::BuildFinished {
+            image: image.to_string(),
+            digest,
+        });
+    }
+
+    fn send(&self, event: ProgressEvent) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(event);
+        }
+    }
+}
+
+// One running step. A step dropped without finishing is reported as failed,
+// so an error propagated with `?` never leaves it running in the UI.
+#[derive(Debug)]
+pub struct StepProgress {
+    id: usize,
+    progress: Progress,
+    finished: bool,
+}
+
+impl StepProgress {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    // Where the step's command output goes, if anyone is listening
+    pub fn log_sink(&self) -> Option<LogSink> {
+        self.progress.sender.clone().map(|sender| LogSink { id: self.id, sender })
+    }
+
+    pub fn layer_committed(&self, digest: &str, size: u64, compressed_size: u64) {
+        self.progress.send(ProgressEvent::LayerCommitted {
+            id: self.id,
+            digest: digest.to_string(),
+            size,
+            compressed_size,
+        });
+    }
+
+    pub fn retrying(&self, attempt: u32, retries: u32, error: &anyhow::Error) {
+        self.progress.send(ProgressEvent::StepRetrying {
+            id: self.id,
+            attempt,
+            retries,
+            error: error.to_string(),
+        });
+    }
+
+    pub fn cached(mut self, digest: Option<&str>, size: u64, compressed_size: u64) {
+        self.finish(ProgressEvent::StepCached {
+            id: self.id,
+            digest: digest.map(str::to_string),
+            size,
+            compressed_size,
+        });
+    }
+
+    pub fn done(mut self) {
+        self.finish(ProgressEvent::StepDone { id: self.id });
+    }
+
+    pub fn failed(mut self, error: &anyhow::Error) {
+        self.finish(ProgressEvent::StepFailed {
+            id: self.id,
+            error: error.to_string(),
+        });
+    }
+
+    fn finish(&mut self, event: ProgressEvent) {
+        self.finished = true;
+        self.progress.send(event);
+    }
+}
+
+impl Drop for StepProgress {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.progress.send(ProgressEvent::StepFailed {
+                id: self.id,
+                error: String::new(),
+            });
+        }
+    }
+}
+
+// Reports how far a transfer got, at most every 100ms so fast transfers do
+// not flood the display. Dropped unfinished, its step is reported as failed.
+#[derive(Debug)]
+pub struct TransferMeter {
+    step: StepProgress,
+    total: u64,
+    reported: Option<Instant>,
+}
+
+impl TransferMeter {
+    pub fn update(&mut self, current: u64) {
+        let recent = self.reported.is_some_and(|reported| reported.elapsed() < Duration::from_millis(100));
+        if recent && current < self.total {
+            return;
+        }
+        self.reported = Some(Instant::now());
+        self.step.progress.send(ProgressEvent::StepBytes {
+            id: self.step.id,
+            current,
+            total: self.total,
+        });
+    }
+
+    pub fn done(mut self) {
+        self.update(self.total);
+        self.step.done();
+    }
+
+    pub fn failed(self, error: &anyhow::Error) {
+        self.step.failed(error);
+    }
+}
+
+// Receives the output of a step's command, line by line
+#[derive(Debug, Clone)]
+pub struct LogSink {
+    id: usize,
+    sender: UnboundedSender<ProgressEvent>,
+}
+
+impl LogSink {
+    pub fn line(&self, line: String) {
+        let _ = self.sender.send(ProgressEvent::StepLog { id: self.id, line });
+    }
+}
+
+// How progress is shown (`--progress`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressMode {
+    // A live display when stdout is a terminal, plain output otherwise
+    Auto,
+    Plain,
+    Tty,
+    // Newline-delimited JSON events, for CI systems and wrappers
+    Json,
+}
+
+impl ProgressMode {
+    pub fn parse(mode: &str) -> Result<Self> {
+        match mode {
+            "auto" => Ok(ProgressMode::Auto),
+            "plain" => Ok(ProgressMode::Plain),
+            "tty" => Ok(ProgressMode::Tty),
+            "json" => Ok(ProgressMode::Json),
+            other => Err(anyhow::anyhow!("Unknown progress mode {:?}: expected auto, plain, tty or json", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StepStatus {
+    Running,
+    Cached,
+    Done,
+    Failed,
+}
+
+#[derive(Debug)]
+struct StepView {
+    name: String,
+    status: StepStatus,
+    started: Instant,
+    elapsed: Option<Duration>,
+    logs: Vec<String>,
+    // Of a transfer: bytes so far and in all
+    bytes: Option<(u64, u64)>,
+}
+
+// Log lines shown under a running step in the live display
+const TTY_LOG_LINES: usize = 6;
+
+// Show progress events until the engine drops its last Progress handle; with
+// `ci`, plain output groups each stage's steps
+pub async fn render(mode: ProgressMode, ci: Option<Ci>, mut events: UnboundedReceiver<ProgressEvent>) {
+    use std::io::IsTerminal;
+
+    let tty = match mode {
+        ProgressMode::Auto => std::io::stdout().is_terminal(),
+        ProgressMode::Plain => false,
+        ProgressMode::Tty => true,
+        ProgressMode::Json => {
+            while let Some(event) = events.recv().await {
+                write_json(&event);
+            }
+            return;
+        }
+    };
+    if !tty {
+        let mut plain = PlainRenderer { ci, ..PlainRenderer::default() };
+        while let Some(event) = events.recv().await {
+            plain.handle(event);
+        }
+        plain.end_group();
+        return;
+    }
+
+    let mut display = TtyRenderer::new();
+    let mut ticker = tokio::time::interval(Duration::from_millis(100));
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => display.handle(event),
+                None => break,
+            },
+            _ = ticker.tick() => display.draw(),
+        }
+    }
+    display.finish();
+}
+
+// One JSON object per line, stamped with the time it was written
+fn write_json(event: &ProgressEvent) {
+    let Ok(serde_json::Value::Object(mut object)) = serde_json::to_value(event) else {
+        return;
+    };
+    object.insert(
+        "time".to_string(),
+        serde_json::Value::String(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+    );
+    let mut out = std::io::stdout().lock();
+    let _ = writeln!(out, "{}", serde_json::Value::Object(object));
+    let _ = out.flush();
+}
+
+// BuildKit-style plain output: every event on its own line, prefixed with the step number
+#[derive(Default)]
+struct PlainRenderer {
+    started: BTreeMap<usize, Instant>,
+    // When each transfer's progress was last printed
+    reported: BTreeMap<usize, Instant>,
+    ci: Option<Ci>,
+    // Whether a stage's group is open; stages built in parallel share groups,
+    // since each one starts where its stage does
+    in_group: bool,
+}
+
+// Plain output prints how far a transfer got this often
+const PLAIN_TRANSFER_INTERVAL: Duration = Duration::from_secs(5);
+
+impl PlainRenderer {
+    fn handle(&mut self, event: ProgressEvent) {
+        let mut out = std::io::stdout().lock();
+        let _ = match event {
+            ProgressEvent::StepStarted { id, name } => {
+                self.started.insert(id, Instant::now());
+                writeln!(out, "#{} {}", id, name)
+            }
+            ProgressEvent::StepLog { id, line } => {
+                let elapsed = self.elapsed(id);
+                writeln!(out, "#{} {:.3} {}", id, elapsed.as_secs_f64(), line)
+            }
+            ProgressEvent::StepCached { id, .. } => writeln!(out, "#{} CACHED", id),
+            ProgressEvent::StepBytes { id, current, total } => {
+                let due = self.reported.get(&id).is_none_or(|last| last.elapsed() >= PLAIN_TRANSFER_INTERVAL);
+                if !due || current >= total {
+                    return;
+                }
+                self.reported.insert(id, Instant::now());
+                writeln!(out, "#{} {}", id, transfer_status(current, total, self.elapsed(id)))
+            }
+            ProgressEvent::StepDone { id } => writeln!(out, "#{} DONE {:.1}s", id, self.elapsed(id).as_secs_f64()),
+            ProgressEvent::StepRetrying {
+                id,
+                attempt,
+                retries,
+                error,
+            } => writeln!(out, "#{} RETRY {}/{}: {}", id, attempt, retries, error),
+            ProgressEvent::StepFailed { id, error } if error.is_empty() => writeln!(out, "#{} ERROR", id),
+            ProgressEvent::StepFailed { id, error } => writeln!(out, "#{} ERROR: {}", id, error),
+            ProgressEvent::StageStarted { stage, name } => match self.ci {
+                Some(ci) => {
+                    self.end_group();
+                    self.in_group = true;
+                    writeln!(out, "{}", ci.start_group(&format!("Stage {}: {}", stage + 1, name)))
+                }
+                None => Ok(()),
+            },
+            // What comes after the image is written, such as pushing it, belongs to no stage
+            ProgressEvent::BuildFinished { .. } => {
+                self.end_group();
+                Ok(())
+            }
+            ProgressEvent::LayerCommitted { .. } => Ok(()),
+        };
+    }
+
+    fn end_group(&mut self) {
+        if let Some(ci) = self.ci
+            && std::mem::take(&mut self.in_group)
+        {
+            println!("{}", ci.end_group());
+        }
+    }
+
+    fn elapsed(&self, id: usize) -> Duration {
+        self.started.get(&id).map(Instant::elapsed).unwrap_or_default()
+    }
+}
+
+// A live view redrawn in place: one line per step with its status and
+// elapsed time, and the latest output of steps that are still running
+struct TtyRenderer {
+    started: Instant,
+    steps: BTreeMap<usize, StepView>,
+    // Lines drawn last time, which the next draw moves back over
+    drawn: usize,
+    width: usize,
+}
+
+impl TtyRenderer {
+    fn new() -> Self {
+        let width = std::env::var("COLUMNS")
+            .ok()
+            .and_then(|columns| columns.parse().ok())
+            .unwrap_or(80);
+        Self {
+            started: Instant::now(),
+            steps: BTreeMap::new(),
+            drawn: 0,
+            width,
+        }
+    }
+
+    fn handle(&mut self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::StepStarted { id, name } => {
+                self.steps.insert(
+                    id,
+                    StepView {
+                        name,
+                        status: StepStatus::Running,
+                        started: Instant::now(),
+                        elapsed: None,
+                        logs: Vec::new(),
+                        bytes: None,
+                    },
+                );
+            }
+            ProgressEvent::StepLog { id, line } => {
+                if let Some(step) = self.steps.get_mut(&id) {
+                    step.logs.push(line);
+                }
+            }
+            ProgressEvent::StepCached { id, .. } => self.finish_step(id, StepStatus::Cached),
+            ProgressEvent::StepBytes { id, current, total } => {
+                if let Some(step) = self.steps.get_mut(&id) {
+                    step.bytes = Some((current, total));
+                }
+            }
+            ProgressEvent::StepDone { id } => self.finish_step(id, StepStatus::Done),
+            ProgressEvent::StepRetrying {
+                id,
+                attempt,
+                retries,
+                error,
+            } => {
+                if let Some(step) = self.steps.get_mut(&id) {
+                    step.logs.push(format!("retrying ({}/{}): {}", attempt, retries, error));
+                }
+            }
+            // The error itself is reported once the build returns
+            ProgressEvent::StepFailed { id, .. } => self.finish_step(id, StepStatus::Failed),
+            ProgressEvent::StageStarted { .. }
+            | ProgressEvent::LayerCommitted { .. }
+            | ProgressEvent::BuildFinished { .. } => {}
+        }
+    }
+
+    fn finish_step(&mut self, id: usize, status: StepStatus) {
+        if let Some(step) = self.steps.get_mut(&id) {
+            step.status = status;
+            step.elapsed = Some(step.started.elapsed());
+        }
+    }
+
+    fn draw(&mut self) {
+        let finished = self.steps.values().filter(|step| step.status != StepStatus::Running).count();
+        let mut lines = vec![format!(
+            "[+] Building {:.1}s ({}/{})",
+            self.started.elapsed().as_secs_f64(),
+            finished,
+            self.steps.len()
+        )];
+
+        for step in self.steps.values() {
+            let elapsed = step.elapsed.unwrap_or_else(|| step.started.elapsed());
+            let label = match step.status {
+                StepStatus::Cached => format!(" => CACHED {}", step.name),
+                StepStatus::Failed => format!(" => ERROR {}", step.name),
+                StepStatus::Running => match step.bytes {
+                    Some((current, total)) => {
+                        format!(" => {} {}", step.name, transfer_status(current, total, step.started.elapsed()))
+                    }
+                    None => format!(" => {}", step.name),
+                },
+                StepStatus::Done => format!(" => {}", step.name),
+            };
+            lines.push(with_time(&label, elapsed, self.width));
+
+            // Output is collapsed once a step finishes
+            if step.status == StepStatus::Running {
+                let skip = step.logs.len().saturating_sub(TTY_LOG_LINES);
+                for line in &step.logs[skip..] {
+                    lines.push(truncate(&format!(" => => # {}", line), self.width));
+                }
+            }
+        }
+
+        let mut out = std::io::stdout().lock();
+        if self.drawn > 0 {
+            let _ = write!(out, "\x1b[{}A", self.drawn);
+        }
+        for line in &lines {
+            let _ = writeln!(out, "\x1b[2K{}", line);
+        }
+        // Clear whatever is left of a taller previous frame
+        let _ = write!(out, "\x1b[J");
+        let _ = out.flush();
+        self.drawn = lines.len();
+    }
+
+    // Draw the final state, then print the output of any failed step in full
+    fn finish(&mut self) {
+        self.draw();
+        let mut out = std::io::stdout().lock();
+        for step in self.steps.values().filter(|step| step.status == StepStatus::Failed) {
+            if step.logs.is_empty() {
+                continue;
+            }
+            let _ = writeln!(out, "------\n > {}:", step.name);
+            for line in &step.logs {
+                let _ = writeln!(out, "{}", line);
+            }
+            let _ = writeln!(out, "------");
+        }
+    }
+}
+
+// A transfer's bar, byte counts, rate and time left, e.g.
+// [#####-----] 12.0 MB / 24.0 MB 4.0 MB/s ETA 3s
+fn transfer_status(current: u64, total: u64, elapsed: Duration) -> String {
+    const BAR: usize = 20;
+    let done = match total {
+        0 => BAR,
+        total => (current.min(total) as f64 / total as f64 * BAR as f64) as usize,
+    };
+    let bar = format!("[{}{}]", "#".repeat(done), "-".repeat(BAR - done));
+    let sizes = format!("{} / {}", crate::report::human_size(current), crate::report::human_size(total));
+    let rate = current as f64 / elapsed.as_secs_f64();
+    if current == 0 || !rate.is_finite() {
+        return format!("{} {}", bar, sizes);
+    }
+    let left = total.saturating_sub(current) as f64 / rate;
+    format!("{} {} {}/s ETA {:.0}s", bar, sizes, crate::report::human_size(rate as u64), left)
+}
+
+// Pad `label` so the elapsed time lines up at the right edge
+fn with_time(label: &str, elapsed: Duration, width: usize) -> String {
+    let time = format!("{:.1}s", elapsed.as_secs_f64());
+    let room = width.saturating_sub(time.len() + 1);
+    let label = truncate(label, room);
+    format!("{:<room$} {}", label, time, room = room)
+}
+
+fn truncate(line: &str, width: usize) -> String {
+    if line.chars().count() <= width {
+        return line.to_string();
+    }
+    let mut truncated: String = line.chars().take(width.saturating_sub(3)).collect();
+    truncated.push_str("...");
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dropped_step_is_reported_failed() {
+        let (progress, mut events) = Progress::channel();
+        let cached = progress.start("[1/2] FROM base".to_string());
+        cached.cached(Some("sha256:abc"), 2048, 100);
+        let step = progress.start("[2/2] RUN make".to_string());
+        step.log_sink().unwrap().line("compiling".to_string());
+        drop(step);
+
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        assert_eq!(
+            received,
+            vec![
+                ProgressEvent::StepStarted {
+                    id: 1,
+                    name: "[1/2] FROM base".to_string()
+                },
+                ProgressEvent::StepCached {
+                    id: 1,
+                    digest: Some("sha256:abc".to_string()),
+                    size: 2048,
+                    compressed_size: 100
+                },
+                ProgressEvent::StepStarted {
+                    id: 2,
+                    name: "[2/2] RUN make".to_string()
+                },
+                ProgressEvent::StepLog {
+                    id: 2,
+                    line: "compiling".to_string()
+                },
+                ProgressEvent::StepFailed {
+                    id: 2,
+                    error: String::new()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_json_event_shape() {
+        let event = ProgressEvent::LayerCommitted {
+            id: 3,
+            digest: "sha256:abc".to_string(),
+            size: 2048,
+            compressed_size: 100,
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "type": "layer_committed",
+                "id": 3,
+                "digest": "sha256:abc",
+                "size": 2048,
+                "compressed_size": 100
+            })
+        );
+        let log = ProgressEvent::StepLog {
+            id: 1,
+            line: "ok".to_string(),
+        };
+        assert_eq!(serde_json::to_value(&log).unwrap()["type"], "log");
+    }
+
+    #[test]
+    fn test_transfer_status() {
+        assert_eq!(
+            transfer_status(12_000_000, 24_000_000, Duration::from_secs(3)),
+            "[##########----------] 12.0 MB / 24.0 MB 4.0 MB/s ETA 3s"
+        );
+        assert_eq!(transfer_status(0, 1000, Duration::ZERO), "[--------------------] 0 B / 1.0 kB");
+    }
+
+    #[test]
+    fn test_with_time_aligns_right() {
+        let line = with_time(" => [1/2] RUN a very long command that does not fit", Duration::from_millis(1500), 30);
+        assert_eq!(line.chars().count(), 30);
+        assert!(line.ends_with(" 1.5s"));
+    }
+}
@@ -0,0 +1,317 @@
+use anyhow::Result;
+
+// Image references, as the distribution reference grammar has them:
+// [registry[:port]/]repository[:tag][@digest]. The first component of the
+// repository path names the registry when it has a . or :, is localhost or
+// has upper case letters; otherwise the image is on Docker Hub, which keeps
+// official images such as alpine under library/.
+
+// Docker Hub, as references name it, and where its registry API is served
+const DOCKER_HUB: &str = "docker.io";
+const DOCKER_HUB_URL: &str = "https://registry-1.docker.io";
+const DOCKER_HUB_ALIASES: [&str; 3] = ["docker.io", "index.docker.io", "registry-1.docker.io"];
+
+// The longest name, registry included, the grammar allows
+const MAX_NAME_LENGTH: usize = 255;
+const MAX_TAG_LENGTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    // The name as written, without its tag or digest; stored images keep it
+    name: String,
+    // host[:port], docker.io for Docker Hub whatever name it went by
+    registry: String,
+    // The repository's path on the registry, library/alpine for alpine
+    repository: String,
+    tag: Option<String>,
+    digest: Option<String>,
+}
+
+impl Reference {
+    pub fn parse(reference: &str) -> Result<Self> {
+        let invalid = |why: String| anyhow::anyhow!("Invalid image reference {:?}: {}", reference, why);
+        if reference.contains("://") {
+            return Err(invalid(
+                "references name a registry without a scheme; registries on localhost are reached over HTTP, and \
+                 others with --insecure"
+                    .to_string(),
+            ));
+        }
+        let (name, tag, digest) = split(reference);
+        if let Some(digest) = digest {
+            check_digest(digest).map_err(invalid)?;
+        }
+        if let Some(tag) = tag
+            && !is_tag(tag)
+        {
+            return Err(invalid(format!(
+                "tag {:?} is not 1 to {} letters, digits, _, . and -, starting with a letter, digit or _",
+                tag, MAX_TAG_LENGTH
+            )));
+        }
+        if name.is_empty() {
+            return Err(invalid("the repository name is empty".to_string()));
+        }
+        if name.len() > MAX_NAME_LENGTH {
+            return Err(invalid(format!("the name is longer than {} characters", MAX_NAME_LENGTH)));
+        }
+
+        let (domain, path) = match name.split_once('/') {
+            Some((first, path))
+                if first.contains(['.', ':']) || first == "localhost" || first.chars().any(|c| c.is_ascii_uppercase()) =>
+            {
+                (Some(first), path)
+            }
+            _ => (None, name),
+        };
+        if let Some(domain) = domain
+            && !is_domain(domain)
+        {
+            return Err(invalid(format!("{:?} is not a registry host[:port]", domain)));
+        }
+        if let Some(component) = path.split('/').find(|component| !is_path_component(component)) {
+            return Err(invalid(format!(
+                "repository path component {:?} is not lower case letters and digits, separated by ., _, __ or -",
+                component
+            )));
+        }
+
+        let registry = match domain {
+            Some(domain) if !DOCKER_HUB_ALIASES.contains(&domain) => domain,
+            _ => DOCKER_HUB,
+        };
+        let repository = if registry == DOCKER_HUB && !path.contains('/') {
+            format!("library/{}", path)
+        } else {
+            path.to_string()
+        };
+        Ok(Self {
+            name: name.to_string(),
+            registry: registry.to_string(),
+            repository,
+            tag: tag.map(str::to_string),
+            digest: digest.map(str::to_string),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn registry(&self) -> &str {
+        &self.registry
+    }
+
+    // The base URL of the registry's API: plain HTTP for registries on this
+    // machine, HTTPS for the rest
+    pub fn registry_url(&self) -> String {
+        if self.registry == DOCKER_HUB {
+            return DOCKER_HUB_URL.to_string();
+        }
+        let host = match self.registry.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => host,
+            _ => self.registry.as_str(),
+        };
+        match host {
+            "localhost" | "127.0.0.1" | "[::1]" => format!("http://{}", self.registry),
+            _ => format!("https://{}", self.registry),
+        }
+    }
+
+    pub fn repository(&self) -> &str {
+        &self.repository
+    }
+
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    pub fn digest(&self) -> Option<&str> {
+        self.digest.as_deref()
+    }
+
+    // What the registry's manifest endpoint is asked for: the digest, else
+    // the tag, else latest
+    pub fn manifest_reference(&self) -> &str {
+        self.digest().or(self.tag()).unwrap_or("latest")
+    }
+
+    // The name a digest reference is stored under: name@sha256:..., without
+    // any tag it also had (alpine:3.20@sha256:... is stored as alpine@sha256:...)
+    pub fn pinned(&self) -> Option<String> {
+        Some(format!("{}@{}", self.name, self.digest()?))
+    }
+}
+
+impl std::fmt::Display for Reference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(tag) = self.tag() {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = self.digest() {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+// Split a reference into its name, tag and digest as written, without
+// checking any of them; a : before the last / is a registry's port
+pub fn split(reference: &str) -> (&str, Option<&str>, Option<&str>) {
+    let (rest, digest) = match reference.split_once('@') {
+        Some((rest, digest)) => (rest, Some(digest)),
+        None => (reference, None),
+    };
+    match rest.rsplit_once(':') {
+        Some((name, tag)) if !tag.contains('/') => (name, Some(tag), digest),
+        _ => (rest, None, digest),
+    }
+}
+
+// Whether `name` is a registry host name or address, with an optional port
+fn is_domain(name: &str) -> bool {
+    let (host, port) = match name.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, Some(port)),
+        _ => (name, None),
+    };
+    if port.is_some_and(|port| port.is_empty() || !port.bytes().all(|byte| byte.is_ascii_digit())) {
+        return false;
+    }
+    if let Some(address) = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+        return address.parse::<std::net::Ipv6Addr>().is_ok();
+    }
+    !host.is_empty()
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+        })
+}
+
+// [a-z0-9]+ runs joined by one ., one or two _, or any number of -
+fn is_path_component(component: &str) -> bool {
+    let bytes = component.as_bytes();
+    let alphanumeric = |byte: &u8| byte.is_ascii_lowercase() || byte.is_ascii_digit();
+    if !bytes.first().is_some_and(alphanumeric) || !bytes.last().is_some_and(alphanumeric) {
+        return false;
+    }
+    let mut separator = String::new();
+    for &byte in bytes {
+        if alphanumeric(&byte) {
+            let allowed = matches!(separator.as_str(), "" | "." | "_" | "__") || separator.bytes().all(|b| b == b'-');
+            if !allowed {
+                return false;
+            }
+            separator.clear();
+        } else if matches!(byte, b'.' | b'_' | b'-') {
+            separator.push(byte as char);
+        } else {
+            return false;
+        }
+    }
+    true
+}
+
+fn is_tag(tag: &str) -> bool {
+    let word = |byte: u8| byte.is_ascii_alphanumeric() || byte == b'_';
+    tag.len() <= MAX_TAG_LENGTH
+        && tag.bytes().next().is_some_and(word)
+        && tag.bytes().all(|byte| word(byte) || byte == b'.' || byte == b'-')
+}
+
+// algorithm:encoded, with the hex length its algorithm gives for sha256 and
+// sha512 digests
+fn check_digest(digest: &str) -> std::result::Result<(), String> {
+    let Some((algorithm, encoded)) = digest.split_once(':') else {
+        return Err(format!("digest {:?} is not algorithm:hex", digest));
+    };
+    let algorithm_ok = !algorithm.is_empty()
+        && algorithm.split(['+', '.', '_', '-']).all(|part| {
+            !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit())
+        });
+    if !algorithm_ok {
+        return Err(format!("digest algorithm {:?} is not valid", algorithm));
+    }
+    let hex_length = match algorithm {
+        "sha256" => Some(64),
+        "sha512" => Some(128),
+        _ => None,
+    };
+    let valid = match hex_length {
+        Some(length) => {
+            encoded.len() == length && encoded.bytes().all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+        }
+        None => {
+            encoded.len() >= 32 && encoded.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"=_-".contains(&byte))
+        }
+    };
+    if !valid {
+        return Err(format!("digest {:?} is not a valid {} digest", digest, algorithm));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_references() {
+        let digest = format!("sha256:{}", "ab".repeat(32));
+        let parts = |reference: &str| {
+            let parsed = Reference::parse(reference).unwrap();
+            let (registry, repository) = (parsed.registry().to_string(), parsed.repository().to_string());
+            (registry, repository, parsed.manifest_reference().to_string(), parsed.registry_url())
+        };
+        let hub = |repository: &str, reference: &str| {
+            ("docker.io".to_string(), repository.to_string(), reference.to_string(), DOCKER_HUB_URL.to_string())
+        };
+        assert_eq!(parts("alpine"), hub("library/alpine", "latest"));
+        assert_eq!(parts("docker.io/alpine:3.20"), hub("library/alpine", "3.20"));
+        assert_eq!(parts("index.docker.io/library/alpine"), hub("library/alpine", "latest"));
+        assert_eq!(parts("bitnami/redis:7"), hub("bitnami/redis", "7"));
+        assert_eq!(parts(&format!("alpine:3.20@{}", digest)), hub("library/alpine", &digest));
+        let other = |registry: &str, repository: &str, reference: &str, url: &str| {
+            (registry.to_string(), repository.to_string(), reference.to_string(), url.to_string())
+        };
+        assert_eq!(
+            parts("ghcr.io/org/app/service:v1"),
+            other("ghcr.io", "org/app/service", "v1", "https://ghcr.io")
+        );
+        assert_eq!(parts("localhost:5000/app"), other("localhost:5000", "app", "latest", "http://localhost:5000"));
+        assert_eq!(parts("localhost/team/app:v2"), other("localhost", "team/app", "v2", "http://localhost"));
+        assert_eq!(parts("[::1]:5000/app:v1"), other("[::1]:5000", "app", "v1", "http://[::1]:5000"));
+        assert_eq!(
+            parts(&format!("registry.example.com:443/a/b@{}", digest)),
+            other("registry.example.com:443", "a/b", &digest, "https://registry.example.com:443")
+        );
+
+        let pinned = Reference::parse(&format!("localhost:5000/app:v1@{}", digest)).unwrap();
+        assert_eq!(pinned.pinned(), Some(format!("localhost:5000/app@{}", digest)));
+        assert_eq!(pinned.to_string(), format!("localhost:5000/app:v1@{}", digest));
+        assert_eq!(Reference::parse("localhost:5000/app:v1").unwrap().pinned(), None);
+        assert_eq!(split("localhost:5000/app"), ("localhost:5000/app", None, None));
+
+        for invalid in [
+            "",
+            "app:",
+            "App",
+            "app:-v1",
+            "ghcr.io/org//app",
+            "ghcr.io/org/app_-x",
+            "-host.io/app",
+            "host.io:port/app",
+            "app@sha256:abc",
+            "app@sha512:ab",
+            "app@SHA256:abc",
+            "http://localhost:5000/app",
+        ] {
+            assert!(Reference::parse(invalid).is_err(), "{}", invalid);
+        }
+        for valid in ["a__b/c-d.e", "a---b", "Registry.Example.com/app", "app@md5:0123456789abcdef0123456789abcdef"] {
+            assert!(Reference::parse(valid).is_ok(), "{}", valid);
+        }
+    }
+}
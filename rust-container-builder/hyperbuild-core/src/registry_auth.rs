@@ -0,0 +1,419 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Tokens are refreshed this long before the registry says they expire
+const EXPIRY_MARGIN: Duration = Duration::from_secs(10);
+
+// A username and password, or identity token, for one registry
+#[derive(Clone, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Credentials({})", self.username)
+    }
+}
+
+impl Credentials {
+    // The registry's entry in the Docker config file ($DOCKER_CONFIG/config.json
+    // or ~/.docker/config.json), as `docker login` writes it
+    pub fn from_docker_config(registry_url: &str) -> Result<Option<Self>> {
+        let Some(path) = docker_config_path() else {
+            return Ok(None);
+        };
+        let contents = match std::fs::read(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(anyhow::anyhow!("Failed to read Docker config {:?}: {}", path, e)),
+        };
+        let config: serde_json::Value = serde_json::from_slice(&contents)
+            .map_err(|e| anyhow::anyhow!("Invalid Docker config {:?}: {}", path, e))?;
+        Ok(Self::from_config(&config, registry_url))
+    }
+
+    fn from_config(config: &serde_json::Value, registry_url: &str) -> Option<Self> {
+        let host = registry_host(registry_url);
+        let auths = config.get("auths")?.as_object()?;
+        let (_, entry) = auths.iter().find(|(key, _)| registry_host(key) == host)?;
+
+        if let Some(auth) = entry.get("auth").and_then(|auth| auth.as_str()).filter(|auth| !auth.is_empty()) {
+            let decoded = openssl::base64::decode_block(auth).ok()?;
+            let (username, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+            return Some(Self {
+                username: username.to_string(),
+                password: password.to_string(),
+            });
+        }
+        let field = |name: &str| entry.get(name).and_then(|value| value.as_str()).map(str::to_string);
+        Some(Self {
+            username: field("username")?,
+            password: field("password")?,
+        })
+    }
+}
+
+fn docker_config_path() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("DOCKER_CONFIG") {
+        return Some(PathBuf::from(dir).join("config.json"));
+    }
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".docker").join("config.json"))
+}
+
+// The host a registry URL or Docker config key names; Docker Hub goes by
+// several names, and `docker login` records it as https://index.docker.io/v1/
+pub fn registry_host(url: &str) -> String {
+    let host = url
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    match host {
+        "docker.io" | "index.docker.io" | "registry-1.docker.io" => "docker.io".to_string(),
+        host => host.to_string(),
+    }
+}
+
+// Where credentials come from for a registry the Docker config has none for
+#[derive(Debug, Clone, PartialEq)]
+pub enum Provider {
+    // Amazon ECR, whose tokens AWS credentials are exchanged for
+    Ecr(crate::ecr::EcrRegistry),
+    // Google Artifact Registry and Container Registry, which take OAuth2
+    // access tokens for Google credentials
+    Gcp,
+    // Azure Container Registry, by host, which exchanges Azure AD tokens
+    // for refresh tokens of its own
+    Acr(String),
+}
+
+impl Provider {
+    // The provider for the registry a URL or image name points at, if it
+    // is one credentials can be fetched for
+    pub fn detect(registry_url: &str) -> Option<Self> {
+        let host = registry_host(registry_url);
+        if crate::gcp::is_registry(&host) {
+            return Some(Provider::Gcp);
+        }
+        if crate::acr::is_registry(&host) {
+            return Some(Provider::Acr(host));
+        }
+        crate::ecr::EcrRegistry::parse(&host).map(Provider::Ecr)
+    }
+
+    // Credentials for the registry, and how long they can be used
+    async fn credentials(&self, client: &reqwest::Client) -> Result<(Credentials, Duration)> {
+        match self {
+            Provider::Ecr(registry) => crate::ecr::login(client, registry).await,
+            Provider::Gcp => crate::gcp::login(client).await,
+            Provider::Acr(host) => crate::acr::login(client, host).await,
+        }
+    }
+}
+
+// How a registry asks to be authenticated, from the WWW-Authenticate header of a 401
+#[derive(Debug, Clone, PartialEq)]
+pub enum Challenge {
+    Basic,
+    // Fetch a token for the scope from the realm, an OAuth2 token endpoint
+    Bearer {
+        realm: String,
+        service: Option<String>,
+        scope: Option<String>,
+    },
+}
+
+impl Challenge {
+    pub fn parse(header: &str) -> Option<Self> {
+        let (scheme, params) = header.trim().split_once(' ').unwrap_or((header.trim(), ""));
+        let params = parse_params(params);
+        match scheme.to_ascii_lowercase().as_str() {
+            "basic" => Some(Challenge::Basic),
+            "bearer" => Some(Challenge::Bearer {
+                realm: params.get("realm")?.clone(),
+                service: params.get("service").cloned(),
+                scope: params.get("scope").cloned(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+// key="value" pairs separated by commas; quoted values may hold commas, as
+// in scope="repository:app:pull,push"
+fn parse_params(params: &str) -> HashMap<String, String> {
+    let mut parsed = HashMap::new();
+    let mut rest = params.trim();
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_ascii_lowercase();
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                (&quoted[..end], quoted.get(end + 1..).unwrap_or_default())
+            }
+            None => {
+                let end = after.find(',').unwrap_or(after.len());
+                (after[..end].trim(), &after[end..])
+            }
+        };
+        parsed.insert(key, value.to_string());
+        rest = after.trim_start_matches(',').trim();
+    }
+    parsed
+}
+
+// The token scope a request on a repository needs; requests involving several
+// repositories, such as blob mounts, join their scopes with spaces
+pub fn scope(repo: &str, push: bool) -> String {
+    format!("repository:{}:{}", repo, if push { "pull,push" } else { "pull" })
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    access_token: Option<String>,
+    // Seconds; the token spec's default is 60
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Token {
+    value: String,
+    expires: Instant,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // Learned from the registry's first 401, so later scopes get their
+    // token before the request instead of after another 401
+    challenge: Option<Challenge>,
+    // Bearer tokens by scope
+    tokens: HashMap<String, Token>,
+    // The provider's credentials, until they expire
+    provided: Option<(Credentials, Instant)>,
+}
+
+impl State {
+    fn provided(&self) -> Option<Credentials> {
+        let (credentials, expires) = self.provided.as_ref()?;
+        (*expires > Instant::now()).then(|| credentials.clone())
+    }
+}
+
+// Authentication with one registry: its credentials, if any, and the tokens
+// it handed out, kept until they expire
+#[derive(Debug, Default)]
+pub struct Auth {
+    credentials: Option<Credentials>,
+    // Fetches credentials when the registry asks for them and there are
+    // none of the Docker config's, again once they expire
+    provider: Option<Provider>,
+    // Held while the provider is asked, so concurrent requests ask once
+    providing: tokio::sync::Mutex<()>,
+    state: Mutex<State>,
+}
+
+impl Auth {
+    pub fn new(credentials: Option<Credentials>) -> Self {
+        Self {
+            credentials,
+            provider: None,
+            providing: tokio::sync::Mutex::default(),
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn with_provider(mut self, provider: Option<Provider>) -> Self {
+        self.provider = provider;
+        self
+    }
+
+    // The Docker config's credentials, else the provider's, fetched when
+    // there are none yet or they expired
+    async fn credentials(&self, client: &reqwest::Client) -> Result<Option<Credentials>> {
+        let Some(provider) = self.provider.as_ref().filter(|_| self.credentials.is_none()) else {
+            return Ok(self.credentials.clone());
+        };
+        let _providing = self.providing.lock().await;
+        if let Some(credentials) = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).provided() {
+            return Ok(Some(credentials));
+        }
+        let (credentials, lifetime) = provider.credentials(client).await?;
+        let expires = Instant::now() + lifetime.saturating_sub(EXPIRY_MARGIN);
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).provided =
+            Some((credentials.clone(), expires));
+        Ok(Some(credentials))
+    }
+
+    // Whether there are credentials to answer a basic challenge with
+    pub async fn has_credentials(&self, client: &reqwest::Client) -> Result<bool> {
+        Ok(self.credentials(client).await?.is_some())
+    }
+
+    // The Authorization header for a request needing `scope`, if there is one
+    pub fn header(&self, scope: &str) -> Option<String> {
+        let state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match state.challenge.as_ref()? {
+            Challenge::Basic => self.basic(&state),
+            Challenge::Bearer { .. } => {
+                // A token that allows pushing to a repository also allows pulling from it
+                let token = state
+                    .tokens
+                    .get(scope)
+                    .or_else(|| state.tokens.get(&format!("{},push", scope)))
+                    .filter(|token| token.expires > Instant::now())?;
+                Some(format!("Bearer {}", token.value))
+            }
+        }
+    }
+
+    // Get a token for `scope` ahead of a request, once the registry is known
+    // to want one and none is cached or the cached one expired
+    pub async fn refresh(&self, client: &reqwest::Client, scope: &str) -> Result<()> {
+        let challenge = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).challenge.clone();
+        if let Some(challenge @ Challenge::Bearer { .. }) = challenge
+            && self.header(scope).is_none()
+        {
+            self.fetch_token(client, &challenge, scope).await?;
+        }
+        Ok(())
+    }
+
+    // Answer a 401's challenge for `scope`; false if nothing can be done
+    // about it, e.g. basic authentication without credentials
+    pub async fn authenticate(&self, client: &reqwest::Client, header: &str, scope: &str) -> Result<bool> {
+        let Some(challenge) = Challenge::parse(header) else {
+            return Ok(false);
+        };
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).challenge = Some(challenge.clone());
+        match challenge {
+            Challenge::Basic => Ok(self.credentials(client).await?.is_some()),
+            Challenge::Bearer { .. } => {
+                self.fetch_token(client, &challenge, scope).await?;
+                Ok(true)
+            }
+        }
+    }
+
+    async fn fetch_token(&self, client: &reqwest::Client, challenge: &Challenge, scope: &str) -> Result<()> {
+        let Challenge::Bearer { realm, service, .. } = challenge else {
+            return Ok(());
+        };
+        // Each repository a request involves is a scope of its own
+        let mut query: Vec<_> = scope.split(' ').map(|scope| ("scope", scope)).collect();
+        if let Some(service) = service {
+            query.push(("service", service));
+        }
+        let mut request = client.get(realm).query(&query);
+        let credentials = self.credentials(client).await?;
+        if let Some(credentials) = &credentials {
+            request = request.basic_auth(&credentials.username, Some(&credentials.password));
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+            let hint = if credentials.is_none() { " (no credentials in the Docker config)" } else { "" };
+            return Err(anyhow::anyhow!(
+                "Failed to get a token for {} from {}{}: {} - {}",
+                scope,
+                realm,
+                hint,
+                status,
+                error_text
+            ));
+        }
+        let token: TokenResponse = serde_json::from_slice(&response.bytes().await?)
+            .map_err(|e| anyhow::anyhow!("Invalid token response from {}: {}", realm, e))?;
+        self.store_token(scope, token)
+    }
+
+    fn store_token(&self, scope: &str, response: TokenResponse) -> Result<()> {
+        let value = response
+            .token
+            .or(response.access_token)
+            .ok_or_else(|| anyhow::anyhow!("Token response for {} holds no token", scope))?;
+        let lifetime = Duration::from_secs(response.expires_in.unwrap_or(60));
+        let token = Token {
+            value,
+            expires: Instant::now() + lifetime.saturating_sub(EXPIRY_MARGIN),
+        };
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.tokens.insert(scope.to_string(), token);
+        Ok(())
+    }
+
+    fn basic(&self, state: &State) -> Option<String> {
+        let credentials = self.credentials.clone().or_else(|| state.provided())?;
+        let pair = format!("{}:{}", credentials.username, credentials.password);
+        Some(format!("Basic {}", openssl::base64::encode_block(pair.as_bytes())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenges_credentials_and_tokens() {
+        assert_eq!(
+            Challenge::parse(concat!(
+                r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io","#,
+                r#"scope="repository:library/alpine:pull,push""#
+            )),
+            Some(Challenge::Bearer {
+                realm: "https://auth.docker.io/token".to_string(),
+                service: Some("registry.docker.io".to_string()),
+                scope: Some("repository:library/alpine:pull,push".to_string()),
+            })
+        );
+        assert_eq!(Challenge::parse(r#"Basic realm="Registry""#), Some(Challenge::Basic));
+        assert_eq!(Challenge::parse("Bearer service=x"), None);
+
+        // `docker login` stores Docker Hub under its v1 index URL
+        let config = serde_json::json!({ "auths": {
+            "https://index.docker.io/v1/": { "auth": openssl::base64::encode_block(b"me:secret") },
+            "ghcr.io": { "username": "bot", "password": "pat" },
+        }});
+        let hub = Credentials::from_config(&config, "https://registry-1.docker.io").unwrap();
+        assert_eq!((hub.username.as_str(), hub.password.as_str()), ("me", "secret"));
+        assert_eq!(Credentials::from_config(&config, "https://ghcr.io").unwrap().username, "bot");
+        assert!(Credentials::from_config(&config, "http://localhost:5000").is_none());
+
+        let auth = Auth::new(Some(hub));
+        assert_eq!(auth.header(&scope("app", false)), None);
+        auth.state.lock().unwrap().challenge = Challenge::parse(r#"Bearer realm="https://auth.example.com/token""#);
+        let push_token = TokenResponse {
+            token: Some("abc".to_string()),
+            access_token: None,
+            expires_in: Some(300),
+        };
+        auth.store_token(&scope("app", true), push_token).unwrap();
+        // A push token serves pulls too, but not other repositories
+        assert_eq!(auth.header(&scope("app", false)).as_deref(), Some("Bearer abc"));
+        assert_eq!(auth.header(&scope("other", false)), None);
+
+        let short_lived = TokenResponse {
+            token: None,
+            access_token: Some("def".to_string()),
+            expires_in: Some(5),
+        };
+        auth.store_token(&scope("other", false), short_lived).unwrap();
+        assert_eq!(auth.header(&scope("other", false)), None);
+
+        auth.state.lock().unwrap().challenge = Some(Challenge::Basic);
+        let basic = format!("Basic {}", openssl::base64::encode_block(b"me:secret"));
+        assert_eq!(auth.header(&scope("app", true)), Some(basic));
+    }
+}
@@ -0,0 +1,429 @@
+use crate::progress::ProgressEvent;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildStatus {
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    Running,
+    Cached,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepReport {
+    pub id: usize,
+    pub name: String,
+    pub status: StepStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // Captured RUN output, relative to the build's directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<f64>,
+    // How often a failing RUN step was run again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+    // The layer the step produced or reused from the cache
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer: Option<LayerReport>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayerReport {
+    pub digest: String,
+    pub size: u64,
+    pub compressed_size: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BuildSummary {
+    pub steps: usize,
+    pub cache_hits: usize,
+    // Steps that had to build their layer
+    pub cache_misses: usize,
+    #[serde(default)]
+    pub retried: usize,
+    pub layer_bytes: u64,
+    // What pushing the build's layers transfers
+    pub compressed_bytes: u64,
+}
+
+// What a build did, kept under <output-dir>/builds/<build-id>/report.json
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildReport {
+    pub build_id: String,
+    pub status: BuildStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    pub started: String,
+    pub finished: String,
+    #[serde(default)]
+    pub duration_secs: f64,
+    #[serde(default)]
+    pub summary: BuildSummary,
+    pub steps: Vec<StepReport>,
+}
+
+// Builds a report from progress events, writing each step's output to its own log file
+pub struct BuildRecorder {
+    dir: PathBuf,
+    report: BuildReport,
+    logs: HashMap<usize, BufWriter<fs::File>>,
+    started: Instant,
+    step_starts: HashMap<usize, Instant>,
+}
+
+impl BuildRecorder {
+    pub fn create(builds_dir: &Path, build_id: &str) -> Result<Self> {
+        let dir = builds_dir.join(build_id);
+        fs::create_dir_all(dir.join("logs"))?;
+        Ok(Self {
+            dir,
+            report: BuildReport {
+                build_id: build_id.to_string(),
+                status: BuildStatus::Failed,
+                image: None,
+                digest: None,
+                started: now(),
+                finished: String::new(),
+                duration_secs: 0.0,
+                summary: BuildSummary::default(),
+                steps: Vec::new(),
+            },
+            logs: HashMap::new(),
+            started: Instant::now(),
+            step_starts: HashMap::new(),
+        })
+    }
+
+    pub fn record(&mut self, event: &ProgressEvent) -> Result<()> {
+        match event {
+            ProgressEvent::StepStarted { id, name } => {
+                self.step_starts.insert(*id, Instant::now());
+                self.report.steps.push(StepReport {
+                    id: *id,
+                    name: name.clone(),
+                    status: StepStatus::Running,
+                    error: None,
+                    log: None,
+                    duration_secs: None,
+                    retries: None,
+                    layer: None,
+                });
+            }
+            ProgressEvent::StepLog { id, line } => {
+                if !self.logs.contains_key(id) {
+                    let relative = format!("logs/{}.log", id);
+                    let file = fs::File::create(self.dir.join(&relative))?;
+                    self.logs.insert(*id, BufWriter::new(file));
+                    if let Some(step) = self.step(*id) {
+                        step.log = Some(relative);
+                    }
+                }
+                if let Some(log) = self.logs.get_mut(id) {
+                    writeln!(log, "{}", line)?;
+                }
+            }
+            ProgressEvent::StepCached {
+                id,
+                digest,
+                size,
+                compressed_size,
+            } => {
+                if let Some(digest) = digest {
+                    self.set_layer(*id, digest, *size, *compressed_size);
+                }
+                self.finish_step(*id, StepStatus::Cached, None)?;
+            }
+            ProgressEvent::LayerCommitted {
+                id,
+                digest,
+                size,
+                compressed_size,
+            } => self.set_layer(*id, digest, *size, *compressed_size),
+            ProgressEvent::StepRetrying { id, attempt, .. } => {
+                if let Some(step) = self.step(*id) {
+                    step.retries = Some(*attempt);
+                }
+            }
+            ProgressEvent::StepDone { id } => self.finish_step(*id, StepStatus::Done, None)?,
+            ProgressEvent::StepFailed { id, error } => {
+                let error = (!error.is_empty()).then(|| error.clone());
+                self.finish_step(*id, StepStatus::Failed, error)?;
+            }
+            ProgressEvent::BuildFinished { image, digest } => {
+                self.report.status = BuildStatus::Succeeded;
+                self.report.image = Some(image.clone());
+                self.report.digest = Some(digest.clone());
+            }
+            ProgressEvent::StageStarted { .. } | ProgressEvent::StepBytes { .. } => {}
+        }
+        Ok(())
+    }
+
+    // Write the report once the build is over, successful or not
+    pub fn finish(mut self) -> Result<BuildReport> {
+        for log in self.logs.values_mut() {
+            log.flush()?;
+        }
+        self.report.finished = now();
+        self.report.duration_secs = self.started.elapsed().as_secs_f64();
+        self.report.summary = summarize(&self.report.steps);
+        fs::write(self.dir.join("report.json"), serde_json::to_vec_pretty(&self.report)?)?;
+        Ok(self.report)
+    }
+
+    fn step(&mut self, id: usize) -> Option<&mut StepReport> {
+        self.report.steps.iter_mut().find(|step| step.id == id)
+    }
+
+    fn set_layer(&mut self, id: usize, digest: &str, size: u64, compressed_size: u64) {
+        if let Some(step) = self.step(id) {
+            step.layer = Some(LayerReport {
+                digest: digest.to_string(),
+                size,
+                compressed_size,
+            });
+        }
+    }
+
+    fn finish_step(&mut self, id: usize, status: StepStatus, error: Option<String>) -> Result<()> {
+        if let Some(log) = self.logs.get_mut(&id) {
+            log.flush()?;
+        }
+        let duration = self.step_starts.get(&id).map(|start| start.elapsed().as_secs_f64());
+        if let Some(step) = self.step(id) {
+            step.status = status;
+            step.error = error;
+            step.duration_secs = duration;
+        }
+        Ok(())
+    }
+}
+
+fn summarize(steps: &[StepReport]) -> BuildSummary {
+    let mut summary = BuildSummary {
+        steps: steps.len(),
+        ..BuildSummary::default()
+    };
+    for step in steps {
+        match (&step.status, &step.layer) {
+            (StepStatus::Cached, _) => summary.cache_hits += 1,
+            (_, Some(_)) => summary.cache_misses += 1,
+            _ => {}
+        }
+        if step.retries.is_some() {
+            summary.retried += 1;
+        }
+        if let Some(layer) = &step.layer {
+            summary.layer_bytes += layer.size;
+            summary.compressed_bytes += layer.compressed_size;
+        }
+    }
+    summary
+}
+
+// A table of the steps with their status, duration and layer size, slowest called out
+pub fn render_table(report: &BuildReport) -> String {
+    let mut rows = vec![[
+        "STEP".to_string(),
+        "STATUS".to_string(),
+        "DURATION".to_string(),
+        "SIZE".to_string(),
+        "NAME".to_string(),
+    ]];
+    for step in &report.steps {
+        let status = match step.status {
+            StepStatus::Running => "running",
+            StepStatus::Cached => "cached",
+            StepStatus::Done => "done",
+            StepStatus::Failed => "failed",
+        };
+        let status = match step.retries {
+            Some(retries) => format!("{} (retried {}x)", status, retries),
+            None => status.to_string(),
+        };
+        rows.push([
+            format!("#{}", step.id),
+            status,
+            step.duration_secs.map(|secs| format!("{:.2}s", secs)).unwrap_or_default(),
+            step.layer.as_ref().map(|layer| human_size(layer.size)).unwrap_or_default(),
+            step.name.clone(),
+        ]);
+    }
+
+    let mut widths = [0usize; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut table = String::new();
+    for row in &rows {
+        table.push_str(&format!(
+            "{:<w0$}  {:<w1$}  {:>w2$}  {:>w3$}  {}\n",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            row[4],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3],
+        ));
+    }
+
+    let summary = &report.summary;
+    table.push_str(&format!(
+        "\n{} steps in {:.2}s: {} cached, {} built; layers {} ({} compressed)\n",
+        summary.steps,
+        report.duration_secs,
+        summary.cache_hits,
+        summary.cache_misses,
+        human_size(summary.layer_bytes),
+        human_size(summary.compressed_bytes),
+    ));
+    if summary.retried > 0 {
+        table.push_str(&format!("Retried steps: {}\n", summary.retried));
+    }
+    if let Some(slowest) = report
+        .steps
+        .iter()
+        .filter(|step| step.duration_secs.is_some())
+        .max_by(|a, b| a.duration_secs.partial_cmp(&b.duration_secs).unwrap_or(std::cmp::Ordering::Equal))
+    {
+        table.push_str(&format!(
+            "Slowest step: #{} {} ({:.2}s)\n",
+            slowest.id,
+            slowest.name,
+            slowest.duration_secs.unwrap_or_default()
+        ));
+    }
+    table
+}
+
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "kB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+// Find a recorded build by id or unique id prefix; without one, the most recent build
+pub fn load_report(builds_dir: &Path, build_id: Option<&str>) -> Result<(PathBuf, BuildReport)> {
+    let mut reports = Vec::new();
+    if builds_dir.exists() {
+        for entry in fs::read_dir(builds_dir)? {
+            let dir = entry?.path();
+            let name = dir.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+            if build_id.is_some_and(|id| !name.starts_with(id)) {
+                continue;
+            }
+            let Ok(contents) = fs::read(dir.join("report.json")) else {
+                continue;
+            };
+            let report: BuildReport = serde_json::from_slice(&contents)?;
+            if build_id == Some(name.as_str()) {
+                return Ok((dir, report));
+            }
+            reports.push((dir, report));
+        }
+    }
+
+    match (build_id, reports.len()) {
+        (_, 0) => Err(anyhow::anyhow!(
+            "No recorded build{} in {:?}",
+            build_id.map(|id| format!(" matching {}", id)).unwrap_or_default(),
+            builds_dir
+        )),
+        (Some(id), n) if n > 1 => Err(anyhow::anyhow!("Build id {} is ambiguous ({} builds match)", id, n)),
+        _ => Ok(reports
+            .into_iter()
+            .max_by(|(_, a), (_, b)| a.started.cmp(&b.started))
+            .expect("at least one report")),
+    }
+}
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recorder_writes_logs_and_report() {
+        let builds = tempfile::tempdir().unwrap();
+        let mut recorder = BuildRecorder::create(builds.path(), "build_1234").unwrap();
+        let events = [
+            ProgressEvent::StepStarted {
+                id: 1,
+                name: "[1/2] FROM base".to_string(),
+            },
+            ProgressEvent::StepCached {
+                id: 1,
+                digest: Some("sha256:aaa".to_string()),
+                size: 4096,
+                compressed_size: 1000,
+            },
+            ProgressEvent::StepStarted {
+                id: 2,
+                name: "[2/2] RUN make".to_string(),
+            },
+            ProgressEvent::StepLog {
+                id: 2,
+                line: "cc main.c".to_string(),
+            },
+            ProgressEvent::StepFailed {
+                id: 2,
+                error: "exit status 2".to_string(),
+            },
+        ];
+        for event in &events {
+            recorder.record(event).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let (dir, report) = load_report(builds.path(), Some("build_12")).unwrap();
+        assert_eq!(report.status, BuildStatus::Failed);
+        assert_eq!(report.steps[0].status, StepStatus::Cached);
+        assert_eq!(report.steps[1].error.as_deref(), Some("exit status 2"));
+        let log = report.steps[1].log.as_ref().unwrap();
+        assert_eq!(fs::read_to_string(dir.join(log)).unwrap(), "cc main.c\n");
+
+        assert_eq!(report.summary.cache_hits, 1);
+        assert_eq!(report.summary.compressed_bytes, 1000);
+        assert!(report.steps[1].duration_secs.is_some());
+        let table = render_table(&report);
+        assert!(table.contains("4.1 kB"));
+        assert!(table.contains("2 steps"));
+    }
+}
@@ -0,0 +1,165 @@
+use crate::containerd::ContainerdSettings;
+use crate::engine::hooks::Hook;
+use crate::engine::plugins::{self, Plugin};
+use crate::registry_client::RegistrySettings;
+use crate::storage::{Compression, EncryptionSettings};
+use anyhow::Result;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+// Settings read from a TOML config file: the one passed with --config,
+// else $HYPERBUILD_CONFIG, ./hyperbuild.toml or ~/.config/hyperbuild/config.toml
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    #[serde(default)]
+    pub hooks: Vec<Hook>,
+    #[serde(default)]
+    pub plugins: Vec<Plugin>,
+    #[serde(default)]
+    pub compression: Compression,
+    // Store used when --output-dir is not given
+    #[serde(default)]
+    pub root: Option<PathBuf>,
+    // Key that layer blobs are encrypted with at rest
+    #[serde(default)]
+    pub encryption: EncryptionSettings,
+    #[serde(default)]
+    pub registry: RegistrySettings,
+    // Where `load --to containerd` and `build --store containerd` put images
+    #[serde(default)]
+    pub containerd: ContainerdSettings,
+}
+
+impl Settings {
+    pub fn load(explicit: Option<&Path>) -> Result<Self> {
+        let path = match explicit {
+            Some(path) => Some(path.to_path_buf()),
+            None => default_path(),
+        };
+        match path {
+            Some(path) => Self::from_file(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read config file {:?}: {}", path, e))?;
+        let settings: Self =
+            toml::from_str(&contents).map_err(|e| anyhow::anyhow!("Invalid config file {:?}: {}", path, e))?;
+        settings
+            .compression
+            .validate()
+            .map_err(|e| anyhow::anyhow!("Invalid config file {:?}: {}", path, e))?;
+        settings
+            .registry
+            .validate()
+            .map_err(|e| anyhow::anyhow!("Invalid config file {:?}: {}", path, e))?;
+        plugins::validate(&settings.plugins).map_err(|e| anyhow::anyhow!("Invalid config file {:?}: {}", path, e))?;
+        Ok(settings)
+    }
+
+    // The image store: --output-dir, else $HYPERBUILD_ROOT, the config file's
+    // root, or $XDG_DATA_HOME/hyperbuild, so every project shares one store
+    pub fn store_root(&self, explicit: Option<PathBuf>) -> PathBuf {
+        explicit
+            .or_else(|| std::env::var_os("HYPERBUILD_ROOT").map(PathBuf::from))
+            .or_else(|| self.root.clone())
+            .or_else(|| {
+                let data_home = std::env::var_os("XDG_DATA_HOME")
+                    .map(PathBuf::from)
+                    .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))?;
+                Some(data_home.join("hyperbuild"))
+            })
+            .unwrap_or_else(|| PathBuf::from("build-output"))
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("HYPERBUILD_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let local = PathBuf::from("hyperbuild.toml");
+    if local.exists() {
+        return Some(local);
+    }
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    let user = config_home.join("hyperbuild").join("config.toml");
+    user.exists().then_some(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::hooks::HookEvent;
+
+    #[test]
+    fn test_parse_hooks() {
+        let settings: Settings = toml::from_str(
+            r#"
+            [[hooks]]
+            on = "build-start"
+            command = ["notify-send", "build started"]
+
+            [[hooks]]
+            on = "pre-step"
+            command = ["/usr/local/bin/audit"]
+            required = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(settings.hooks.len(), 2);
+        assert_eq!(settings.hooks[1].on, HookEvent::PreStep);
+        assert!(settings.hooks[1].required);
+
+        assert!(toml::from_str::<Settings>("[[hooks]]\non = \"sometimes\"\ncommand = []\n").is_err());
+
+        let settings: Settings = toml::from_str("root = \"/var/lib/hyperbuild\"\n[compression]\nlevel = 9\n").unwrap();
+        assert_eq!(settings.compression, Compression { level: Some(9), ..Compression::default() });
+        let zstd: Settings = toml::from_str("[compression]\nalgorithm = \"zstd\"\nlevel = 19\n").unwrap();
+        assert_eq!(zstd.compression.algorithm, crate::storage::CompressionAlgorithm::Zstd);
+        assert!(zstd.compression.validate().is_ok());
+        assert_eq!(settings.store_root(Some(PathBuf::from("out"))), PathBuf::from("out"));
+        assert!(settings.encryption.keyfile.is_none());
+
+        let settings: Settings = toml::from_str("[encryption]\nkeyfile = \"/etc/hyperbuild/key\"\n").unwrap();
+        assert_eq!(settings.encryption.keyfile, Some(PathBuf::from("/etc/hyperbuild/key")));
+        assert!(toml::from_str::<Settings>("[encryption]\nkey = \"secret\"\n").is_err());
+
+        let settings: Settings = toml::from_str("[registry]\nchunk_size = 5242880\n").unwrap();
+        assert_eq!((settings.registry.chunk_size, settings.registry.max_attempts), (5 << 20, 4));
+        let settings: Settings = toml::from_str("[registry]\nmedia_types = \"docker\"\n").unwrap();
+        assert_eq!(settings.registry.media_types, crate::registry_client::MediaTypes::Docker);
+        let hosts = "[registry.hosts.\"registry.internal:5000\"]\ninsecure = true\n";
+        let settings: Settings = toml::from_str(hosts).unwrap();
+        assert!(settings.registry.host("https://registry.internal:5000").insecure);
+        let settings: Settings = toml::from_str("[registry]\nparallel = 0\n").unwrap();
+        assert!(settings.registry.validate().is_err());
+
+        let proxy = "[registry.proxy]\nhttp = \"proxy.corp.example:3128\"\nhttps = \"http://proxy.corp.example:3128\"\n";
+        let settings: Settings = toml::from_str(proxy).unwrap();
+        assert_eq!(settings.registry.proxy.or_env().http.as_deref(), Some("proxy.corp.example:3128"));
+        assert!(settings.registry.validate().is_ok());
+        let socks = "[registry.proxy]\nhttp = \"socks5://127.0.0.1:1080\"\nhttps = \"socks5h://127.0.0.1:1080\"\n";
+        let settings: Settings = toml::from_str(socks).unwrap();
+        assert!(settings.registry.validate().is_ok());
+        let client = crate::registry_client::RegistryClient::new("https://ghcr.io".to_string()).unwrap();
+        assert!(client.with_settings(settings.registry).is_ok());
+        let socks4 = "[registry.proxy]\nhttps = \"socks4://proxy:1080\"\n";
+        let settings: Settings = toml::from_str(socks4).unwrap();
+        assert!(settings.registry.validate().is_err());
+        let http = "[registry.http]\nread_timeout = 0\nhttp2 = false\n";
+        let settings: Settings = toml::from_str(http).unwrap();
+        assert_eq!((settings.registry.http.read_timeout, settings.registry.http.connect_timeout), (0, 30));
+        assert!(!settings.registry.http.http2);
+        let settings: Settings = toml::from_str("[registry]\npolicy = \"/nonexistent/policy.json\"\n").unwrap();
+        assert!(settings.registry.validate().is_err());
+
+        let settings: Settings = toml::from_str("[containerd]\nnamespace = \"k8s.io\"\n").unwrap();
+        assert_eq!(settings.containerd.namespace.as_deref(), Some("k8s.io"));
+        assert!(toml::from_str::<Settings>("[containerd]\nsocket = \"/run/containerd.sock\"\n").is_err());
+    }
+}
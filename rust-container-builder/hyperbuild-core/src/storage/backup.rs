@@ -0,0 +1,233 @@
+use super::{Layer, RefIndex, StorageManager};
+use anyhow::Result;
+use std::collections::BTreeSet;
+use std::path::Path;
+use tokio::fs;
+
+#[derive(Debug, Default)]
+pub struct BackupReport {
+    pub images: usize,
+    pub indexes: usize,
+    pub blobs: usize,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    pub images: usize,
+    pub indexes: usize,
+    pub blobs: usize,
+    // Images, indexes and blobs the store already had
+    pub skipped: usize,
+}
+
+impl StorageManager {
+    // Write the store's names, images, image indexes and the blobs they use
+    // to a tar at `dest`. The name index stays locked throughout, so no image
+    // is removed half-way through, and the tar is renamed into place at the end.
+    pub async fn backup(&self, dest: &Path) -> Result<BackupReport> {
+        fs::create_dir_all(&self.root_dir).await?;
+        let (root, refs_path) = (self.root_dir.clone(), self.refs_path());
+        let (images_dir, indexes_dir, blobs_dir) = (self.images_dir.clone(), self.indexes_dir.clone(), self.blobs_dir.clone());
+        let dest = dest.to_path_buf();
+
+        tokio::task::spawn_blocking(move || -> Result<BackupReport> {
+            let _lock = RefIndex::lock(&refs_path)?;
+            let mut report = BackupReport::default();
+            let partial = dest.with_file_name(format!(
+                ".{}.partial",
+                dest.file_name().unwrap_or_default().to_string_lossy()
+            ));
+            let written = (|| -> Result<()> {
+                let mut builder = tar::Builder::new(std::io::BufWriter::new(std::fs::File::create(&partial)?));
+                builder.follow_symlinks(false);
+                for file in ["version", "refs.json", "encryption"] {
+                    if root.join(file).exists() {
+                        builder.append_path_with_name(root.join(file), file)?;
+                    }
+                }
+
+                let mut blobs = BTreeSet::new();
+                for id in dir_names(&images_dir)? {
+                    let layers: Vec<Layer> = serde_json::from_slice(&std::fs::read(images_dir.join(&id).join("layers.json"))?)
+                        .map_err(|e| anyhow::anyhow!("Image {} has an invalid layer list: {}", id, e))?;
+                    blobs.extend(layers.into_iter().map(|layer| layer.digest));
+                    builder.append_dir_all(format!("images/{}", id), images_dir.join(&id))?;
+                    report.images += 1;
+                }
+                for id in dir_names(&indexes_dir)? {
+                    builder.append_dir_all(format!("indexes/{}", id), indexes_dir.join(&id))?;
+                    report.indexes += 1;
+                }
+                for digest in blobs {
+                    let hex = digest.strip_prefix("sha256:").unwrap_or(&digest);
+                    let path = blobs_dir.join(hex);
+                    let size = std::fs::metadata(&path)
+                        .map_err(|e| anyhow::anyhow!("Blob {} is missing: {}", digest, e))?
+                        .len();
+                    builder.append_path_with_name(&path, format!("blobs/sha256/{}", hex))?;
+                    report.blobs += 1;
+                    report.bytes += size;
+                }
+
+                use std::io::Write;
+                builder.into_inner()?.flush()?;
+                Ok(())
+            })();
+            if let Err(e) = written {
+                let _ = std::fs::remove_file(&partial);
+                return Err(e);
+            }
+            std::fs::rename(&partial, &dest)?;
+            Ok(report)
+        })
+        .await?
+    }
+
+    // Add the contents of a backup to the store. The backup is unpacked and
+    // verified aside, migrated if it is older, then moved in: blobs first,
+    // then images and indexes, and the names last, which win over the
+    // store's own.
+    pub async fn restore(&self, src: &Path) -> Result<RestoreReport> {
+        let staging = self.tmp_dir().join(format!("restore_{}", uuid::Uuid::new_v4()));
+        let restored = self.restore_from(src, &staging).await;
+        let _ = fs::remove_dir_all(&staging).await;
+        restored
+    }
+
+    async fn restore_from(&self, src: &Path, staging: &Path) -> Result<RestoreReport> {
+        let (archive, unpack_dir) = (src.to_path_buf(), staging.to_path_buf());
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let file = std::fs::File::open(&archive)
+                .map_err(|e| anyhow::anyhow!("Failed to open backup {:?}: {}", archive, e))?;
+            std::fs::create_dir_all(&unpack_dir)?;
+            tar::Archive::new(std::io::BufReader::new(file)).unpack(&unpack_dir)?;
+            Ok(())
+        })
+        .await??;
+
+        // Blobs of an encrypted store's backup stay encrypted, so it only
+        // restores into a store with the same key
+        let staged = StorageManager::new(staging.to_path_buf())?.with_encryption(self.encryption.clone());
+        staged.init().await?;
+        let verified = staged.verify(false).await?;
+        if let Some(problem) = verified.problems.first() {
+            return Err(anyhow::anyhow!(
+                "Backup {:?} is damaged ({} problems), e.g. {}: {}",
+                src,
+                verified.problems.len(),
+                problem.subject,
+                problem.message
+            ));
+        }
+
+        let mut report = RestoreReport::default();
+        for hex in dir_names(&staged.blobs_dir)? {
+            if move_new(&staged.blobs_dir.join(&hex), &self.blobs_dir.join(&hex)).await? {
+                report.blobs += 1;
+            } else {
+                report.skipped += 1;
+            }
+        }
+        for (from, to, count) in [
+            (&staged.images_dir, &self.images_dir, &mut report.images),
+            (&staged.indexes_dir, &self.indexes_dir, &mut report.indexes),
+        ] {
+            for id in dir_names(from)? {
+                if move_new(&from.join(&id), &to.join(&id)).await? {
+                    *count += 1;
+                } else {
+                    report.skipped += 1;
+                }
+            }
+        }
+
+        let restored = staged.refs().await?;
+        self.update_refs(move |refs| {
+            refs.tags.extend(restored.tags);
+            refs.digests.extend(restored.digests);
+            for (id, used) in restored.last_used {
+                let last_used = refs.last_used.entry(id).or_default();
+                *last_used = (*last_used).max(used);
+            }
+        })
+        .await?;
+        Ok(report)
+    }
+}
+
+// Entries of a directory that may not exist, sorted
+fn dir_names(dir: &Path) -> Result<Vec<String>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        names.push(entry?.file_name().to_string_lossy().to_string());
+    }
+    names.sort();
+    Ok(names)
+}
+
+// Move `from` to `to` unless `to` exists; blobs and image ids name their
+// content, so an existing one is the same
+async fn move_new(from: &Path, to: &Path) -> Result<bool> {
+    if to.exists() {
+        return Ok(false);
+    }
+    fs::rename(from, to).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Image, sha256_of};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_backup_and_restore_into_another_store() {
+        let root = tempfile::tempdir().unwrap();
+        let source = StorageManager::new(root.path().join("source")).unwrap();
+        source.init().await.unwrap();
+        let layer = source.create_layer(b"layer contents").await.unwrap();
+        let orphan = source.create_layer(b"unused").await.unwrap();
+
+        let mut config = oci_spec::image::ImageConfiguration::default();
+        let mut rootfs = config.rootfs().clone();
+        rootfs.set_diff_ids(vec![layer.diff_id.clone()]);
+        config.set_rootfs(rootfs);
+        let raw_config = serde_json::to_vec(&config).unwrap();
+        let layers = vec![layer.clone()];
+        source
+            .save_image(&Image {
+                id: "image_1".to_string(),
+                name: "app:latest".to_string(),
+                manifest: crate::engine::image_manifest(&sha256_of(&raw_config), raw_config.len() as u64, &layers)
+                    .unwrap(),
+                layers,
+                config,
+                raw_config,
+            })
+            .await
+            .unwrap();
+
+        let archive = root.path().join("store.tar");
+        let report = source.backup(&archive).await.unwrap();
+        // Blobs no image uses are left out
+        assert_eq!((report.images, report.blobs, report.bytes), (1, 1, layer.size));
+
+        let target = StorageManager::new(root.path().join("target")).unwrap();
+        target.init().await.unwrap();
+        let report = target.restore(&archive).await.unwrap();
+        assert_eq!((report.images, report.blobs, report.skipped), (1, 1, 0));
+        let image = target.get_image_by_name("app:latest").await.unwrap().unwrap();
+        assert_eq!(image.layers[0].path, target.blob_path(&layer.digest).unwrap());
+        assert!(!target.has_blob(&orphan.digest));
+        assert!(target.verify(false).await.unwrap().problems.is_empty());
+
+        // Restoring twice adds nothing
+        let report = target.restore(&archive).await.unwrap();
+        assert_eq!((report.images, report.blobs, report.skipped), (0, 0, 2));
+        assert_eq!(std::fs::read_dir(target.tmp_dir()).unwrap().count(), 0);
+    }
+}
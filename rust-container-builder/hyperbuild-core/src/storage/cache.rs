@@ -0,0 +1,190 @@
+use super::{Layer, RefIndex, StorageManager};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// What a build step produced, kept so later builds can skip the step. Records
+// are keyed by the step's cache key; the layer's blob stays in the store, even
+// when no image uses it, until the record is pruned.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheRecord {
+    // None for steps that changed no files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer: Option<Layer>,
+    // Unix seconds
+    pub created: u64,
+    pub last_used: u64,
+    // The build that ran the step
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct CachePruneReport {
+    pub removed: Vec<String>,
+    pub bytes: u64,
+}
+
+impl StorageManager {
+    fn cache_path(&self) -> PathBuf {
+        self.root_dir.join("cache.json")
+    }
+
+    // Every build cache record, by cache key
+    pub async fn cache_records(&self) -> Result<BTreeMap<String, CacheRecord>> {
+        let path = self.cache_path();
+        let mut records = tokio::task::spawn_blocking(move || read_records(&path)).await??;
+        // Blob paths follow from digests, as for images
+        for layer in records.values_mut().filter_map(|record| record.layer.as_mut()) {
+            layer.path = self.blob_path(&layer.digest)?;
+            layer.key = self.encryption.clone();
+        }
+        Ok(records)
+    }
+
+    // Record what a step produced, replacing an older record for the same key
+    pub async fn record_cache(&self, key: &str, layer: Option<&Layer>, build: Option<&str>) -> Result<()> {
+        let now = now();
+        let (key, record) = (
+            key.to_string(),
+            CacheRecord {
+                layer: layer.cloned(),
+                created: now,
+                last_used: now,
+                build: build.map(str::to_string),
+            },
+        );
+        self.update_cache(move |records| {
+            records.insert(key, record);
+        })
+        .await
+    }
+
+    // Record that a later build reused a step
+    pub async fn touch_cache(&self, key: &str) -> Result<()> {
+        let key = key.to_string();
+        self.update_cache(move |records| {
+            if let Some(record) = records.get_mut(&key) {
+                record.last_used = now();
+            }
+        })
+        .await
+    }
+
+    // Remove the cache records not used for `until`, or all of them, and
+    // garbage-collect the layers no image or remaining record uses
+    pub async fn prune_cache(&self, until: Option<Duration>, dry_run: bool) -> Result<CachePruneReport> {
+        let records = self.cache_records().await?;
+        let now = now();
+        let (removed, kept): (Vec<_>, Vec<_>) = records
+            .into_iter()
+            .partition(|(_, record)| until.is_none_or(|until| now.saturating_sub(record.last_used) > until.as_secs()));
+
+        let mut report = CachePruneReport {
+            removed: removed.iter().map(|(key, _)| key.clone()).collect(),
+            bytes: 0,
+        };
+        if dry_run {
+            // Blobs only the removed records use would be freed
+            let mut used: BTreeSet<String> = kept
+                .iter()
+                .filter_map(|(_, record)| record.layer.as_ref())
+                .map(|layer| layer.digest.clone())
+                .collect();
+            for id in self.list_images().await? {
+                let image = self.get_image(&id).await?;
+                used.extend(image.into_iter().flat_map(|image| image.layers).map(|layer| layer.digest));
+            }
+            let freed: BTreeMap<&String, &Path> = removed
+                .iter()
+                .filter_map(|(_, record)| record.layer.as_ref())
+                .filter(|layer| !used.contains(&layer.digest))
+                .map(|layer| (&layer.digest, layer.path.as_path()))
+                .collect();
+            report.bytes = freed
+                .values()
+                .filter_map(|path| std::fs::metadata(path).ok())
+                .map(|metadata| metadata.len())
+                .sum();
+        } else {
+            let keys = report.removed.clone();
+            self.update_cache(move |records| {
+                for key in &keys {
+                    records.remove(key);
+                }
+            })
+            .await?;
+            report.bytes = self.gc(false).await?.bytes;
+        }
+        Ok(report)
+    }
+
+    // Apply `change` to the records under the index's lock, like the name index
+    async fn update_cache(
+        &self,
+        change: impl FnOnce(&mut BTreeMap<String, CacheRecord>) + Send + 'static,
+    ) -> Result<()> {
+        let path = self.cache_path();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let _lock = RefIndex::lock(&path)?;
+            let mut records = read_records(&path)?;
+            change(&mut records);
+            let partial = path.with_extension("json.partial");
+            std::fs::write(&partial, serde_json::to_vec_pretty(&records)?)?;
+            std::fs::rename(&partial, &path)?;
+            Ok(())
+        })
+        .await?
+    }
+}
+
+fn read_records(path: &Path) -> Result<BTreeMap<String, CacheRecord>> {
+    match std::fs::read(path) {
+        Ok(data) => {
+            serde_json::from_slice(&data).map_err(|e| anyhow::anyhow!("Invalid build cache index {:?}: {}", path, e))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cache_records_keep_layers_until_pruned() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = StorageManager::new(root.path().to_path_buf()).unwrap();
+        storage.init().await.unwrap();
+
+        let layer = storage.create_layer(b"step output").await.unwrap();
+        storage.record_cache("sha256:step", Some(&layer), Some("build_1")).await.unwrap();
+        storage.record_cache("sha256:empty", None, Some("build_1")).await.unwrap();
+
+        let records = storage.cache_records().await.unwrap();
+        assert_eq!(records.len(), 2);
+        let cached = records["sha256:step"].layer.as_ref().unwrap();
+        assert_eq!((&cached.diff_id, &cached.path), (&layer.diff_id, &layer.path));
+        assert_eq!(records["sha256:step"].build.as_deref(), Some("build_1"));
+
+        // No image uses the layer, but the record keeps it
+        assert!(storage.gc(false).await.unwrap().removed.is_empty());
+
+        let recent = storage.prune_cache(Some(Duration::from_secs(3600)), false).await.unwrap();
+        assert!(recent.removed.is_empty());
+        let report = storage.prune_cache(None, true).await.unwrap();
+        assert_eq!((report.removed.len(), report.bytes), (2, layer.size));
+        assert!(layer.path.exists());
+
+        storage.prune_cache(None, false).await.unwrap();
+        assert!(storage.cache_records().await.unwrap().is_empty());
+        assert!(!layer.path.exists());
+    }
+}
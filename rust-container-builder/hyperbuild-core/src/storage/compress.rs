@@ -0,0 +1,228 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Read, Write};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+// What a layer blob is compressed with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    #[default]
+    Gzip,
+    Zstd,
+}
+
+impl CompressionAlgorithm {
+    // Enough leading bytes for `detect` to tell
+    pub const MAGIC_LEN: usize = ZSTD_MAGIC.len();
+
+    pub fn is_gzip(&self) -> bool {
+        *self == Self::Gzip
+    }
+
+    // The algorithm a blob starting with `head` is compressed with, if any
+    pub fn detect(head: &[u8]) -> Option<Self> {
+        if head.starts_with(&GZIP_MAGIC) {
+            Some(Self::Gzip)
+        } else if head.starts_with(&ZSTD_MAGIC) {
+            Some(Self::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+// How new layers are compressed, from the [compression] table of the config
+// file or `--compression`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Compression {
+    pub algorithm: CompressionAlgorithm,
+    // gzip level, 0 (store only) to 9 (smallest), default 6; zstd level, 1
+    // to 22, default 3
+    pub level: Option<u32>,
+    // Chunks compressed at once; 0 uses every core
+    pub threads: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            algorithm: CompressionAlgorithm::Gzip,
+            level: None,
+            threads: 0,
+        }
+    }
+}
+
+impl Compression {
+    pub fn validate(&self) -> Result<()> {
+        let range = match self.algorithm {
+            CompressionAlgorithm::Gzip => 0..=9,
+            CompressionAlgorithm::Zstd => 1..=22,
+        };
+        match self.level {
+            Some(level) if !range.contains(&level) => Err(anyhow::anyhow!(
+                "Invalid {:?} compression level {}: expected {} to {}",
+                self.algorithm,
+                level,
+                range.start(),
+                range.end()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Apply a `--compression gzip|zstd[,level=N]` value; the thread count is kept
+    pub fn with_flag(self, spec: &str) -> Result<Self> {
+        let mut parts = spec.split(',');
+        let algorithm = match parts.next() {
+            Some("gzip") => CompressionAlgorithm::Gzip,
+            Some("zstd") => CompressionAlgorithm::Zstd,
+            _ => return Err(anyhow::anyhow!("Invalid --compression {:?}: expected gzip or zstd", spec)),
+        };
+        let mut level = None;
+        for part in parts {
+            match part.split_once('=') {
+                Some(("level", value)) => {
+                    level = Some(value.parse().map_err(|_| anyhow::anyhow!("Invalid compression level {:?}", value))?)
+                }
+                _ => return Err(anyhow::anyhow!("Invalid --compression {:?}: expected ALGORITHM[,level=N]", spec)),
+            }
+        }
+        let compression = Self { algorithm, level, ..self };
+        compression.validate()?;
+        Ok(compression)
+    }
+
+    // The same settings for gzip, e.g. for eStargz, which is gzip whatever new
+    // layers are compressed with; a zstd level does not carry over
+    pub fn gzip(self) -> Self {
+        match self.algorithm {
+            CompressionAlgorithm::Gzip => self,
+            CompressionAlgorithm::Zstd => Self { algorithm: CompressionAlgorithm::Gzip, level: None, ..self },
+        }
+    }
+
+    pub fn level(&self) -> u32 {
+        match (self.level, self.algorithm) {
+            (Some(level), _) => level,
+            (None, CompressionAlgorithm::Gzip) => 6,
+            (None, CompressionAlgorithm::Zstd) => 3,
+        }
+    }
+
+    pub fn workers(&self) -> usize {
+        match self.threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            threads => threads,
+        }
+    }
+
+    // The gzip header: no name or timestamp, so the same tar always
+    // compresses to the same bytes. zstd frames need none.
+    pub(super) fn header(&self) -> Vec<u8> {
+        if self.algorithm == CompressionAlgorithm::Zstd {
+            return Vec::new();
+        }
+        let extra_flags = match self.level() {
+            9 => 2,
+            1 => 4,
+            _ => 0,
+        };
+        vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, extra_flags, 255]
+    }
+
+    // Compress one chunk on its own so chunks compressed in parallel
+    // concatenate into one blob: a deflate run ending on a byte boundary, as
+    // pigz does, or a zstd frame of its own
+    pub(super) fn compress_chunk(&self, chunk: &[u8]) -> Result<(Vec<u8>, flate2::Crc)> {
+        let mut crc = flate2::Crc::new();
+        if self.algorithm == CompressionAlgorithm::Zstd {
+            return Ok((zstd::bulk::compress(chunk, self.level() as i32)?, crc));
+        }
+        crc.update(chunk);
+
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::new(self.level()));
+        encoder.write_all(chunk)?;
+        encoder.flush()?;
+        Ok((std::mem::take(encoder.get_mut()), crc))
+    }
+
+    // The final empty deflate block, then the checksum and size of the whole tar
+    pub(super) fn trailer(&self, crc: &flate2::Crc) -> Result<Vec<u8>> {
+        if self.algorithm == CompressionAlgorithm::Zstd {
+            return Ok(Vec::new());
+        }
+        let mut trailer =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::new(self.level())).finish()?;
+        trailer.extend_from_slice(&crc.sum().to_le_bytes());
+        trailer.extend_from_slice(&crc.amount().to_le_bytes());
+        Ok(trailer)
+    }
+}
+
+// The tar inside a gzip or zstd layer blob, going by its first bytes; every
+// gzip member or zstd frame is read, not only the first
+pub fn decompress<'a>(blob: impl Read + Send + 'a) -> Result<Box<dyn Read + Send + 'a>> {
+    let mut blob = std::io::BufReader::new(blob);
+    let head = blob.fill_buf()?;
+    match CompressionAlgorithm::detect(head) {
+        Some(CompressionAlgorithm::Gzip) => Ok(Box::new(flate2::read::MultiGzDecoder::new(blob))),
+        Some(CompressionAlgorithm::Zstd) => Ok(Box::new(zstd::stream::read::Decoder::with_buffer(blob)?)),
+        None => Err(anyhow::anyhow!("Layer blob is neither gzip nor zstd")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compress(compression: &Compression, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut crc = flate2::Crc::new();
+        let mut out = compression.header().to_vec();
+        for chunk in chunks {
+            let (deflated, chunk_crc) = compression.compress_chunk(chunk).unwrap();
+            out.extend(deflated);
+            crc.combine(&chunk_crc);
+        }
+        out.extend(compression.trailer(&crc).unwrap());
+        out
+    }
+
+    #[test]
+    fn test_chunks_form_one_gzip_stream() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 7) as u8).collect();
+        for level in [0, 1, 6, 9] {
+            let compression = Compression { level: Some(level), threads: 1, ..Default::default() };
+            let compressed = compress(&compression, &[&data[..40_000], &data[40_000..], &[]]);
+
+            // A single-member decoder would stop early on concatenated members
+            let mut decoded = Vec::new();
+            flate2::read::GzDecoder::new(&compressed[..]).read_to_end(&mut decoded).unwrap();
+            assert_eq!(decoded, data);
+        }
+
+        assert!(Compression { level: Some(10), ..Default::default() }.validate().is_err());
+        assert!(Compression::default().workers() >= 1);
+    }
+
+    #[test]
+    fn test_zstd_frames_form_one_blob() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 7) as u8).collect();
+        let compression = Compression::default().with_flag("zstd,level=19").unwrap();
+        assert_eq!((compression.algorithm, compression.level()), (CompressionAlgorithm::Zstd, 19));
+        let compressed = compress(&compression, &[&data[..40_000], &data[40_000..], &[]]);
+        assert_eq!(CompressionAlgorithm::detect(&compressed), Some(CompressionAlgorithm::Zstd));
+
+        let mut decoded = Vec::new();
+        decompress(&compressed[..]).unwrap().read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, data);
+
+        assert_eq!(Compression::default().with_flag("zstd").unwrap().level(), 3);
+        assert!(Compression::default().with_flag("zstd,level=23").is_err());
+        assert!(Compression::default().with_flag("brotli").is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use super::StorageManager;
+use anyhow::Result;
+use std::path::PathBuf;
+use tokio::fs;
+
+#[derive(Debug, Default)]
+pub struct DedupeReport {
+    // Legacy layer files with the same bytes as a stored blob, and its digest
+    pub duplicates: Vec<(PathBuf, String)>,
+    pub bytes: u64,
+    // Legacy layer files with no stored copy, left for garbage collection
+    pub unique: Vec<PathBuf>,
+}
+
+impl StorageManager {
+    // Find layer files still under their version 1 UUID names in layers/ that
+    // are byte-identical to a blob in the content-addressed store, and delete
+    // them. Migration already pointed every image at the blob, so nothing
+    // refers to the legacy copy any more.
+    pub async fn dedupe(&self, dry_run: bool) -> Result<DedupeReport> {
+        let mut report = DedupeReport::default();
+        let legacy_dir = self.root_dir.join("layers");
+        if !legacy_dir.exists() {
+            return Ok(report);
+        }
+
+        let mut files = Vec::new();
+        let mut entries = fs::read_dir(&legacy_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_file() {
+                files.push(entry.path());
+            }
+        }
+        files.sort();
+
+        for path in files {
+            let file = path.clone();
+            let digest = tokio::task::spawn_blocking(move || -> Result<String> {
+                use sha2::{Digest, Sha256};
+                let mut hasher = Sha256::new();
+                std::io::copy(&mut std::fs::File::open(&file)?, &mut hasher)?;
+                Ok(format!("sha256:{:x}", hasher.finalize()))
+            })
+            .await??;
+            if !self.has_blob(&digest) {
+                report.unique.push(path);
+                continue;
+            }
+
+            report.bytes += fs::metadata(&path).await?.len();
+            if !dry_run {
+                fs::remove_file(&path).await?;
+            }
+            report.duplicates.push((path, digest));
+        }
+
+        if !dry_run {
+            let _ = fs::remove_dir(&legacy_dir).await;
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dedupe_removes_legacy_copies() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = StorageManager::new(root.path().to_path_buf()).unwrap();
+        storage.init().await.unwrap();
+
+        let layer = storage.create_layer(b"layer contents").await.unwrap();
+        let legacy_dir = root.path().join("layers");
+        std::fs::create_dir_all(&legacy_dir).unwrap();
+        let copy = legacy_dir.join("0b6f6c8e-uuid.tar.gz");
+        let unique = legacy_dir.join("9d2a1f4c-uuid.tar.gz");
+        std::fs::copy(&layer.path, &copy).unwrap();
+        std::fs::write(&unique, b"only here").unwrap();
+
+        let report = storage.dedupe(true).await.unwrap();
+        assert_eq!(report.duplicates, vec![(copy.clone(), layer.digest.clone())]);
+        assert_eq!((report.bytes, report.unique.clone()), (layer.size, vec![unique.clone()]));
+        assert!(copy.exists());
+
+        storage.dedupe(false).await.unwrap();
+        assert!(!copy.exists() && unique.exists() && layer.path.exists());
+        assert!(storage.dedupe(false).await.unwrap().duplicates.is_empty());
+    }
+}
@@ -0,0 +1,284 @@
+use anyhow::Result;
+use openssl::symm::{Cipher, decrypt_aead, encrypt_aead};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Encrypted blobs start with this, then the key id and the nonce prefix.
+// Plain blobs are gzip streams, which never do.
+const MAGIC: &[u8; 8] = b"HBBLOB\x00\x01";
+// Plaintext per segment; each segment is sealed with its own nonce and tag
+const SEGMENT_SIZE: usize = 1 << 20;
+const TAG_SIZE: usize = 16;
+
+// The [encryption] table of the config file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionSettings {
+    // 32-byte AES-256 key, raw or as 64 hex digits, e.g. from `openssl rand -hex 32`
+    pub keyfile: Option<PathBuf>,
+}
+
+impl EncryptionSettings {
+    pub fn key(&self) -> Result<Option<Arc<BlobKey>>> {
+        self.keyfile
+            .as_deref()
+            .map(|path| BlobKey::from_file(path).map(Arc::new))
+            .transpose()
+    }
+}
+
+// The key layer blobs are encrypted with at rest, using AES-256-GCM
+pub struct BlobKey {
+    key: [u8; 32],
+    // The first bytes of the key's sha256, recorded with every blob and in
+    // the store so a wrong key is reported as such
+    id: [u8; 8],
+}
+
+impl std::fmt::Debug for BlobKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BlobKey({})", self.id())
+    }
+}
+
+impl BlobKey {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read(path).map_err(|e| anyhow::anyhow!("Failed to read keyfile {:?}: {}", path, e))?;
+        let hex = String::from_utf8_lossy(&contents).trim().to_string();
+        let key: Vec<u8> = if hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            (0..32).map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)).collect::<Result<_, _>>()?
+        } else {
+            contents
+        };
+        let key: [u8; 32] = key
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Keyfile {:?} must hold a 32-byte key, raw or as 64 hex digits", path))?;
+        Ok(Self::new(key))
+    }
+
+    pub fn new(key: [u8; 32]) -> Self {
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&Sha256::digest(key)[..8]);
+        Self { key, id }
+    }
+
+    pub fn id(&self) -> String {
+        self.id.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+// Encrypts a blob as it is written: a header, then segments of
+// [last flag][ciphertext length, u32 LE][ciphertext][tag]. The flag is
+// authenticated, so a blob cut short at a segment boundary is detected.
+pub(super) struct Sealer {
+    key: Arc<BlobKey>,
+    prefix: [u8; 8],
+    counter: u32,
+    pending: Vec<u8>,
+}
+
+impl Sealer {
+    // The sealer and the header to write before anything it returns
+    pub fn new(key: Arc<BlobKey>) -> Result<(Self, Vec<u8>)> {
+        let mut prefix = [0u8; 8];
+        openssl::rand::rand_bytes(&mut prefix)?;
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&key.id);
+        header.extend_from_slice(&prefix);
+        let sealer = Self {
+            key,
+            prefix,
+            counter: 0,
+            pending: Vec::new(),
+        };
+        Ok((sealer, header))
+    }
+
+    // Sealed segments for every full segment of plaintext buffered so far
+    pub fn seal(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.pending.extend_from_slice(data);
+        let mut out = Vec::new();
+        while self.pending.len() > SEGMENT_SIZE {
+            let rest = self.pending.split_off(SEGMENT_SIZE);
+            let segment = std::mem::replace(&mut self.pending, rest);
+            out.extend(self.segment(&segment, false)?);
+        }
+        Ok(out)
+    }
+
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        let last = std::mem::take(&mut self.pending);
+        self.segment(&last, true)
+    }
+
+    fn segment(&mut self, plain: &[u8], last: bool) -> Result<Vec<u8>> {
+        let nonce = nonce(&self.prefix, self.counter);
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| anyhow::anyhow!("Blob too large to encrypt"))?;
+        let mut tag = [0u8; TAG_SIZE];
+        let sealed = encrypt_aead(Cipher::aes_256_gcm(), &self.key.key, Some(&nonce), &[last as u8], plain, &mut tag)?;
+
+        let mut out = Vec::with_capacity(5 + sealed.len() + TAG_SIZE);
+        out.push(last as u8);
+        out.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+        out.extend(sealed);
+        out.extend_from_slice(&tag);
+        Ok(out)
+    }
+}
+
+// Encrypt a whole blob at once
+pub(super) fn seal(key: Arc<BlobKey>, data: &[u8]) -> Result<Vec<u8>> {
+    let (mut sealer, mut out) = Sealer::new(key)?;
+    out.extend(sealer.seal(data)?);
+    out.extend(sealer.finish()?);
+    Ok(out)
+}
+
+// Open a stored blob for reading, decrypting it if it was stored encrypted.
+// Blobs written before encryption was turned on are read as they are.
+pub fn open_blob(path: &Path, key: Option<&Arc<BlobKey>>) -> Result<Box<dyn Read + Send>> {
+    let mut file = std::fs::File::open(path).map_err(|e| anyhow::anyhow!("Failed to open blob {:?}: {}", path, e))?;
+    let mut header = [0u8; 24];
+    let read = read_full(&mut file, &mut header)?;
+    if read < header.len() || &header[..8] != MAGIC {
+        file.rewind()?;
+        return Ok(Box::new(file));
+    }
+
+    let key = key.ok_or_else(|| {
+        anyhow::anyhow!("Blob {:?} is encrypted; set keyfile under [encryption] in the config file", path)
+    })?;
+    if header[8..16] != key.id {
+        return Err(anyhow::anyhow!("Blob {:?} was encrypted with another key than {}", path, key.id()));
+    }
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&header[16..24]);
+    Ok(Box::new(Opener {
+        inner: std::io::BufReader::new(file),
+        key: key.clone(),
+        prefix,
+        counter: 0,
+        plain: Vec::new(),
+        position: 0,
+        done: false,
+    }))
+}
+
+struct Opener<R> {
+    inner: R,
+    key: Arc<BlobKey>,
+    prefix: [u8; 8],
+    counter: u32,
+    plain: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+
+impl<R: Read> Opener<R> {
+    fn next_segment(&mut self) -> std::io::Result<()> {
+        let mut head = [0u8; 5];
+        if read_full(&mut self.inner, &mut head)? < head.len() {
+            return Err(invalid("encrypted blob is truncated"));
+        }
+        let last = match head[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(invalid("encrypted blob is corrupt")),
+        };
+        let length = u32::from_le_bytes([head[1], head[2], head[3], head[4]]) as usize;
+        if length > SEGMENT_SIZE {
+            return Err(invalid("encrypted blob is corrupt"));
+        }
+        let mut sealed = vec![0u8; length + TAG_SIZE];
+        if read_full(&mut self.inner, &mut sealed)? < sealed.len() {
+            return Err(invalid("encrypted blob is truncated"));
+        }
+        let (sealed, tag) = sealed.split_at(length);
+
+        let nonce = nonce(&self.prefix, self.counter);
+        self.counter = self.counter.wrapping_add(1);
+        self.plain = decrypt_aead(Cipher::aes_256_gcm(), &self.key.key, Some(&nonce), &[last as u8], sealed, tag)
+            .map_err(|_| invalid("encrypted blob failed authentication"))?;
+        self.position = 0;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Opener<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.plain.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_segment()?;
+        }
+        let read = buf.len().min(self.plain.len() - self.position);
+        buf[..read].copy_from_slice(&self.plain[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+fn nonce(prefix: &[u8; 8], counter: u32) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(prefix);
+    nonce[8..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open_across_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Arc::new(BlobKey::new([7; 32]));
+        let data: Vec<u8> = (0..SEGMENT_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+
+        let path = dir.path().join("blob");
+        let sealed = seal(key.clone(), &data).unwrap();
+        std::fs::write(&path, &sealed).unwrap();
+        let mut opened = Vec::new();
+        open_blob(&path, Some(&key)).unwrap().read_to_end(&mut opened).unwrap();
+        assert_eq!(opened, data);
+
+        // Wrong or missing keys, truncation and tampering are all refused
+        assert!(open_blob(&path, Some(&Arc::new(BlobKey::new([8; 32])))).is_err());
+        assert!(open_blob(&path, None).is_err());
+        std::fs::write(&path, &sealed[..sealed.len() - SEGMENT_SIZE / 2]).unwrap();
+        assert!(open_blob(&path, Some(&key)).unwrap().read_to_end(&mut Vec::new()).is_err());
+        let mut tampered = sealed.clone();
+        tampered[100] ^= 1;
+        std::fs::write(&path, &tampered).unwrap();
+        assert!(open_blob(&path, Some(&key)).unwrap().read_to_end(&mut Vec::new()).is_err());
+
+        // Blobs stored before encryption was turned on read as they are
+        std::fs::write(&path, b"\x1f\x8bplain").unwrap();
+        let mut plain = Vec::new();
+        open_blob(&path, Some(&key)).unwrap().read_to_end(&mut plain).unwrap();
+        assert_eq!(plain, b"\x1f\x8bplain");
+    }
+}
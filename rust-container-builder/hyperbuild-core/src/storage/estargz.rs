@@ -0,0 +1,728 @@
+use super::{Compression, Image, ImageList, Layer, StorageManager, sha256_of};
+use crate::engine::snapshot::{OPAQUE_WHITEOUT, WHITEOUT_PREFIX};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use tokio::io::AsyncReadExt;
+
+// eStargz, the seekable tar.gz of the stargz snapshotter: the data of every
+// regular file starts a gzip member of its own, and a table of contents (TOC)
+// near the end lists each entry with the offset of that member, with a footer
+// pointing at the TOC. To any client it is still an ordinary gzip tar, while
+// one that knows the format reads the footer and TOC with range requests and
+// then fetches single files.
+
+// The tar entry holding the TOC, the last one in the layer
+pub const TOC_NAME: &str = "stargz.index.json";
+// The layer descriptor annotation giving the digest of the TOC's JSON
+pub const TOC_DIGEST_ANNOTATION: &str = "containerd.io/snapshot/stargz/toc.digest";
+// The empty gzip member at the very end whose extra field gives the TOC's offset
+pub const FOOTER_SIZE: u64 = 51;
+// Files larger than this are split into chunks, each its own gzip member
+const CHUNK_SIZE: u64 = 4 << 20;
+// Entries marking where the snapshotter's prefetch ends, not files
+const LANDMARKS: [&str; 2] = [".prefetch.landmark", ".no.prefetch.landmark"];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Toc {
+    pub version: u32,
+    pub entries: Vec<TocEntry>,
+}
+
+// An entry of the TOC, in the JSON the stargz snapshotter reads: a file,
+// directory, link or device, or one more chunk of a large file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TocEntry {
+    pub name: String,
+    // dir, reg, symlink, hardlink, char, block, fifo or chunk
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub size: u64,
+    #[serde(rename = "modtime", default, skip_serializing_if = "String::is_empty")]
+    pub mod_time: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub link_name: String,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub mode: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub uid: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub gid: u64,
+    #[serde(rename = "userName", default, skip_serializing_if = "String::is_empty")]
+    pub user_name: String,
+    #[serde(rename = "groupName", default, skip_serializing_if = "String::is_empty")]
+    pub group_name: String,
+    // Where the gzip member holding the data of this file or chunk starts
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub offset: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub dev_major: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub dev_minor: u64,
+    // Extended attributes, their values base64-encoded as Go encodes bytes
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub digest: String,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub chunk_offset: u64,
+    // 0 for a chunk running to the end of the file
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub chunk_size: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub chunk_digest: String,
+    // Where the chunk starts in its member's data, when chunks share members
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub inner_offset: u64,
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+// Part of a file's data as a blob holds it: the gzip member from `offset` to
+// `end`, whose data from `inner_offset` on holds `size` bytes of the file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub offset: u64,
+    pub end: u64,
+    pub inner_offset: u64,
+    pub size: u64,
+    pub digest: String,
+}
+
+impl Toc {
+    // The chunks of the regular file `name`, in order. A member ends where
+    // the next one starts, or at the TOC's, at `toc_offset`.
+    pub fn chunks(&self, name: &str, toc_offset: u64) -> Vec<Chunk> {
+        let mut starts: Vec<u64> = self.entries.iter().map(|entry| entry.offset).filter(|offset| *offset > 0).collect();
+        starts.push(toc_offset);
+        starts.sort_unstable();
+        starts.dedup();
+        let parts: Vec<&TocEntry> = self
+            .entries
+            .iter()
+            .filter(|entry| (entry.kind == "reg" || entry.kind == "chunk") && clean_name(&entry.name) == name)
+            .collect();
+        let Some(file) = parts.iter().find(|entry| entry.kind == "reg") else {
+            return Vec::new();
+        };
+        parts
+            .iter()
+            .filter(|entry| entry.kind == "chunk" || file.size > 0)
+            .map(|entry| Chunk {
+                offset: entry.offset,
+                end: starts.iter().copied().find(|start| *start > entry.offset).unwrap_or(toc_offset),
+                inner_offset: entry.inner_offset,
+                size: match entry.chunk_size {
+                    0 => file.size.saturating_sub(entry.chunk_offset),
+                    size => size,
+                },
+                digest: entry.chunk_digest.clone(),
+            })
+            .collect()
+    }
+}
+
+// gzip members written one after another, each compressed on its own
+struct Members<W> {
+    out: W,
+    // Where the next member starts
+    offset: u64,
+    pending: Vec<u8>,
+    compression: Compression,
+}
+
+impl<W: Write> Members<W> {
+    fn write(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+    }
+
+    // End the current member, so what is written next starts one at `offset`
+    fn close(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let (deflated, crc) = self.compression.compress_chunk(&self.pending)?;
+        for part in [self.compression.header(), deflated, self.compression.trailer(&crc)?] {
+            self.out.write_all(&part)?;
+            self.offset += part.len() as u64;
+        }
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+// Rewrite an uncompressed tar as an eStargz blob into `out`, returning the
+// digest of its TOC. A TOC the tar already has is replaced.
+pub fn convert(mut tar: impl Read, out: impl Write, compression: Compression) -> Result<String> {
+    let mut members = Members {
+        out,
+        offset: 0,
+        pending: Vec::new(),
+        compression,
+    };
+    let mut toc = Toc {
+        version: 1,
+        entries: Vec::new(),
+    };
+    walk_tar(&mut tar, |headers, mut entry, data| {
+        if clean_name(&entry.name) == TOC_NAME {
+            return Ok(());
+        }
+        members.write(headers);
+        let size = match entry.kind.as_str() {
+            "reg" => entry.size,
+            _ => {
+                let mut rest = Vec::new();
+                data.read_to_end(&mut rest)?;
+                members.write(&rest);
+                members.write(&padding(rest.len() as u64));
+                toc.entries.push(entry);
+                return Ok(());
+            }
+        };
+
+        // Each chunk of the data starts a member, whose offset the TOC records
+        let mut file_hasher = Sha256::new();
+        let mut chunks = Vec::new();
+        let mut chunk_offset = 0;
+        while chunk_offset < size {
+            let len = CHUNK_SIZE.min(size - chunk_offset);
+            let mut chunk = vec![0u8; len as usize];
+            data.read_exact(&mut chunk)?;
+            file_hasher.update(&chunk);
+            members.close()?;
+            let chunk_digest = sha256_of(&chunk);
+            if chunk_offset == 0 {
+                entry.offset = members.offset;
+                entry.chunk_digest = chunk_digest;
+                if len < size {
+                    entry.chunk_size = len;
+                }
+            } else {
+                chunks.push(TocEntry {
+                    name: entry.name.clone(),
+                    kind: "chunk".to_string(),
+                    offset: members.offset,
+                    chunk_offset,
+                    chunk_size: len,
+                    chunk_digest,
+                    ..Default::default()
+                });
+            }
+            members.write(&chunk);
+            chunk_offset += len;
+        }
+        if size > 0 {
+            entry.digest = format!("sha256:{:x}", file_hasher.finalize());
+        }
+        members.write(&padding(size));
+        toc.entries.push(entry);
+        toc.entries.extend(chunks);
+        Ok(())
+    })?;
+
+    // The TOC, with the end of the archive, is a member of its own
+    members.close()?;
+    let toc_offset = members.offset;
+    let toc_json = serde_json::to_vec(&toc)?;
+    let mut header = tar::Header::new_ustar();
+    header.set_path(TOC_NAME)?;
+    header.set_size(toc_json.len() as u64);
+    header.set_mode(0o444);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_cksum();
+    members.write(header.as_bytes());
+    members.write(&toc_json);
+    members.write(&padding(toc_json.len() as u64));
+    members.write(&[0u8; 1024]);
+    members.close()?;
+    members.out.write_all(&footer(toc_offset))?;
+    members.out.flush()?;
+    Ok(sha256_of(&toc_json))
+}
+
+// An empty gzip member whose extra field, subfield SG, gives the TOC's offset
+// in hex, as the stargz snapshotter's footer does
+fn footer(toc_offset: u64) -> Vec<u8> {
+    let subfield = format!("{:016x}STARGZ", toc_offset);
+    let mut footer = vec![0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 255];
+    footer.extend_from_slice(&(4 + subfield.len() as u16).to_le_bytes());
+    footer.extend_from_slice(b"SG");
+    footer.extend_from_slice(&(subfield.len() as u16).to_le_bytes());
+    footer.extend_from_slice(subfield.as_bytes());
+    // A final stored block holding nothing, then the CRC and size of nothing
+    footer.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    footer.extend_from_slice(&[0; 8]);
+    footer
+}
+
+// The TOC's offset, from the last FOOTER_SIZE bytes of a blob; None when
+// they are not an eStargz footer
+pub fn toc_offset(footer: &[u8]) -> Option<u64> {
+    if footer.len() as u64 != FOOTER_SIZE || footer[..3] != [0x1f, 0x8b, 8] || footer[3] & 4 == 0 {
+        return None;
+    }
+    if footer[10..16] != [26, 0, b'S', b'G', 22, 0] {
+        return None;
+    }
+    let hex = std::str::from_utf8(&footer[16..38]).ok()?.strip_suffix("STARGZ")?;
+    u64::from_str_radix(hex, 16).ok()
+}
+
+// The TOC, and the digest of its JSON, from the gzip member at the TOC's
+// offset: the blob from there up to the footer
+pub fn read_toc(member: &[u8]) -> Result<(Toc, String)> {
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(member));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if clean_name(&entry.path()?.to_string_lossy()) == TOC_NAME {
+            let mut json = Vec::new();
+            entry.read_to_end(&mut json)?;
+            let toc = serde_json::from_slice(&json).map_err(|e| anyhow::anyhow!("Invalid eStargz TOC: {}", e))?;
+            return Ok((toc, sha256_of(&json)));
+        }
+    }
+    Err(anyhow::anyhow!("No {} where the eStargz footer points", TOC_NAME))
+}
+
+// A chunk's data, from its gzip member, checked against its digest
+pub fn read_chunk(member: &[u8], chunk: &Chunk) -> Result<Vec<u8>> {
+    let mut decoder = flate2::read::GzDecoder::new(member);
+    std::io::copy(&mut (&mut decoder).take(chunk.inner_offset), &mut std::io::sink())?;
+    let mut data = vec![0u8; chunk.size as usize];
+    decoder.read_exact(&mut data)?;
+    if !chunk.digest.is_empty() && sha256_of(&data) != chunk.digest {
+        return Err(anyhow::anyhow!("Chunk at offset {} does not match its digest {}", chunk.offset, chunk.digest));
+    }
+    Ok(data)
+}
+
+// The entries of an uncompressed tar, as a TOC without offsets, for layers
+// that are not read remotely; the TOC entry of an eStargz layer is left out
+pub fn tar_toc(mut tar: impl Read) -> Result<Toc> {
+    let mut toc = Toc {
+        version: 1,
+        entries: Vec::new(),
+    };
+    walk_tar(&mut tar, |_, entry, _| {
+        if clean_name(&entry.name) != TOC_NAME {
+            toc.entries.push(entry);
+        }
+        Ok(())
+    })?;
+    Ok(toc)
+}
+
+// The data of the last entry named `name` in an uncompressed tar
+pub fn read_tar_file(mut tar: impl Read, name: &str) -> Result<Option<Vec<u8>>> {
+    let mut found = None;
+    walk_tar(&mut tar, |_, entry, data| {
+        if clean_name(&entry.name) == name {
+            let mut contents = Vec::new();
+            data.read_to_end(&mut contents)?;
+            found = Some(contents);
+        }
+        Ok(())
+    })?;
+    Ok(found)
+}
+
+// The files of an image by path, from the TOCs of its layers, bottom first:
+// each as the topmost layer that has it leaves it, with that layer's index,
+// and without what the layers above delete with whiteouts
+pub fn merge(layers: &[Toc]) -> BTreeMap<String, (usize, TocEntry)> {
+    let mut files = BTreeMap::new();
+    for (index, toc) in layers.iter().enumerate() {
+        for entry in &toc.entries {
+            let name = clean_name(&entry.name);
+            if name.is_empty() || entry.kind == "chunk" || name == TOC_NAME || LANDMARKS.contains(&name.as_str()) {
+                continue;
+            }
+            let (parent, base) = name.rsplit_once('/').unwrap_or(("", &name));
+            if base == OPAQUE_WHITEOUT {
+                hide_below(&mut files, parent, index);
+                continue;
+            }
+            if let Some(target) = base.strip_prefix(WHITEOUT_PREFIX) {
+                let target = if parent.is_empty() { target.to_string() } else { format!("{}/{}", parent, target) };
+                if files.get(&target).is_some_and(|(layer, _)| *layer < index) {
+                    files.remove(&target);
+                }
+                hide_below(&mut files, &target, index);
+                continue;
+            }
+            // A file or link in place of a directory hides what was in it
+            if entry.kind != "dir" {
+                hide_below(&mut files, &name, index);
+            }
+            files.insert(name, (index, entry.clone()));
+        }
+    }
+    files
+}
+
+// Drop what layers below `layer` put under `dir` ("" for the root)
+fn hide_below(files: &mut BTreeMap<String, (usize, TocEntry)>, dir: &str, layer: usize) {
+    let prefix = if dir.is_empty() { String::new() } else { format!("{}/", dir) };
+    let hidden: Vec<String> = files
+        .range(prefix.clone()..)
+        .take_while(|(name, _)| name.starts_with(&prefix))
+        .filter(|(_, (below, _))| *below < layer)
+        .map(|(name, _)| name.clone())
+        .collect();
+    for name in hidden {
+        files.remove(&name);
+    }
+}
+
+// The file at `path` in merged files, following symlinks on the way as the
+// image's root would, and hard links; with the path it resolved to
+pub fn resolve<'a>(
+    files: &'a BTreeMap<String, (usize, TocEntry)>,
+    path: &str,
+) -> Result<(String, &'a (usize, TocEntry))> {
+    let mut pending: Vec<String> = path.split('/').rev().map(str::to_string).collect();
+    let mut current: Vec<String> = Vec::new();
+    let mut links = 0;
+    while let Some(component) = pending.pop() {
+        match component.as_str() {
+            "" | "." => continue,
+            ".." => {
+                current.pop();
+                continue;
+            }
+            _ => current.push(component),
+        }
+        if let Some((_, entry)) = files.get(&current.join("/"))
+            && entry.kind == "symlink"
+        {
+            links += 1;
+            if links > 40 {
+                return Err(anyhow::anyhow!("Too many levels of symbolic links in {}", path));
+            }
+            current.pop();
+            if entry.link_name.starts_with('/') {
+                current.clear();
+            }
+            pending.extend(entry.link_name.split('/').rev().map(str::to_string));
+        }
+    }
+    let name = current.join("/");
+    let found = files.get(&name).ok_or_else(|| anyhow::anyhow!("No such file: {}", path))?;
+    if found.1.kind == "hardlink" {
+        let target = clean_name(&found.1.link_name);
+        let linked = files
+            .get(&target)
+            .ok_or_else(|| anyhow::anyhow!("{} is a hard link to {}, which is missing", path, target))?;
+        return Ok((target, linked));
+    }
+    Ok((name, found))
+}
+
+// A tar or TOC name as a path from the root: no ./ or / in front, no / after
+pub fn clean_name(name: &str) -> String {
+    name.split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Walk an uncompressed tar, handing each entry to `each` with its raw header
+// blocks (any PAX or GNU extension headers first), the TOC entry describing
+// it and a reader over its data; data `each` leaves unread is skipped
+fn walk_tar<R: Read>(tar: &mut R, mut each: impl FnMut(&[u8], TocEntry, &mut dyn Read) -> Result<()>) -> Result<()> {
+    let mut headers = Vec::new();
+    let (mut long_name, mut long_link) = (None, None);
+    let mut pax: HashMap<String, Vec<u8>> = HashMap::new();
+    loop {
+        let mut block = [0u8; 512];
+        if !read_block(tar, &mut block)? || block.iter().all(|byte| *byte == 0) {
+            return Ok(());
+        }
+        headers.extend_from_slice(&block);
+        let header = tar::Header::from_byte_slice(&block);
+        let mut size = header.entry_size()?;
+        let entry_type = header.entry_type();
+        let extension = [
+            tar::EntryType::XHeader,
+            tar::EntryType::XGlobalHeader,
+            tar::EntryType::GNULongName,
+            tar::EntryType::GNULongLink,
+        ];
+        if extension.contains(&entry_type) {
+            let mut data = vec![0u8; (size + padding(size).len() as u64) as usize];
+            tar.read_exact(&mut data)?;
+            headers.extend_from_slice(&data);
+            data.truncate(size as usize);
+            let text = || String::from_utf8_lossy(&data).trim_end_matches('\0').to_string();
+            match entry_type {
+                tar::EntryType::GNULongName => long_name = Some(text()),
+                tar::EntryType::GNULongLink => long_link = Some(text()),
+                tar::EntryType::XHeader => {
+                    for extension in tar::PaxExtensions::new(&data) {
+                        let extension = extension?;
+                        pax.insert(extension.key()?.to_string(), extension.value_bytes().to_vec());
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        let pax_text = |key: &str| pax.get(key).map(|value| String::from_utf8_lossy(value).to_string());
+        let pax_number = |key: &str| pax_text(key).and_then(|value| value.split('.').next()?.parse::<u64>().ok());
+        if let Some(pax_size) = pax_number("size") {
+            size = pax_size;
+        }
+        let kind = match entry_type {
+            tar::EntryType::Regular | tar::EntryType::Continuous => "reg",
+            tar::EntryType::Directory => "dir",
+            tar::EntryType::Symlink => "symlink",
+            tar::EntryType::Link => "hardlink",
+            tar::EntryType::Char => "char",
+            tar::EntryType::Block => "block",
+            tar::EntryType::Fifo => "fifo",
+            other => return Err(anyhow::anyhow!("Unsupported tar entry type {:?}", other)),
+        };
+        let mtime = pax_number("mtime").unwrap_or(header.mtime()?);
+        let (dev_major, dev_minor) = match kind {
+            "char" | "block" => (header.device_major()?.unwrap_or(0), header.device_minor()?.unwrap_or(0)),
+            _ => (0, 0),
+        };
+        let entry = TocEntry {
+            name: pax_text("path")
+                .or(long_name.take())
+                .unwrap_or_else(|| String::from_utf8_lossy(&header.path_bytes()).to_string()),
+            kind: kind.to_string(),
+            size: if kind == "reg" { size } else { 0 },
+            mod_time: chrono::DateTime::from_timestamp(mtime as i64, 0)
+                .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+                .unwrap_or_default(),
+            link_name: pax_text("linkpath")
+                .or(long_link.take())
+                .or_else(|| header.link_name_bytes().map(|link| String::from_utf8_lossy(&link).to_string()))
+                .unwrap_or_default(),
+            mode: header.mode()? as u64,
+            uid: pax_number("uid").unwrap_or(header.uid()?),
+            gid: pax_number("gid").unwrap_or(header.gid()?),
+            user_name: pax_text("uname")
+                .or_else(|| header.username_bytes().map(|name| String::from_utf8_lossy(name).to_string()))
+                .unwrap_or_default(),
+            group_name: pax_text("gname")
+                .or_else(|| header.groupname_bytes().map(|name| String::from_utf8_lossy(name).to_string()))
+                .unwrap_or_default(),
+            dev_major: dev_major as u64,
+            dev_minor: dev_minor as u64,
+            xattrs: pax
+                .iter()
+                .filter_map(|(key, value)| {
+                    let name = key.strip_prefix("SCHILY.xattr.")?;
+                    Some((name.to_string(), openssl::base64::encode_block(value)))
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        let mut data = (&mut *tar).take(size);
+        each(&headers, entry, &mut data)?;
+        std::io::copy(&mut data, &mut std::io::sink())?;
+        let pad = padding(size).len() as u64;
+        if data.limit() > 0 || std::io::copy(&mut (&mut *tar).take(pad), &mut std::io::sink())? != pad {
+            return Err(anyhow::anyhow!("The tar ends in the middle of an entry"));
+        }
+        headers.clear();
+        pax.clear();
+    }
+}
+
+// Read one 512-byte block; false at the end of the stream
+fn read_block(reader: &mut impl Read, block: &mut [u8; 512]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < block.len() {
+        match reader.read(&mut block[filled..])? {
+            0 if filled == 0 => return Ok(false),
+            0 => return Err(anyhow::anyhow!("The tar ends in the middle of a header")),
+            read => filled += read,
+        }
+    }
+    Ok(true)
+}
+
+// The zeros that pad data of `size` bytes to a whole number of blocks
+fn padding(size: u64) -> Vec<u8> {
+    vec![0u8; ((512 - size % 512) % 512) as usize]
+}
+
+impl StorageManager {
+    // A stored layer rewritten as eStargz, as a new blob with a digest,
+    // diff_id (its tar gains the TOC) and TOC digest of its own
+    pub async fn estargz_layer(&self, layer: &Layer) -> Result<Layer> {
+        if layer.toc_digest.is_some() || !layer.urls.is_empty() {
+            return Ok(layer.clone());
+        }
+        let partial = tempfile::NamedTempFile::new_in(self.tmp_dir())?;
+        let (source, out, compression) = (layer.clone(), partial.reopen()?, self.compression.gzip());
+        let toc_digest = tokio::task::spawn_blocking(move || {
+            let tar = source.open_tar()?;
+            convert(tar, std::io::BufWriter::new(out), compression)
+        })
+        .await??;
+
+        // Stored as a received blob is, encrypted if the store encrypts blobs
+        let mut writer = self.blob_writer().await?;
+        let mut file = tokio::fs::File::open(partial.path()).await?;
+        let mut chunk = vec![0u8; 1 << 20];
+        loop {
+            let read = file.read(&mut chunk).await?;
+            if read == 0 {
+                break;
+            }
+            writer.write(&chunk[..read]).await?;
+        }
+        let mut converted = self.commit_layer(writer).await?;
+        converted.toc_digest = Some(toc_digest);
+        Ok(converted)
+    }
+
+    // An image with every layer as eStargz, and a config and manifest to
+    // match, for pushing; the stored image stays as it is
+    pub async fn estargz_image(&self, image: &Image) -> Result<Image> {
+        let mut layers = Vec::new();
+        for layer in &image.layers {
+            layers.push(self.estargz_layer(layer).await?);
+        }
+        if layers.iter().map(|layer| &layer.digest).eq(image.layers.iter().map(|layer| &layer.digest)) {
+            return Ok(image.clone());
+        }
+        let mut config: serde_json::Value = serde_json::from_slice(&image.raw_config)?;
+        config["rootfs"]["diff_ids"] = layers.iter().map(|layer| layer.diff_id.clone()).collect::<Vec<_>>().into();
+        let raw_config = serde_json::to_vec(&config)?;
+        let manifest = crate::engine::image_manifest(&sha256_of(&raw_config), raw_config.len() as u64, &layers)?;
+        Ok(Image {
+            id: image.id.clone(),
+            name: image.name.clone(),
+            layers,
+            config: serde_json::from_slice(&raw_config)?,
+            raw_config,
+            manifest,
+        })
+    }
+
+    // An image list with every platform image as eStargz, its index pointing
+    // at their new manifests
+    pub async fn estargz_list(&self, list: &ImageList) -> Result<ImageList> {
+        let mut images = Vec::new();
+        let mut moved = HashMap::new();
+        for image in &list.images {
+            let converted = self.estargz_image(image).await?;
+            let manifest_json = serde_json::to_vec(&converted.manifest)?;
+            let stored_digest = sha256_of(&serde_json::to_vec(&image.manifest)?);
+            moved.insert(stored_digest, (sha256_of(&manifest_json), manifest_json.len() as u64));
+            images.push(converted);
+        }
+        let mut index = list.index.clone();
+        let mut manifests = Vec::new();
+        for descriptor in index.manifests() {
+            let mut descriptor = descriptor.clone();
+            if let Some((digest, size)) = moved.get(&descriptor.digest().to_string()) {
+                descriptor.set_digest(digest.parse()?);
+                descriptor.set_size(*size);
+            }
+            manifests.push(descriptor);
+        }
+        index.set_manifests(manifests);
+        Ok(ImageList {
+            id: list.id.clone(),
+            name: list.name.clone(),
+            index,
+            images,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estargz_round_trip() {
+        let big: Vec<u8> = (0..CHUNK_SIZE + 1000).map(|i| (i % 251) as u8).collect();
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut append = |path: &str, kind: tar::EntryType, link: Option<&str>, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(kind);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o755);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            match link {
+                Some(link) => builder.append_link(&mut header, path, link).unwrap(),
+                None => builder.append_data(&mut header, path, data).unwrap(),
+            }
+        };
+        append("etc/", tar::EntryType::Directory, None, b"");
+        append("etc/os-release", tar::EntryType::Regular, None, b"ID=test\n");
+        append("bin/", tar::EntryType::Directory, None, b"");
+        append("bin/big", tar::EntryType::Regular, None, &big);
+        append("bin/sh", tar::EntryType::Symlink, Some("big"), b"");
+        append(&format!("{}/{}", "x".repeat(90), "long-name"), tar::EntryType::Regular, None, b"long");
+        let tar = builder.into_inner().unwrap();
+
+        let mut blob = Vec::new();
+        let toc_digest = convert(tar.as_slice(), &mut blob, Compression::default()).unwrap();
+        // Still one gzip tar to any client, with the TOC as its last entry
+        let mut names = Vec::new();
+        let mut archive = tar::Archive::new(flate2::read::MultiGzDecoder::new(blob.as_slice()));
+        for entry in archive.entries().unwrap() {
+            names.push(entry.unwrap().path().unwrap().to_string_lossy().to_string());
+        }
+        assert_eq!(names.len(), 7);
+        assert_eq!(names.last().map(String::as_str), Some(TOC_NAME));
+
+        // The footer leads to the TOC, and the TOC to each chunk
+        let footer = &blob[blob.len() - FOOTER_SIZE as usize..];
+        let offset = toc_offset(footer).unwrap();
+        let (toc, digest) = read_toc(&blob[offset as usize..blob.len() - FOOTER_SIZE as usize]).unwrap();
+        assert_eq!(digest, toc_digest);
+        let chunks = toc.chunks("bin/big", offset);
+        assert_eq!(chunks.len(), 2);
+        let mut data = Vec::new();
+        for chunk in &chunks {
+            data.extend(read_chunk(&blob[chunk.offset as usize..chunk.end as usize], chunk).unwrap());
+        }
+        assert!(data == big);
+        let chunk = &toc.chunks("etc/os-release", offset)[0];
+        assert_eq!(read_chunk(&blob[chunk.offset as usize..chunk.end as usize], chunk).unwrap(), b"ID=test\n");
+        assert_eq!(toc_offset(&blob[..FOOTER_SIZE as usize]), None);
+
+        // Upper layers override and white out lower ones; links resolve
+        let upper = Toc {
+            version: 1,
+            entries: vec![
+                TocEntry { name: "etc/.wh..wh..opq".to_string(), kind: "reg".to_string(), ..Default::default() },
+                TocEntry { name: "etc/hostname".to_string(), kind: "reg".to_string(), ..Default::default() },
+                TocEntry { name: "./bin/.wh.big".to_string(), kind: "reg".to_string(), ..Default::default() },
+            ],
+        };
+        let files = merge(&[toc.clone(), upper]);
+        let names: Vec<&str> = files.keys().map(String::as_str).collect();
+        assert_eq!(names, ["bin", "bin/sh", "etc", "etc/hostname", &format!("{}/long-name", "x".repeat(90))]);
+        assert!(resolve(&files, "/bin/sh").is_err());
+        let files = merge(&[toc]);
+        assert_eq!(resolve(&files, "/bin/../bin/sh").unwrap().0, "bin/big");
+        assert_eq!(tar_toc(tar.as_slice()).unwrap().entries.len(), 6);
+        let converted = tar_toc(flate2::read::MultiGzDecoder::new(blob.as_slice())).unwrap();
+        assert_eq!(converted.entries.len(), 6);
+        assert_eq!(read_tar_file(tar.as_slice(), "etc/os-release").unwrap().unwrap(), b"ID=test\n");
+    }
+}
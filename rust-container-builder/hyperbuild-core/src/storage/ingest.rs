@@ -0,0 +1,166 @@
+use super::{BlobKey, CompressionAlgorithm, Layer, StorageManager, encrypt, write_part};
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+// A layer blob being received, e.g. from a registry, written to a partial
+// file in the store's tmp dir a chunk at a time and hashed on the way, so
+// memory use does not grow with the layer. The partial file is removed if the
+// writer is dropped before the layer is committed.
+pub struct BlobWriter {
+    partial_path: PathBuf,
+    file: fs::File,
+    hasher: Sha256,
+    size: u64,
+    // The first bytes, until there are enough to tell a gzip or zstd blob,
+    // which is stored as it is, from an uncompressed tar, which is compressed first
+    head: Vec<u8>,
+    compressed: Option<bool>,
+    // The store's key, and the sealer encrypting a compressed blob with it
+    key: Option<Arc<BlobKey>>,
+    sealer: Option<encrypt::Sealer>,
+    committed: bool,
+}
+
+impl BlobWriter {
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+        if self.compressed.is_none() {
+            self.head.extend_from_slice(data);
+            if self.head.len() < CompressionAlgorithm::MAGIC_LEN {
+                return Ok(());
+            }
+            return self.start().await;
+        }
+        write_part(&mut self.file, &mut self.sealer, data).await
+    }
+
+    // The digest of the bytes written so far
+    pub fn digest(&self) -> String {
+        format!("sha256:{:x}", self.hasher.clone().finalize())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Write the buffered first bytes, now that they tell what the blob is
+    async fn start(&mut self) -> Result<()> {
+        let compressed = CompressionAlgorithm::detect(&self.head).is_some();
+        self.compressed = Some(compressed);
+        // Only compressed blobs are kept; a tar is compressed into a new blob
+        // from the partial file, which is read back unencrypted
+        if compressed && let Some(key) = &self.key {
+            let (sealer, header) = encrypt::Sealer::new(key.clone())?;
+            self.file.write_all(&header).await?;
+            self.sealer = Some(sealer);
+        }
+        let head = std::mem::take(&mut self.head);
+        write_part(&mut self.file, &mut self.sealer, &head).await
+    }
+}
+
+impl Drop for BlobWriter {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.partial_path);
+        }
+    }
+}
+
+impl StorageManager {
+    pub async fn blob_writer(&self) -> Result<BlobWriter> {
+        let partial_path = self.tmp_dir().join(format!("{}.partial", uuid::Uuid::new_v4()));
+        let file = fs::File::create(&partial_path).await?;
+        Ok(BlobWriter {
+            partial_path,
+            file,
+            hasher: Sha256::new(),
+            size: 0,
+            head: Vec::new(),
+            compressed: None,
+            key: self.encryption.clone(),
+            sealer: None,
+            committed: false,
+        })
+    }
+
+    // Move a received layer into the store: a gzip or zstd blob is renamed into place
+    // under its digest, so a store never holds a truncated blob, and an
+    // uncompressed tar is compressed into a new blob
+    pub async fn commit_layer(&self, mut writer: BlobWriter) -> Result<Layer> {
+        if writer.compressed.is_none() {
+            writer.start().await?;
+        }
+        if let Some(sealer) = writer.sealer.take() {
+            writer.file.write_all(&sealer.finish()?).await?;
+        }
+        writer.file.flush().await?;
+
+        if writer.compressed != Some(true) {
+            let tar = fs::File::open(&writer.partial_path).await?;
+            return self.create_layer_from_reader(tar).await;
+        }
+
+        // A blob that does not decompress is not stored
+        let (partial, key) = (writer.partial_path.clone(), self.encryption.clone());
+        let (diff_id, diff_size, algorithm) =
+            tokio::task::spawn_blocking(move || super::diff_digest(encrypt::open_blob(&partial, key.as_ref())?)).await??;
+        let digest = writer.digest();
+        let path = self.blob_path(&digest)?;
+        if !path.exists() {
+            fs::rename(&writer.partial_path, &path).await?;
+            writer.committed = true;
+        }
+        Ok(Layer {
+            digest,
+            size: writer.size,
+            diff_id,
+            diff_size,
+            path,
+            urls: Vec::new(),
+            toc_digest: None,
+            compression: algorithm,
+            key: self.encryption.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_received_layers_are_committed_or_discarded() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = StorageManager::new(root.path().to_path_buf()).unwrap();
+        storage.init().await.unwrap();
+        let built = storage.create_layer(b"layer contents").await.unwrap();
+        let blob = std::fs::read(&built.path).unwrap();
+        std::fs::remove_file(&built.path).unwrap();
+
+        // A gzip blob split mid-magic is stored as it is
+        let mut writer = storage.blob_writer().await.unwrap();
+        for part in [&blob[..1], &blob[1..]] {
+            writer.write(part).await.unwrap();
+        }
+        assert_eq!(writer.digest(), built.digest);
+        let layer = storage.commit_layer(writer).await.unwrap();
+        assert_eq!((&layer.digest, &layer.diff_id, layer.size), (&built.digest, &built.diff_id, built.size));
+        assert!(layer.path.exists());
+
+        // An uncompressed tar is compressed into a blob
+        let mut writer = storage.blob_writer().await.unwrap();
+        writer.write(b"layer contents").await.unwrap();
+        assert_eq!(storage.commit_layer(writer).await.unwrap().diff_id, built.diff_id);
+
+        let mut writer = storage.blob_writer().await.unwrap();
+        writer.write(&blob).await.unwrap();
+        drop(writer);
+        assert_eq!(std::fs::read_dir(storage.tmp_dir()).unwrap().count(), 0);
+    }
+}
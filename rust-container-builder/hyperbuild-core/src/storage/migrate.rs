@@ -0,0 +1,342 @@
+use super::{CompressionAlgorithm, Layer, RefIndex, StorageManager, sha256_of};
+use anyhow::Result;
+use oci_spec::image::{ImageIndex, ImageManifest};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tokio::fs;
+
+// The layout version this build reads and writes. A change to the on-disk
+// layout bumps it and adds a migration from the previous version:
+//
+//   1  layers under layers/<uuid>.tar.gz, names only in name.txt files
+//   2  content-addressed blobs/sha256/, refs.json, and blob digests and
+//      diff_ids recorded separately in layers.json
+pub const STORE_VERSION: u32 = 2;
+
+// An entry of layers.json in a version 1 store: `id` was the blob digest, or
+// a UUID for layers stored under layers/, and `digest` and `size` described
+// the uncompressed tar
+#[derive(Debug, Deserialize)]
+struct LegacyLayer {
+    id: String,
+    digest: String,
+    size: u64,
+    path: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum StoredLayer {
+    Current(Layer),
+    Legacy(LegacyLayer),
+}
+
+impl StorageManager {
+    fn version_path(&self) -> PathBuf {
+        self.root_dir.join("version")
+    }
+
+    // Bring the store up to STORE_VERSION in place. A lock keeps concurrent
+    // commands from migrating the same store twice.
+    pub(super) async fn upgrade(&self) -> Result<()> {
+        fs::create_dir_all(&self.root_dir).await?;
+        let lock_path = self.root_dir.join("version.lock");
+        let lock = tokio::task::spawn_blocking(move || -> Result<std::fs::File> {
+            let lock = std::fs::File::create(lock_path)?;
+            lock.lock()?;
+            Ok(lock)
+        })
+        .await??;
+
+        let (mut version, recorded) = self.stored_version().await?;
+        if version > STORE_VERSION {
+            return Err(anyhow::anyhow!(
+                "Store {:?} has layout version {}, newer than the {} this hyperbuild supports; upgrade hyperbuild",
+                self.root_dir,
+                version,
+                STORE_VERSION
+            ));
+        }
+        while version < STORE_VERSION {
+            tracing::info!("Migrating store {:?} from layout version {} to {}", self.root_dir, version, version + 1);
+            match version {
+                1 => self.migrate_to_v2().await?,
+                _ => unreachable!("no migration from store layout version {}", version),
+            }
+            version += 1;
+            self.write_version(version).await?;
+        }
+        if !recorded {
+            self.write_version(version).await?;
+        }
+
+        drop(lock);
+        Ok(())
+    }
+
+    // The store's layout version, and whether it was recorded. Stores from
+    // before versioning have images or layers but no version file.
+    async fn stored_version(&self) -> Result<(u32, bool)> {
+        match fs::read_to_string(self.version_path()).await {
+            Ok(contents) => {
+                let version = contents
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid store version file {:?}: {:?}", self.version_path(), contents))?;
+                Ok((version, true))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let populated = [&self.images_dir, &self.indexes_dir, &self.root_dir.join("layers")]
+                    .iter()
+                    .any(|dir| dir.exists());
+                Ok((if populated { 1 } else { STORE_VERSION }, false))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn write_version(&self, version: u32) -> Result<()> {
+        let partial = self.version_path().with_extension("partial");
+        fs::write(&partial, format!("{}\n", version)).await?;
+        fs::rename(&partial, self.version_path()).await?;
+        Ok(())
+    }
+
+    // Move layers/<uuid>.tar.gz files into the blob store under their digest,
+    // record blob digests in layers.json, rebuild the manifests listing them,
+    // and index the names in name.txt files
+    async fn migrate_to_v2(&self) -> Result<()> {
+        fs::create_dir_all(&self.blobs_dir).await?;
+        let mut moved = HashMap::new();
+        // Image id, old and new manifest digest, and new manifest size
+        let mut rebuilt = Vec::new();
+
+        if self.images_dir.exists() {
+            for id in self.list_images().await? {
+                let path = self.images_dir.join(&id).join("layers.json");
+                if !path.exists() {
+                    continue;
+                }
+                let stored: Vec<StoredLayer> = serde_json::from_slice(&fs::read(&path).await?)
+                    .map_err(|e| anyhow::anyhow!("Invalid layer list {:?}: {}", path, e))?;
+                let mut layers = Vec::new();
+                let mut migrated = false;
+                for layer in stored {
+                    layers.push(match layer {
+                        StoredLayer::Current(layer) => layer,
+                        StoredLayer::Legacy(legacy) => {
+                            migrated = true;
+                            self.migrate_layer(legacy, &mut moved)
+                                .await
+                                .map_err(|e| anyhow::anyhow!("Image {}: {}", id, e))?
+                        }
+                    });
+                }
+
+                let partial = path.with_extension("json.partial");
+                fs::write(&partial, serde_json::to_string_pretty(&layers)?).await?;
+                fs::rename(&partial, &path).await?;
+                if migrated {
+                    rebuilt.push(self.rebuild_manifest(&id, &layers).await?);
+                }
+            }
+        }
+
+        // Image indexes and digest references name the rebuilt manifests by digest
+        let rebuilt_digests: HashMap<String, (String, u64)> = rebuilt
+            .iter()
+            .map(|(_, old, new, size)| (old.clone(), (new.clone(), *size)))
+            .collect();
+        if !rebuilt_digests.is_empty() && self.indexes_dir.exists() {
+            for id in self.list_image_lists().await? {
+                let path = self.indexes_dir.join(&id).join("index.json");
+                let mut index: ImageIndex = serde_json::from_slice(&fs::read(&path).await?)
+                    .map_err(|e| anyhow::anyhow!("Invalid image index {:?}: {}", path, e))?;
+                let mut manifests = index.manifests().clone();
+                for descriptor in manifests.iter_mut() {
+                    if let Some((digest, size)) = rebuilt_digests.get(&descriptor.digest().to_string()) {
+                        descriptor.set_digest(digest.parse()?);
+                        descriptor.set_size(*size);
+                    }
+                }
+                index.set_manifests(manifests);
+                let partial = path.with_extension("json.partial");
+                fs::write(&partial, serde_json::to_vec(&index)?).await?;
+                fs::rename(&partial, &path).await?;
+            }
+        }
+
+        if !self.refs_path().exists() {
+            let legacy = self.legacy_refs().await?;
+            let path = self.refs_path();
+            tokio::task::spawn_blocking(move || RefIndex::update(&path, |refs| *refs = legacy)).await??;
+        } else if !rebuilt.is_empty() {
+            self.update_refs(move |refs| {
+                for (id, old, new, _) in rebuilt {
+                    if refs.digests.remove(&old).is_some() {
+                        refs.digests.insert(new, id);
+                    }
+                }
+            })
+            .await?;
+        }
+
+        // Layer files no image used are left for gc; an emptied directory goes
+        let _ = fs::remove_dir(self.root_dir.join("layers")).await;
+        Ok(())
+    }
+
+    async fn migrate_layer(&self, legacy: LegacyLayer, moved: &mut HashMap<PathBuf, String>) -> Result<Layer> {
+        // Recorded paths are relative to wherever the store was used from, so
+        // the file is looked up in this store's layers/ by name
+        let source = self.root_dir.join("layers").join(legacy.path.file_name().unwrap_or_default());
+        let digest = if legacy.id.starts_with("sha256:") {
+            legacy.id
+        } else if let Some(digest) = moved.get(&source) {
+            digest.clone()
+        } else {
+            let file = source.clone();
+            let digest = tokio::task::spawn_blocking(move || -> Result<String> {
+                use sha2::{Digest, Sha256};
+                let mut reader = std::fs::File::open(&file)
+                    .map_err(|e| anyhow::anyhow!("Layer file {:?} is unreadable: {}", file, e))?;
+                let mut hasher = Sha256::new();
+                std::io::copy(&mut reader, &mut hasher)?;
+                Ok(format!("sha256:{:x}", hasher.finalize()))
+            })
+            .await??;
+
+            let blob = self.blob_path(&digest)?;
+            if blob.exists() {
+                fs::remove_file(&source).await?;
+            } else {
+                fs::rename(&source, &blob).await?;
+            }
+            moved.insert(source, digest.clone());
+            digest
+        };
+
+        let path = self.blob_path(&digest)?;
+        let size = fs::metadata(&path)
+            .await
+            .map_err(|e| anyhow::anyhow!("Layer blob {:?} is missing: {}", path, e))?
+            .len();
+        Ok(Layer {
+            digest,
+            size,
+            diff_id: legacy.digest,
+            diff_size: legacy.size,
+            path,
+            urls: Vec::new(),
+            toc_digest: None,
+            compression: CompressionAlgorithm::Gzip,
+            key: self.encryption.clone(),
+        })
+    }
+
+    // Version 1 manifests listed no layers, or listed them by their tar
+    // digests; they are rebuilt from the migrated layers
+    async fn rebuild_manifest(&self, id: &str, layers: &[Layer]) -> Result<(String, String, String, u64)> {
+        let path = self.images_dir.join(id).join("manifest.json");
+        let old: ImageManifest = serde_json::from_slice(&fs::read(&path).await?)
+            .map_err(|e| anyhow::anyhow!("Invalid manifest {:?}: {}", path, e))?;
+        let config = old.config();
+        let manifest = crate::engine::image_manifest(config.digest().as_ref(), config.size(), layers)?;
+
+        let partial = path.with_extension("json.partial");
+        fs::write(&partial, serde_json::to_string_pretty(&manifest)?).await?;
+        fs::rename(&partial, &path).await?;
+        let raw = serde_json::to_vec(&manifest)?;
+        Ok((id.to_string(), sha256_of(&serde_json::to_vec(&old)?), sha256_of(&raw), raw.len() as u64))
+    }
+
+    // Names from name.txt files, oldest first so the newest image keeps a
+    // name shared by several; image indexes win over their platform images
+    async fn legacy_refs(&self) -> Result<RefIndex> {
+        let mut refs = RefIndex::default();
+        for (dir, manifest_file) in [(&self.images_dir, "manifest.json"), (&self.indexes_dir, "index.json")] {
+            if !dir.exists() {
+                continue;
+            }
+            let mut entries = Vec::new();
+            let mut read_dir = fs::read_dir(dir).await?;
+            while let Some(entry) = read_dir.next_entry().await? {
+                let modified = entry.metadata().await?.modified()?;
+                entries.push((modified, entry.path()));
+            }
+            entries.sort();
+
+            for (_, path) in entries {
+                let id = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+                if let Ok(names) = fs::read_to_string(path.join("name.txt")).await {
+                    for name in names.lines().map(str::trim).filter(|name| !name.is_empty()) {
+                        refs.tag(name, &id);
+                    }
+                }
+                // Digests are taken over the typed manifest, as when it was saved
+                if let Ok(data) = fs::read(path.join(manifest_file)).await {
+                    let typed = match manifest_file {
+                        "manifest.json" => serde_json::from_slice::<ImageManifest>(&data).and_then(|m| serde_json::to_vec(&m)),
+                        _ => serde_json::from_slice::<ImageIndex>(&data).and_then(|index| serde_json::to_vec(&index)),
+                    };
+                    if let Ok(typed) = typed {
+                        refs.digests.insert(sha256_of(&typed), id);
+                    }
+                }
+            }
+        }
+        Ok(refs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn test_migrate_uuid_layers_into_blob_store() {
+        let root = tempfile::tempdir().unwrap();
+
+        // A version 1 store: one image whose layer sits under layers/
+        let tar = b"layer tar";
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(tar).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let legacy_path = root.path().join("layers/0c4f.tar.gz");
+        let image_dir = root.path().join("images/image_1");
+        std::fs::create_dir_all(legacy_path.parent().unwrap()).unwrap();
+        std::fs::create_dir_all(&image_dir).unwrap();
+        std::fs::write(&legacy_path, &gzip).unwrap();
+        let diff_id = sha256_of(tar);
+        // Recorded relative to the directory the store was used from
+        let layers = serde_json::json!([{ "id": "0c4f", "digest": diff_id, "size": tar.len(), "path": "old/layers/0c4f.tar.gz" }]);
+        std::fs::write(image_dir.join("layers.json"), layers.to_string()).unwrap();
+        std::fs::write(image_dir.join("name.txt"), "app:latest\n").unwrap();
+        let config = serde_json::to_vec(&oci_spec::image::ImageConfiguration::default()).unwrap();
+        std::fs::write(image_dir.join("config.json"), &config).unwrap();
+        let manifest = crate::engine::image_manifest(&sha256_of(&config), config.len() as u64, &[]).unwrap();
+        std::fs::write(image_dir.join("manifest.json"), serde_json::to_vec(&manifest).unwrap()).unwrap();
+
+        let storage = StorageManager::new(root.path().to_path_buf()).unwrap();
+        storage.init().await.unwrap();
+        assert_eq!(std::fs::read_to_string(root.path().join("version")).unwrap(), "2\n");
+        assert!(!root.path().join("layers").exists());
+
+        let image = storage.get_image_by_name("app:latest").await.unwrap().unwrap();
+        let layer = &image.layers[0];
+        assert_eq!(layer.digest, sha256_of(&gzip));
+        assert_eq!((&layer.diff_id, layer.size), (&diff_id, gzip.len() as u64));
+        assert_eq!(layer.path, storage.blob_path(&layer.digest).unwrap());
+        let descriptors = image.manifest.layers();
+        assert_eq!(descriptors.len(), 1);
+        assert_eq!((descriptors[0].digest().to_string(), descriptors[0].size()), (layer.digest.clone(), layer.size));
+        let manifest_digest = sha256_of(&serde_json::to_vec(&image.manifest).unwrap());
+        assert_eq!(storage.resolve(&manifest_digest).await.unwrap().as_deref(), Some("image_1"));
+
+        // Stores from a newer hyperbuild are refused rather than misread
+        std::fs::write(root.path().join("version"), "3\n").unwrap();
+        assert!(storage.init().await.is_err());
+    }
+}
@@ -0,0 +1,1010 @@
+mod backup;
+mod cache;
+mod compress;
+mod dedupe;
+mod encrypt;
+pub mod estargz;
+mod ingest;
+mod migrate;
+mod prune;
+mod refs;
+mod rootfs;
+mod usage;
+mod verify;
+
+use crate::platform::Platform;
+use crate::reference::Reference;
+use anyhow::Result;
+use oci_spec::image::{ImageConfiguration, ImageIndex, ImageManifest};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+pub use backup::{BackupReport, RestoreReport};
+pub use cache::{CachePruneReport, CacheRecord};
+pub use compress::{Compression, CompressionAlgorithm};
+pub use dedupe::DedupeReport;
+pub use encrypt::{BlobKey, EncryptionSettings};
+pub use prune::{PruneOptions, PruneReport};
+pub use refs::RefIndex;
+pub use usage::{Usage, UsageKind};
+pub use verify::{Problem, VerifyReport};
+
+// How much of a layer tar is read and compressed at a time
+const LAYER_CHUNK_SIZE: usize = 1 << 20;
+
+const SBOM_FILES: [(&str, &str); 2] = [
+    ("application/spdx+json", "sbom.spdx.json"),
+    ("application/vnd.cyclonedx+json", "sbom.cdx.json"),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Layer {
+    // The compressed blob as stored and pushed; manifests refer to layers by these
+    pub digest: String,
+    pub size: u64,
+    // The uncompressed tar, listed in the image config's rootfs.diff_ids
+    pub diff_id: String,
+    pub diff_size: u64,
+    pub path: PathBuf,
+    // Where a non-distributable (foreign) layer, such as a Windows base
+    // layer, is downloaded from; such layers are never pushed to registries
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub urls: Vec<String>,
+    // The digest of an eStargz layer's table of contents, which its manifest
+    // descriptor gives in an annotation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub toc_digest: Option<String>,
+    #[serde(default, skip_serializing_if = "CompressionAlgorithm::is_gzip")]
+    pub compression: CompressionAlgorithm,
+    // The store's key, if the blob may be encrypted
+    #[serde(skip)]
+    pub key: Option<Arc<BlobKey>>,
+}
+
+impl Layer {
+    // The compressed blob, decrypted if the store encrypted it
+    pub fn open(&self) -> Result<Box<dyn std::io::Read + Send>> {
+        encrypt::open_blob(&self.path, self.key.as_ref())
+    }
+
+    // The layer's uncompressed tar
+    pub fn open_tar(&self) -> Result<Box<dyn std::io::Read + Send>> {
+        compress::decompress(self.open()?)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub id: String,
+    pub name: String,
+    pub layers: Vec<Layer>,
+    pub config: ImageConfiguration,
+    // The config blob exactly as stored and pushed; its digest is the one in the manifest
+    pub raw_config: Vec<u8>,
+    pub manifest: ImageManifest,
+}
+
+// A multi-platform image: an OCI image index with one image per platform
+#[derive(Debug, Clone)]
+pub struct ImageList {
+    pub id: String,
+    pub name: String,
+    pub index: ImageIndex,
+    pub images: Vec<Image>,
+}
+
+// Blobs garbage collection removed, or would remove in a dry run
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub removed: Vec<PathBuf>,
+    pub bytes: u64,
+}
+
+// What removing an image reference did
+#[derive(Debug, Default)]
+pub struct Removed {
+    pub untagged: Vec<String>,
+    pub deleted: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct StorageManager {
+    root_dir: PathBuf,
+    // Content-addressed: each blob is stored once, named by the sha256 of its bytes
+    blobs_dir: PathBuf,
+    images_dir: PathBuf,
+    indexes_dir: PathBuf,
+    compression: Compression,
+    // Blobs are written encrypted with this key
+    encryption: Option<Arc<BlobKey>>,
+}
+
+impl StorageManager {
+    pub fn new(root_dir: PathBuf) -> Result<Self> {
+        let blobs_dir = root_dir.join("blobs").join("sha256");
+        let images_dir = root_dir.join("images");
+        let indexes_dir = root_dir.join("indexes");
+
+        Ok(Self {
+            root_dir,
+            blobs_dir,
+            images_dir,
+            indexes_dir,
+            compression: Compression::default(),
+            encryption: None,
+        })
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_encryption(mut self, key: Option<Arc<BlobKey>>) -> Self {
+        self.encryption = key;
+        self
+    }
+
+    // Scratch space for in-progress builds
+    pub fn tmp_dir(&self) -> PathBuf {
+        self.root_dir.join("tmp")
+    }
+
+    // Reports and step logs of past builds
+    pub fn builds_dir(&self) -> PathBuf {
+        self.root_dir.join("builds")
+    }
+
+    pub async fn init(&self) -> Result<()> {
+        // Stores written by older versions are migrated before anything reads them
+        self.upgrade().await?;
+
+        // Create necessary directories
+        fs::create_dir_all(&self.blobs_dir).await?;
+        fs::create_dir_all(&self.images_dir).await?;
+        fs::create_dir_all(&self.indexes_dir).await?;
+        fs::create_dir_all(self.tmp_dir()).await?;
+
+        // A store that encrypts its blobs records the key id, so it is never
+        // used with another key, or without one and written to in the clear
+        let marker = self.root_dir.join("encryption");
+        let recorded = match fs::read_to_string(&marker).await {
+            Ok(id) => Some(id.trim().to_string()),
+            Err(_) => None,
+        };
+        match (&self.encryption, recorded) {
+            (None, Some(id)) => {
+                return Err(anyhow::anyhow!(
+                    "Store {:?} is encrypted with key {}; set keyfile under [encryption] in the config file",
+                    self.root_dir,
+                    id
+                ));
+            }
+            (Some(key), Some(id)) if key.id() != id => {
+                return Err(anyhow::anyhow!(
+                    "Store {:?} is encrypted with key {}, not the configured key {}",
+                    self.root_dir,
+                    id,
+                    key.id()
+                ));
+            }
+            (Some(key), None) => fs::write(&marker, key.id()).await?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub async fn create_layer(&self, data: &[u8]) -> Result<Layer> {
+        self.create_layer_from_reader(data).await
+    }
+
+    // Create a layer from an uncompressed tar stream. The tar is hashed and
+    // compressed a chunk at a time straight to disk, so memory use stays
+    // bounded however large the layer is. Chunks are compressed on several
+    // threads; the result does not depend on how many.
+    pub async fn create_layer_from_reader<R: AsyncRead + Unpin>(&self, mut reader: R) -> Result<Layer> {
+        use sha2::{Digest, Sha256};
+        use std::collections::VecDeque;
+
+        let partial_path = self.tmp_dir().join(format!("{}.partial", uuid::Uuid::new_v4()));
+        let mut partial = fs::File::create(&partial_path).await?;
+        let mut diff_hasher = Sha256::new();
+        let mut blob_hasher = Sha256::new();
+        let mut crc = flate2::Crc::new();
+        let mut diff_size = 0u64;
+        // Digests and sizes are of the blob as pushed, before any encryption
+        let mut size = 0u64;
+        let mut sealer = match &self.encryption {
+            Some(key) => {
+                let (sealer, header) = encrypt::Sealer::new(key.clone())?;
+                partial.write_all(&header).await?;
+                Some(sealer)
+            }
+            None => None,
+        };
+
+        let compression = self.compression;
+        let header = compression.header();
+        blob_hasher.update(&header);
+        size += header.len() as u64;
+        write_part(&mut partial, &mut sealer, &header).await?;
+
+        let mut pending = VecDeque::new();
+        loop {
+            let mut chunk = vec![0u8; LAYER_CHUNK_SIZE];
+            let read = read_chunk(&mut reader, &mut chunk).await?;
+            if read > 0 {
+                chunk.truncate(read);
+                diff_hasher.update(&chunk);
+                diff_size += read as u64;
+                pending.push_back(tokio::task::spawn_blocking(move || compression.compress_chunk(&chunk)));
+            }
+
+            // Written in order, keeping at most one chunk per worker in memory
+            while pending.len() >= compression.workers() || (read == 0 && !pending.is_empty()) {
+                let Some(task) = pending.pop_front() else {
+                    break;
+                };
+                let (compressed, chunk_crc) = task.await??;
+                crc.combine(&chunk_crc);
+                blob_hasher.update(&compressed);
+                size += compressed.len() as u64;
+                write_part(&mut partial, &mut sealer, &compressed).await?;
+            }
+            if read == 0 {
+                break;
+            }
+        }
+        let trailer = compression.trailer(&crc)?;
+        blob_hasher.update(&trailer);
+        size += trailer.len() as u64;
+        write_part(&mut partial, &mut sealer, &trailer).await?;
+        if let Some(sealer) = sealer {
+            partial.write_all(&sealer.finish()?).await?;
+        }
+        partial.flush().await?;
+        drop(partial);
+
+        // Written aside and moved into place, so an interrupted build
+        // never leaves a truncated layer in the store
+        let blob_digest = format!("sha256:{:x}", blob_hasher.finalize());
+        let path = self.blob_path(&blob_digest)?;
+        if path.exists() {
+            fs::remove_file(&partial_path).await?;
+        } else {
+            fs::rename(&partial_path, &path).await?;
+        }
+
+        Ok(Layer {
+            digest: blob_digest,
+            size,
+            diff_id: format!("sha256:{:x}", diff_hasher.finalize()),
+            diff_size,
+            path,
+            urls: Vec::new(),
+            toc_digest: None,
+            compression: compression.algorithm,
+            key: self.encryption.clone(),
+        })
+    }
+
+    // Store a gzip or zstd layer blob as it is, e.g. one downloaded from a registry
+    pub async fn import_layer(&self, compressed: Vec<u8>) -> Result<Layer> {
+        let (compressed, (diff_id, diff_size, algorithm)) = tokio::task::spawn_blocking(move || {
+            let diff = diff_digest(compressed.as_slice());
+            diff.map(|diff| (compressed, diff))
+        })
+        .await??;
+        let (digest, path) = self.write_blob(&compressed).await?;
+        Ok(Layer {
+            digest,
+            size: compressed.len() as u64,
+            diff_id,
+            diff_size,
+            path,
+            urls: Vec::new(),
+            toc_digest: None,
+            compression: algorithm,
+            key: self.encryption.clone(),
+        })
+    }
+
+    // The stored layer with this blob (compressed) digest, if any
+    pub async fn get_layer(&self, digest: &str) -> Result<Option<Layer>> {
+        let path = self.blob_path(digest)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        // Read a chunk at a time, however large the layer
+        let (blob, key) = (path.clone(), self.encryption.clone());
+        let (size, (diff_id, diff_size, algorithm)) = tokio::task::spawn_blocking(move || -> Result<_> {
+            let mut counted = CountingReader {
+                inner: encrypt::open_blob(&blob, key.as_ref())?,
+                count: 0,
+            };
+            let diff = diff_digest(&mut counted)?;
+            // The compressed stream may end before the blob does
+            std::io::copy(&mut counted, &mut std::io::sink())?;
+            Ok((counted.count, diff))
+        })
+        .await??;
+        Ok(Some(Layer {
+            digest: digest.to_string(),
+            size,
+            diff_id,
+            diff_size,
+            path,
+            urls: Vec::new(),
+            toc_digest: None,
+            compression: algorithm,
+            key: self.encryption.clone(),
+        }))
+    }
+
+    pub fn blob_path(&self, digest: &str) -> Result<PathBuf> {
+        let hex = digest
+            .strip_prefix("sha256:")
+            .filter(|hex| hex.len() == 64 && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')))
+            .ok_or_else(|| anyhow::anyhow!("Invalid blob digest {}", digest))?;
+        Ok(self.blobs_dir.join(hex))
+    }
+
+    pub fn has_blob(&self, digest: &str) -> bool {
+        self.blob_path(digest).is_ok_and(|path| path.exists())
+    }
+
+    // Write a blob under its digest; a blob that is already stored is not written again
+    async fn write_blob(&self, data: &[u8]) -> Result<(String, PathBuf)> {
+        use sha2::{Digest, Sha256};
+
+        let digest = format!("sha256:{:x}", Sha256::digest(data));
+        let path = self.blob_path(&digest)?;
+        if path.exists() {
+            return Ok((digest, path));
+        }
+
+        // Written aside and renamed into place, so an interrupted build
+        // never leaves a truncated blob in the store
+        let partial_path = self.tmp_dir().join(format!("{}.partial", uuid::Uuid::new_v4()));
+        match &self.encryption {
+            Some(key) => fs::write(&partial_path, encrypt::seal(key.clone(), data)?).await?,
+            None => fs::write(&partial_path, data).await?,
+        }
+        fs::rename(&partial_path, &path).await?;
+        Ok((digest, path))
+    }
+
+    pub async fn save_image(&self, image: &Image) -> Result<()> {
+        // The image only appears in the store once all of its files are written
+        let image_path = self.tmp_dir().join(&image.id);
+        fs::create_dir_all(&image_path).await?;
+
+        // Save image config
+        let config_path = image_path.join("config.json");
+        fs::write(&config_path, &image.raw_config).await?;
+
+        // Save image manifest
+        let manifest_path = image_path.join("manifest.json");
+        let manifest_json = serde_json::to_string_pretty(&image.manifest)?;
+        fs::write(&manifest_path, manifest_json).await?;
+
+        // Save image name for lookup
+        let name_path = image_path.join("name.txt");
+        fs::write(&name_path, &image.name).await?;
+
+        // Save the layer list so the image can be reconstructed later
+        let layers_path = image_path.join("layers.json");
+        let layers_json = serde_json::to_string_pretty(&image.layers)?;
+        fs::write(&layers_path, layers_json).await?;
+
+        fs::rename(&image_path, self.images_dir.join(&image.id)).await?;
+
+        let (id, name) = (image.id.clone(), image.name.clone());
+        let digest = sha256_of(&serde_json::to_vec(&image.manifest)?);
+        self.update_refs(move |refs| {
+            refs.tag(&name, &id);
+            refs.touch(&id);
+            refs.digests.insert(digest, id);
+        })
+        .await
+    }
+
+    pub async fn get_image(&self, id: &str) -> Result<Option<Image>> {
+        let image_path = self.images_dir.join(id);
+        if !image_path.exists() {
+            return Ok(None);
+        }
+
+        // Read image config
+        let config_path = image_path.join("config.json");
+        let raw_config = fs::read(&config_path).await?;
+        let config: ImageConfiguration = serde_json::from_slice(&raw_config)?;
+
+        // Read image manifest
+        let manifest_path = image_path.join("manifest.json");
+        let manifest_content = fs::read_to_string(&manifest_path).await?;
+        let manifest: ImageManifest = serde_json::from_str(&manifest_content)?;
+
+        // Images saved before layer lists were recorded have no layers.json
+        let layers_path = image_path.join("layers.json");
+        let mut layers: Vec<Layer> = if layers_path.exists() {
+            serde_json::from_str(&fs::read_to_string(&layers_path).await?)?
+        } else {
+            vec![]
+        };
+        // Blob paths follow from digests, so a store still works after being moved
+        for layer in &mut layers {
+            layer.path = self.blob_path(&layer.digest)?;
+            layer.key = self.encryption.clone();
+        }
+
+        // name.txt holds one name per line; the first is the one the image was built as
+        let name_path = image_path.join("name.txt");
+        let name = if name_path.exists() {
+            let names = fs::read_to_string(&name_path).await?;
+            names.lines().next().unwrap_or_default().trim().to_string()
+        } else {
+            id.to_string()
+        };
+
+        Ok(Some(Image {
+            id: id.to_string(),
+            name,
+            layers,
+            config,
+            raw_config,
+            manifest,
+        }))
+    }
+
+    pub async fn get_image_by_name(&self, name: &str) -> Result<Option<Image>> {
+        let Some(id) = self.resolve(name).await? else {
+            return Ok(None);
+        };
+        // name.txt keeps the first tag, which may have been removed since
+        let tagged = self.refs().await?.tags.contains_key(name);
+        Ok(self.get_image(&id).await?.map(|image| match tagged {
+            true => Image { name: name.to_string(), ..image },
+            false => image,
+        }))
+    }
+
+    // Find the image with this name built for the given platform; for a
+    // multi-platform name, the image in its index for that platform
+    pub async fn get_image_by_name_for_platform(&self, name: &str, platform: &Platform) -> Result<Option<Image>> {
+        let images = match self.get_image_list_by_name(name).await? {
+            Some(list) => list.images,
+            None => self.get_image_by_name(name).await?.into_iter().collect(),
+        };
+        Ok(images.into_iter().find(|image| {
+            image.config.os().to_string() == platform.os
+                && image.config.architecture().to_string() == platform.architecture
+                && (platform.variant.is_none() || image.config.variant() == &platform.variant)
+        }))
+    }
+
+    // The per-platform images are saved on their own; the index refers to them by id
+    pub async fn save_image_list(&self, list: &ImageList) -> Result<()> {
+        let index_path = self.tmp_dir().join(&list.id);
+        fs::create_dir_all(&index_path).await?;
+
+        fs::write(index_path.join("index.json"), serde_json::to_vec(&list.index)?).await?;
+        fs::write(index_path.join("name.txt"), &list.name).await?;
+
+        let image_ids: Vec<&String> = list.images.iter().map(|image| &image.id).collect();
+        fs::write(index_path.join("images.json"), serde_json::to_string_pretty(&image_ids)?).await?;
+
+        fs::rename(&index_path, self.indexes_dir.join(&list.id)).await?;
+
+        let (id, name) = (list.id.clone(), list.name.clone());
+        let digest = sha256_of(&serde_json::to_vec(&list.index)?);
+        self.update_refs(move |refs| {
+            refs.tag(&name, &id);
+            refs.touch(&id);
+            refs.digests.insert(digest, id);
+        })
+        .await
+    }
+
+    pub async fn get_image_list_by_name(&self, name: &str) -> Result<Option<ImageList>> {
+        let Some(id) = self.resolve(name).await? else {
+            return Ok(None);
+        };
+        Ok(self.get_image_list(&id).await?.map(|list| ImageList {
+            name: name.to_string(),
+            ..list
+        }))
+    }
+
+    pub async fn get_image_list(&self, id: &str) -> Result<Option<ImageList>> {
+        let index_path = self.indexes_dir.join(id);
+        if !index_path.exists() {
+            return Ok(None);
+        }
+
+        let index: ImageIndex = serde_json::from_slice(&fs::read(index_path.join("index.json")).await?)?;
+        let image_ids: Vec<String> = serde_json::from_slice(&fs::read(index_path.join("images.json")).await?)?;
+        let mut images = Vec::new();
+        for image_id in &image_ids {
+            let image = self
+                .get_image(image_id)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Image {} referenced by index {} is missing", image_id, id))?;
+            images.push(image);
+        }
+
+        let names = fs::read_to_string(index_path.join("name.txt")).await.unwrap_or_default();
+        Ok(Some(ImageList {
+            id: id.to_string(),
+            name: names.lines().next().unwrap_or(id).trim().to_string(),
+            index,
+            images,
+        }))
+    }
+
+    // An SBOM of the image, kept next to its config under a file named for its format
+    pub async fn save_sbom(&self, id: &str, media_type: &str, data: &[u8]) -> Result<PathBuf> {
+        let (_, file) = SBOM_FILES
+            .iter()
+            .find(|(known, _)| *known == media_type)
+            .ok_or_else(|| anyhow::anyhow!("Unsupported SBOM media type {}", media_type))?;
+        let path = self.images_dir.join(id).join(file);
+        fs::write(&path, data).await?;
+        Ok(path)
+    }
+
+    // The image's SBOM and its media type, if the build generated one
+    pub async fn get_sbom(&self, id: &str) -> Result<Option<(String, Vec<u8>)>> {
+        for (media_type, file) in SBOM_FILES {
+            let path = self.images_dir.join(id).join(file);
+            if path.exists() {
+                return Ok(Some((media_type.to_string(), fs::read(&path).await?)));
+            }
+        }
+        Ok(None)
+    }
+
+    // The id of the image or image index a reference names: a name such as
+    // app:latest, a manifest digest, or the id itself
+    pub async fn resolve(&self, reference: &str) -> Result<Option<String>> {
+        if let Some(id) = self.refs().await?.resolve(reference) {
+            return Ok(Some(id.to_string()));
+        }
+        let is_id = !reference.is_empty() && !reference.contains(['/', '\\']) && !reference.starts_with('.');
+        if is_id && (self.images_dir.join(reference).exists() || self.indexes_dir.join(reference).exists()) {
+            return Ok(Some(reference.to_string()));
+        }
+        Ok(None)
+    }
+
+    // Point `name` at the image or image index `reference` resolves to,
+    // moving it off whatever it named before
+    pub async fn tag(&self, reference: &str, name: &str) -> Result<()> {
+        if Reference::parse(name)?.digest().is_some() {
+            return Err(anyhow::anyhow!("Cannot tag {}: a digest reference names content, not a tag", name));
+        }
+        let id = self
+            .resolve(reference)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("No such image: {}", reference))?;
+        let name = name.to_string();
+        self.update_refs(move |refs| refs.tag(&name, &id)).await
+    }
+
+    // Record a digest-pinned name such as alpine@sha256:..., which `tag`
+    // does not take, for an image pulled by digest
+    pub async fn pin(&self, name: &str, id: &str) -> Result<()> {
+        let (name, id) = (name.to_string(), id.to_string());
+        self.update_refs(move |refs| refs.tag(&name, &id)).await
+    }
+
+    // Let `digest` resolve to an image, e.g. the registry's digest of a
+    // pulled manifest, which can differ from the digest of the stored one
+    pub async fn add_digest(&self, digest: &str, id: &str) -> Result<()> {
+        let (digest, id) = (digest.to_string(), id.to_string());
+        self.update_refs(move |refs| {
+            refs.digests.insert(digest, id);
+        })
+        .await
+    }
+
+    // Record that an image was just used, e.g. as a base image or cache source
+    pub async fn touch(&self, id: &str) -> Result<()> {
+        let id = id.to_string();
+        self.update_refs(move |refs| refs.touch(&id)).await
+    }
+
+    // Remove a name; returns the id it pointed at
+    pub async fn untag(&self, name: &str) -> Result<Option<String>> {
+        let name = name.to_string();
+        self.update_refs(move |refs| refs.untag(&name)).await
+    }
+
+    pub async fn refs(&self) -> Result<RefIndex> {
+        let path = self.refs_path();
+        Ok(tokio::task::spawn_blocking(move || RefIndex::read(&path)).await??.unwrap_or_default())
+    }
+
+    async fn update_refs<T: Send + 'static>(&self, change: impl FnOnce(&mut RefIndex) -> T + Send + 'static) -> Result<T> {
+        let path = self.refs_path();
+        tokio::task::spawn_blocking(move || RefIndex::update(&path, change)).await?
+    }
+
+    fn refs_path(&self) -> PathBuf {
+        self.root_dir.join("refs.json")
+    }
+
+    pub fn clone_for_build(&self) -> StorageManager {
+        StorageManager {
+            root_dir: self.root_dir.clone(),
+            blobs_dir: self.blobs_dir.clone(),
+            images_dir: self.images_dir.clone(),
+            indexes_dir: self.indexes_dir.clone(),
+            compression: self.compression,
+            encryption: self.encryption.clone(),
+        }
+    }
+
+    pub async fn list_images(&self) -> Result<Vec<String>> {
+        let mut images = Vec::new();
+        let mut entries = fs::read_dir(&self.images_dir).await?;
+        
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                images.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        
+        Ok(images)
+    }
+
+    pub async fn list_image_lists(&self) -> Result<Vec<String>> {
+        let mut lists = Vec::new();
+        if !self.indexes_dir.exists() {
+            return Ok(lists);
+        }
+        let mut entries = fs::read_dir(&self.indexes_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                lists.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        Ok(lists)
+    }
+
+    pub async fn remove_image(&self, id: &str) -> Result<()> {
+        // Names go first, so none is left pointing at a half-deleted image
+        let forgotten = id.to_string();
+        self.update_refs(move |refs| refs.forget(&forgotten)).await?;
+        let image_path = self.images_dir.join(id);
+        if image_path.exists() {
+            fs::remove_dir_all(&image_path).await?;
+        }
+        Ok(())
+    }
+
+    // Remove an image reference like `rmi`. A name is untagged; an id or
+    // digest drops every name of the image. An image or image index left
+    // without names is deleted, together with platform images only it used.
+    // Layer blobs stay in the store until garbage collection.
+    pub async fn remove_reference(&self, reference: &str) -> Result<Removed> {
+        let id = self
+            .resolve(reference)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("No such image: {}", reference))?;
+        let mut removed = Removed::default();
+
+        let refs = self.refs().await?;
+        if refs.tags.contains_key(reference) {
+            self.untag(reference).await?;
+            removed.untagged.push(reference.to_string());
+            if refs.names_of(&id).len() > 1 {
+                return Ok(removed);
+            }
+        } else {
+            removed.untagged.extend(refs.names_of(&id).into_iter().map(str::to_string));
+        }
+
+        let lists = self.list_image_lists().await?;
+        let mut users = Vec::new();
+        for list_id in &lists {
+            if list_id != &id && self.list_image_ids(list_id).await?.contains(&id) {
+                users.push(list_id.clone());
+            }
+        }
+        if !users.is_empty() {
+            return Err(anyhow::anyhow!("Image {} is part of image index {}; remove the index instead", id, users.join(", ")));
+        }
+
+        if lists.contains(&id) {
+            let images = self.list_image_ids(&id).await?;
+            let forgotten = id.clone();
+            self.update_refs(move |refs| refs.forget(&forgotten)).await?;
+            fs::remove_dir_all(self.indexes_dir.join(&id)).await?;
+            removed.deleted.push(id.clone());
+
+            let refs = self.refs().await?;
+            for image in images {
+                let mut shared = !refs.names_of(&image).is_empty();
+                for list_id in self.list_image_lists().await? {
+                    shared |= self.list_image_ids(&list_id).await?.contains(&image);
+                }
+                if !shared {
+                    self.remove_image(&image).await?;
+                    removed.deleted.push(image);
+                }
+            }
+        } else {
+            self.remove_image(&id).await?;
+            removed.deleted.push(id);
+        }
+        Ok(removed)
+    }
+
+    async fn list_image_ids(&self, list_id: &str) -> Result<Vec<String>> {
+        Ok(serde_json::from_slice(&fs::read(self.indexes_dir.join(list_id).join("images.json")).await?)?)
+    }
+
+    // Delete layer blobs no stored image or build cache record refers to; the
+    // inline cache of an image only refers to its own layers, so it is
+    // covered too. Blobs written since the oldest in-progress build started
+    // are kept, since that build may not have saved the image using them yet.
+    pub async fn gc(&self, dry_run: bool) -> Result<GcReport> {
+        let mut layers = Vec::new();
+        for id in self.list_images().await? {
+            layers.extend(self.get_image(&id).await?.into_iter().flat_map(|image| image.layers));
+        }
+        layers.extend(self.cache_records().await?.into_values().filter_map(|record| record.layer));
+        let referenced: BTreeSet<_> = layers
+            .iter()
+            .filter_map(|layer| layer.path.file_name().map(|name| name.to_owned()))
+            .collect();
+        let running_since = self.oldest_build_start().await?;
+
+        let mut report = GcReport::default();
+        // Layers from before the blob store live under layers/
+        for dir in [self.blobs_dir.clone(), self.root_dir.join("layers")] {
+            if !dir.exists() {
+                continue;
+            }
+            let mut entries = fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if !metadata.is_file() || referenced.contains(&entry.file_name()) {
+                    continue;
+                }
+                if running_since.is_some_and(|since| metadata.modified().is_ok_and(|modified| modified >= since)) {
+                    continue;
+                }
+                if !dry_run {
+                    fs::remove_file(entry.path()).await?;
+                }
+                report.bytes += metadata.len();
+                report.removed.push(entry.path());
+            }
+        }
+
+        // Snapshots of layers no image or cache record uses any more
+        let digests: BTreeSet<String> = layers
+            .iter()
+            .map(|layer| layer.digest.trim_start_matches("sha256:").to_string())
+            .collect();
+        if self.snapshots_dir().exists() {
+            let mut entries = fs::read_dir(self.snapshots_dir()).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if digests.contains(entry.file_name().to_string_lossy().as_ref()) {
+                    continue;
+                }
+                if running_since.is_some_and(|since| metadata.modified().is_ok_and(|modified| modified >= since)) {
+                    continue;
+                }
+                let path = entry.path();
+                let size = tokio::task::spawn_blocking({
+                    let path = path.clone();
+                    move || dir_size(&path)
+                })
+                .await?;
+                if !dry_run {
+                    fs::remove_dir_all(&path).await?;
+                }
+                report.bytes += size;
+                report.removed.push(path);
+            }
+        }
+        report.removed.sort();
+        Ok(report)
+    }
+
+    async fn oldest_build_start(&self) -> Result<Option<std::time::SystemTime>> {
+        let mut oldest = None;
+        if !self.tmp_dir().exists() {
+            return Ok(oldest);
+        }
+        let mut entries = fs::read_dir(self.tmp_dir()).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            // Builds and overlay mounts in progress may use blobs no image refers to yet
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with("build_") || name.starts_with("mount_") {
+                let created = entry.metadata().await?.modified()?;
+                oldest = Some(oldest.map_or(created, |oldest: std::time::SystemTime| oldest.min(created)));
+            }
+        }
+        Ok(oldest)
+    }
+}
+
+// Bytes of the files below `path`, which is not followed if it is a symlink
+fn dir_size(path: &std::path::Path) -> u64 {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    std::fs::read_dir(path)
+        .map(|entries| entries.flatten().map(|entry| dir_size(&entry.path())).sum())
+        .unwrap_or(0)
+}
+
+// Write part of a blob, through the sealer if the store encrypts blobs
+async fn write_part(file: &mut fs::File, sealer: &mut Option<encrypt::Sealer>, data: &[u8]) -> Result<()> {
+    match sealer {
+        Some(sealer) => file.write_all(&sealer.seal(data)?).await?,
+        None => file.write_all(data).await?,
+    }
+    Ok(())
+}
+
+// Fill `chunk` unless the reader ends first, so chunk boundaries, and with
+// them the compressed bytes, do not depend on how the reader splits its data
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R, chunk: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < chunk.len() {
+        let read = reader.read(&mut chunk[filled..]).await?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    Ok(filled)
+}
+
+// The digest and size of a layer's uncompressed tar, and what the layer is compressed with
+fn diff_digest(compressed: impl std::io::Read + Send) -> Result<(String, u64, CompressionAlgorithm)> {
+    use sha2::{Digest, Sha256};
+    use std::io::BufRead;
+
+    let mut compressed = std::io::BufReader::new(compressed);
+    let algorithm = CompressionAlgorithm::detect(compressed.fill_buf()?)
+        .ok_or_else(|| anyhow::anyhow!("Layer blob is neither gzip nor zstd"))?;
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut compress::decompress(compressed)?, &mut hasher)?;
+    Ok((format!("sha256:{:x}", hasher.finalize()), size, algorithm))
+}
+
+// Counts the bytes read through it
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: std::io::Read> std::io::Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+fn sha256_of(data: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_layers_are_stored_once() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = StorageManager::new(root.path().to_path_buf()).unwrap();
+        storage.init().await.unwrap();
+
+        let first = storage.create_layer(b"layer contents").await.unwrap();
+        let second = storage.create_layer(b"layer contents").await.unwrap();
+        assert_eq!(first.path, second.path);
+        assert_eq!(std::fs::read_dir(root.path().join("blobs/sha256")).unwrap().count(), 1);
+
+        let imported = storage.import_layer(std::fs::read(&first.path).unwrap()).await.unwrap();
+        assert_eq!((&imported.digest, &imported.diff_id), (&first.digest, &first.diff_id));
+        assert_eq!((imported.size, imported.diff_size), (first.size, first.diff_size));
+        assert_eq!(first.size, std::fs::metadata(&first.path).unwrap().len());
+        assert_eq!(first.diff_size, b"layer contents".len() as u64);
+
+        let found = storage.get_layer(&first.digest).await.unwrap().unwrap();
+        assert_eq!((found.diff_id, found.diff_size), (first.diff_id, first.diff_size));
+        assert!(storage.get_layer(&format!("sha256:{}", "0".repeat(64))).await.unwrap().is_none());
+        assert!(storage.blob_path("sha256:../../etc").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_streamed_layer_spans_chunks() {
+        use sha2::{Digest, Sha256};
+        use std::io::Read;
+
+        let root = tempfile::tempdir().unwrap();
+        let storage = StorageManager::new(root.path().to_path_buf()).unwrap();
+        storage.init().await.unwrap();
+
+        let data: Vec<u8> = (0..LAYER_CHUNK_SIZE * 2 + 17).map(|i| (i % 251) as u8).collect();
+        let layer = storage.create_layer_from_reader(&data[..]).await.unwrap();
+
+        // The same blob whatever the number of compression threads
+        let single = StorageManager::new(root.path().to_path_buf())
+            .unwrap()
+            .with_compression(Compression { threads: 1, ..Compression::default() });
+        assert_eq!(single.create_layer_from_reader(&data[..]).await.unwrap().digest, layer.digest);
+        assert_eq!(layer.diff_id, format!("sha256:{:x}", Sha256::digest(&data)));
+        assert_eq!(layer.diff_size, data.len() as u64);
+        assert_eq!(layer.digest, format!("sha256:{:x}", Sha256::digest(std::fs::read(&layer.path).unwrap())));
+
+        let mut stored = Vec::new();
+        flate2::read::GzDecoder::new(std::fs::File::open(&layer.path).unwrap())
+            .read_to_end(&mut stored)
+            .unwrap();
+        assert_eq!(stored, data);
+
+        // zstd layers are read back, and named in manifests, as zstd
+        let zstd = StorageManager::new(root.path().to_path_buf())
+            .unwrap()
+            .with_compression(Compression::default().with_flag("zstd,level=9").unwrap());
+        let layer = zstd.create_layer_from_reader(&data[..]).await.unwrap();
+        assert_eq!(layer.compression, CompressionAlgorithm::Zstd);
+        let found = storage.get_layer(&layer.digest).await.unwrap().unwrap();
+        assert_eq!((found.compression, &found.diff_id), (CompressionAlgorithm::Zstd, &layer.diff_id));
+        let mut stored = Vec::new();
+        found.open_tar().unwrap().read_to_end(&mut stored).unwrap();
+        assert_eq!(stored, data);
+        let manifest = crate::engine::image_manifest(&layer.digest, 2, std::slice::from_ref(&layer)).unwrap();
+        assert_eq!(manifest.layers()[0].media_type(), &oci_spec::image::MediaType::ImageLayerZstd);
+    }
+
+    #[tokio::test]
+    async fn test_gc_keeps_referenced_layers() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = StorageManager::new(root.path().to_path_buf()).unwrap();
+        storage.init().await.unwrap();
+
+        let kept = storage.create_layer(b"kept").await.unwrap();
+        let orphan = storage.create_layer(b"orphan").await.unwrap();
+        let config = ImageConfiguration::default();
+        let raw_config = serde_json::to_vec(&config).unwrap();
+        let layers = vec![kept.clone()];
+        let image = Image {
+            id: "image_1".to_string(),
+            name: "app:latest".to_string(),
+            manifest: crate::engine::image_manifest(&sha256_of(&raw_config), raw_config.len() as u64, &layers).unwrap(),
+            layers,
+            config,
+            raw_config,
+        };
+        storage.save_image(&image).await.unwrap();
+
+        let orphan_size = std::fs::metadata(&orphan.path).unwrap().len();
+        let report = storage.gc(true).await.unwrap();
+        assert_eq!(report.removed, vec![orphan.path.clone()]);
+        assert_eq!(report.bytes, orphan_size);
+        assert!(orphan.path.exists());
+
+        storage.gc(false).await.unwrap();
+        assert!(!orphan.path.exists());
+        assert!(kept.path.exists());
+        assert!(storage.gc(false).await.unwrap().removed.is_empty());
+    }
+}
@@ -0,0 +1,205 @@
+use super::StorageManager;
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// What `prune` removes besides dangling images
+#[derive(Debug, Default, Clone)]
+pub struct PruneOptions {
+    // Also remove images not used for this long
+    pub until: Option<Duration>,
+    // Remove least recently used images until the store is at most this many bytes
+    pub keep_storage: Option<u64>,
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    pub deleted: Vec<String>,
+    pub bytes: u64,
+}
+
+// An image, or an image index together with its platform images, which are
+// removed as one
+pub(super) struct Unit {
+    id: String,
+    named: bool,
+    last_used: u64,
+    blobs: BTreeMap<OsString, u64>,
+}
+
+impl StorageManager {
+    // Remove images with no name, then those matching the age and size
+    // policies, and garbage-collect the layers they leave unused
+    pub async fn prune(&self, options: &PruneOptions) -> Result<PruneReport> {
+        let units = self.prune_units().await?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let selected = select(&units, options.until, options.keep_storage, now);
+
+        let mut report = PruneReport::default();
+        for id in selected {
+            if !options.dry_run {
+                self.remove_reference(&id).await?;
+            }
+            report.deleted.push(id);
+        }
+
+        if options.dry_run {
+            // Blobs only the pruned images use would be freed
+            let deleted: BTreeSet<&String> = report.deleted.iter().collect();
+            let kept: BTreeSet<&OsString> = units
+                .iter()
+                .filter(|unit| !deleted.contains(&unit.id))
+                .flat_map(|unit| unit.blobs.keys())
+                .collect();
+            let freed: BTreeMap<&OsString, u64> = units
+                .iter()
+                .filter(|unit| deleted.contains(&unit.id))
+                .flat_map(|unit| &unit.blobs)
+                .filter(|(blob, _)| !kept.contains(blob))
+                .map(|(blob, size)| (blob, *size))
+                .collect();
+            report.bytes = freed.values().sum();
+        } else {
+            report.bytes = self.gc(false).await?.bytes;
+        }
+        Ok(report)
+    }
+
+    pub(super) async fn prune_units(&self) -> Result<Vec<Unit>> {
+        let refs = self.refs().await?;
+        let mut units = Vec::new();
+        let mut in_lists = BTreeSet::new();
+
+        for id in self.list_image_lists().await? {
+            let Some(list) = self.get_image_list(&id).await? else {
+                continue;
+            };
+            let mut ids = vec![id.clone()];
+            ids.extend(list.images.iter().map(|image| image.id.clone()));
+            in_lists.extend(list.images.iter().map(|image| image.id.clone()));
+            let layers = list.images.iter().flat_map(|image| &image.layers);
+            units.push(Unit {
+                named: !refs.names_of(&id).is_empty(),
+                last_used: self.last_used(&refs, &ids).await,
+                blobs: blob_sizes(layers),
+                id,
+            });
+        }
+        for id in self.list_images().await? {
+            if in_lists.contains(&id) {
+                continue;
+            }
+            let Some(image) = self.get_image(&id).await? else {
+                continue;
+            };
+            units.push(Unit {
+                named: !refs.names_of(&id).is_empty(),
+                last_used: self.last_used(&refs, std::slice::from_ref(&id)).await,
+                blobs: blob_sizes(&image.layers),
+                id,
+            });
+        }
+        Ok(units)
+    }
+
+    // Images used before use was tracked count as used when they were saved
+    async fn last_used(&self, refs: &super::RefIndex, ids: &[String]) -> u64 {
+        let mut last_used = 0;
+        for id in ids {
+            let used = match refs.last_used.get(id) {
+                Some(used) => *used,
+                None => {
+                    let dir = [self.images_dir.join(id), self.indexes_dir.join(id)];
+                    let modified = dir.iter().find_map(|dir| std::fs::metadata(dir).and_then(|m| m.modified()).ok());
+                    modified
+                        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                        .map(|since| since.as_secs())
+                        .unwrap_or_default()
+                }
+            };
+            last_used = last_used.max(used);
+        }
+        last_used
+    }
+}
+
+fn blob_sizes<'a>(layers: impl IntoIterator<Item = &'a super::Layer>) -> BTreeMap<OsString, u64> {
+    layers
+        .into_iter()
+        .filter_map(|layer| {
+            let size = std::fs::metadata(&layer.path).ok()?.len();
+            Some((layer.path.file_name()?.to_owned(), size))
+        })
+        .collect()
+}
+
+// The ids to remove: unnamed images, images unused for `until`, then the
+// least recently used until the blobs still in use fit in `keep_storage`
+fn select(units: &[Unit], until: Option<Duration>, keep_storage: Option<u64>, now: u64) -> Vec<String> {
+    let mut by_age: Vec<&Unit> = units.iter().collect();
+    by_age.sort_by_key(|unit| unit.last_used);
+
+    let mut selected = Vec::new();
+    let mut kept = Vec::new();
+    for unit in by_age {
+        let expired = until.is_some_and(|until| now.saturating_sub(unit.last_used) > until.as_secs());
+        if !unit.named || expired {
+            selected.push(unit.id.clone());
+        } else {
+            kept.push(unit);
+        }
+    }
+
+    if let Some(limit) = keep_storage {
+        let mut kept = kept.into_iter();
+        loop {
+            let rest: Vec<&Unit> = kept.clone().collect();
+            let used: BTreeMap<&OsString, u64> = rest
+                .iter()
+                .flat_map(|unit| &unit.blobs)
+                .map(|(blob, size)| (blob, *size))
+                .collect();
+            if used.values().sum::<u64>() <= limit {
+                break;
+            }
+            match kept.next() {
+                Some(unit) => selected.push(unit.id.clone()),
+                None => break,
+            }
+        }
+    }
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit(id: &str, named: bool, last_used: u64, blobs: &[(&str, u64)]) -> Unit {
+        Unit {
+            id: id.to_string(),
+            named,
+            last_used,
+            blobs: blobs.iter().map(|(name, size)| (OsString::from(name), *size)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_select_prune_candidates() {
+        let units = vec![
+            unit("dangling", false, 900, &[("a", 10)]),
+            unit("old", true, 100, &[("b", 50)]),
+            unit("shared-1", true, 500, &[("c", 100), ("d", 20)]),
+            unit("shared-2", true, 800, &[("c", 100)]),
+        ];
+
+        assert_eq!(select(&units, None, None, 1000), vec!["dangling"]);
+        assert_eq!(select(&units, Some(Duration::from_secs(600)), None, 1000), vec!["old", "dangling"]);
+        // Removing shared-1 alone frees only "d": "c" is still used by shared-2
+        assert_eq!(select(&units, None, Some(120), 1000), vec!["dangling", "old"]);
+        assert_eq!(select(&units, None, Some(100), 1000), vec!["dangling", "old", "shared-1"]);
+        assert_eq!(select(&units, None, Some(0), 1000).len(), 4);
+    }
+}
@@ -0,0 +1,126 @@
+use crate::reference::split;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+// Which stored image or image index each name and manifest digest refers to.
+// A name points at one id at a time: tagging a new build moves the name.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RefIndex {
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default)]
+    pub digests: BTreeMap<String, String>,
+    // When each image was last built, pulled or used (Unix seconds), for pruning
+    #[serde(default)]
+    pub last_used: BTreeMap<String, u64>,
+}
+
+impl RefIndex {
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        match std::fs::read(path) {
+            Ok(data) => Ok(Some(
+                serde_json::from_slice(&data).map_err(|e| anyhow::anyhow!("Invalid image index {:?}: {}", path, e))?,
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Apply `change` to the index at `path` under an exclusive lock, so
+    // concurrent builds sharing a store do not lose each other's updates
+    pub fn update<T>(path: &Path, change: impl FnOnce(&mut RefIndex) -> T) -> Result<T> {
+        let _lock = Self::lock(path)?;
+
+        let mut index = Self::read(path)?.unwrap_or_default();
+        let result = change(&mut index);
+
+        // Written aside and renamed so readers never see a partial index
+        let partial = path.with_extension("json.partial");
+        std::fs::write(&partial, serde_json::to_vec_pretty(&index)?)?;
+        std::fs::rename(&partial, path)?;
+        Ok(result)
+    }
+
+    // Take the index's exclusive lock, held until the file is dropped. Names
+    // are only added or removed under it, and images only removed after.
+    pub fn lock(path: &Path) -> Result<std::fs::File> {
+        let lock = std::fs::File::create(path.with_extension("lock"))?;
+        lock.lock()?;
+        Ok(lock)
+    }
+
+    // The id a reference names, looked up as a tag and then as a manifest
+    // digest. A digest reference such as alpine@sha256:... is found by its
+    // digest, whatever repository it was pulled from.
+    pub fn resolve(&self, reference: &str) -> Option<&str> {
+        self.tags
+            .get(reference)
+            .or_else(|| self.digests.get(reference))
+            .or_else(|| split(reference).2.and_then(|digest| self.digests.get(digest)))
+            .map(String::as_str)
+    }
+
+    pub fn tag(&mut self, name: &str, id: &str) {
+        self.tags.insert(name.to_string(), id.to_string());
+    }
+
+    pub fn untag(&mut self, name: &str) -> Option<String> {
+        self.tags.remove(name)
+    }
+
+    // Names currently pointing at `id`
+    pub fn names_of(&self, id: &str) -> Vec<&str> {
+        self.tags
+            .iter()
+            .filter(|(_, tagged)| tagged.as_str() == id)
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    pub fn touch(&mut self, id: &str) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or_default();
+        self.last_used.insert(id.to_string(), now);
+    }
+
+    // Drop every name and digest pointing at `id`
+    pub fn forget(&mut self, id: &str) {
+        self.tags.retain(|_, tagged| tagged != id);
+        self.digests.retain(|_, tagged| tagged != id);
+        self.last_used.remove(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retag_moves_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("refs.json");
+        assert_eq!(RefIndex::read(&path).unwrap(), None);
+
+        RefIndex::update(&path, |index| {
+            index.tag("app:latest", "image_1");
+            index.digests.insert("sha256:aaa".to_string(), "image_1".to_string());
+        })
+        .unwrap();
+        RefIndex::update(&path, |index| index.tag("app:latest", "image_2")).unwrap();
+
+        let index = RefIndex::read(&path).unwrap().unwrap();
+        assert_eq!(index.resolve("app:latest"), Some("image_2"));
+        assert_eq!(index.resolve("sha256:aaa"), Some("image_1"));
+        assert_eq!(index.resolve("registry.example.com/app@sha256:aaa"), Some("image_1"));
+        assert_eq!(index.resolve("app@sha256:bbb"), None);
+        assert!(index.names_of("image_1").is_empty());
+
+        let removed = RefIndex::update(&path, |index| index.untag("app:latest")).unwrap();
+        assert_eq!(removed.as_deref(), Some("image_2"));
+        assert_eq!(RefIndex::read(&path).unwrap().unwrap().resolve("app:latest"), None);
+    }
+}
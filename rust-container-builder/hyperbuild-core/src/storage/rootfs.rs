@@ -0,0 +1,223 @@
+use super::{Layer, StorageManager};
+use crate::engine::snapshot::{self, OPAQUE_WHITEOUT, WHITEOUT_PREFIX};
+use anyhow::Result;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+// An overlay of stored layers mounted by `mount_layers`. Changes made
+// through it land in its own upper directory, never in the snapshots.
+#[derive(Debug)]
+pub struct MountedRootfs {
+    pub target: PathBuf,
+    scratch: PathBuf,
+}
+
+impl MountedRootfs {
+    // Files created, changed or whited out through the mount
+    pub fn upper_dir(&self) -> PathBuf {
+        self.scratch.join("upper")
+    }
+
+    pub async fn unmount(self) -> Result<()> {
+        run_mount_command("umount", &[self.target.as_os_str()]).await?;
+        fs::remove_dir_all(&self.scratch).await?;
+        Ok(())
+    }
+}
+
+impl StorageManager {
+    // Unpacked layers shared by overlay mounts, one directory per blob digest
+    pub fn snapshots_dir(&self) -> PathBuf {
+        self.root_dir.join("snapshots")
+    }
+
+    // Apply a chain of layers, lowest first, on top of `target`. Whiteouts
+    // in each layer remove what the layers below it put there.
+    pub async fn extract_layers(&self, layers: &[Layer], target: &Path) -> Result<()> {
+        fs::create_dir_all(target).await?;
+        for layer in layers {
+            let (layer, target) = (layer.clone(), target.to_path_buf());
+            tokio::task::spawn_blocking(move || {
+                snapshot::apply_layer(layer.open_tar()?, &target)
+            })
+            .await??;
+        }
+        Ok(())
+    }
+
+    // Mount a chain of layers, lowest first, at `target` with overlayfs.
+    // Each layer is unpacked once into the snapshots directory and shared by
+    // every mount using it. Needs root privileges.
+    pub async fn mount_layers(&self, layers: &[Layer], target: &Path) -> Result<MountedRootfs> {
+        let mut lower = Vec::new();
+        for layer in layers.iter().rev() {
+            lower.push(self.snapshot(layer).await?);
+        }
+
+        let scratch = self.tmp_dir().join(format!("mount_{}", uuid::Uuid::new_v4()));
+        let (upper, work) = (scratch.join("upper"), scratch.join("work"));
+        fs::create_dir_all(&upper).await?;
+        fs::create_dir_all(&work).await?;
+        fs::create_dir_all(target).await?;
+        // overlayfs needs at least one lower directory
+        if lower.is_empty() {
+            let empty = scratch.join("empty");
+            fs::create_dir_all(&empty).await?;
+            lower.push(empty);
+        }
+
+        let lower: Vec<String> = lower.iter().map(|dir| dir.to_string_lossy().to_string()).collect();
+        let options = format!(
+            "lowerdir={},upperdir={},workdir={}",
+            lower.join(":"),
+            upper.to_string_lossy(),
+            work.to_string_lossy()
+        );
+        let mounted = run_mount_command(
+            "mount",
+            &["-t".as_ref(), "overlay".as_ref(), "overlay".as_ref(), "-o".as_ref(), options.as_ref(), target.as_os_str()],
+        )
+        .await;
+        if let Err(e) = mounted {
+            let _ = fs::remove_dir_all(&scratch).await;
+            return Err(e);
+        }
+
+        Ok(MountedRootfs {
+            target: target.to_path_buf(),
+            scratch,
+        })
+    }
+
+    // The layer unpacked on its own, whiteouts kept in overlayfs form
+    async fn snapshot(&self, layer: &Layer) -> Result<PathBuf> {
+        let hex = layer.digest.strip_prefix("sha256:").unwrap_or(&layer.digest);
+        let dir = self.snapshots_dir().join(hex);
+        if dir.exists() {
+            return Ok(dir);
+        }
+
+        // Unpacked aside and renamed into place, so a snapshot is never seen half-written
+        let partial = self.tmp_dir().join(format!("snapshot_{}", uuid::Uuid::new_v4()));
+        let (layer, unpack_dir) = (layer.clone(), partial.clone());
+        let unpacked = tokio::task::spawn_blocking(move || {
+            std::fs::create_dir_all(&unpack_dir)?;
+            unpack_for_overlay(layer.open_tar()?, &unpack_dir)
+        })
+        .await?;
+        if let Err(e) = unpacked {
+            let _ = fs::remove_dir_all(&partial).await;
+            return Err(e);
+        }
+
+        fs::create_dir_all(self.snapshots_dir()).await?;
+        if fs::rename(&partial, &dir).await.is_err() {
+            // Another mount unpacked the same layer first
+            fs::remove_dir_all(&partial).await?;
+        }
+        Ok(dir)
+    }
+}
+
+// Unpack a layer tar into an empty directory, turning OCI whiteout files into
+// the 0/0 character devices and opaque xattrs overlayfs expects
+fn unpack_for_overlay<R: std::io::Read>(reader: R, dir: &Path) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    archive.set_preserve_mtime(true);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = snapshot::normalize(&entry.path()?);
+        let name = path.file_name().map(|name| name.to_string_lossy().to_string());
+        match name.as_deref() {
+            Some(OPAQUE_WHITEOUT) => {
+                let parent = dir.join(path.parent().unwrap_or(Path::new("")));
+                std::fs::create_dir_all(&parent)?;
+                set_xattr(&parent, "trusted.overlay.opaque", b"y")?;
+            }
+            Some(name) if name.starts_with(WHITEOUT_PREFIX) => {
+                let parent = dir.join(path.parent().unwrap_or(Path::new("")));
+                std::fs::create_dir_all(&parent)?;
+                make_whiteout(&parent.join(&name[WHITEOUT_PREFIX.len()..]))?;
+            }
+            _ => {
+                entry.unpack_in(dir)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| anyhow::anyhow!("Invalid path {:?}", path))
+}
+
+fn make_whiteout(path: &Path) -> Result<()> {
+    let c_path = c_path(path)?;
+    // SAFETY: c_path is a valid NUL-terminated string for the duration of the call
+    if unsafe { libc::mknod(c_path.as_ptr(), libc::S_IFCHR, libc::makedev(0, 0)) } != 0 {
+        return Err(anyhow::anyhow!("Failed to create whiteout {:?}: {}", path, std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> Result<()> {
+    let (c_path, c_name) = (c_path(path)?, CString::new(name)?);
+    // SAFETY: both strings are NUL-terminated and value points at value.len() bytes
+    let result = unsafe { libc::lsetxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_ptr().cast(), value.len(), 0) };
+    if result != 0 {
+        return Err(anyhow::anyhow!("Failed to set {} on {:?}: {}", name, path, std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+async fn run_mount_command(program: &str, args: &[&std::ffi::OsStr]) -> Result<()> {
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to run {}: {}", program, e))?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::layer_tar;
+
+    #[tokio::test]
+    async fn test_extract_applies_whiteouts_in_order() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = StorageManager::new(root.path().join("store")).unwrap();
+        storage.init().await.unwrap();
+
+        let base = storage
+            .create_layer(&layer_tar(&[("etc/a", b"a", 0o644), ("etc/b", b"b", 0o644), ("opt/old", b"old", 0o644)]))
+            .await
+            .unwrap();
+        let whiteouts = layer_tar(&[
+            ("etc/.wh.a", b"", 0o644),
+            ("opt/.wh..wh..opq", b"", 0o644),
+            ("opt/new", b"new", 0o644),
+        ]);
+        let top = storage.create_layer(&whiteouts).await.unwrap();
+
+        let target = root.path().join("rootfs");
+        storage.extract_layers(&[base, top], &target).await.unwrap();
+        assert!(!target.join("etc/a").exists());
+        assert_eq!(std::fs::read(target.join("etc/b")).unwrap(), b"b");
+        assert!(!target.join("opt/old").exists());
+        assert_eq!(std::fs::read(target.join("opt/new")).unwrap(), b"new");
+    }
+}
@@ -0,0 +1,170 @@
+use super::{StorageManager, dir_size};
+use anyhow::Result;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UsageKind {
+    // Layer blobs of stored images, and the layers unpacked for overlay mounts
+    Images,
+    // Build cache records, and the layer blobs only they keep, as `cache ls` and `cache prune` count them
+    BuildCache,
+    // Directories kept between builds for `RUN --mount=type=cache`, which
+    // builds do not support yet, so there are never any
+    CacheMounts,
+    // Reports and step logs of past builds
+    BuildLogs,
+    // Scratch space of running or interrupted builds
+    Temporary,
+}
+
+impl std::fmt::Display for UsageKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            UsageKind::Images => "Images",
+            UsageKind::BuildCache => "Build cache",
+            UsageKind::CacheMounts => "Cache mounts",
+            UsageKind::BuildLogs => "Build logs",
+            UsageKind::Temporary => "Temporary files",
+        };
+        f.write_str(name)
+    }
+}
+
+// Space one kind of data takes, and how much of it `gc` and `prune` would free
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Usage {
+    pub kind: UsageKind,
+    pub count: usize,
+    pub size: u64,
+    pub reclaimable: u64,
+}
+
+impl StorageManager {
+    pub async fn disk_usage(&self) -> Result<Vec<Usage>> {
+        let collectable = self.gc(true).await?.removed;
+        // Images without a name are what a default prune removes
+        let dangling = self.prune(&super::PruneOptions { dry_run: true, ..Default::default() }).await?;
+        // Pruning every record frees the blobs no image shares
+        let cache_only = self.prune_cache(None, true).await?;
+
+        let image_dirs = vec![self.blobs_dir.clone(), self.root_dir.join("layers"), self.snapshots_dir()];
+        let images = Usage {
+            kind: UsageKind::Images,
+            count: self.prune_units().await?.len(),
+            size: sizes(image_dirs).await?.saturating_sub(cache_only.bytes),
+            reclaimable: sizes(collectable).await? + dangling.bytes,
+        };
+        let build_cache = Usage {
+            kind: UsageKind::BuildCache,
+            count: cache_only.removed.len(),
+            size: cache_only.bytes,
+            reclaimable: cache_only.bytes,
+        };
+        let cache_mounts = Usage {
+            kind: UsageKind::CacheMounts,
+            count: 0,
+            size: 0,
+            reclaimable: 0,
+        };
+        let build_logs = Usage {
+            kind: UsageKind::BuildLogs,
+            count: count_entries(&self.builds_dir()).await?,
+            size: sizes(vec![self.builds_dir()]).await?,
+            reclaimable: 0,
+        };
+        let temporary = Usage {
+            kind: UsageKind::Temporary,
+            count: count_entries(&self.tmp_dir()).await?,
+            size: sizes(vec![self.tmp_dir()]).await?,
+            reclaimable: 0,
+        };
+        Ok(vec![images, build_cache, cache_mounts, build_logs, temporary])
+    }
+}
+
+async fn sizes(paths: Vec<PathBuf>) -> Result<u64> {
+    Ok(tokio::task::spawn_blocking(move || paths.iter().map(|path| dir_size(path)).sum()).await?)
+}
+
+async fn count_entries(dir: &Path) -> Result<usize> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let mut count = 0;
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while entries.next_entry().await?.is_some() {
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Image, sha256_of};
+
+    #[tokio::test]
+    async fn test_unused_blobs_are_reclaimable() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = StorageManager::new(root.path().to_path_buf()).unwrap();
+        storage.init().await.unwrap();
+
+        let orphan = storage.create_layer(b"orphan").await.unwrap();
+        std::fs::create_dir_all(storage.builds_dir().join("build_1")).unwrap();
+        std::fs::write(storage.builds_dir().join("build_1/report.json"), b"{}").unwrap();
+
+        let usage = storage.disk_usage().await.unwrap();
+        assert_eq!(usage[0].kind, UsageKind::Images);
+        assert_eq!((usage[0].count, usage[0].size, usage[0].reclaimable), (0, orphan.size, orphan.size));
+        assert_eq!((usage[3].count, usage[3].size, usage[3].reclaimable), (1, 2, 0));
+    }
+
+    #[tokio::test]
+    async fn test_every_kind_is_accounted() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = StorageManager::new(root.path().to_path_buf()).unwrap();
+        storage.init().await.unwrap();
+
+        let layer = storage.create_layer(b"layer").await.unwrap();
+        std::fs::create_dir_all(storage.snapshots_dir().join("snapshot_1")).unwrap();
+        std::fs::write(storage.snapshots_dir().join("snapshot_1/file"), b"12345").unwrap();
+        let config = oci_spec::image::ImageConfiguration::default();
+        let raw_config = serde_json::to_vec(&config).unwrap();
+        let layers = vec![layer.clone()];
+        let image = Image {
+            id: "image_1".to_string(),
+            name: "app:latest".to_string(),
+            manifest: crate::engine::image_manifest(&sha256_of(&raw_config), raw_config.len() as u64, &layers).unwrap(),
+            layers,
+            config,
+            raw_config,
+        };
+        storage.save_image(&image).await.unwrap();
+        // A record of a layer the image shares, one of a layer only the cache keeps, and one without a layer
+        let cached = storage.create_layer(b"cached step").await.unwrap();
+        storage.record_cache("sha256:shared", Some(&layer), None).await.unwrap();
+        storage.record_cache("sha256:cached", Some(&cached), None).await.unwrap();
+        storage.record_cache("sha256:empty", None, None).await.unwrap();
+        std::fs::write(storage.tmp_dir().join("upload.partial"), b"123").unwrap();
+
+        let usage = storage.disk_usage().await.unwrap();
+        let kinds: Vec<UsageKind> = usage.iter().map(|entry| entry.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                UsageKind::Images,
+                UsageKind::BuildCache,
+                UsageKind::CacheMounts,
+                UsageKind::BuildLogs,
+                UsageKind::Temporary
+            ]
+        );
+        assert_eq!((usage[0].count, usage[0].size), (1, layer.size + 5));
+        assert_eq!((usage[1].count, usage[1].size, usage[1].reclaimable), (3, cached.size, cached.size));
+        assert_eq!((usage[2].count, usage[2].size, usage[2].reclaimable), (0, 0, 0));
+        assert_eq!((usage[3].count, usage[3].size), (0, 0));
+        assert_eq!((usage[4].count, usage[4].size, usage[4].reclaimable), (1, 3, 0));
+    }
+}
@@ -0,0 +1,244 @@
+use super::{BlobKey, Image, StorageManager, encrypt, sha256_of};
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::fs;
+
+// Something wrong with a blob, image or image index
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub subject: String,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub blobs: usize,
+    pub images: usize,
+    pub problems: Vec<Problem>,
+    // Blobs, images and indexes deleted by a repair
+    pub removed: Vec<String>,
+}
+
+impl VerifyReport {
+    fn problem(&mut self, subject: &str, message: String) {
+        self.problems.push(Problem {
+            subject: subject.to_string(),
+            message,
+        });
+    }
+}
+
+impl StorageManager {
+    // Re-hash every layer blob and check each image against its manifest and
+    // config. With `repair`, damaged blobs are deleted, along with the images
+    // and image indexes that need them.
+    pub async fn verify(&self, repair: bool) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+
+        // The diff_id and size of every intact blob, by blob digest
+        let mut intact = HashMap::new();
+        let mut corrupt = Vec::new();
+        let mut entries = fs::read_dir(&self.blobs_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let digest = format!("sha256:{}", entry.file_name().to_string_lossy());
+            report.blobs += 1;
+            let (path, key) = (entry.path(), self.encryption.clone());
+            match tokio::task::spawn_blocking(move || hash_layer_blob(&path, key.as_ref())).await? {
+                Ok((actual, diff_id, size)) if actual == digest => {
+                    intact.insert(digest, (diff_id, size));
+                }
+                Ok((actual, _, _)) => {
+                    report.problem(&digest, format!("content hashes to {}", actual));
+                    corrupt.push((digest, entry.path()));
+                }
+                Err(e) => {
+                    report.problem(&digest, format!("is not a readable gzip or zstd layer: {}", e));
+                    corrupt.push((digest, entry.path()));
+                }
+            }
+        }
+
+        let mut broken_images = BTreeSet::new();
+        for id in self.list_images().await? {
+            report.images += 1;
+            let problems = match self.get_image(&id).await {
+                Ok(Some(image)) => self.check_image(&image, &intact).await,
+                Ok(None) => continue,
+                Err(e) => vec![format!("is unreadable: {}", e)],
+            };
+            if !problems.is_empty() {
+                broken_images.insert(id.clone());
+            }
+            for message in problems {
+                report.problem(&id, message);
+            }
+        }
+
+        let mut broken_lists = Vec::new();
+        for id in self.list_image_lists().await? {
+            let problems = match self.list_image_ids(&id).await {
+                Ok(images) => images
+                    .iter()
+                    .filter(|image| broken_images.contains(*image) || !self.images_dir.join(image).exists())
+                    .map(|image| format!("platform image {} is missing or damaged", image))
+                    .collect(),
+                Err(e) => vec![format!("is unreadable: {}", e)],
+            };
+            if !problems.is_empty() {
+                broken_lists.push(id.clone());
+            }
+            for message in problems {
+                report.problem(&id, message);
+            }
+        }
+
+        if repair {
+            for id in broken_lists {
+                let forgotten = id.clone();
+                self.update_refs(move |refs| refs.forget(&forgotten)).await?;
+                fs::remove_dir_all(self.indexes_dir.join(&id)).await?;
+                report.removed.push(id);
+            }
+            for id in broken_images {
+                self.remove_image(&id).await?;
+                report.removed.push(id);
+            }
+            for (digest, path) in corrupt {
+                fs::remove_file(&path).await?;
+                report.removed.push(digest);
+            }
+        }
+        Ok(report)
+    }
+
+    async fn check_image(&self, image: &Image, intact: &HashMap<String, (String, u64)>) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let config = image.manifest.config();
+        if config.digest().to_string() != sha256_of(&image.raw_config) || config.size() != image.raw_config.len() as u64 {
+            problems.push(format!("config does not match its manifest descriptor {}", config.digest()));
+        }
+
+        let descriptors = image.manifest.layers();
+        let diff_ids = image.config.rootfs().diff_ids();
+        if descriptors.len() != diff_ids.len() || descriptors.len() != image.layers.len() {
+            problems.push(format!(
+                "manifest lists {} layers, config {} diff_ids and layers.json {} layers",
+                descriptors.len(),
+                diff_ids.len(),
+                image.layers.len()
+            ));
+        }
+
+        for (index, descriptor) in descriptors.iter().enumerate() {
+            let digest = descriptor.digest().to_string();
+            let path = match self.blob_path(&digest) {
+                Ok(path) => path,
+                Err(e) => {
+                    problems.push(format!("layer {}: {}", index + 1, e));
+                    continue;
+                }
+            };
+            let Some((diff_id, size)) = intact.get(&digest) else {
+                let state = if path.exists() { "corrupt" } else { "missing" };
+                problems.push(format!("layer {} blob {} is {}", index + 1, digest, state));
+                continue;
+            };
+            if *size != descriptor.size() {
+                problems.push(format!(
+                    "layer {} blob {} is {} bytes, the manifest says {}",
+                    index + 1,
+                    digest,
+                    size,
+                    descriptor.size()
+                ));
+            }
+            if diff_ids.get(index) != Some(diff_id) {
+                problems.push(format!("layer {} unpacks to {}, not the diff_id in the config", index + 1, diff_id));
+            }
+            if image.layers.get(index).is_some_and(|layer| layer.digest != digest) {
+                problems.push(format!("layer {} in layers.json is not {}", index + 1, digest));
+            }
+        }
+        problems
+    }
+}
+
+// The digest of a gzip or zstd layer blob and of the tar inside it, and the blob's
+// size, in one read
+fn hash_layer_blob(path: &Path, key: Option<&Arc<BlobKey>>) -> Result<(String, String, u64)> {
+    let mut blob = HashingReader {
+        inner: encrypt::open_blob(path, key)?,
+        hasher: Sha256::new(),
+        size: 0,
+    };
+    let mut diff_hasher = Sha256::new();
+    std::io::copy(&mut super::compress::decompress(&mut blob)?, &mut diff_hasher)?;
+    // Anything after the compressed stream still counts towards the blob digest
+    std::io::copy(&mut blob, &mut std::io::sink())?;
+    Ok((
+        format!("sha256:{:x}", blob.hasher.finalize()),
+        format!("sha256:{:x}", diff_hasher.finalize()),
+        blob.size,
+    ))
+}
+
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.size += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_verify_and_repair_corrupt_blob() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = StorageManager::new(root.path().to_path_buf()).unwrap();
+        storage.init().await.unwrap();
+
+        let layer = storage.create_layer(b"layer contents").await.unwrap();
+        let mut config = oci_spec::image::ImageConfiguration::default();
+        let mut rootfs = config.rootfs().clone();
+        rootfs.set_diff_ids(vec![layer.diff_id.clone()]);
+        config.set_rootfs(rootfs);
+        let raw_config = serde_json::to_vec(&config).unwrap();
+        let layers = vec![layer.clone()];
+        let image = Image {
+            id: "image_1".to_string(),
+            name: "app:latest".to_string(),
+            manifest: crate::engine::image_manifest(&sha256_of(&raw_config), raw_config.len() as u64, &layers).unwrap(),
+            layers,
+            config,
+            raw_config,
+        };
+        storage.save_image(&image).await.unwrap();
+
+        let report = storage.verify(false).await.unwrap();
+        assert_eq!((report.blobs, report.images), (1, 1));
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+
+        std::fs::write(&layer.path, b"not gzip").unwrap();
+        let report = storage.verify(true).await.unwrap();
+        let subjects: Vec<&str> = report.problems.iter().map(|problem| problem.subject.as_str()).collect();
+        assert_eq!(subjects, vec![layer.digest.as_str(), "image_1"]);
+        assert_eq!(report.removed, vec!["image_1".to_string(), layer.digest.clone()]);
+        assert!(storage.get_image_by_name("app:latest").await.unwrap().is_none());
+        assert!(storage.verify(false).await.unwrap().problems.is_empty());
+    }
+}
@@ -0,0 +1,199 @@
+// `build --watch`: which changes to the context matter, going by its
+// .dockerignore, and a watcher that reports them once they settle
+use crate::images::glob;
+use anyhow::Result;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// How long the context must stay quiet before a batch of changes is reported,
+// so saving several files or a checkout triggers one build
+const SETTLE: Duration = Duration::from_millis(300);
+
+// The patterns of a .dockerignore file, with Docker's rules: the last
+// matching line wins, `!` makes an exception, `**` spans directories, and a
+// pattern matching a directory covers everything in it
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    rules: Vec<(Vec<String>, bool)>,
+}
+
+impl IgnoreRules {
+    pub fn load(context: &Path) -> Result<Self> {
+        match std::fs::read_to_string(context.join(".dockerignore")) {
+            Ok(contents) => Ok(Self::parse(&contents)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(anyhow::anyhow!("Failed to read .dockerignore: {}", e)),
+        }
+    }
+
+    pub fn parse(contents: &str) -> Self {
+        let rules = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let (pattern, exception) = match line.strip_prefix('!') {
+                    Some(pattern) => (pattern.trim(), true),
+                    None => (line, false),
+                };
+                let segments: Vec<String> = pattern
+                    .split('/')
+                    .filter(|segment| !segment.is_empty() && *segment != ".")
+                    .map(String::from)
+                    .collect();
+                (!segments.is_empty()).then_some((segments, exception))
+            })
+            .collect();
+        IgnoreRules { rules }
+    }
+
+    // Whether a path relative to the context is left out of it
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let segments: Vec<&str> = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => name.to_str(),
+                _ => None,
+            })
+            .collect();
+        let mut ignored = false;
+        for (pattern, exception) in &self.rules {
+            if (1..=segments.len()).any(|len| matches(pattern, &segments[..len])) {
+                ignored = !exception;
+            }
+        }
+        ignored
+    }
+}
+
+fn matches(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => (0..=path.len()).any(|skip| matches(rest, &path[skip..])),
+        Some((first, rest)) => path
+            .split_first()
+            .is_some_and(|(segment, path)| glob(first.as_bytes(), segment.as_bytes()) && matches(rest, path)),
+    }
+}
+
+// Watches a build context and its Dockerfile
+pub struct ContextWatcher {
+    context: PathBuf,
+    dockerfile: PathBuf,
+    excluded: Vec<PathBuf>,
+    changes: mpsc::UnboundedReceiver<PathBuf>,
+    // Watching stops when this is dropped
+    _watchers: Vec<notify::RecommendedWatcher>,
+}
+
+impl ContextWatcher {
+    pub fn new(context: &Path, dockerfile: &Path) -> Result<Self> {
+        let context = std::fs::canonicalize(context)
+            .map_err(|e| anyhow::anyhow!("Failed to watch {:?}: {}", context, e))?;
+        let dockerfile = std::fs::canonicalize(dockerfile)
+            .map_err(|e| anyhow::anyhow!("Failed to watch {:?}: {}", dockerfile, e))?;
+        let (sender, changes) = mpsc::unbounded_channel();
+        let watcher = |path: &Path, mode: RecursiveMode| -> Result<notify::RecommendedWatcher> {
+            let sender = sender.clone();
+            let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                // Reads, including the build's own, are not changes
+                let Ok(event) = event else {
+                    return;
+                };
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)) {
+                    for path in event.paths {
+                        let _ = sender.send(path);
+                    }
+                }
+            })?;
+            watcher
+                .watch(path, mode)
+                .map_err(|e| anyhow::anyhow!("Failed to watch {}: {}", path.display(), e))?;
+            Ok(watcher)
+        };
+        let mut watchers = vec![watcher(&context, RecursiveMode::Recursive)?];
+        // A Dockerfile outside the context is watched through its directory
+        if !dockerfile.starts_with(&context)
+            && let Some(dir) = dockerfile.parent()
+        {
+            watchers.push(watcher(dir, RecursiveMode::NonRecursive)?);
+        }
+        Ok(ContextWatcher { context, dockerfile, excluded: Vec::new(), changes, _watchers: watchers })
+    }
+
+    // Leave out a path the build writes to, such as an --output inside the
+    // context, which would otherwise trigger a build after every build
+    pub fn exclude(mut self, path: &Path) -> Self {
+        let path = std::fs::canonicalize(path).unwrap_or_else(|_| std::path::absolute(path).unwrap_or_default());
+        self.excluded.push(path);
+        self
+    }
+
+    // Wait for changes to the Dockerfile or to files .dockerignore does not
+    // exclude, and return them, relative to the context, once they settle.
+    // Changes made while a build ran are reported on the next call.
+    pub async fn changed(&mut self) -> Result<Vec<PathBuf>> {
+        let mut changed = Vec::new();
+        loop {
+            let path = if changed.is_empty() {
+                self.changes.recv().await
+            } else {
+                match tokio::time::timeout(SETTLE, self.changes.recv()).await {
+                    Ok(path) => path,
+                    Err(_) => return Ok(changed),
+                }
+            };
+            let path = path.ok_or_else(|| anyhow::anyhow!("Stopped watching {}", self.context.display()))?;
+            // Read again each time, since .dockerignore can change too
+            let rules = IgnoreRules::load(&self.context)?;
+            if let Some(path) = self.relevant(&path, &rules)
+                && !changed.contains(&path)
+            {
+                changed.push(path);
+            }
+        }
+    }
+
+    fn relevant(&self, path: &Path, rules: &IgnoreRules) -> Option<PathBuf> {
+        if self.excluded.iter().any(|excluded| path.starts_with(excluded)) {
+            return None;
+        }
+        if path == self.dockerfile {
+            return Some(path.strip_prefix(&self.context).unwrap_or(path).to_path_buf());
+        }
+        let relative = path.strip_prefix(&self.context).ok()?;
+        (!rules.is_ignored(relative)).then(|| relative.to_path_buf())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dockerignore_rules() {
+        let rules = IgnoreRules::parse(
+            "# build output\n/target\nnode_modules\n*.log\n**/*.tmp\ndocs/**\n!docs/README.md\n!important.log\n",
+        );
+        for (path, ignored) in [
+            ("target", true),
+            ("target/debug/app", true),
+            ("src/target", false),
+            ("node_modules/left-pad/index.js", true),
+            ("app/node_modules/x.js", false),
+            ("build.log", true),
+            ("logs/build.log", false),
+            ("important.log", false),
+            ("a/b/c.tmp", true),
+            ("x.tmp", true),
+            ("docs/guide/intro.md", true),
+            ("docs/README.md", false),
+            ("src/main.rs", false),
+        ] {
+            assert_eq!(rules.is_ignored(Path::new(path)), ignored, "{}", path);
+        }
+        assert!(!IgnoreRules::default().is_ignored(Path::new("anything")));
+    }
+}
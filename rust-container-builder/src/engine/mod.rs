@@ -1,103 +0,0 @@
-use crate::dockerfile::{DockerfileParser, Instruction};
-use crate::storage::{Image, Layer, StorageManager};
-use anyhow::Result;
-use std::path::PathBuf;
-
-pub struct BuildEngine {
-    storage: StorageManager,
-    context_dir: PathBuf,
-}
-
-impl BuildEngine {
-    pub fn new(storage: StorageManager, context_dir: PathBuf) -> Self {
-        Self {
-            storage,
-            context_dir,
-        }
-    }
-
-    pub async fn build_image(&mut self, dockerfile_path: &PathBuf, image_name: &str) -> Result<Image> {
-        // Parse the Dockerfile
-        let parsed_dockerfile = DockerfileParser::parse_from_path(dockerfile_path).await?;
-
-        // Process each stage in the Dockerfile
-        let mut final_layers = Vec::new();
-
-        for (stage_idx, stage) in parsed_dockerfile.stages.iter().enumerate() {
-            tracing::info!("Processing stage {} of {}: {}",
-                          stage_idx + 1,
-                          parsed_dockerfile.stages.len(),
-                          stage.name.as_deref().unwrap_or(&stage.base_image));
-
-            // For now, we'll simulate building each stage
-            // In a real implementation, we'd actually execute the instructions
-
-            for (inst_idx, instruction) in stage.instructions.iter().enumerate() {
-                tracing::info!("Processing instruction {}: {:?}", inst_idx, instruction);
-
-                // Simulate creating a layer for each instruction
-                let layer_data = format!("layer_for_stage_{}_instruction_{}", stage_idx, inst_idx).into_bytes();
-                let layer = self.storage.create_layer(&layer_data).await?;
-                final_layers.push(layer);
-            }
-        }
-
-        // Create the final image
-        let image_id = format!("image_{}", uuid::Uuid::new_v4());
-
-        // Create a minimal image configuration (using a simpler approach)
-        let config_json = r#"{
-            "created": "2023-01-01T00:00:00Z",
-            "architecture": "amd64",
-            "os": "linux",
-            "config": {},
-            "rootfs": {
-                "type": "layers",
-                "diff_ids": []
-            }
-        }"#;
-
-        // Calculate digest for the config
-        use sha2::{Digest, Sha256};
-        let mut hasher = Sha256::new();
-        hasher.update(config_json.as_bytes());
-        let hash_result = hasher.finalize();
-        let config_digest = format!("sha256:{:x}", hash_result);
-        let config_size = config_json.len() as u64;
-
-        // Create a minimal manifest (using a simpler approach)
-        let manifest_json = format!(
-            r#"{{
-                "schemaVersion": 2,
-                "mediaType": "application/vnd.oci.image.manifest.v1+json",
-                "config": {{
-                    "mediaType": "application/vnd.oci.image.config.v1+json",
-                    "digest": "{}",
-                    "size": {}
-                }},
-                "layers": []
-            }}"#,
-            config_digest,
-            config_size
-        );
-
-        use oci_spec::image::ImageManifest;
-        let manifest: ImageManifest = serde_json::from_str(&manifest_json)?;
-
-        use oci_spec::image::ImageConfiguration;
-        let config: ImageConfiguration = serde_json::from_str(config_json)?;
-
-        let image = Image {
-            id: image_id,
-            name: image_name.to_string(),
-            layers: final_layers,
-            config,
-            manifest,
-        };
-
-        // Save the image to storage
-        self.storage.save_image(&image).await?;
-
-        Ok(image)
-    }
-}
\ No newline at end of file
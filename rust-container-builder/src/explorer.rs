@@ -0,0 +1,230 @@
+use anyhow::Result;
+use hyperbuild_core::dive::{ChangeKind, ImageAnalysis, TreeRow};
+use hyperbuild_core::report::human_size;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::HashSet;
+
+const HELP: &str = " ↑/↓ move  Tab switch pane  Space collapse  a whole filesystem/changes  q quit ";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pane {
+    Layers,
+    Files,
+}
+
+struct Explorer<'a> {
+    analysis: &'a ImageAnalysis,
+    pane: Pane,
+    layers: ListState,
+    files: ListState,
+    // Show the whole filesystem at the selected layer rather than its changes
+    whole: bool,
+    collapsed: HashSet<String>,
+    rows: Vec<TreeRow>,
+}
+
+// Browse an analysed image until the user quits
+pub fn explore(analysis: &ImageAnalysis) -> Result<()> {
+    if analysis.layers.is_empty() {
+        return Err(anyhow::anyhow!("{} has no layers to explore", analysis.name));
+    }
+    let mut explorer = Explorer {
+        analysis,
+        pane: Pane::Layers,
+        layers: ListState::default().with_selected(Some(0)),
+        files: ListState::default().with_selected(Some(0)),
+        whole: false,
+        collapsed: HashSet::new(),
+        rows: analysis.tree(0, false),
+    };
+    let mut terminal = ratatui::try_init()?;
+    let explored = explorer.run(&mut terminal);
+    ratatui::restore();
+    explored
+}
+
+impl Explorer<'_> {
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Tab | KeyCode::BackTab | KeyCode::Left | KeyCode::Right => {
+                    self.pane = match self.pane {
+                        Pane::Layers => Pane::Files,
+                        Pane::Files => Pane::Layers,
+                    }
+                }
+                KeyCode::Up | KeyCode::Char('k') => self.step(-1),
+                KeyCode::Down | KeyCode::Char('j') => self.step(1),
+                KeyCode::PageUp => self.step(-20),
+                KeyCode::PageDown => self.step(20),
+                KeyCode::Char('a') => {
+                    self.whole = !self.whole;
+                    self.reload();
+                }
+                KeyCode::Char(' ') | KeyCode::Enter if self.pane == Pane::Files => self.toggle_collapsed(),
+                _ => {}
+            }
+        }
+    }
+
+    fn step(&mut self, by: isize) {
+        let (state, len) = match self.pane {
+            Pane::Layers => (&mut self.layers, self.analysis.layers.len()),
+            Pane::Files => {
+                let len = self.visible_rows().len();
+                (&mut self.files, len)
+            }
+        };
+        if len == 0 {
+            return;
+        }
+        let selected = state.selected().unwrap_or(0) as isize + by;
+        state.select(Some(selected.clamp(0, len as isize - 1) as usize));
+        if self.pane == Pane::Layers {
+            self.reload();
+        }
+    }
+
+    fn layer(&self) -> usize {
+        self.layers.selected().unwrap_or(0)
+    }
+
+    fn reload(&mut self) {
+        self.rows = self.analysis.tree(self.layer(), self.whole);
+        self.files.select(Some(0));
+    }
+
+    fn toggle_collapsed(&mut self) {
+        let selected = self.files.selected().and_then(|index| self.visible_rows().get(index).copied());
+        let Some(path) = selected.filter(|row| row.is_dir).map(|row| row.path.clone()) else {
+            return;
+        };
+        if !self.collapsed.remove(&path) {
+            self.collapsed.insert(path);
+        }
+    }
+
+    // Rows not hidden under a collapsed directory
+    fn visible_rows(&self) -> Vec<&TreeRow> {
+        let mut rows = Vec::new();
+        let mut hidden_below: Option<usize> = None;
+        for row in &self.rows {
+            if let Some(depth) = hidden_below {
+                if row.depth > depth {
+                    continue;
+                }
+                hidden_below = None;
+            }
+            if row.is_dir && self.collapsed.contains(&row.path) {
+                hidden_below = Some(row.depth);
+            }
+            rows.push(row);
+        }
+        rows
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, help] = Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] = Layout::horizontal([Constraint::Percentage(45), Constraint::Percentage(55)]).areas(main);
+        let [layers_area, layer_area, image_area] =
+            Layout::vertical([Constraint::Percentage(40), Constraint::Length(6), Constraint::Min(6)]).areas(left);
+
+        let layers: Vec<ListItem> = self
+            .analysis
+            .layers
+            .iter()
+            .map(|layer| ListItem::new(format!("{:>9}  {}", human_size(layer.diff_size), layer.instruction)))
+            .collect();
+        let list = List::new(layers)
+            .block(self.block(" Layers ", Pane::Layers))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, layers_area, &mut self.layers);
+
+        let layer = &self.analysis.layers[self.layer()];
+        let details = vec![
+            Line::from(format!("Digest: {}", layer.digest)),
+            Line::from(format!(
+                "Size: {} ({} compressed), {} changes",
+                human_size(layer.diff_size),
+                human_size(layer.size),
+                layer.changes.len()
+            )),
+            Line::from(format!("Command: {}", layer.instruction)),
+        ];
+        let details = Paragraph::new(details).wrap(Wrap { trim: false }).block(Block::bordered().title(" Layer "));
+        frame.render_widget(details, layer_area);
+
+        let mut image = vec![
+            Line::from(format!("Total image size: {}", human_size(self.analysis.image_size()))),
+            Line::from(format!("Potential wasted space: {}", human_size(self.analysis.wasted_bytes))),
+            Line::from(format!("Image efficiency score: {:.1} %", self.analysis.efficiency * 100.0)),
+            Line::from(""),
+        ];
+        if !self.analysis.wasted.is_empty() {
+            image.push(Line::styled(
+                format!("{:>6}  {:>9}  PATH", "COPIES", "WASTED"),
+                Style::default().add_modifier(Modifier::BOLD),
+            ));
+        }
+        for file in &self.analysis.wasted {
+            image.push(Line::from(format!("{:>6}  {:>9}  /{}", file.copies, human_size(file.bytes), file.path)));
+        }
+        frame.render_widget(Paragraph::new(image).block(Block::bordered().title(" Image ")), image_area);
+
+        let title = if self.whole {
+            format!(" Filesystem at layer {} ", self.layer() + 1)
+        } else {
+            format!(" Changes in layer {} ", self.layer() + 1)
+        };
+        let files: Vec<ListItem> = self.visible_rows().into_iter().map(|row| self.file_item(row)).collect();
+        let list = List::new(files)
+            .block(self.block(&title, Pane::Files))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, right, &mut self.files);
+
+        frame.render_widget(Paragraph::new(HELP).style(Style::default().fg(Color::DarkGray)), help);
+    }
+
+    fn block<'b>(&self, title: &'b str, pane: Pane) -> Block<'b> {
+        let style = if self.pane == pane {
+            Style::default().fg(Color::Cyan)
+        } else {
+            Style::default()
+        };
+        Block::bordered().title(title).border_style(style)
+    }
+
+    fn file_item(&self, row: &TreeRow) -> ListItem<'static> {
+        let color = match row.kind {
+            Some(ChangeKind::Added) => Color::Green,
+            Some(ChangeKind::Modified) => Color::Yellow,
+            Some(ChangeKind::Deleted) => Color::Red,
+            None => Color::Reset,
+        };
+        let marker = row.kind.map(ChangeKind::marker).unwrap_or(' ');
+        let name = match (row.is_dir, self.collapsed.contains(&row.path)) {
+            (true, true) => format!("{}/ …", row.name),
+            (true, false) => format!("{}/", row.name),
+            (false, _) => row.name.clone(),
+        };
+        ListItem::new(Line::from(vec![
+            Span::raw(format!("{} {:>9}  ", marker, human_size(row.size))),
+            Span::raw("  ".repeat(row.depth)),
+            Span::raw(name),
+        ]))
+        .style(Style::default().fg(color))
+    }
+}
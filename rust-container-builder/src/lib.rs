@@ -1,4 +0,0 @@
-pub mod dockerfile;
-pub mod storage;
-pub mod engine;
-pub mod registry_client;
\ No newline at end of file
@@ -0,0 +1,176 @@
+use anyhow::Result;
+use serde_json::{Map, Value};
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::format::{JsonFields, Writer};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    // One JSON object per line, for log shippers such as Loki or Elasticsearch
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(format: &str) -> Result<Self> {
+        match format {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => Err(anyhow::anyhow!("Unknown log format {:?}, expected text or json", other)),
+        }
+    }
+}
+
+// Set up tracing for a command: -v shows info, -vv debug and -vvv trace,
+// otherwise RUST_LOG decides (errors only when unset)
+pub fn init(verbose: u8, format: &str, to_stderr: bool) -> Result<()> {
+    let format = LogFormat::parse(format)?;
+    if verbose > 0 {
+        let level = match verbose {
+            1 => "info",
+            2 => "debug",
+            _ => "trace",
+        };
+        unsafe {
+            std::env::set_var("RUST_LOG", level);
+        }
+    }
+
+    let writer = if to_stderr {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(writer);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.fmt_fields(JsonFields::new()).event_format(FlatJson).init(),
+    }
+    Ok(())
+}
+
+// Writes each event as one flat JSON object: timestamp, level, target, the
+// event's message and fields, and the fields of every span it happened in, so
+// `build_id`, `stage`, `instruction` and `duration_ms` are top-level keys
+// whichever span recorded them
+pub struct FlatJson;
+
+impl<S, N> FormatEvent<S, N> for FlatJson
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut line = Map::new();
+        line.insert(
+            "timestamp".to_string(),
+            json_string(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+        );
+        line.insert("level".to_string(), json_string(metadata.level().as_str()));
+        line.insert("target".to_string(), json_string(metadata.target()));
+
+        // Outer spans first, so a field set by an inner span wins
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                if let Some(fields) = extensions.get::<FormattedFields<N>>()
+                    && let Ok(Value::Object(fields)) = serde_json::from_str::<Value>(fields)
+                {
+                    line.extend(fields);
+                }
+            }
+        }
+        event.record(&mut FieldVisitor(&mut line));
+
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+fn json_string(value: impl Into<String>) -> Value {
+    Value::String(value.into())
+}
+
+struct FieldVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), json_string(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), json_string(format!("{:?}", value)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_json_lines_flatten_span_fields() {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&output);
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || SharedWriter(Arc::clone(&sink)))
+            .fmt_fields(JsonFields::new())
+            .event_format(FlatJson)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let build = tracing::info_span!("build", build_id = "build_1");
+            let _build = build.enter();
+            let stage = tracing::info_span!("stage", stage = "builder");
+            let _stage = stage.enter();
+            tracing::info!(instruction = "RUN make", duration_ms = 42u64, "Step finished");
+        });
+
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        let line: Value = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["message"], "Step finished");
+        assert_eq!(line["build_id"], "build_1");
+        assert_eq!(line["stage"], "builder");
+        assert_eq!(line["instruction"], "RUN make");
+        assert_eq!(line["duration_ms"], 42);
+        assert!(line["timestamp"].as_str().unwrap().ends_with('Z'));
+
+        assert!(LogFormat::parse("json").is_ok());
+        assert!(LogFormat::parse("logfmt").is_err());
+    }
+
+    struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
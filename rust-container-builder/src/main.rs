@@ -1,27 +1,112 @@
 use anyhow::Result;
 use clap::Parser;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
-mod dockerfile;
-mod storage;
-mod engine;
-mod registry_client;
+mod explorer;
+mod logging;
 
-use engine::BuildEngine;
-use storage::StorageManager;
-use registry_client::RegistryClient;
+use hyperbuild_core::bake::{self, BakeFile};
+use hyperbuild_core::ci::Ci;
+use hyperbuild_core::compose::{self, ComposeProject, ServiceBuild};
+use hyperbuild_core::containerd::{ContainerdClient, ContainerdSettings};
+use hyperbuild_core::{diff, dive, docker};
+use hyperbuild_core::progress::{self, Progress, ProgressEvent, ProgressMode};
+use hyperbuild_core::{Build, BuildEngine, BuildOptions, BuildOutput, BuildRequest, PullPolicy, StepLimits};
+use hyperbuild_core::{ContainerSpec, PortMapping, Runtime, Volume, run_image};
+use hyperbuild_core::{clean_toc_name, export_image, export_image_list, export_rootfs, load_archive};
+use hyperbuild_core::{Hook, Hooks, NetworkMode, Plugins, SbomFormat, parse_duration};
+use hyperbuild_core::images::{self, Column};
+use hyperbuild_core::metrics::Metrics;
+use hyperbuild_core::platform::Platform;
+use hyperbuild_core::reference::Reference;
+use hyperbuild_core::{ArtifactFile, FetchedManifest, MediaTypes, RegistryClient, RegistrySettings};
+use hyperbuild_core::report::{self, BuildRecorder, StepStatus};
+use hyperbuild_core::settings::Settings;
+use hyperbuild_core::signing::{SigningKey, Verifier};
+use hyperbuild_core::watch::ContextWatcher;
+use hyperbuild_core::{CacheRecord, Image, PruneOptions, StorageManager, TocEntry, Usage};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 enum Args {
     /// Build a container image from a Dockerfile
-    Build(BuildArgs),
+    Build(Box<BuildArgs>),
 
     /// Push a built image to a registry
     Push(PushArgs),
 
     /// Pull an image from a registry
     Pull(PullArgs),
+
+    /// List the tags of a repository on a registry
+    Tags(TagsArgs),
+
+    /// Inspect image manifests and indexes on a registry
+    Manifest(ManifestArgs),
+
+    /// Copy an image from one registry or repository to another, without storing it locally
+    Copy(CopyArgs),
+
+    /// Push and pull arbitrary OCI artifacts, such as Helm charts, WASM modules or config bundles
+    Artifact(ArtifactArgs),
+
+    /// List and read the files of an image on a registry without pulling it
+    Remote(RemoteArgs),
+
+    /// Show the output of each step of a past build
+    Logs(LogsArgs),
+
+    /// List images in local storage
+    Images(ImagesArgs),
+
+    /// Remove images from local storage
+    Rmi(RmiArgs),
+
+    /// Give a stored image another name
+    Tag(TagArgs),
+
+    /// Delete layer blobs no stored image uses
+    Gc(GcArgs),
+
+    /// Remove unused images and the layers only they use
+    Prune(PruneArgs),
+
+    /// Show how much disk space the store uses, and how much could be freed
+    Df(DfArgs),
+
+    /// Check stored blobs and images for corruption
+    Verify(VerifyArgs),
+
+    /// Write a stored image to an OCI image layout or docker archive
+    Save(SaveArgs),
+
+    /// Import images from an OCI image layout or docker archive into local storage
+    Load(LoadArgs),
+
+    /// Write the merged filesystem of a stored image to a tar or directory
+    Export(ExportArgs),
+
+    /// Run a stored image's entrypoint locally, e.g. to smoke-test a fresh build
+    Run(RunArgs),
+
+    /// Explore a stored image's layers, the files each one changes and the space wasted
+    Dive(DiveArgs),
+
+    /// Compare two images: the layers they do not share, their config and their files
+    Diff(DiffArgs),
+
+    /// Build the services of a Compose file
+    Compose(ComposeArgs),
+
+    /// Build the targets of a bake file (HCL or JSON), with groups, inheritance and matrices
+    Bake(BakeArgs),
+
+    /// Back up, restore or deduplicate the image store
+    Store(StoreArgs),
+
+    /// List or prune the build cache
+    Cache(CacheArgs),
 }
 
 #[derive(clap::Args)]
@@ -34,13 +119,150 @@ struct BuildArgs {
     #[arg(short, long, default_value = "./Dockerfile")]
     dockerfile: PathBuf,
 
-    /// Name of the output image
+    /// Name of the output image (can be repeated to give it several tags)
+    #[arg(short = 't', long = "tag", visible_short_alias = 'i', visible_alias = "image-name", required = true)]
+    tags: Vec<String>,
+
+    /// Set a label on the image, overriding the Dockerfile's LABEL of the same key (can be repeated)
+    #[arg(long, value_name = "KEY=VALUE")]
+    label: Vec<String>,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    /// Export build cache metadata (only `type=inline` is supported)
+    #[arg(long)]
+    cache_to: Option<String>,
+
+    /// Image to import build cache from (can be repeated)
+    #[arg(long)]
+    cache_from: Vec<String>,
+
+    /// Run every step, ignoring the build cache and --cache-from
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Maximum number of independent stages to build concurrently (0 = number of CPUs)
+    #[arg(long, default_value_t = 0)]
+    parallelism: usize,
+
+    /// Set a build-time variable as KEY=VALUE, or KEY to take it from the environment (can be repeated)
+    #[arg(long)]
+    build_arg: Vec<String>,
+
+    /// Read build-time variables from a KEY=VALUE file (can be repeated)
+    #[arg(long)]
+    build_arg_file: Vec<PathBuf>,
+
+    /// Secret to expose to RUN --mount=type=secret steps, as id=ID,src=PATH (can be repeated)
+    #[arg(long)]
+    secret: Vec<String>,
+
+    /// Produce bit-identical output for identical inputs, clamping timestamps to SOURCE_DATE_EPOCH (default 0)
+    /// and recording files as owned by root
+    #[arg(long)]
+    reproducible: bool,
+
+    /// Target platform(s) as os/arch[/variant], comma separated; several platforms produce an image index
+    #[arg(long, value_delimiter = ',')]
+    platform: Vec<String>,
+
+    /// Network for RUN steps: none, host or default (RUN --network=... overrides it per step)
+    #[arg(long, default_value = "default")]
+    network: String,
+
+    /// Add a host-to-IP mapping to /etc/hosts in RUN steps, as host:ip (can be repeated)
+    #[arg(long, value_name = "HOST:IP")]
+    add_host: Vec<String>,
+
+    /// Memory limit for each RUN step, e.g. 512m or 2g (requires cgroup v2)
+    #[arg(long)]
+    memory: Option<String>,
+
+    /// CPU limit for each RUN step, in CPUs, e.g. 1.5 (requires cgroup v2)
+    #[arg(long)]
+    cpus: Option<f64>,
+
+    /// Kill RUN steps that run longer than this, e.g. 30s or 10m
+    #[arg(long)]
+    step_timeout: Option<String>,
+
+    /// Run failing RUN steps again up to this many times, with backoff (RUN --retry=N overrides it)
+    #[arg(long, default_value_t = 0)]
+    retry: u32,
+
+    /// Compress new layers with gzip or zstd, optionally at a level: gzip[,level=0-9] or zstd[,level=1-22]
+    /// (overrides the config file's [compression] table)
+    #[arg(long, value_name = "ALGORITHM[,level=N]")]
+    compression: Option<String>,
+
+    /// Merge the layers created by this build into a single layer
+    #[arg(long)]
+    squash: bool,
+
+    /// Squash only the layers created from this stage onward (implies --squash)
+    #[arg(long, value_name = "STAGE")]
+    squash_from: Option<String>,
+
+    /// Also export the build result: type=local,dest=DIR or type=tar,dest=FILE for the filesystem, type=oci or type=docker with dest=FILE for the image
     #[arg(short, long)]
-    image_name: String,
+    output: Option<String>,
+
+    /// How to show build progress: auto, plain, tty or json (newline-delimited events)
+    #[arg(long, default_value = "auto")]
+    progress: String,
+
+    /// Write output for a CI system: github (a log group per stage, failures annotated on their Dockerfile line,
+    /// imageid and digest step outputs) or none; defaults to github when $GITHUB_ACTIONS is true
+    #[arg(long)]
+    ci: Option<String>,
+
+    /// When to pull FROM images from their registry: always, missing (only when not stored locally) or never
+    #[arg(long, default_value = "missing")]
+    pull: String,
 
-    /// Output directory for build artifacts
-    #[arg(long, default_value = "./build-output")]
-    output_dir: PathBuf,
+    /// Push the image under each of its tags once it is built
+    #[arg(long)]
+    push: bool,
+
+    /// Generate an SBOM of the image's packages: spdx or cyclonedx
+    #[arg(long, value_name = "FORMAT")]
+    sbom: Option<String>,
+
+    /// With --push, attach the SBOM to the pushed image as an OCI referrer
+    #[arg(long)]
+    attach_sbom: bool,
+
+    /// Config file with build hooks (default: $HYPERBUILD_CONFIG, ./hyperbuild.toml or ~/.config/hyperbuild/config.toml)
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Write step timings, cache hits and layer sizes to this JSON file and print them as a table
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// Write Prometheus metrics (builds, cache hits, step durations, bytes pushed and pulled) to FILE when done
+    #[arg(long, value_name = "FILE")]
+    metrics_file: Option<PathBuf>,
+
+    /// Keep watching the context and Dockerfile, building again whenever a file .dockerignore does not exclude
+    /// changes; cached steps make each rebuild redo only what the change affects
+    #[arg(long)]
+    watch: bool,
+
+    /// While watching, serve Prometheus metrics of every build since the watch started at http://ADDR/metrics
+    #[arg(long, value_name = "ADDR", requires = "watch")]
+    metrics_addr: Option<std::net::SocketAddr>,
+
+    /// Where the built image goes besides the image store: local (nowhere else) or containerd, which it is loaded
+    /// into as with `load --to containerd`, using the config file's [containerd] settings
+    #[arg(long, default_value = "local")]
+    store: String,
+
+    /// Log format: text, or json for one JSON object per line with build_id, stage, instruction and duration_ms
+    #[arg(long, default_value = "text", value_name = "FORMAT")]
+    log_format: String,
 
     /// Verbose output
     #[arg(short, long, action = clap::ArgAction::Count)]
@@ -61,9 +283,57 @@ struct PushArgs {
     #[arg(short, long, default_value = "./Dockerfile")]
     dockerfile: PathBuf,
 
-    /// Output directory for build artifacts
-    #[arg(long, default_value = "./build-output")]
-    output_dir: PathBuf,
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    /// Attach the image's SBOM, if its build generated one, as an OCI referrer
+    #[arg(long)]
+    attach_sbom: bool,
+
+    /// Upload this many layers at once (defaults to the config file's, or 4)
+    #[arg(long)]
+    parallel: Option<usize>,
+
+    /// Manifest media types to push: oci, or docker for registries that only take Docker schema2 manifests
+    #[arg(long)]
+    media_types: Option<String>,
+
+    /// Push layers as eStargz, so clients that know the format can fetch single files without pulling the image
+    #[arg(long)]
+    estargz: bool,
+
+    /// Talk to the registry over plain HTTP
+    #[arg(long)]
+    insecure: bool,
+
+    /// Talk to the registry over TLS without verifying its certificate, e.g. a self-signed one
+    #[arg(long)]
+    skip_tls_verify: bool,
+
+    /// Seconds to wait for a connection to the registry (defaults to the config file's, or 30; 0 waits forever)
+    #[arg(long)]
+    connect_timeout: Option<u64>,
+
+    /// Seconds to wait for the registry to answer or send more data (defaults to the config file's, or 120)
+    #[arg(long)]
+    read_timeout: Option<u64>,
+
+    /// Platform(s) to build for if the image is not stored, comma separated; several are pushed as one image index
+    #[arg(long, value_delimiter = ',')]
+    platform: Vec<String>,
+
+    /// Sign the pushed manifest cosign-style with key=PATH (a cosign.key, decrypted with $COSIGN_PASSWORD, or PEM)
+    #[arg(long)]
+    sign: Option<String>,
+
+    /// Write Prometheus metrics (builds, cache hits, step durations, bytes pushed and pulled) to FILE when done
+    #[arg(long, value_name = "FILE")]
+    metrics_file: Option<PathBuf>,
+
+    /// Log format: text, or json for one JSON object per line with build_id, stage, instruction and duration_ms
+    #[arg(long, default_value = "text", value_name = "FORMAT")]
+    log_format: String,
 
     /// Verbose output
     #[arg(short, long, action = clap::ArgAction::Count)]
@@ -76,161 +346,2677 @@ struct PullArgs {
     #[arg(short, long)]
     image_name: String,
 
-    /// Output directory for pulled image artifacts
-    #[arg(long, default_value = "./pull-output")]
-    output_dir: PathBuf,
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    /// Download this many layers at once (defaults to the config file's, or 4)
+    #[arg(long)]
+    parallel: Option<usize>,
+
+    /// Talk to the registry over plain HTTP
+    #[arg(long)]
+    insecure: bool,
+
+    /// Talk to the registry over TLS without verifying its certificate, e.g. a self-signed one
+    #[arg(long)]
+    skip_tls_verify: bool,
+
+    /// Seconds to wait for a connection to the registry (defaults to the config file's, or 30; 0 waits forever)
+    #[arg(long)]
+    connect_timeout: Option<u64>,
+
+    /// Seconds to wait for the registry to answer or send more data (defaults to the config file's, or 120)
+    #[arg(long)]
+    read_timeout: Option<u64>,
+
+    /// Platform to pull from a multi-platform image, as os/arch[/variant] (defaults to the host's)
+    #[arg(long)]
+    platform: Option<String>,
+
+    /// Refuse the image unless a cosign signature verifies: key=cosign.pub, or identity=,issuer=,roots=,rekor= for keyless
+    #[arg(long)]
+    verify_signature: Option<String>,
+
+    /// Write Prometheus metrics (builds, cache hits, step durations, bytes pushed and pulled) to FILE when done
+    #[arg(long, value_name = "FILE")]
+    metrics_file: Option<PathBuf>,
+
+    /// Log format: text, or json for one JSON object per line with build_id, stage, instruction and duration_ms
+    #[arg(long, default_value = "text", value_name = "FORMAT")]
+    log_format: String,
 
     /// Verbose output
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    match Args::parse() {
-        Args::Build(args) => build_command(args).await,
-        Args::Push(args) => push_command(args).await,
-        Args::Pull(args) => pull_command(args).await,
-    }
+#[derive(clap::Args)]
+struct TagsArgs {
+    /// Repository to list (including registry URL), e.g. ghcr.io/org/app
+    repository: String,
+
+    /// Output format: text (one tag per line) or json
+    #[arg(long, default_value = "text")]
+    format: String,
+
+    /// Talk to the registry over plain HTTP
+    #[arg(long)]
+    insecure: bool,
+
+    /// Talk to the registry over TLS without verifying its certificate, e.g. a self-signed one
+    #[arg(long)]
+    skip_tls_verify: bool,
 }
 
-async fn build_command(args: BuildArgs) -> Result<()> {
-    // Initialize tracing
-    if args.verbose > 0 {
-        let level = match args.verbose {
-            1 => "info",
-            2 => "debug",
-            _ => "trace",
-        };
-        unsafe {
-            std::env::set_var("RUST_LOG", level);
-        }
-    }
+#[derive(clap::Args)]
+struct ManifestArgs {
+    #[command(subcommand)]
+    command: ManifestCommand,
+}
 
-    tracing_subscriber::fmt::init();
+#[derive(clap::Subcommand)]
+enum ManifestCommand {
+    /// Show the manifest or index an image name points at, and the manifest of each platform of an index
+    Inspect(ManifestInspectArgs),
+}
 
-    tracing::info!("Starting Rust container builder");
-    tracing::info!("Context: {:?}", args.context);
-    tracing::info!("Dockerfile: {:?}", args.dockerfile);
-    tracing::info!("Image name: {}", args.image_name);
+#[derive(clap::Args)]
+struct ManifestInspectArgs {
+    /// Image to inspect (including registry URL), by tag or @sha256:<digest>
+    image_name: String,
 
-    // Initialize storage manager
-    let storage = StorageManager::new(args.output_dir)?;
-    storage.init().await?;
+    /// Print the manifest's exact bytes, and its digest on stderr
+    #[arg(long)]
+    raw: bool,
 
-    // Create build engine
-    let mut engine = BuildEngine::new(storage, args.context);
+    /// Talk to the registry over plain HTTP
+    #[arg(long)]
+    insecure: bool,
 
-    // Build the image
-    let image = engine.build_image(&args.dockerfile, &args.image_name).await?;
+    /// Talk to the registry over TLS without verifying its certificate, e.g. a self-signed one
+    #[arg(long)]
+    skip_tls_verify: bool,
+}
 
-    tracing::info!("Successfully built image: {}", image.name);
-    tracing::info!("Image ID: {}", image.id);
-    tracing::info!("Number of layers: {}", image.layers.len());
+#[derive(clap::Args)]
+struct CopyArgs {
+    /// Image to copy (including registry URL), by tag or @sha256:<digest>
+    source: String,
 
-    Ok(())
+    /// Name to copy it to (including registry URL)
+    destination: String,
+
+    /// Copy this many blobs at once (defaults to the config file's, or 4)
+    #[arg(long)]
+    parallel: Option<usize>,
+
+    /// Talk to both registries over plain HTTP (use [registry.hosts] in the config file to set one)
+    #[arg(long)]
+    insecure: bool,
+
+    /// Talk to both registries over TLS without verifying their certificates
+    #[arg(long)]
+    skip_tls_verify: bool,
+
+    /// Seconds to wait for a connection to the registry (defaults to the config file's, or 30; 0 waits forever)
+    #[arg(long)]
+    connect_timeout: Option<u64>,
+
+    /// Seconds to wait for the registry to answer or send more data (defaults to the config file's, or 120)
+    #[arg(long)]
+    read_timeout: Option<u64>,
+
+    /// Verbose output
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
 }
 
-async fn push_command(args: PushArgs) -> Result<()> {
-    // Initialize tracing
-    if args.verbose > 0 {
-        let level = match args.verbose {
-            1 => "info",
-            2 => "debug",
-            _ => "trace",
-        };
-        unsafe {
-            std::env::set_var("RUST_LOG", level);
-        }
-    }
+#[derive(clap::Args)]
+struct ArtifactArgs {
+    #[command(subcommand)]
+    command: ArtifactCommand,
+}
 
-    tracing_subscriber::fmt::init();
+#[derive(clap::Subcommand)]
+enum ArtifactCommand {
+    /// Push files as the layers of an artifact, each titled with its file name
+    Push(ArtifactPushArgs),
 
-    tracing::info!("Starting push operation");
-    tracing::info!("Image name: {}", args.image_name);
+    /// Download the files of an artifact into a directory
+    Pull(ArtifactPullArgs),
+}
 
-    // Extract registry URL from image name
-    let registry_url = extract_registry_url(&args.image_name);
-    tracing::info!("Target registry: {}", registry_url);
+#[derive(clap::Args)]
+struct ArtifactPushArgs {
+    /// Name to push the artifact as (including registry URL)
+    reference: String,
 
-    // Initialize storage manager
-    let storage = StorageManager::new(args.output_dir)?;
-    storage.init().await?;
+    /// Files to push, as FILE or FILE:MEDIA_TYPE (defaults to application/vnd.oci.image.layer.v1.tar)
+    #[arg(required = true)]
+    files: Vec<String>,
 
-    // Check if image exists in storage, if not build it
-    let image = if let Some(stored_image) = storage.get_image_by_name(&args.image_name).await? {
-        tracing::info!("Found existing image in storage, using it for push");
-        stored_image
-    } else {
-        tracing::info!("Image not found in storage, building it first");
-        let mut engine = BuildEngine::new(storage.clone_for_build(), args.context);
-        engine.build_image(&args.dockerfile, &args.image_name).await?
-    };
+    /// The artifact's type, e.g. application/vnd.cncf.helm.config.v1+json
+    #[arg(long, default_value = "application/vnd.unknown.artifact.v1")]
+    artifact_type: String,
 
-    // Create registry client
-    let client = RegistryClient::new(registry_url)?;
+    /// Config blob, as FILE or FILE:MEDIA_TYPE (defaults to the empty JSON object)
+    #[arg(long)]
+    config: Option<String>,
 
-    // Push the image
-    client.push_image(&args.image_name, &image).await?;
+    /// Annotation for the manifest, as KEY=VALUE (repeatable)
+    #[arg(long = "annotation")]
+    annotations: Vec<String>,
 
-    tracing::info!("Successfully pushed image: {}", args.image_name);
-    Ok(())
+    /// Push this many files at once (defaults to the config file's, or 4)
+    #[arg(long)]
+    parallel: Option<usize>,
+
+    /// Talk to the registry over plain HTTP
+    #[arg(long)]
+    insecure: bool,
+
+    /// Talk to the registry over TLS without verifying its certificate, e.g. a self-signed one
+    #[arg(long)]
+    skip_tls_verify: bool,
+
+    /// Seconds to wait for a connection to the registry (defaults to the config file's, or 30; 0 waits forever)
+    #[arg(long)]
+    connect_timeout: Option<u64>,
+
+    /// Seconds to wait for the registry to answer or send more data (defaults to the config file's, or 120)
+    #[arg(long)]
+    read_timeout: Option<u64>,
+
+    /// Verbose output
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
 }
 
-async fn pull_command(args: PullArgs) -> Result<()> {
-    // Initialize tracing
-    if args.verbose > 0 {
-        let level = match args.verbose {
-            1 => "info",
-            2 => "debug",
-            _ => "trace",
-        };
-        unsafe {
-            std::env::set_var("RUST_LOG", level);
-        }
-    }
+#[derive(clap::Args)]
+struct ArtifactPullArgs {
+    /// Artifact to pull (including registry URL), by tag or @sha256:<digest>
+    reference: String,
 
-    tracing_subscriber::fmt::init();
+    /// Directory to write the files into
+    #[arg(short, long, default_value = ".")]
+    output: PathBuf,
 
-    tracing::info!("Starting pull operation");
-    tracing::info!("Image name: {}", args.image_name);
+    /// Pull this many files at once (defaults to the config file's, or 4)
+    #[arg(long)]
+    parallel: Option<usize>,
 
-    // Extract registry URL from image name
-    let registry_url = extract_registry_url(&args.image_name);
-    tracing::info!("Source registry: {}", registry_url);
+    /// Talk to the registry over plain HTTP
+    #[arg(long)]
+    insecure: bool,
 
-    // Create registry client
-    let client = RegistryClient::new(registry_url)?;
+    /// Talk to the registry over TLS without verifying its certificate, e.g. a self-signed one
+    #[arg(long)]
+    skip_tls_verify: bool,
 
-    // Pull the image
-    client.pull_image(&args.image_name, args.output_dir.to_str().unwrap()).await?;
+    /// Seconds to wait for a connection to the registry (defaults to the config file's, or 30; 0 waits forever)
+    #[arg(long)]
+    connect_timeout: Option<u64>,
 
-    tracing::info!("Successfully pulled image: {}", args.image_name);
-    Ok(())
+    /// Seconds to wait for the registry to answer or send more data (defaults to the config file's, or 120)
+    #[arg(long)]
+    read_timeout: Option<u64>,
+
+    /// Verbose output
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
 }
 
-// Helper function to extract registry URL from image name
-fn extract_registry_url(image_name: &str) -> String {
-    // If image name contains a registry (like localhost:5000/myimage:tag or docker.io/myimage:tag)
-    if image_name.contains('/') {
-        let parts: Vec<&str> = image_name.splitn(2, '/').collect();
-        let host_part = parts[0];
+#[derive(clap::Args)]
+struct RemoteArgs {
+    #[command(subcommand)]
+    command: RemoteCommand,
+}
 
-        // Check if it looks like a registry (contains dot or colon)
-        if host_part.contains('.') || host_part.contains(':') {
-            if host_part.starts_with("http://") || host_part.starts_with("https://") {
-                return host_part.to_string();
-            } else {
-                // Assume http for localhost, https for others
-                if host_part.starts_with("localhost:") || host_part.starts_with("127.0.0.1:") {
-                    return format!("http://{}", host_part);
-                } else {
-                    return format!("https://{}", host_part);
-                }
-            }
-        }
+#[derive(clap::Subcommand)]
+enum RemoteCommand {
+    /// List the files of an image, or those under a directory of it
+    Ls(RemoteLsArgs),
+
+    /// Print a file of an image, fetching only its chunks from eStargz layers
+    Cat(RemoteCatArgs),
+}
+
+#[derive(clap::Args)]
+struct RemoteLsArgs {
+    /// Image to read (including registry URL), by tag or @sha256:<digest>
+    image_name: String,
+
+    /// File or directory to list (defaults to the whole filesystem)
+    path: Option<String>,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    /// Platform to read from a multi-platform image, as os/arch[/variant] (defaults to the host's)
+    #[arg(long)]
+    platform: Option<String>,
+
+    /// Talk to the registry over plain HTTP
+    #[arg(long)]
+    insecure: bool,
+
+    /// Talk to the registry over TLS without verifying its certificate, e.g. a self-signed one
+    #[arg(long)]
+    skip_tls_verify: bool,
+
+    /// Seconds to wait for a connection to the registry (defaults to the config file's, or 30; 0 waits forever)
+    #[arg(long)]
+    connect_timeout: Option<u64>,
+
+    /// Seconds to wait for the registry to answer or send more data (defaults to the config file's, or 120)
+    #[arg(long)]
+    read_timeout: Option<u64>,
+
+    /// Verbose output
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+#[derive(clap::Args)]
+struct RemoteCatArgs {
+    /// Image to read (including registry URL), by tag or @sha256:<digest>
+    image_name: String,
+
+    /// File to print, e.g. /etc/os-release; symlinks are followed
+    path: String,
+
+    /// Write the file here instead of to stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    /// Platform to read from a multi-platform image, as os/arch[/variant] (defaults to the host's)
+    #[arg(long)]
+    platform: Option<String>,
+
+    /// Talk to the registry over plain HTTP
+    #[arg(long)]
+    insecure: bool,
+
+    /// Talk to the registry over TLS without verifying its certificate, e.g. a self-signed one
+    #[arg(long)]
+    skip_tls_verify: bool,
+
+    /// Seconds to wait for a connection to the registry (defaults to the config file's, or 30; 0 waits forever)
+    #[arg(long)]
+    connect_timeout: Option<u64>,
+
+    /// Seconds to wait for the registry to answer or send more data (defaults to the config file's, or 120)
+    #[arg(long)]
+    read_timeout: Option<u64>,
+
+    /// Verbose output
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+#[derive(clap::Args)]
+struct LogsArgs {
+    /// Build to show, by id or unique id prefix (defaults to the most recent build)
+    build_id: Option<String>,
+
+    /// Only show this step, by its number in the progress output
+    #[arg(long)]
+    step: Option<usize>,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct ImagesArgs {
+    /// Output format (table or json)
+    #[arg(long, default_value = "table")]
+    format: String,
+
+    /// Columns to show, comma separated (repository, tag, id, created, size)
+    #[arg(long, value_delimiter = ',')]
+    columns: Vec<String>,
+
+    /// Only list images matching label=KEY, label=KEY=VALUE or reference=PATTERN (can be repeated; all must match)
+    #[arg(long = "filter", value_name = "FILTER")]
+    filters: Vec<String>,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct RmiArgs {
+    /// Images to remove, by name, ID or manifest digest
+    #[arg(required = true)]
+    images: Vec<String>,
+
+    /// Delete the images' manifests from their registries instead, which removes every tag pointing at them there
+    #[arg(long)]
+    remote: bool,
+
+    /// Talk to the registry over plain HTTP (with --remote)
+    #[arg(long, requires = "remote")]
+    insecure: bool,
+
+    /// Talk to the registry over TLS without verifying its certificate (with --remote)
+    #[arg(long, requires = "remote")]
+    skip_tls_verify: bool,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct TagArgs {
+    /// Existing image, by name, ID or manifest digest
+    source: String,
+
+    /// New name for it
+    target: String,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct GcArgs {
+    /// List what would be removed without removing it
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct PruneArgs {
+    /// Also remove images not built or used for this long (e.g. 72h)
+    #[arg(long)]
+    until: Option<String>,
+
+    /// Remove least recently used images until the store fits in this size (e.g. 10GB)
+    #[arg(long)]
+    keep_storage: Option<String>,
+
+    /// List what would be removed without removing it
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct DfArgs {
+    /// Output format (table or json)
+    #[arg(long, default_value = "table")]
+    format: String,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct VerifyArgs {
+    /// Delete corrupt blobs and the images and indexes that need them
+    #[arg(long)]
+    repair: bool,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct SaveArgs {
+    /// Format: oci (an OCI layout tar), oci-dir (an OCI layout directory) or docker (a `docker load` archive)
+    #[arg(long, default_value = "oci")]
+    format: String,
+
+    /// Image to save, by name, ID or manifest digest
+    image: String,
+
+    /// Archive or directory to write
+    dest: PathBuf,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct ExportArgs {
+    /// Image to export, by name, ID or manifest digest
+    image: String,
+
+    /// Tar file or directory to write the filesystem to
+    #[arg(long)]
+    dest: PathBuf,
+
+    /// Format: tar or dir (defaults to tar when --dest ends in .tar, dir otherwise)
+    #[arg(long)]
+    format: Option<String>,
+
+    /// Platform to export from a multi-platform image, as os/arch[/variant] (defaults to the host's)
+    #[arg(long)]
+    platform: Option<String>,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct DiveArgs {
+    /// Image to explore, by name, ID or manifest digest
+    image: String,
+
+    /// Output: tui, text or json (defaults to tui on a terminal, text otherwise)
+    #[arg(long)]
+    format: Option<String>,
+
+    /// Fail when the image efficiency score is below this ratio, e.g. 0.95 in CI
+    #[arg(long)]
+    lowest_efficiency: Option<f64>,
+
+    /// Platform to explore from a multi-platform image, as os/arch[/variant] (defaults to the host's)
+    #[arg(long)]
+    platform: Option<String>,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct DiffArgs {
+    /// Image to compare from, by name, ID or manifest digest; pulled if not stored locally
+    old: String,
+
+    /// Image to compare to, by name, ID or manifest digest; pulled if not stored locally
+    new: String,
+
+    /// Output format (text or json)
+    #[arg(long, default_value = "text")]
+    format: String,
+
+    /// Platform to compare from multi-platform images, as os/arch[/variant] (defaults to the host's)
+    #[arg(long)]
+    platform: Option<String>,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    /// Pull over plain HTTP
+    #[arg(long)]
+    insecure: bool,
+
+    /// Pull over TLS without verifying the registry's certificate, e.g. a self-signed one
+    #[arg(long)]
+    skip_tls_verify: bool,
+
+    /// Verbose output
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+#[derive(clap::Args)]
+struct ComposeArgs {
+    #[command(subcommand)]
+    command: ComposeCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum ComposeCommand {
+    /// Build every service with a build section, in parallel unless one builds FROM another's image
+    Build(ComposeBuildArgs),
+}
+
+#[derive(clap::Args)]
+struct ComposeBuildArgs {
+    /// Services to build (defaults to every service with a build section)
+    services: Vec<String>,
+
+    /// Compose file (defaults to compose.yaml, compose.yml, docker-compose.yaml or docker-compose.yml)
+    #[arg(short, long)]
+    file: Option<PathBuf>,
+
+    /// How many services to build at once (defaults to all that are ready)
+    #[arg(long)]
+    parallel: Option<usize>,
+
+    /// Build argument for every service, as KEY=VALUE, overriding the Compose file's
+    #[arg(long)]
+    build_arg: Vec<String>,
+
+    /// Do not use the build cache
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Pull every base image, even ones already stored
+    #[arg(long)]
+    pull: bool,
+
+    /// Push each service's image and tags once built
+    #[arg(long)]
+    push: bool,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    /// Config file with build hooks (default: $HYPERBUILD_CONFIG, ./hyperbuild.toml or ~/.config/hyperbuild/config.toml)
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Verbose output
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+#[derive(clap::Args)]
+struct BakeArgs {
+    /// Targets or groups to build (defaults to the "default" group)
+    targets: Vec<String>,
+
+    /// Bake file, HCL or JSON; can be repeated, later files overriding earlier ones (defaults to every one of
+    /// docker-bake.json, docker-bake.override.json, docker-bake.hcl and docker-bake.override.hcl found here)
+    #[arg(short, long)]
+    file: Vec<PathBuf>,
+
+    /// Print the resolved targets as JSON instead of building them
+    #[arg(long)]
+    print: bool,
+
+    /// How many targets to build at once (defaults to all that are ready)
+    #[arg(long)]
+    parallel: Option<usize>,
+
+    /// Build argument for every target, as KEY=VALUE, overriding the bake file's
+    #[arg(long)]
+    build_arg: Vec<String>,
+
+    /// Do not use the build cache
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Pull every base image, even ones already stored
+    #[arg(long)]
+    pull: bool,
+
+    /// Push each target's tags once built
+    #[arg(long)]
+    push: bool,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    /// Config file with build hooks (default: $HYPERBUILD_CONFIG, ./hyperbuild.toml or ~/.config/hyperbuild/config.toml)
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Verbose output
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+#[derive(clap::Args)]
+struct RunArgs {
+    /// Image to run, by name, ID or manifest digest
+    image: String,
+
+    /// Command to run instead of the image's CMD
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
+
+    /// Set an environment variable; KEY alone passes on the host's value (can be repeated)
+    #[arg(short, long, value_name = "KEY[=VALUE]")]
+    env: Vec<String>,
+
+    /// Bind-mount a host file or directory into the container (can be repeated)
+    #[arg(short, long, value_name = "SOURCE:TARGET[:ro]")]
+    volume: Vec<String>,
+
+    /// Forward a host TCP port to a container port (can be repeated)
+    #[arg(short, long, value_name = "HOST:CONTAINER")]
+    publish: Vec<String>,
+
+    /// Working directory in the container, instead of the image's WORKDIR
+    #[arg(short, long)]
+    workdir: Option<String>,
+
+    /// User to run as, as name or uid and optionally :group, instead of the image's USER
+    #[arg(short, long)]
+    user: Option<String>,
+
+    /// Program to run instead of the image's ENTRYPOINT; an empty string clears it
+    #[arg(long)]
+    entrypoint: Option<String>,
+
+    /// What runs the container: auto (runc or crun when installed, else chroot), chroot, or an OCI runtime's path
+    #[arg(long, default_value = "auto")]
+    runtime: String,
+
+    /// Platform to run from a multi-platform image, as os/arch[/variant] (defaults to the host's)
+    #[arg(long)]
+    platform: Option<String>,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    /// Verbose output (-v is --volume here)
+    #[arg(long, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+#[derive(clap::Args)]
+struct StoreArgs {
+    #[command(subcommand)]
+    command: StoreCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum StoreCommand {
+    /// Write every image, name and the blobs they use to a tar, e.g. to keep a CI cache warm
+    Backup(StoreBackupArgs),
+
+    /// Add the images and names of a backup tar to the store
+    Restore(StoreRestoreArgs),
+
+    /// Delete layer files left under legacy UUID names that duplicate a stored blob
+    Dedupe(StoreDedupeArgs),
+}
+
+#[derive(clap::Args)]
+struct StoreBackupArgs {
+    /// Tar file to write
+    archive: PathBuf,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct StoreRestoreArgs {
+    /// Tar file written by `store backup`
+    archive: PathBuf,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct StoreDedupeArgs {
+    /// List the duplicates and the space they take without deleting them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct CacheArgs {
+    #[command(subcommand)]
+    command: CacheCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum CacheCommand {
+    /// List the build steps later builds can reuse
+    Ls(CacheLsArgs),
+
+    /// Remove build cache records and the layers only they keep
+    Prune(CachePruneArgs),
+}
+
+#[derive(clap::Args)]
+struct CacheLsArgs {
+    /// Output format (table or json)
+    #[arg(long, default_value = "table")]
+    format: String,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct CachePruneArgs {
+    /// Only remove records not used for this long (e.g. 72h)
+    #[arg(long)]
+    until: Option<String>,
+
+    /// List what would be removed without removing it
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct LoadArgs {
+    /// OCI image layout or `docker save` archive, as a directory or tar; with --to containerd or docker, a stored
+    /// image may be named instead
+    input: PathBuf,
+
+    /// Name for the loaded image, instead of the one recorded in the layout
+    #[arg(short, long)]
+    tag: Option<String>,
+
+    /// Where to load the image: local (the image store), containerd (its content store and snapshotter, by its
+    /// API, so nerdctl and the kubelet can run it) or docker (the Docker daemon, for `docker run`)
+    #[arg(long, default_value = "local")]
+    to: String,
+
+    /// containerd or Docker socket (defaults to the config file's [containerd] address, $CONTAINERD_ADDRESS or
+    /// /run/containerd/containerd.sock for containerd, a unix:// $DOCKER_HOST or /var/run/docker.sock for Docker)
+    #[arg(long, value_name = "SOCKET")]
+    address: Option<PathBuf>,
+
+    /// containerd namespace: k8s.io for the kubelet (defaults to the config file's, $CONTAINERD_NAMESPACE or default)
+    #[arg(short, long)]
+    namespace: Option<String>,
+
+    /// Snapshotter the layers are unpacked with (defaults to the config file's, $CONTAINERD_SNAPSHOTTER or overlayfs)
+    #[arg(long)]
+    snapshotter: Option<String>,
+
+    /// Variant of a multi-platform image to load into containerd or Docker (defaults to the host's)
+    #[arg(long)]
+    platform: Option<String>,
+
+    /// Directory holding the image store (defaults to $HYPERBUILD_ROOT, the config file's root, or ~/.local/share/hyperbuild)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    match Args::parse() {
+        Args::Build(args) => build_command(*args).await,
+        Args::Push(args) => push_command(args).await,
+        Args::Pull(args) => pull_command(args).await,
+        Args::Tags(args) => tags_command(args).await,
+        Args::Copy(args) => copy_command(args).await,
+        Args::Manifest(args) => match args.command {
+            ManifestCommand::Inspect(args) => manifest_inspect_command(args).await,
+        },
+        Args::Artifact(args) => match args.command {
+            ArtifactCommand::Push(args) => artifact_push_command(args).await,
+            ArtifactCommand::Pull(args) => artifact_pull_command(args).await,
+        },
+        Args::Remote(args) => match args.command {
+            RemoteCommand::Ls(args) => remote_ls_command(args).await,
+            RemoteCommand::Cat(args) => remote_cat_command(args).await,
+        },
+        Args::Logs(args) => logs_command(args),
+        Args::Images(args) => images_command(args).await,
+        Args::Rmi(args) => rmi_command(args).await,
+        Args::Tag(args) => tag_command(args).await,
+        Args::Gc(args) => gc_command(args).await,
+        Args::Prune(args) => prune_command(args).await,
+        Args::Df(args) => df_command(args).await,
+        Args::Verify(args) => verify_command(args).await,
+        Args::Save(args) => save_command(args).await,
+        Args::Load(args) => load_command(args).await,
+        Args::Export(args) => export_command(args).await,
+        Args::Run(args) => run_command(args).await,
+        Args::Dive(args) => dive_command(args).await,
+        Args::Diff(args) => diff_command(args).await,
+        Args::Compose(args) => match args.command {
+            ComposeCommand::Build(args) => compose_build_command(args).await,
+        },
+        Args::Bake(args) => bake_command(args).await,
+        Args::Store(args) => match args.command {
+            StoreCommand::Backup(args) => store_backup_command(args).await,
+            StoreCommand::Restore(args) => store_restore_command(args).await,
+            StoreCommand::Dedupe(args) => store_dedupe_command(args).await,
+        },
+        Args::Cache(args) => match args.command {
+            CacheCommand::Ls(args) => cache_ls_command(args).await,
+            CacheCommand::Prune(args) => cache_prune_command(args).await,
+        },
+    }
+}
+
+async fn build_command(args: BuildArgs) -> Result<()> {
+    // JSON progress owns stdout, so logs go to stderr
+    logging::init(args.verbose, &args.log_format, args.progress == "json")?;
+    if args.watch {
+        return watch_build(&args).await;
+    }
+    build_once(&args, &Metrics::default()).await.map(|_| ())
+}
+
+// Build, then build again after every change to the context until Ctrl+C
+async fn watch_build(args: &BuildArgs) -> Result<()> {
+    let settings = Settings::load(args.config.as_deref())?;
+    let mut watcher = ContextWatcher::new(&args.context, &args.dockerfile)?
+        .exclude(&settings.store_root(args.output_dir.clone()));
+    // Nothing the build writes may count as a change, or it would never stop
+    let outputs = [
+        args.output.as_deref().map(BuildOutput::parse).transpose()?.map(|output| output.dest().to_path_buf()),
+        args.report.clone(),
+        args.metrics_file.clone(),
+    ];
+    for path in outputs.iter().flatten() {
+        watcher = watcher.exclude(path);
+    }
+
+    // The counters add up over every build of the watch
+    let metrics = Metrics::default();
+    if let Some(addr) = args.metrics_addr {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .map_err(|e| anyhow::anyhow!("Cannot serve metrics on {}: {}", addr, e))?;
+        eprintln!("Serving metrics at http://{}/metrics", listener.local_addr()?);
+        let server = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server.serve(listener).await {
+                tracing::warn!("Stopped serving metrics: {}", e);
+            }
+        });
+    }
+
+    loop {
+        match build_once(args, &metrics).await {
+            Ok(build) => eprintln!("Image ID: {}", build.id()),
+            // A failed build waits for the change that fixes it
+            Err(e) => eprintln!("Error: {:#}", e),
+        }
+        eprintln!("Watching {} for changes (Ctrl+C to stop)", args.context.display());
+        let changed = tokio::select! {
+            changed = watcher.changed() => changed?,
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        };
+        match changed.as_slice() {
+            [path] => eprintln!("\n{} changed, rebuilding", path.display()),
+            [path, rest @ ..] => eprintln!("\n{} and {} more changed, rebuilding", path.display(), rest.len()),
+            [] => {}
+        }
+    }
+}
+
+async fn build_once(args: &BuildArgs, metrics: &Metrics) -> Result<Build> {
+    tracing::info!("Starting Rust container builder");
+    tracing::info!("Context: {:?}", args.context);
+    tracing::info!("Dockerfile: {:?}", args.dockerfile);
+    tracing::info!("Image name: {}", args.tags.join(", "));
+
+    let settings = Settings::load(args.config.as_deref())?;
+    let ci = Ci::detect(args.ci.as_deref())?;
+    if !matches!(args.store.as_str(), "local" | "containerd") {
+        return Err(anyhow::anyhow!("Unknown --store {:?}: expected local or containerd", args.store));
+    }
+
+    let compression = match &args.compression {
+        Some(spec) => settings.compression.with_flag(spec)?,
+        None => settings.compression,
+    };
+
+    // Initialize storage manager
+    let storage = StorageManager::new(settings.store_root(args.output_dir.clone()))?
+        .with_compression(compression)
+        .with_encryption(settings.encryption.key()?);
+    storage.init().await?;
+
+    let platforms = args
+        .platform
+        .iter()
+        .map(|spec| Platform::parse(spec))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Verbose logs share stdout with the progress display, so they need plain output
+    let progress_mode = match ProgressMode::parse(&args.progress)? {
+        ProgressMode::Auto if args.verbose > 0 => ProgressMode::Plain,
+        mode => mode,
+    };
+    let (progress, mut events) = Progress::channel();
+    let (display, display_events) = tokio::sync::mpsc::unbounded_channel();
+    let renderer = tokio::spawn(progress::render(progress_mode, ci, display_events));
+
+    // Every build is recorded with its step logs, for `logs` to show later
+    let build_id = format!("build_{}", uuid::Uuid::new_v4());
+    let mut recorder = BuildRecorder::create(&storage.builds_dir(), &build_id)?;
+    let recording = tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            if let Err(e) = recorder.record(&event) {
+                tracing::warn!("Failed to record build progress: {}", e);
+            }
+            let _ = display.send(event);
+        }
+        recorder.finish()
+    });
+
+    let push_progress = args.push.then(|| progress.clone());
+    let options = BuildOptions {
+        inline_cache: match &args.cache_to {
+            Some(spec) => parse_cache_to(spec)?,
+            None => false,
+        },
+        cache_from: args.cache_from.clone(),
+        no_cache: args.no_cache,
+        build_id: Some(build_id.clone()),
+        parallelism: args.parallelism,
+        build_args: collect_build_args(&args.build_arg, &args.build_arg_file)?,
+        secrets: args
+            .secret
+            .iter()
+            .map(|spec| parse_secret(spec))
+            .collect::<Result<HashMap<_, _>>>()?,
+        source_date_epoch: if args.reproducible {
+            Some(source_date_epoch()?)
+        } else {
+            None
+        },
+        network: NetworkMode::parse(&args.network)?,
+        extra_hosts: args
+            .add_host
+            .iter()
+            .map(|spec| parse_add_host(spec))
+            .collect::<Result<Vec<_>>>()?,
+        step_limits: StepLimits {
+            memory_bytes: args.memory.as_deref().map(parse_size).transpose()?,
+            cpus: args.cpus,
+            timeout: args
+                .step_timeout
+                .as_deref()
+                .map(|spec| parse_duration(spec).map(std::time::Duration::from_nanos))
+                .transpose()?,
+        },
+        retries: args.retry,
+        squash: args.squash || args.squash_from.is_some(),
+        squash_from: args.squash_from.clone(),
+        output: args.output.as_deref().map(BuildOutput::parse).transpose()?,
+        progress,
+        hooks: Hooks::new(settings.hooks, Some(build_id.clone())),
+        plugins: Plugins::new(settings.plugins),
+        sbom: args.sbom.as_deref().map(SbomFormat::parse).transpose()?,
+        pull: PullPolicy::parse(&args.pull)?,
+        labels: args
+            .label
+            .iter()
+            .map(|spec| parse_label(spec))
+            .collect::<Result<BTreeMap<_, _>>>()?,
+        registry: settings.registry.clone(),
+        metrics: metrics.clone(),
+        ..BuildOptions::default()
+    };
+
+    let pushed_from = storage.clone_for_build();
+    let mut request = BuildRequest::new(&args.context)
+        .dockerfile(&args.dockerfile)
+        .tags(args.tags.iter().cloned())
+        .store(storage)
+        .options(options);
+    for platform in platforms {
+        request = request.platform(platform);
+    }
+
+    // The first Ctrl+C cancels the build and lets it clean up, a second one exits at once
+    let cancel = request.cancellation_token();
+    let on_interrupt = cancel.clone();
+    let interrupt_handler = tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("Cancelling build (press Ctrl+C again to exit immediately)");
+            on_interrupt.cancel();
+        }
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+
+    // Build the image
+    let built = async {
+        let build = request.run().await?;
+        match &build {
+            Build::Index(list) => {
+                tracing::info!("Successfully built image index: {}", list.name);
+                tracing::info!("Platforms: {}", args.platform.join(", "));
+            }
+            Build::Image(image) => {
+                tracing::info!("Successfully built image: {}", image.name);
+                tracing::info!("Image ID: {}", image.id);
+                tracing::info!("Number of layers: {}", image.layers.len());
+            }
+        }
+        if let Some(progress) = &push_progress {
+            let registry = &settings.registry;
+            push_built(progress, metrics, &pushed_from, registry, &args.tags, &build, args.attach_sbom).await?;
+        }
+        if args.store == "containerd" {
+            load_built_into_containerd(&pushed_from, &settings.containerd, &args.tags, &build).await?;
+        }
+        Ok::<Build, anyhow::Error>(build)
+    }
+    .await;
+    // Under --watch, a Ctrl+C from here on stops watching instead
+    interrupt_handler.abort();
+    write_metrics(metrics, args.metrics_file.as_deref());
+
+    // The record and display finish once the build's progress handles are gone
+    drop(push_progress);
+    let recorded = match recording.await? {
+        Ok(report) => Some(report),
+        Err(e) => {
+            tracing::warn!("Failed to write the build report: {}", e);
+            None
+        }
+    };
+    let _ = renderer.await;
+    if let Some(report) = recorded {
+        eprintln!("Build ID: {}", build_id);
+        if let Some(path) = &args.report {
+            std::fs::write(path, serde_json::to_vec_pretty(&report)?)?;
+            eprint!("\n{}", report::render_table(&report));
+        }
+    }
+
+    if built.is_err() && cancel.is_cancelled() {
+        eprintln!("Build cancelled");
+        std::process::exit(130);
+    }
+    if let Some(ci) = ci {
+        match &built {
+            Ok(build) => ci.set_outputs(&[("imageid", build.id()), ("digest", &build.digest()?)])?,
+            Err(e) => eprintln!("{}", ci.error(e, &args.dockerfile)),
+        }
+    }
+    built
+}
+
+// Load the built image into containerd under each of its tags (`--store
+// containerd`); of a multi-platform build, the host's platform is loaded
+async fn load_built_into_containerd(
+    storage: &StorageManager,
+    settings: &ContainerdSettings,
+    tags: &[String],
+    build: &Build,
+) -> Result<()> {
+    let image = match build {
+        Build::Image(image) => image.clone(),
+        Build::Index(list) => stored_image_for_platform(storage, &list.name, &Platform::host()).await?,
+    };
+    let client = ContainerdClient::connect(settings).await?;
+    for tag in tags {
+        let loaded = client.import(&image, tag).await?;
+        eprintln!("Loaded image: {} (containerd namespace {})", loaded, client.namespace());
+    }
+    Ok(())
+}
+
+// Push what was just built under every tag, without looking it up or building it again
+async fn push_built(
+    progress: &Progress,
+    metrics: &Metrics,
+    storage: &StorageManager,
+    registry: &RegistrySettings,
+    tags: &[String],
+    built: &Build,
+    attach_sbom: bool,
+) -> Result<()> {
+    let images = built.images();
+    for tag in tags {
+        let client = RegistryClient::new(Reference::parse(tag)?.registry_url())?
+            .with_settings(registry.clone())?
+            .with_progress(progress.clone())
+            .with_metrics(metrics.clone());
+        let step = progress.start(format!("pushing {}", tag));
+        let pushed = async {
+            client.ping().await?;
+            client.learn_mount_sources(storage).await?;
+            match built {
+                Build::Image(image) if registry.estargz => {
+                    client.push_image(tag, &storage.estargz_image(image).await?).await?
+                }
+                Build::Image(image) => client.push_image(tag, image).await?,
+                Build::Index(list) if registry.estargz => {
+                    client.push_index(tag, &storage.estargz_list(list).await?).await?
+                }
+                Build::Index(list) => client.push_index(tag, list).await?,
+            };
+            if attach_sbom {
+                attach_sboms(&client, storage, tag, &images).await?;
+            }
+            Ok(())
+        }
+        .await;
+        match &pushed {
+            Ok(()) => step.done(),
+            Err(e) => step.failed(e),
+        }
+        pushed?;
+    }
+    Ok(())
+}
+
+// Attach each image's stored SBOM to its pushed manifest
+async fn attach_sboms(client: &RegistryClient, storage: &StorageManager, tag: &str, images: &[&Image]) -> Result<()> {
+    for image in images {
+        match storage.get_sbom(&image.id).await? {
+            Some((media_type, sbom)) => client.push_referrer(tag, &image.manifest, &media_type, &sbom).await?,
+            None => tracing::warn!("Image {} has no SBOM to attach; build it with --sbom", image.id),
+        }
+    }
+    Ok(())
+}
+
+// Write a command's metrics for node_exporter's textfile collector, whether it succeeded or not
+fn write_metrics(metrics: &Metrics, path: Option<&Path>) {
+    if let Some(path) = path
+        && let Err(e) = metrics.write_textfile(path)
+    {
+        tracing::warn!("Failed to write metrics to {}: {}", path.display(), e);
+    }
+}
+
+async fn push_command(args: PushArgs) -> Result<()> {
+    logging::init(args.verbose, &args.log_format, false)?;
+
+    tracing::info!("Starting push operation");
+    tracing::info!("Image name: {}", args.image_name);
+
+    // Extract registry URL from image name
+    let registry_url = Reference::parse(&args.image_name)?.registry_url();
+    tracing::info!("Target registry: {}", registry_url);
+
+    // Initialize storage manager
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+
+    // Create registry client; layers the registry holds in another repository
+    // are mounted from there
+    let platforms = args
+        .platform
+        .iter()
+        .map(|spec| Platform::parse(spec))
+        .collect::<Result<Vec<_>>>()?;
+    // A key that cannot be loaded fails the push before anything is uploaded
+    let signing_key = args.sign.as_deref().map(SigningKey::parse).transpose()?;
+    let mut registry = registry_settings(&registry_url, args.parallel, args.insecure, args.skip_tls_verify)?;
+    registry.http = registry.http.with_timeouts(args.connect_timeout, args.read_timeout);
+    if let Some(spec) = &args.media_types {
+        registry.media_types = MediaTypes::parse(spec)?;
+    }
+    registry.estargz |= args.estargz;
+    let estargz = registry.estargz;
+    let (progress, renderer) = transfer_progress(args.verbose);
+    let metrics = Metrics::default();
+    let client = RegistryClient::new(registry_url)?
+        .with_settings(registry.clone())?
+        .with_progress(progress)
+        .with_metrics(metrics.clone());
+    let pushed = async {
+        client.ping().await?;
+        client.learn_mount_sources(&storage).await?;
+
+        // Multi-platform images are pushed as an index over the platform manifests
+        if let Some(list) = storage.get_image_list_by_name(&args.image_name).await? {
+            let list = if estargz { storage.estargz_list(&list).await? } else { list };
+            let digest = client.push_index(&args.image_name, &list).await?;
+            if args.attach_sbom {
+                let images: Vec<&Image> = list.images.iter().collect();
+                attach_sboms(&client, &storage, &args.image_name, &images).await?;
+            }
+            return Ok(digest);
+        }
+
+        // Check if image exists in storage, if not build it
+        let image = if let Some(stored_image) = storage.get_image_by_name(&args.image_name).await? {
+            tracing::info!("Found existing image in storage, using it for push");
+            stored_image
+        } else if Reference::parse(&args.image_name)?.digest().is_some() {
+            // A build could never produce the exact manifest a digest names
+            return Err(anyhow::anyhow!("No such image: {}", args.image_name));
+        } else {
+            tracing::info!("Image not found in storage, building it first");
+            let options = BuildOptions {
+                platform: platforms.first().cloned(),
+                registry,
+                metrics: metrics.clone(),
+                ..Default::default()
+            };
+            let mut engine = BuildEngine::with_options(storage.clone_for_build(), args.context, options);
+            if platforms.len() > 1 {
+                // Published under the one tag as an index of the platform images
+                let list = engine.build_image_list(&args.dockerfile, &args.image_name, &platforms).await?;
+                let list = if estargz { storage.estargz_list(&list).await? } else { list };
+                let digest = client.push_index(&args.image_name, &list).await?;
+                if args.attach_sbom {
+                    let images: Vec<&Image> = list.images.iter().collect();
+                    attach_sboms(&client, &storage, &args.image_name, &images).await?;
+                }
+                return Ok(digest);
+            }
+            engine.build_image(&args.dockerfile, &args.image_name).await?
+        };
+
+        // Push the image, its layers rewritten as eStargz if asked
+        let image = if estargz { storage.estargz_image(&image).await? } else { image };
+        let digest = client.push_image(&args.image_name, &image).await?;
+        if args.attach_sbom {
+            attach_sboms(&client, &storage, &args.image_name, &[&image]).await?;
+        }
+        Ok(digest)
+    }
+    .await;
+    let signed = match (&pushed, &signing_key) {
+        (Ok(digest), Some(key)) => client.sign_image(&args.image_name, digest, key).await,
+        _ => Ok(()),
+    };
+    write_metrics(&metrics, args.metrics_file.as_deref());
+    // The display ends once every progress handle is gone
+    drop(client);
+    let _ = renderer.await;
+    let digest = pushed?;
+    println!("Pushed {}", args.image_name);
+    signed?;
+    if signing_key.is_some() {
+        println!("Signed {} ({})", args.image_name, digest);
+    }
+    tracing::info!("Successfully pushed image: {}", args.image_name);
+    Ok(())
+}
+
+async fn pull_command(args: PullArgs) -> Result<()> {
+    logging::init(args.verbose, &args.log_format, false)?;
+
+    tracing::info!("Starting pull operation");
+    tracing::info!("Image name: {}", args.image_name);
+
+    // Extract registry URL from image name
+    let registry_url = Reference::parse(&args.image_name)?.registry_url();
+    tracing::info!("Source registry: {}", registry_url);
+
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+    let platform = match &args.platform {
+        Some(spec) => Platform::parse(spec)?,
+        None => Platform::host(),
+    };
+
+    // Create registry client
+    let mut registry = registry_settings(&registry_url, args.parallel, args.insecure, args.skip_tls_verify)?;
+    registry.http = registry.http.with_timeouts(args.connect_timeout, args.read_timeout);
+    let (progress, renderer) = transfer_progress(args.verbose);
+    let metrics = Metrics::default();
+    let mut client = RegistryClient::new(registry_url)?
+        .with_settings(registry)?
+        .with_progress(progress)
+        .with_metrics(metrics.clone());
+    if let Some(spec) = &args.verify_signature {
+        client = client.with_verifier(Verifier::parse(spec)?);
+    }
+
+    // Pull the image into the store, where builds and push find it by name
+    let pulled = async {
+        client.ping().await?;
+        client.pull_image_to_storage(&args.image_name, &storage, &platform).await
+    }
+    .await;
+    write_metrics(&metrics, args.metrics_file.as_deref());
+    drop(client);
+    let _ = renderer.await;
+    let image = pulled?;
+    println!("Pulled {} as {}", args.image_name, image.id);
+
+    tracing::info!("Successfully pulled image: {}", args.image_name);
+    Ok(())
+}
+
+async fn tags_command(args: TagsArgs) -> Result<()> {
+    let registry_url = Reference::parse(&args.repository)?.registry_url();
+    let registry = registry_settings(&registry_url, None, args.insecure, args.skip_tls_verify)?;
+    let client = RegistryClient::new(registry_url)?.with_settings(registry)?;
+    let tags = client.list_tags(&args.repository).await?;
+
+    match args.format.as_str() {
+        "text" => tags.iter().for_each(|tag| println!("{}", tag)),
+        "json" => println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({ "repository": args.repository, "tags": tags }))?
+        ),
+        other => return Err(anyhow::anyhow!("Unknown format {:?}: expected text or json", other)),
+    }
+    Ok(())
+}
+
+async fn copy_command(args: CopyArgs) -> Result<()> {
+    // Initialize tracing
+    if args.verbose > 0 {
+        let level = match args.verbose {
+            1 => "info",
+            2 => "debug",
+            _ => "trace",
+        };
+        unsafe {
+            std::env::set_var("RUST_LOG", level);
+        }
+    }
+    tracing_subscriber::fmt::init();
+
+    let (progress, renderer) = transfer_progress(args.verbose);
+    let mut clients = Vec::new();
+    for name in [&args.source, &args.destination] {
+        let registry_url = Reference::parse(name)?.registry_url();
+        let mut registry = registry_settings(&registry_url, args.parallel, args.insecure, args.skip_tls_verify)?;
+        registry.http = registry.http.with_timeouts(args.connect_timeout, args.read_timeout);
+        clients.push(RegistryClient::new(registry_url)?.with_settings(registry)?.with_progress(progress.clone()));
+    }
+    drop(progress);
+    let copied = async {
+        for client in &clients {
+            client.ping().await?;
+        }
+        clients[0].copy_image(&args.source, &clients[1], &args.destination).await
+    }
+    .await;
+    drop(clients);
+    let _ = renderer.await;
+    println!("Copied {} to {} ({})", args.source, args.destination, copied?);
+    Ok(())
+}
+
+async fn artifact_push_command(args: ArtifactPushArgs) -> Result<()> {
+    // Initialize tracing
+    if args.verbose > 0 {
+        let level = match args.verbose {
+            1 => "info",
+            2 => "debug",
+            _ => "trace",
+        };
+        unsafe {
+            std::env::set_var("RUST_LOG", level);
+        }
+    }
+    tracing_subscriber::fmt::init();
+
+    let mut files: Vec<ArtifactFile> = Vec::new();
+    for spec in &args.files {
+        let (path, media_type) = parse_artifact_file(spec, "application/vnd.oci.image.layer.v1.tar");
+        let title = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or_else(|| anyhow::anyhow!("{:?} has no file name to title it with", path))?;
+        if files.iter().any(|file| file.title == title) {
+            return Err(anyhow::anyhow!("More than one file is named {}; artifact files need distinct names", title));
+        }
+        files.push(ArtifactFile { path, title, media_type });
+    }
+    let config = match &args.config {
+        Some(spec) => {
+            let (path, media_type) = parse_artifact_file(spec, "application/vnd.oci.image.config.v1+json");
+            let data = std::fs::read(&path).map_err(|e| anyhow::anyhow!("Failed to read {:?}: {}", path, e))?;
+            Some((data, media_type))
+        }
+        None => None,
+    };
+    let annotations: BTreeMap<String, String> = args
+        .annotations
+        .iter()
+        .map(|spec| match spec.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+            _ => Err(anyhow::anyhow!("Invalid --annotation {:?}: expected KEY=VALUE", spec)),
+        })
+        .collect::<Result<_>>()?;
+
+    let registry_url = Reference::parse(&args.reference)?.registry_url();
+    let mut registry = registry_settings(&registry_url, args.parallel, args.insecure, args.skip_tls_verify)?;
+    registry.http = registry.http.with_timeouts(args.connect_timeout, args.read_timeout);
+    let (progress, renderer) = transfer_progress(args.verbose);
+    let client = RegistryClient::new(registry_url)?.with_settings(registry)?.with_progress(progress);
+    let pushed = async {
+        client.ping().await?;
+        client.push_artifact(&args.reference, &args.artifact_type, config, &files, &annotations).await
+    }
+    .await;
+    drop(client);
+    let _ = renderer.await;
+    println!("Pushed {} ({})", args.reference, pushed?);
+    Ok(())
+}
+
+async fn artifact_pull_command(args: ArtifactPullArgs) -> Result<()> {
+    // Initialize tracing
+    if args.verbose > 0 {
+        let level = match args.verbose {
+            1 => "info",
+            2 => "debug",
+            _ => "trace",
+        };
+        unsafe {
+            std::env::set_var("RUST_LOG", level);
+        }
+    }
+    tracing_subscriber::fmt::init();
+
+    let registry_url = Reference::parse(&args.reference)?.registry_url();
+    let mut registry = registry_settings(&registry_url, args.parallel, args.insecure, args.skip_tls_verify)?;
+    registry.http = registry.http.with_timeouts(args.connect_timeout, args.read_timeout);
+    let (progress, renderer) = transfer_progress(args.verbose);
+    let client = RegistryClient::new(registry_url)?.with_settings(registry)?.with_progress(progress);
+    let pulled = async {
+        client.ping().await?;
+        client.pull_artifact(&args.reference, &args.output).await
+    }
+    .await;
+    drop(client);
+    let _ = renderer.await;
+    let (digest, paths) = pulled?;
+    for path in &paths {
+        println!("{}", path.display());
+    }
+    println!("Pulled {} files from {} ({}) into {}", paths.len(), args.reference, digest, args.output.display());
+    Ok(())
+}
+
+async fn remote_ls_command(args: RemoteLsArgs) -> Result<()> {
+    // Initialize tracing
+    if args.verbose > 0 {
+        let level = match args.verbose {
+            1 => "info",
+            2 => "debug",
+            _ => "trace",
+        };
+        unsafe {
+            std::env::set_var("RUST_LOG", level);
+        }
+    }
+    // Files and listings own stdout, so logs go to stderr
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+    let platform = match &args.platform {
+        Some(spec) => Platform::parse(spec)?,
+        None => Platform::host(),
+    };
+    let registry_url = Reference::parse(&args.image_name)?.registry_url();
+    let mut registry = registry_settings(&registry_url, None, args.insecure, args.skip_tls_verify)?;
+    registry.http = registry.http.with_timeouts(args.connect_timeout, args.read_timeout);
+    let client = RegistryClient::new(registry_url)?.with_settings(registry)?;
+    client.ping().await?;
+    let image = client.open_lazy(&args.image_name, &storage, &platform).await?;
+
+    // A directory lists everything under it
+    let prefix = args.path.as_deref().map(clean_toc_name).unwrap_or_default();
+    let mut listed = 0;
+    for (name, (_, entry)) in &image.files {
+        let under = name.strip_prefix(&prefix).is_some_and(|rest| rest.starts_with('/'));
+        if prefix.is_empty() || *name == prefix || under {
+            println!("{}", file_line(name, entry));
+            listed += 1;
+        }
+    }
+    if listed == 0 && !prefix.is_empty() {
+        return Err(anyhow::anyhow!("No such file in {}: /{}", args.image_name, prefix));
+    }
+    Ok(())
+}
+
+async fn remote_cat_command(args: RemoteCatArgs) -> Result<()> {
+    // Initialize tracing
+    if args.verbose > 0 {
+        let level = match args.verbose {
+            1 => "info",
+            2 => "debug",
+            _ => "trace",
+        };
+        unsafe {
+            std::env::set_var("RUST_LOG", level);
+        }
+    }
+    // Files and listings own stdout, so logs go to stderr
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+    let platform = match &args.platform {
+        Some(spec) => Platform::parse(spec)?,
+        None => Platform::host(),
+    };
+    let registry_url = Reference::parse(&args.image_name)?.registry_url();
+    let mut registry = registry_settings(&registry_url, None, args.insecure, args.skip_tls_verify)?;
+    registry.http = registry.http.with_timeouts(args.connect_timeout, args.read_timeout);
+    let client = RegistryClient::new(registry_url)?.with_settings(registry)?;
+    client.ping().await?;
+    let image = client.open_lazy(&args.image_name, &storage, &platform).await?;
+    let contents = client.read_lazy_file(&image, &args.path).await?;
+    match &args.output {
+        Some(path) => {
+            std::fs::write(path, &contents).map_err(|e| anyhow::anyhow!("Failed to write {:?}: {}", path, e))?
+        }
+        None => {
+            use std::io::Write;
+            std::io::stdout().write_all(&contents)?;
+        }
+    }
+    Ok(())
+}
+
+// A file as `remote ls` shows it: type and permissions, owner, size,
+// modification time and path, like tar -tv
+fn file_line(name: &str, entry: &TocEntry) -> String {
+    let mut mode = match entry.kind.as_str() {
+        "dir" => "d",
+        "symlink" => "l",
+        "char" => "c",
+        "block" => "b",
+        "fifo" => "p",
+        _ => "-",
+    }
+    .to_string();
+    for (bit, flag) in [(8, 'r'), (7, 'w'), (6, 'x'), (5, 'r'), (4, 'w'), (3, 'x'), (2, 'r'), (1, 'w'), (0, 'x')] {
+        mode.push(if entry.mode & (1 << bit) != 0 { flag } else { '-' });
+    }
+    let target = match entry.kind.as_str() {
+        "symlink" => format!(" -> {}", entry.link_name),
+        "hardlink" => format!(" link to /{}", clean_toc_name(&entry.link_name)),
+        _ => String::new(),
+    };
+    format!("{} {}/{} {:>10} {} /{}{}", mode, entry.uid, entry.gid, entry.size, entry.mod_time, name, target)
+}
+
+// Parse an artifact `FILE[:MEDIA_TYPE]`; media types always have a slash,
+// which tells them apart from colons in the file name
+fn parse_artifact_file(spec: &str, default_media_type: &str) -> (PathBuf, String) {
+    match spec.rsplit_once(':') {
+        Some((path, media_type)) if media_type.contains('/') && !path.is_empty() => {
+            (PathBuf::from(path), media_type.to_string())
+        }
+        _ => (PathBuf::from(spec), default_media_type.to_string()),
+    }
+}
+
+async fn manifest_inspect_command(args: ManifestInspectArgs) -> Result<()> {
+    let registry_url = Reference::parse(&args.image_name)?.registry_url();
+    let registry = registry_settings(&registry_url, None, args.insecure, args.skip_tls_verify)?;
+    let client = RegistryClient::new(registry_url)?.with_settings(registry)?;
+    let manifest = client.get_manifest(&args.image_name).await?;
+
+    // Only the bytes go to stdout, so they can be saved or hashed as they are
+    if args.raw {
+        use std::io::Write;
+        std::io::stdout().write_all(&manifest.bytes)?;
+        eprintln!("Digest: {}", manifest.digest);
+        return Ok(());
+    }
+
+    let mut document = manifest_json(&manifest)?;
+    document["name"] = args.image_name.clone().into();
+    if manifest.is_index() {
+        let mut platforms = Vec::new();
+        for (platform, manifest) in client.get_index_manifests(&args.image_name, &manifest).await? {
+            let mut entry = manifest_json(&manifest)?;
+            entry["platform"] = platform.map(|platform| platform.to_string()).into();
+            platforms.push(entry);
+        }
+        document["platforms"] = platforms.into();
+    }
+    println!("{}", serde_json::to_string_pretty(&document)?);
+    Ok(())
+}
+
+// A fetched manifest with its digest, media type and size
+fn manifest_json(manifest: &FetchedManifest) -> Result<serde_json::Value> {
+    let parsed: serde_json::Value = serde_json::from_slice(&manifest.bytes)?;
+    let media_type = manifest
+        .content_type()
+        .map(str::to_string)
+        .or_else(|| parsed["mediaType"].as_str().map(str::to_string));
+    Ok(serde_json::json!({
+        "digest": manifest.digest,
+        "mediaType": media_type,
+        "size": manifest.bytes.len(),
+        "manifest": parsed,
+    }))
+}
+
+// The store a command works on: --output-dir, else the configured default,
+// with the configured compression and encryption key
+fn open_store(output_dir: Option<PathBuf>) -> Result<StorageManager> {
+    let settings = Settings::load(None)?;
+    Ok(StorageManager::new(settings.store_root(output_dir))?
+        .with_compression(settings.compression)
+        .with_encryption(settings.encryption.key()?))
+}
+
+// A display for push and pull transfers: live bars on a terminal, periodic
+// lines otherwise or when verbose logs share stdout
+fn transfer_progress(verbose: u8) -> (Progress, tokio::task::JoinHandle<()>) {
+    let mode = if verbose > 0 { ProgressMode::Plain } else { ProgressMode::Auto };
+    let (progress, events) = Progress::channel();
+    (progress, tokio::spawn(progress::render(mode, None, events)))
+}
+
+// The config file's [registry] settings, with --parallel, --insecure and
+// --skip-tls-verify applied for the registry at `registry_url`
+fn registry_settings(
+    registry_url: &str,
+    parallel: Option<usize>,
+    insecure: bool,
+    skip_verify: bool,
+) -> Result<RegistrySettings> {
+    let mut settings = Settings::load(None)?.registry.with_host(registry_url, |host| {
+        host.insecure |= insecure;
+        host.skip_verify |= skip_verify;
+    });
+    if let Some(parallel) = parallel {
+        settings.parallel = parallel;
+    }
+    settings.validate()?;
+    Ok(settings)
+}
+
+// Parse a memory size such as 512m or 2g into bytes
+fn parse_size(spec: &str) -> Result<u64> {
+    let spec = spec.trim().to_lowercase();
+    let (number, scale) = match spec.trim_end_matches('b').char_indices().last() {
+        Some((idx, 'k')) => (&spec[..idx], 1u64 << 10),
+        Some((idx, 'm')) => (&spec[..idx], 1 << 20),
+        Some((idx, 'g')) => (&spec[..idx], 1 << 30),
+        _ => (spec.as_str(), 1),
+    };
+    let value: u64 = number
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid size {:?}, expected e.g. 512m or 2g", spec))?;
+    Ok(value * scale)
+}
+
+// Read SOURCE_DATE_EPOCH from the environment, falling back to the Unix epoch
+fn source_date_epoch() -> Result<u64> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid SOURCE_DATE_EPOCH {:?}: expected seconds since the Unix epoch", value)),
+        Err(_) => Ok(0),
+    }
+}
+
+// Parse a `--cache-to` value such as `type=inline`
+fn parse_cache_to(spec: &str) -> Result<bool> {
+    for part in spec.split(',') {
+        if let Some(("type", cache_type)) = part.split_once('=') {
+            return match cache_type {
+                "inline" => Ok(true),
+                other => Err(anyhow::anyhow!("Unsupported cache export type: {}", other)),
+            };
+        }
+    }
+    Err(anyhow::anyhow!("--cache-to requires a type, e.g. type=inline"))
+}
+
+// Merge --build-arg-file contents with --build-arg flags, which take precedence
+fn collect_build_args(flags: &[String], files: &[PathBuf]) -> Result<HashMap<String, String>> {
+    let mut build_args = HashMap::new();
+
+    for file in files {
+        build_args.extend(read_build_arg_file(file)?);
+    }
+
+    for flag in flags {
+        match flag.split_once('=') {
+            Some((key, value)) => {
+                build_args.insert(key.to_string(), value.to_string());
+            }
+            None => {
+                // Like docker, a bare KEY takes its value from the environment
+                if let Ok(value) = std::env::var(flag) {
+                    build_args.insert(flag.clone(), value);
+                }
+            }
+        }
+    }
+
+    Ok(build_args)
+}
+
+fn read_build_arg_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read build arg file {:?}: {}", path, e))?;
+
+    let mut build_args = Vec::new();
+    for (line_no, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line.split_once('=').ok_or_else(|| {
+            anyhow::anyhow!("{:?} line {}: expected KEY=VALUE", path, line_no + 1)
+        })?;
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        build_args.push((key.trim().to_string(), value.to_string()));
+    }
+
+    Ok(build_args)
+}
+
+fn logs_command(args: LogsArgs) -> Result<()> {
+    let storage = open_store(args.output_dir)?;
+    let (dir, report) = report::load_report(&storage.builds_dir(), args.build_id.as_deref())?;
+
+    let steps: Vec<_> = report
+        .steps
+        .iter()
+        .filter(|step| args.step.is_none_or(|id| step.id == id))
+        .collect();
+    if steps.is_empty() {
+        return Err(anyhow::anyhow!("Build {} has no step {}", report.build_id, args.step.unwrap_or_default()));
+    }
+
+    for step in steps {
+        println!("#{} {}", step.id, step.name);
+        if let Some(log) = &step.log {
+            let contents = std::fs::read_to_string(dir.join(log))?;
+            for line in contents.lines() {
+                println!("#{} {}", step.id, line);
+            }
+        }
+        match (&step.status, &step.error) {
+            (StepStatus::Failed, Some(error)) => println!("#{} ERROR: {}", step.id, error),
+            (StepStatus::Failed, None) => println!("#{} ERROR", step.id),
+            (StepStatus::Cached, _) => println!("#{} CACHED", step.id),
+            (StepStatus::Done, _) => println!("#{} DONE", step.id),
+            (StepStatus::Running, _) => println!("#{} INCOMPLETE", step.id),
+        }
+    }
+    Ok(())
+}
+
+async fn images_command(args: ImagesArgs) -> Result<()> {
+    let columns = if args.columns.is_empty() {
+        Column::ALL.to_vec()
+    } else {
+        args.columns.iter().map(|name| Column::parse(name)).collect::<Result<Vec<_>>>()?
+    };
+
+    let filters = args.filters.iter().map(|spec| images::Filter::parse(spec)).collect::<Result<Vec<_>>>()?;
+
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+    let list = images::list(&storage, &filters).await?;
+
+    match args.format.as_str() {
+        "table" => print!("{}", images::render_table(&list, &columns)),
+        "json" => println!("{}", serde_json::to_string_pretty(&list)?),
+        other => return Err(anyhow::anyhow!("Unknown format {:?}: expected table or json", other)),
+    }
+    Ok(())
+}
+
+async fn rmi_command(args: RmiArgs) -> Result<()> {
+    if args.remote {
+        for image in &args.images {
+            let registry_url = Reference::parse(image)?.registry_url();
+            let registry = registry_settings(&registry_url, None, args.insecure, args.skip_tls_verify)?;
+            let client = RegistryClient::new(registry_url)?.with_settings(registry)?;
+            let digest = client.delete_manifest(image).await?;
+            println!("Deleted: {} ({})", image, digest);
+        }
+        return Ok(());
+    }
+
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+
+    for image in &args.images {
+        let removed = storage.remove_reference(image).await?;
+        for name in removed.untagged {
+            println!("Untagged: {}", name);
+        }
+        for id in removed.deleted {
+            println!("Deleted: {}", id);
+        }
+    }
+    Ok(())
+}
+
+async fn tag_command(args: TagArgs) -> Result<()> {
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+    storage.tag(&args.source, &args.target).await
+}
+
+async fn gc_command(args: GcArgs) -> Result<()> {
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+
+    let report = storage.gc(args.dry_run).await?;
+    let verb = if args.dry_run { "Would remove" } else { "Removed" };
+    for path in &report.removed {
+        println!("{} {}", verb, path.display());
+    }
+    let verb = if args.dry_run { "Would free" } else { "Freed" };
+    println!("{} {} in {} blobs", verb, report::human_size(report.bytes), report.removed.len());
+    Ok(())
+}
+
+async fn prune_command(args: PruneArgs) -> Result<()> {
+    let options = PruneOptions {
+        until: args
+            .until
+            .as_deref()
+            .map(|until| parse_duration(until).map(std::time::Duration::from_nanos))
+            .transpose()?,
+        keep_storage: args.keep_storage.as_deref().map(parse_size).transpose()?,
+        dry_run: args.dry_run,
+    };
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+
+    let report = storage.prune(&options).await?;
+    let verb = if args.dry_run { "Would delete" } else { "Deleted" };
+    for id in &report.deleted {
+        println!("{}: {}", verb, id);
+    }
+    println!("Total reclaimed space: {}", report::human_size(report.bytes));
+    Ok(())
+}
+
+async fn df_command(args: DfArgs) -> Result<()> {
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+    let usage = storage.disk_usage().await?;
+
+    match args.format.as_str() {
+        "table" => print!("{}", render_usage(&usage)),
+        "json" => println!("{}", serde_json::to_string_pretty(&usage)?),
+        other => return Err(anyhow::anyhow!("Unknown format {:?}: expected table or json", other)),
+    }
+    Ok(())
+}
+
+fn render_usage(usage: &[Usage]) -> String {
+    let mut rows = vec![["TYPE".to_string(), "COUNT".to_string(), "SIZE".to_string(), "RECLAIMABLE".to_string()]];
+    for entry in usage {
+        let percent = (entry.reclaimable * 100).checked_div(entry.size).unwrap_or(0);
+        rows.push([
+            entry.kind.to_string(),
+            entry.count.to_string(),
+            report::human_size(entry.size),
+            format!("{} ({}%)", report::human_size(entry.reclaimable), percent),
+        ]);
+    }
+
+    let mut widths = [0usize; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut table = String::new();
+    for row in &rows {
+        let line = format!(
+            "{:<w0$}  {:>w1$}  {:>w2$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2]
+        );
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+async fn verify_command(args: VerifyArgs) -> Result<()> {
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+
+    let report = storage.verify(args.repair).await?;
+    for problem in &report.problems {
+        println!("{}: {}", problem.subject, problem.message);
+    }
+    for removed in &report.removed {
+        println!("Removed {}", removed);
+    }
+    println!(
+        "Checked {} blobs and {} images: {} problems",
+        report.blobs,
+        report.images,
+        report.problems.len()
+    );
+    if !report.problems.is_empty() && !args.repair {
+        return Err(anyhow::anyhow!("The store is damaged; `verify --repair` deletes the damaged entries"));
+    }
+    Ok(())
+}
+
+async fn save_command(args: SaveArgs) -> Result<()> {
+    let output = match args.format.as_str() {
+        "oci" => BuildOutput::Oci { dest: args.dest },
+        "oci-dir" => BuildOutput::OciDir { dest: args.dest },
+        "docker" => BuildOutput::Docker { dest: args.dest },
+        other => return Err(anyhow::anyhow!("Unknown format {:?}: expected oci, oci-dir or docker", other)),
+    };
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+
+    if let Some(list) = storage.get_image_list_by_name(&args.image).await? {
+        tokio::task::spawn_blocking(move || export_image_list(&output, &list)).await??;
+    } else if let Some(image) = storage.get_image_by_name(&args.image).await? {
+        tokio::task::spawn_blocking(move || export_image(&output, &image)).await??;
+    } else {
+        return Err(anyhow::anyhow!("No such image: {}", args.image));
+    }
+    Ok(())
+}
+
+// A stored image by name, or the image for `platform` of a stored image index
+async fn stored_image_for_platform(storage: &StorageManager, name: &str, platform: &Platform) -> Result<Image> {
+    match storage.get_image_by_name_for_platform(name, platform).await? {
+        Some(image) => Ok(image),
+        None if storage.get_image_list_by_name(name).await?.is_some() => {
+            Err(anyhow::anyhow!("Image {} has no {} variant", name, platform))
+        }
+        None => storage
+            .get_image_by_name(name)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("No such image: {}", name)),
+    }
+}
+
+async fn export_command(args: ExportArgs) -> Result<()> {
+    let format = match args.format.as_deref() {
+        Some(format) => format,
+        None if args.dest.extension().is_some_and(|extension| extension == "tar") => "tar",
+        None => "dir",
+    };
+    if !matches!(format, "tar" | "dir") {
+        return Err(anyhow::anyhow!("Unknown format {:?}: expected tar or dir", format));
+    }
+    let platform = match &args.platform {
+        Some(spec) => Platform::parse(spec)?,
+        None => Platform::host(),
+    };
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+    let image = stored_image_for_platform(&storage, &args.image, &platform).await?;
+
+    if format == "dir" {
+        // Whiteouts would delete files already there, so only a new or empty directory will do
+        if args.dest.exists() && std::fs::read_dir(&args.dest)?.next().is_some() {
+            return Err(anyhow::anyhow!("Destination {:?} is not empty", args.dest));
+        }
+        storage.extract_layers(&image.layers, &args.dest).await?;
+    } else {
+        // Layers are merged aside, then archived
+        let rootfs = storage.tmp_dir().join(format!("export_{}", uuid::Uuid::new_v4()));
+        let exported = async {
+            storage.extract_layers(&image.layers, &rootfs).await?;
+            let (output, rootfs) = (BuildOutput::Tar { dest: args.dest.clone() }, rootfs.clone());
+            tokio::task::spawn_blocking(move || export_rootfs(&output, &rootfs)).await?
+        }
+        .await;
+        let _ = tokio::fs::remove_dir_all(&rootfs).await;
+        exported?;
+    }
+    println!("Exported {} ({} layers) to {}", args.image, image.layers.len(), args.dest.display());
+    Ok(())
+}
+
+async fn dive_command(args: DiveArgs) -> Result<()> {
+    let format = match args.format.as_deref() {
+        Some(format) => format,
+        None if std::io::IsTerminal::is_terminal(&std::io::stdout()) => "tui",
+        None => "text",
+    };
+    if !matches!(format, "tui" | "text" | "json") {
+        return Err(anyhow::anyhow!("Unknown format {:?}: expected tui, text or json", format));
+    }
+    let platform = match &args.platform {
+        Some(spec) => Platform::parse(spec)?,
+        None => Platform::host(),
+    };
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+    let image = stored_image_for_platform(&storage, &args.image, &platform).await?;
+    let analysis = tokio::task::spawn_blocking(move || dive::analyze(&image)).await??;
+
+    match format {
+        "tui" => {
+            let analysis = analysis.clone();
+            tokio::task::spawn_blocking(move || explorer::explore(&analysis)).await??
+        }
+        "json" => println!("{}", serde_json::to_string_pretty(&analysis)?),
+        _ => print!("{}", dive::render_report(&analysis, 20)),
+    }
+    if let Some(lowest) = args.lowest_efficiency
+        && analysis.efficiency < lowest
+    {
+        return Err(anyhow::anyhow!(
+            "{} has an efficiency score of {:.1} %, below the lowest allowed {:.1} %",
+            args.image,
+            analysis.efficiency * 100.0,
+            lowest * 100.0
+        ));
+    }
+    Ok(())
+}
+
+async fn diff_command(args: DiffArgs) -> Result<()> {
+    // The diff owns stdout
+    logging::init(args.verbose, "text", true)?;
+    if !matches!(args.format.as_str(), "text" | "json") {
+        return Err(anyhow::anyhow!("Unknown format {:?}: expected text or json", args.format));
+    }
+    let platform = match &args.platform {
+        Some(spec) => Platform::parse(spec)?,
+        None => Platform::host(),
+    };
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+    let mut images = Vec::new();
+    for name in [&args.old, &args.new] {
+        let image = if storage.resolve(name).await?.is_some() {
+            stored_image_for_platform(&storage, name, &platform).await?
+        } else {
+            let registry_url = Reference::parse(name)?.registry_url();
+            let registry = registry_settings(&registry_url, None, args.insecure, args.skip_tls_verify)?;
+            let client = RegistryClient::new(registry_url)?.with_settings(registry)?;
+            client.ping().await?;
+            eprintln!("Pulling {}", name);
+            client.pull_image_to_storage(name, &storage, &platform).await?
+        };
+        images.push(image);
+    }
+    let (old, new) = (images.remove(0), images.remove(0));
+    let (old_name, new_name) = (args.old.clone(), args.new.clone());
+    let mut image_diff = tokio::task::spawn_blocking(move || diff::diff_images(&old, &new)).await??;
+    // Name the images as asked, not by the name they are stored under
+    image_diff.old = old_name;
+    image_diff.new = new_name;
+
+    match args.format.as_str() {
+        "json" => println!("{}", serde_json::to_string_pretty(&image_diff)?),
+        _ => print!("{}", diff::render_text(&image_diff)),
+    }
+    Ok(())
+}
+
+async fn compose_build_command(args: ComposeBuildArgs) -> Result<()> {
+    // Build output owns stdout
+    logging::init(args.verbose, "text", true)?;
+
+    let file = match args.file {
+        Some(file) => file,
+        None => compose::DEFAULT_FILES
+            .iter()
+            .map(PathBuf::from)
+            .find(|file| file.is_file())
+            .ok_or_else(|| anyhow::anyhow!("No Compose file here; name one with -f"))?,
+    };
+    let mut project = ComposeProject::load(&file, &std::env::vars().collect())?;
+    project.select(&args.services)?;
+    project.resolve_dependencies()?;
+    if project.builds.is_empty() {
+        return Err(anyhow::anyhow!("{} has no service with a build section", file.display()));
+    }
+
+    let batch = Batch {
+        build_args: collect_build_args(&args.build_arg, &[])?,
+        no_cache: args.no_cache,
+        pull: args.pull,
+        push: args.push,
+        parallel: args.parallel,
+    };
+    let built = build_batch(project.builds, batch, args.config.as_deref(), args.output_dir).await?;
+    eprintln!("Built {}: {}", project.name, built.join(", "));
+    Ok(())
+}
+
+async fn bake_command(args: BakeArgs) -> Result<()> {
+    // Build output owns stdout
+    logging::init(args.verbose, "text", true)?;
+
+    let files = if args.file.is_empty() {
+        let found: Vec<PathBuf> =
+            bake::DEFAULT_FILES.iter().map(PathBuf::from).filter(|file| file.is_file()).collect();
+        if found.is_empty() {
+            return Err(anyhow::anyhow!("No bake file here; name one with -f"));
+        }
+        found
+    } else {
+        args.file
+    };
+    let bake_file = BakeFile::load(&files, &std::env::vars().collect())?;
+    let targets = bake_file.resolve(&args.targets)?;
+    if args.print {
+        let groups: BTreeMap<&String, _> = bake_file
+            .groups
+            .iter()
+            .map(|(name, targets)| (name, serde_json::json!({ "targets": targets })))
+            .collect();
+        let targets: BTreeMap<&String, _> = targets.iter().map(|name| (name, &bake_file.targets[name])).collect();
+        println!("{}", serde_json::to_string_pretty(&serde_json::json!({ "group": groups, "target": targets }))?);
+        return Ok(());
+    }
+    let mut builds = targets
+        .iter()
+        .map(|name| bake_file.targets[name].build(name))
+        .collect::<Result<Vec<_>>>()?;
+    compose::resolve_dependencies(&mut builds)?;
+
+    let batch = Batch {
+        build_args: collect_build_args(&args.build_arg, &[])?,
+        no_cache: args.no_cache,
+        pull: args.pull,
+        push: args.push,
+        parallel: args.parallel,
+    };
+    let built = build_batch(builds, batch, args.config.as_deref(), args.output_dir).await?;
+    eprintln!("Built {}", built.join(", "));
+    Ok(())
+}
+
+// What applies to every build of a `compose build` or `bake`
+struct Batch {
+    build_args: HashMap<String, String>,
+    no_cache: bool,
+    pull: bool,
+    push: bool,
+    parallel: Option<usize>,
+}
+
+// Build in parallel, each build waiting for those it builds FROM, and return
+// the names of those built
+async fn build_batch(
+    builds: Vec<ServiceBuild>,
+    batch: Batch,
+    config: Option<&Path>,
+    output_dir: Option<PathBuf>,
+) -> Result<Vec<String>> {
+    let settings = Settings::load(config)?;
+    let storage = StorageManager::new(settings.store_root(output_dir))?
+        .with_compression(settings.compression)
+        .with_encryption(settings.encryption.key()?);
+    storage.init().await?;
+    let parallel = batch.parallel.unwrap_or(builds.len()).max(1);
+    let width = builds.iter().map(|build| build.service.len()).max().unwrap_or(0);
+
+    // The first Ctrl+C cancels the running builds and starts no more, a second one exits at once
+    let (interrupt, interrupted) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("Cancelling builds (press Ctrl+C again to exit immediately)");
+            let _ = interrupt.send(true);
+        }
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+
+    let mut pending = builds;
+    let mut built: Vec<String> = Vec::new();
+    let mut failed: Vec<String> = Vec::new();
+    let mut running = tokio::task::JoinSet::new();
+    loop {
+        // A build FROM an image that failed to build cannot run
+        while let Some(index) =
+            pending.iter().position(|build| build.depends_on.iter().any(|service| failed.contains(service)))
+        {
+            let build = pending.remove(index);
+            println!("{:width$} | skipped: an image it builds FROM failed", build.service, width = width);
+            failed.push(build.service);
+        }
+        while running.len() < parallel && !*interrupted.borrow() {
+            let Some(index) =
+                pending.iter().position(|build| build.depends_on.iter().all(|service| built.contains(service)))
+            else {
+                break;
+            };
+            let build = pending.remove(index);
+            let mut options = BuildOptions {
+                build_args: build.args.clone(),
+                labels: build.labels.clone(),
+                no_cache: batch.no_cache || build.no_cache,
+                pull: if batch.pull || build.pull { PullPolicy::Always } else { PullPolicy::Missing },
+                registry: settings.registry.clone(),
+                plugins: Plugins::new(settings.plugins.clone()),
+                ..BuildOptions::default()
+            };
+            options.build_args.extend(batch.build_args.clone());
+            let hooks = settings.hooks.clone();
+            let (storage, registry) = (storage.clone_for_build(), settings.registry.clone());
+            let (push, interrupted) = (batch.push, interrupted.clone());
+            running.spawn(async move {
+                let service = build.service.clone();
+                let result = build_service(build, options, hooks, storage, &registry, push, width, interrupted).await;
+                (service, result)
+            });
+        }
+        let Some(finished) = running.join_next().await else {
+            break;
+        };
+        let (service, result) = finished?;
+        match result {
+            Ok(()) => built.push(service),
+            Err(e) => {
+                println!("{:width$} | error: {:#}", service, e, width = width);
+                failed.push(service);
+            }
+        }
+    }
+
+    if *interrupted.borrow() {
+        eprintln!("Build cancelled");
+        std::process::exit(130);
+    }
+    if !failed.is_empty() {
+        return Err(anyhow::anyhow!("Failed to build {}", failed.join(", ")));
+    }
+    Ok(built)
+}
+
+// Build one Compose service or bake target, printing its progress behind its name
+#[allow(clippy::too_many_arguments)]
+async fn build_service(
+    build: ServiceBuild,
+    mut options: BuildOptions,
+    hooks: Vec<Hook>,
+    storage: StorageManager,
+    registry: &RegistrySettings,
+    push: bool,
+    width: usize,
+    mut interrupted: tokio::sync::watch::Receiver<bool>,
+) -> Result<()> {
+    let build_id = format!("build_{}", uuid::Uuid::new_v4());
+    options.build_id = Some(build_id.clone());
+    options.hooks = Hooks::new(hooks, Some(build_id.clone()));
+    let mut recorder = BuildRecorder::create(&storage.builds_dir(), &build_id)?;
+    let pushed_from = storage.clone_for_build();
+    let tags: Vec<String> = std::iter::once(build.image.clone()).chain(build.tags.iter().cloned()).collect();
+
+    let mut request = BuildRequest::new(&build.context)
+        .dockerfile(&build.dockerfile)
+        .tags(tags.iter().cloned())
+        .store(storage)
+        .options(options);
+    for platform in &build.platforms {
+        request = request.platform(platform.clone());
+    }
+    let mut events = request.events();
+    let progress = push.then(Progress::channel);
+    let cancel = request.cancellation_token();
+    tokio::spawn(async move {
+        if interrupted.wait_for(|interrupted| *interrupted).await.is_ok() {
+            cancel.cancel();
+        }
+    });
+
+    let service = build.service.clone();
+    let printing = tokio::spawn(async move {
+        let mut steps = HashMap::new();
+        while let Some(event) = events.recv().await {
+            if let Err(e) = recorder.record(&event) {
+                tracing::warn!("Failed to record build progress: {}", e);
+            }
+            print_service_event(&service, width, &event, &mut steps);
+        }
+        recorder.finish()
+    });
+
+    let result = request.run().await;
+    let _ = printing.await;
+    let build_result = result?;
+    if let Some((progress, mut push_events)) = progress {
+        let service = build.service.clone();
+        let printing = tokio::spawn(async move {
+            let mut steps = HashMap::new();
+            while let Some(event) = push_events.recv().await {
+                print_service_event(&service, width, &event, &mut steps);
+            }
+        });
+        let metrics = Metrics::default();
+        let pushed = push_built(&progress, &metrics, &pushed_from, registry, &tags, &build_result, false).await;
+        drop(progress);
+        let _ = printing.await;
+        pushed?;
+    }
+    Ok(())
+}
+
+fn print_service_event(service: &str, width: usize, event: &ProgressEvent, steps: &mut HashMap<usize, String>) {
+
+    let line = match event {
+        ProgressEvent::StepStarted { id, name } => {
+            steps.insert(*id, name.clone());
+            format!("#{} {}", id, name)
+        }
+        ProgressEvent::StepCached { id, .. } => format!("#{} CACHED", id),
+        ProgressEvent::StepLog { id, line } => format!("#{} {}", id, line),
+        ProgressEvent::StepRetrying { id, attempt, retries, error } => {
+            format!("#{} retrying ({}/{}): {}", id, attempt, retries, error)
+        }
+        ProgressEvent::StepFailed { id, error } => {
+            format!("#{} ERROR {}: {}", id, steps.get(id).map(String::as_str).unwrap_or_default(), error)
+        }
+        ProgressEvent::BuildFinished { image, digest } => format!("built {} {}", image, digest),
+        _ => return,
+    };
+    println!("{:width$} | {}", service, line, width = width);
+}
+
+async fn run_command(args: RunArgs) -> Result<()> {
+    // The container's output owns stdout
+    logging::init(args.verbose, "text", true)?;
+
+    let platform = match &args.platform {
+        Some(spec) => Platform::parse(spec)?,
+        None => Platform::host(),
+    };
+    let spec = ContainerSpec {
+        command: args.command,
+        // Like docker run, --entrypoint names one program; arguments go after the image
+        entrypoint: args
+            .entrypoint
+            .map(|entrypoint| if entrypoint.is_empty() { Vec::new() } else { vec![entrypoint] }),
+        env: args
+            .env
+            .iter()
+            .map(|spec| parse_env(spec))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect(),
+        workdir: args.workdir,
+        user: args.user,
+        volumes: args.volume.iter().map(|spec| Volume::parse(spec)).collect::<Result<_>>()?,
+        ports: args.publish.iter().map(|spec| PortMapping::parse(spec)).collect::<Result<_>>()?,
+        runtime: Runtime::parse(&args.runtime)?,
+    };
+
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+    let image = stored_image_for_platform(&storage, &args.image, &platform).await?;
+    tracing::info!("Running {} ({}) with {:?}", args.image, image.id, spec.runtime);
+    let code = run_image(&storage, &image, &spec).await?;
+    if code != 0 {
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+async fn store_backup_command(args: StoreBackupArgs) -> Result<()> {
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+
+    let report = storage.backup(&args.archive).await?;
+    println!(
+        "Backed up {} images, {} image indexes and {} blobs ({}) to {}",
+        report.images,
+        report.indexes,
+        report.blobs,
+        report::human_size(report.bytes),
+        args.archive.display()
+    );
+    Ok(())
+}
+
+async fn store_restore_command(args: StoreRestoreArgs) -> Result<()> {
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+
+    let report = storage.restore(&args.archive).await?;
+    println!(
+        "Restored {} images, {} image indexes and {} blobs ({} already in the store)",
+        report.images, report.indexes, report.blobs, report.skipped
+    );
+    Ok(())
+}
+
+async fn store_dedupe_command(args: StoreDedupeArgs) -> Result<()> {
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+
+    let report = storage.dedupe(args.dry_run).await?;
+    let verb = if args.dry_run { "Would remove" } else { "Removed" };
+    for (path, digest) in &report.duplicates {
+        println!("{} {} (same as blob {})", verb, path.display(), digest);
+    }
+    let verb = if args.dry_run { "Would free" } else { "Freed" };
+    println!("{} {} in {} duplicate layer files", verb, report::human_size(report.bytes), report.duplicates.len());
+    if !report.unique.is_empty() {
+        println!("{} legacy layer files have no stored copy and no image uses them; `gc` removes them", report.unique.len());
+    }
+    Ok(())
+}
+
+async fn cache_ls_command(args: CacheLsArgs) -> Result<()> {
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+    let records = storage.cache_records().await?;
+
+    match args.format.as_str() {
+        "table" => print!("{}", render_cache(&records)),
+        "json" => println!("{}", serde_json::to_string_pretty(&records)?),
+        other => return Err(anyhow::anyhow!("Unknown format {:?}: expected table or json", other)),
+    }
+    Ok(())
+}
+
+// One row per record, most recently used first, and the total size of the
+// layers they keep, counting a layer several records share once
+fn render_cache(records: &BTreeMap<String, CacheRecord>) -> String {
+    let short = |digest: &str| digest.trim_start_matches("sha256:").chars().take(12).collect::<String>();
+    let time = |secs: u64| {
+        chrono::DateTime::from_timestamp(secs as i64, 0)
+            .map(|time| time.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+            .unwrap_or_default()
+    };
+    let mut sorted: Vec<(&String, &CacheRecord)> = records.iter().collect();
+    sorted.sort_by(|a, b| b.1.last_used.cmp(&a.1.last_used).then_with(|| a.0.cmp(b.0)));
+
+    let mut rows = vec![["KEY", "LAYER", "SIZE", "LAST USED", "BUILD"].map(str::to_string)];
+    let mut layers = BTreeMap::new();
+    for (key, record) in sorted {
+        let (layer, size) = match &record.layer {
+            Some(layer) => {
+                layers.insert(&layer.digest, layer.size);
+                (short(&layer.digest), report::human_size(layer.size))
+            }
+            None => ("<none>".to_string(), "0 B".to_string()),
+        };
+        rows.push([short(key), layer, size, time(record.last_used), record.build.clone().unwrap_or_default()]);
+    }
+
+    let mut widths = [0usize; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut table = String::new();
+    for row in &rows {
+        let line = format!(
+            "{:<w0$}  {:<w1$}  {:>w2$}  {:<w3$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            row[4],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3]
+        );
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table.push_str(&format!(
+        "{} records keeping {} in {} layers\n",
+        records.len(),
+        report::human_size(layers.values().sum()),
+        layers.len()
+    ));
+    table
+}
+
+async fn cache_prune_command(args: CachePruneArgs) -> Result<()> {
+    let until = args
+        .until
+        .as_deref()
+        .map(|until| parse_duration(until).map(std::time::Duration::from_nanos))
+        .transpose()?;
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+
+    let report = storage.prune_cache(until, args.dry_run).await?;
+    let verb = if args.dry_run { "Would free" } else { "Freed" };
+    println!("{} {} by removing {} build cache records", verb, report::human_size(report.bytes), report.removed.len());
+    Ok(())
+}
+
+async fn load_command(args: LoadArgs) -> Result<()> {
+    let storage = open_store(args.output_dir)?;
+    storage.init().await?;
+
+    match args.to.as_str() {
+        "local" => {
+            for name in load_archive(&storage, &args.input, args.tag.as_deref()).await? {
+                println!("Loaded image: {}", name);
+            }
+        }
+        to @ ("containerd" | "docker") => {
+            let platform = args.platform.as_deref().map(Platform::parse).transpose()?.unwrap_or_else(Platform::host);
+            // An archive goes through the image store first; anything else names a stored
+            // image, which --tag renames in containerd or Docker
+            let images: Vec<(String, String)> = if args.input.exists() {
+                let names = load_archive(&storage, &args.input, args.tag.as_deref()).await?;
+                names.into_iter().map(|name| (name.clone(), name)).collect()
+            } else {
+                let stored = args.input.to_string_lossy().to_string();
+                vec![(stored.clone(), args.tag.clone().unwrap_or(stored))]
+            };
+
+            if to == "docker" {
+                let socket = docker::socket(args.address)?;
+                for (stored, name) in images {
+                    let image = stored_image_for_platform(&storage, &stored, &platform).await?;
+                    for loaded in docker::load(&socket, &Image { name, ..image }).await? {
+                        println!("Loaded image: {} (Docker daemon at {})", loaded, socket.display());
+                    }
+                }
+            } else {
+                let mut settings = Settings::load(None)?.containerd;
+                settings.address = args.address.or(settings.address);
+                settings.namespace = args.namespace.or(settings.namespace);
+                settings.snapshotter = args.snapshotter.or(settings.snapshotter);
+                let client = ContainerdClient::connect(&settings).await?;
+                for (stored, name) in images {
+                    let image = stored_image_for_platform(&storage, &stored, &platform).await?;
+                    let loaded = client.import(&image, &name).await?;
+                    println!("Loaded image: {} (containerd namespace {})", loaded, client.namespace());
+                }
+            }
+        }
+        other => return Err(anyhow::anyhow!("Unknown --to {:?}: expected local, containerd or docker", other)),
+    }
+    Ok(())
+}
+
+// Parse a `--secret id=ID,src=PATH` value
+fn parse_secret(spec: &str) -> Result<(String, PathBuf)> {
+    let mut id = None;
+    let mut src = None;
+
+    for part in spec.split(',') {
+        match part.split_once('=') {
+            Some(("id", value)) => id = Some(value.to_string()),
+            Some(("src" | "source", value)) => src = Some(PathBuf::from(value)),
+            Some(("type", "file")) => {}
+            _ => return Err(anyhow::anyhow!("Invalid --secret value {:?}: expected id=ID,src=PATH", spec)),
+        }
+    }
+
+    let src = src.ok_or_else(|| anyhow::anyhow!("--secret {:?} is missing src=PATH", spec))?;
+    if !src.is_file() {
+        return Err(anyhow::anyhow!("Secret file {:?} does not exist", src));
+    }
+
+    // Like docker, the id defaults to the file name
+    let id = match id {
+        Some(id) => id,
+        None => src
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or_else(|| anyhow::anyhow!("--secret {:?} is missing id=ID", spec))?,
+    };
+
+    Ok((id, src))
+}
+
+fn parse_label(spec: &str) -> Result<(String, String)> {
+    match spec.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(anyhow::anyhow!("Invalid --label {:?}: expected KEY=VALUE", spec)),
+    }
+}
+
+// Parse a `run -e` value: KEY=VALUE, or KEY for the host's value, which is
+// left out when the host does not set it
+fn parse_env(spec: &str) -> Result<Option<(String, String)>> {
+    match spec.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok(Some((key.to_string(), value.to_string()))),
+        None if !spec.is_empty() => Ok(std::env::var(spec).ok().map(|value| (spec.to_string(), value))),
+        _ => Err(anyhow::anyhow!("Invalid --env {:?}: expected KEY=VALUE or KEY", spec)),
+    }
+}
+
+// Parse an `--add-host host:ip` value; docker also accepts host=ip, which
+// avoids ambiguity with IPv6 addresses
+fn parse_add_host(spec: &str) -> Result<(String, String)> {
+    let (host, ip) = spec
+        .split_once('=')
+        .or_else(|| spec.split_once(':'))
+        .ok_or_else(|| anyhow::anyhow!("Invalid --add-host value {:?}: expected host:ip", spec))?;
+    let ip = ip.trim_start_matches('[').trim_end_matches(']');
+
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        return Err(anyhow::anyhow!("Invalid host name in --add-host {:?}", spec));
     }
+    let ip: std::net::IpAddr = ip
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid IP address in --add-host {:?}", spec))?;
 
-    // Default to Docker Hub if no registry specified
-    "https://registry-1.docker.io".to_string()
+    Ok((host.to_string(), ip.to_string()))
 }